@@ -2,7 +2,7 @@ use bytes::Bytes;
 use bytes::BytesMut;
 use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
 use redis_proto::asyncresp::RespParser;
-use redis_proto::keys::{key_interact, KeyOps};
+use redis_proto::keys::{key_interact, KeyOps, SetOptions};
 use redis_proto::ops::{op_interact, translate};
 use redis_proto::types::{RedisValueRef, ReturnValue, State, StateStore};
 use std::sync::Arc;
@@ -44,7 +44,11 @@ fn bench_interact(c: &mut Criterion) {
     let s = Arc::new(State::default());
     c.bench_function("KeyOps::Set", |b| {
         b.iter(|| async {
-            let f = KeyOps::Set(Bytes::from_static(b"foo"), Bytes::from_static(b"bar"));
+            let f = KeyOps::Set(
+                Bytes::from_static(b"foo"),
+                Bytes::from_static(b"bar"),
+                SetOptions::default(),
+            );
             key_interact(black_box(f), black_box(s.clone())).await;
         });
     });
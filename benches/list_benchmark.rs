@@ -0,0 +1,150 @@
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use redis_proto::data_structures::quicklist::{Fill, QuickList};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+/// Elements measured for the memory comparison.
+const MEMORY_ELEMENTS: usize = 1_000_000;
+/// Values returned by each range.
+const RANGE_LEN: usize = 100;
+
+/// Counts the bytes currently allocated, to measure memory per element.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn value(i: usize) -> Bytes {
+    Bytes::from(format!("value_{}", i))
+}
+
+/// What lists used to be built on: every value is its own allocation.
+fn vec_deque(size: usize) -> VecDeque<Bytes> {
+    (0..size).map(value).collect()
+}
+
+fn quicklist(size: usize, compress_depth: usize) -> QuickList {
+    let mut list = QuickList::new(Fill::default(), compress_depth);
+    (0..size).for_each(|i| list.push_back(&value(i)));
+    list
+}
+
+/// Bytes allocated per element by what `build` returns.
+fn bytes_per_element<T>(build: impl FnOnce() -> T) -> f64 {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let built = build();
+    let used = ALLOCATED.load(Ordering::Relaxed) - before;
+    drop(built);
+    used as f64 / MEMORY_ELEMENTS as f64
+}
+
+/// Not timed: print how much memory each representation takes.
+fn report_memory() {
+    let payload: usize = (0..MEMORY_ELEMENTS).map(|i| value(i).len()).sum();
+    println!(
+        "memory per element, {} elements of {:.1} bytes on average:",
+        MEMORY_ELEMENTS,
+        payload as f64 / MEMORY_ELEMENTS as f64
+    );
+    let reports = [
+        (
+            "VecDeque<Bytes>",
+            bytes_per_element(|| vec_deque(MEMORY_ELEMENTS)),
+        ),
+        (
+            "quicklist",
+            bytes_per_element(|| quicklist(MEMORY_ELEMENTS, 0)),
+        ),
+        (
+            "quicklist, compress depth 1",
+            bytes_per_element(|| quicklist(MEMORY_ELEMENTS, 1)),
+        ),
+    ];
+    for (name, bytes) in reports {
+        println!("  {:<28} {:>6.1} bytes", name, bytes);
+    }
+}
+
+fn bench_push_pop(c: &mut Criterion) {
+    report_memory();
+    let mut group = c.benchmark_group("lpush_rpop");
+    for size in SIZES {
+        let v = value(size);
+        let mut list = quicklist(size, 0);
+        group.bench_with_input(BenchmarkId::new("quicklist", size), &v, |b, v| {
+            b.iter(|| {
+                list.push_front(black_box(v));
+                list.pop_back()
+            });
+        });
+        let mut deque = vec_deque(size);
+        group.bench_with_input(BenchmarkId::new("vecdeque", size), &v, |b, v| {
+            b.iter(|| {
+                deque.push_front(black_box(v.clone()));
+                deque.pop_back()
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_index(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lindex");
+    for size in SIZES {
+        let index = size / 2;
+        for depth in [0, 1] {
+            let list = quicklist(size, depth);
+            let name = format!("quicklist_depth_{}", depth);
+            group.bench_with_input(BenchmarkId::new(name, size), &index, |b, &index| {
+                b.iter(|| list.get(black_box(index)));
+            });
+        }
+        let deque = vec_deque(size);
+        group.bench_with_input(BenchmarkId::new("vecdeque", size), &index, |b, &index| {
+            b.iter(|| deque.get(black_box(index)).cloned());
+        });
+    }
+    group.finish();
+}
+
+fn bench_range(c: &mut Criterion) {
+    let mut group = c.benchmark_group("lrange");
+    for size in SIZES {
+        let start = size / 2;
+        let list = quicklist(size, 0);
+        group.bench_with_input(BenchmarkId::new("quicklist", size), &start, |b, &start| {
+            b.iter(|| {
+                let range = list.iter().skip(black_box(start)).take(RANGE_LEN);
+                range.collect::<Vec<_>>()
+            });
+        });
+        let deque = vec_deque(size);
+        group.bench_with_input(BenchmarkId::new("vecdeque", size), &start, |b, &start| {
+            b.iter(|| {
+                let range = deque.range(black_box(start)..start + RANGE_LEN);
+                range.cloned().collect::<Vec<_>>()
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_push_pop, bench_index, bench_range);
+criterion_main!(benches);
@@ -0,0 +1,91 @@
+use bytes::Bytes;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use redis_proto::data_structures::sorted_set::{SortedSet, SortedSetMember};
+use smallvec::smallvec;
+use std::collections::BTreeSet;
+
+const SIZES: [usize; 3] = [1_000, 10_000, 100_000];
+/// Members returned by each range.
+const RANGE_LEN: usize = 10;
+
+fn member(i: usize) -> Bytes {
+    Bytes::from(format!("member_{}", i))
+}
+
+fn sorted_set(size: usize) -> SortedSet {
+    let mut zset = SortedSet::new();
+    for i in 0..size {
+        zset.add(smallvec![(i as f64, member(i))]);
+    }
+    zset
+}
+
+/// What sorted sets used to be built on, where ranks need a linear scan.
+fn btree_set(size: usize) -> BTreeSet<SortedSetMember> {
+    (0..size)
+        .map(|i| SortedSetMember {
+            score: i as f64,
+            member: member(i),
+        })
+        .collect()
+}
+
+fn bench_rank(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zrank");
+    for size in SIZES {
+        let key = member(size / 2);
+        let zset = sorted_set(size);
+        group.bench_with_input(BenchmarkId::new("skiplist", size), &key, |b, key| {
+            b.iter(|| zset.rank(black_box(key.clone())));
+        });
+        let btree = btree_set(size);
+        group.bench_with_input(BenchmarkId::new("btreeset", size), &key, |b, key| {
+            b.iter(|| btree.iter().position(|m| m.member == black_box(key)));
+        });
+    }
+    group.finish();
+}
+
+fn bench_range_by_rank(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zrange");
+    for size in SIZES {
+        let start = size / 2;
+        let zset = sorted_set(size);
+        group.bench_with_input(BenchmarkId::new("skiplist", size), &start, |b, &start| {
+            b.iter(|| {
+                let stop = start + RANGE_LEN - 1;
+                zset.range_by_rank(black_box(start as i64), black_box(stop as i64))
+            });
+        });
+        let btree = btree_set(size);
+        group.bench_with_input(BenchmarkId::new("btreeset", size), &start, |b, &start| {
+            b.iter(|| {
+                btree
+                    .iter()
+                    .skip(black_box(start))
+                    .take(RANGE_LEN)
+                    .cloned()
+                    .collect::<Vec<_>>()
+            });
+        });
+    }
+    group.finish();
+}
+
+fn bench_add_remove(c: &mut Criterion) {
+    let mut group = c.benchmark_group("zadd_zrem");
+    for size in SIZES {
+        let mut zset = sorted_set(size);
+        let key = member(size);
+        group.bench_with_input(BenchmarkId::new("skiplist", size), &key, |b, key| {
+            b.iter(|| {
+                zset.add(smallvec![(black_box(0.5), key.clone())]);
+                zset.remove(std::slice::from_ref(key))
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_rank, bench_range_by_rank, bench_add_remove);
+criterion_main!(benches);
@@ -0,0 +1,906 @@
+/// Append only file persistence.
+///
+/// Write commands are logged in RESP form after they run, and replayed
+/// on startup. BGREWRITEAOF compacts the log into the minimal set of
+/// commands needed to rebuild the current state.
+use crate::asyncresp::RespParser;
+use crate::data_structures::stream::StreamId;
+use crate::expiry::ExpiryOps;
+use crate::keys::KeyOps;
+use crate::lists::ListOps;
+use crate::logger::LOGGER;
+use crate::misc::MiscOps;
+use crate::ops::{op_interact, translate, Ops};
+use crate::sets::SetOps;
+use crate::streams::StreamOps;
+use crate::types::{Index, Key, RedisValueRef, ReturnValue, StateRef, StateStoreRef};
+use bytes::{Bytes, BytesMut};
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, OwnedMutexGuard};
+use tokio::time::interval;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Max number of items per command when rewriting big collections.
+const REWRITE_ITEMS_PER_CMD: usize = 64;
+
+const FSYNC_PERIOD_MS: u64 = 1000;
+
+/// When to fsync the append only file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every write. Slow, but nothing is lost on power failure.
+    Always,
+    /// Once per second.
+    EverySec,
+    /// Let the OS decide.
+    No,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "always" => Ok(FsyncPolicy::Always),
+            "everysec" => Ok(FsyncPolicy::EverySec),
+            "no" => Ok(FsyncPolicy::No),
+            _ => Err(format!(
+                "Unknown fsync policy {}, expected always, everysec or no",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Aof {
+    path: PathBuf,
+    writer: Arc<Mutex<AofWriter>>,
+    rewriting: AtomicBool,
+}
+
+pub struct AofWriter {
+    file: File,
+    policy: FsyncPolicy,
+    /// The db selected at the end of the file, if known.
+    db: Option<Index>,
+    /// Set when there are writes that haven't been fsynced yet.
+    dirty: bool,
+    /// Commands logged while a rewrite is running, along with the db
+    /// selected at their end. Appended to the rewritten file.
+    rewrite_buffer: Option<(BytesMut, Option<Index>)>,
+    /// Set while logging a transaction, to whether its MULTI was logged.
+    transaction: Option<bool>,
+}
+
+fn command(args: Vec<Bytes>) -> RedisValueRef {
+    RedisValueRef::Array(args.into_iter().map(RedisValueRef::BulkString).collect())
+}
+
+/// The command logged for a key evicted by maxmemory.
+pub fn eviction(key: Key) -> RedisValueRef {
+    command(vec![Bytes::from_static(b"DEL"), key])
+}
+
+fn pexpireat(key: Key, deadline: i64) -> RedisValueRef {
+    command(vec![
+        Bytes::from_static(b"PEXPIREAT"),
+        key,
+        deadline.to_string().into(),
+    ])
+}
+
+/// Encode `commands`, selecting `db` first if needed.
+fn encode_commands(
+    selected: &mut Option<Index>,
+    db: Index,
+    commands: &[RedisValueRef],
+    dst: &mut BytesMut,
+) -> io::Result<()> {
+    let mut encoder = RespParser::default();
+    if *selected != Some(db) {
+        let select = command(vec![Bytes::from_static(b"SELECT"), db.to_string().into()]);
+        encoder.encode(select, dst)?;
+        *selected = Some(db);
+    }
+    for command in commands {
+        encoder.encode(command.clone(), dst)?;
+    }
+    Ok(())
+}
+
+impl AofWriter {
+    /// Log `commands`, which ran against `db`.
+    pub fn append(&mut self, db: Index, mut commands: Vec<RedisValueRef>) -> io::Result<()> {
+        if commands.is_empty() {
+            return Ok(());
+        }
+        if self.transaction == Some(false) {
+            commands.insert(0, command(vec![Bytes::from_static(b"MULTI")]));
+            self.transaction = Some(true);
+        }
+        let mut buf = BytesMut::new();
+        encode_commands(&mut self.db, db, &commands, &mut buf)?;
+        if let Some((rewrite_buffer, rewrite_db)) = self.rewrite_buffer.as_mut() {
+            encode_commands(rewrite_db, db, &commands, rewrite_buffer)?;
+        }
+        self.file.write_all(&buf)?;
+        match self.policy {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::EverySec => self.dirty = true,
+            FsyncPolicy::No => {}
+        }
+        Ok(())
+    }
+
+    /// Log what's appended until `end_transaction` as one MULTI/EXEC
+    /// block, which replay applies whole or not at all.
+    pub fn begin_transaction(&mut self) {
+        self.transaction = Some(false);
+    }
+
+    pub fn end_transaction(&mut self) -> io::Result<()> {
+        match self.transaction.take() {
+            // Nothing was logged, so there's nothing to close.
+            Some(false) | None => Ok(()),
+            Some(true) => {
+                let db = self.db.unwrap_or_default();
+                self.append(db, vec![command(vec![Bytes::from_static(b"EXEC")])])
+            }
+        }
+    }
+}
+
+impl Aof {
+    pub fn open(path: PathBuf, policy: FsyncPolicy) -> io::Result<Aof> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Aof {
+            path,
+            writer: Arc::new(Mutex::new(AofWriter {
+                file,
+                policy,
+                db: None,
+                dirty: false,
+                rewrite_buffer: None,
+                transaction: None,
+            })),
+            rewriting: AtomicBool::new(false),
+        })
+    }
+
+    /// Lock the log. Hold the lock while running a write, so commands
+    /// are logged in the order they ran.
+    pub fn lock(&self) -> impl Future<Output = OwnedMutexGuard<AofWriter>> + Send + 'static {
+        self.writer.clone().lock_owned()
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.rewriting.load(Ordering::SeqCst)
+    }
+}
+
+/// How a write operation is recorded in the log.
+pub enum Record {
+    /// Log the command as it was sent.
+    Verbatim,
+    /// Blocking pops are logged as plain pops of the key they popped from,
+    /// if they popped anything.
+    Pop(&'static [u8]),
+    /// Other blocking list ops are logged only if they were served,
+    /// and replayed without blocking.
+    Served,
+    /// SPOP is random, so log the members it removed instead.
+    SPop(Key),
+    /// Relative expiry times are logged as absolute ones.
+    Expire(Key),
+    /// Log the command, followed by the absolute expiry time of the key.
+    WithExpiry(Key),
+    /// XADD IDs may be generated, so log the ID it added with.
+    /// Holds the number of field / value pairs, which end the command.
+    XAdd(usize),
+    /// Claims depend on idle times, so log the ownership of each claimed
+    /// entry and the acknowledgement of deleted ones instead, as redis does.
+    /// Holds the key, group and consumer, and the IDs asked for by XCLAIM.
+    Claim(Key, Key, Key, Option<Vec<StreamId>>),
+}
+
+impl Record {
+    /// How to record `op`, or None if it doesn't modify anything.
+    pub fn of(op: &Ops) -> Option<Record> {
+        let record = match op {
+            Ops::Misc(MiscOps::FlushAll()) | Ops::Misc(MiscOps::FlushDB()) => Record::Verbatim,
+            Ops::Lists(ListOps::BLPop(..)) => Record::Pop(b"LPOP"),
+            Ops::Lists(ListOps::BRPop(..)) => Record::Pop(b"RPOP"),
+            Ops::Lists(ListOps::BLMove(..)) | Ops::Lists(ListOps::BLMPop(..)) => Record::Served,
+            Ops::Sets(SetOps::SPop(key, _)) => Record::SPop(key.clone()),
+            Ops::Expiry(ExpiryOps::Expire(key, _))
+            | Ops::Expiry(ExpiryOps::PExpire(key, _))
+            | Ops::Expiry(ExpiryOps::ExpireAt(key, _))
+            | Ops::Expiry(ExpiryOps::PExpireAt(key, _)) => Record::Expire(key.clone()),
+            Ops::Streams(StreamOps::XAdd(_, _, fields, ..)) => Record::XAdd(fields.len()),
+            Ops::Streams(StreamOps::XClaim(key, group, consumer, ids, _)) => Record::Claim(
+                key.clone(),
+                group.clone(),
+                consumer.clone(),
+                Some(ids.to_vec()),
+            ),
+            Ops::Streams(StreamOps::XAutoClaim(key, group, consumer, ..)) => {
+                Record::Claim(key.clone(), group.clone(), consumer.clone(), None)
+            }
+            // Relative SET expiry times need the absolute time after them.
+            Ops::Keys(KeyOps::Set(key, _, options)) if options.expiry.is_some() => {
+                Record::WithExpiry(key.clone())
+            }
+            op if op.is_write() => Record::Verbatim,
+            _ => return None,
+        };
+        Some(record)
+    }
+
+    /// The commands to log for `command`, given its result.
+    pub fn commands(
+        self,
+        command: RedisValueRef,
+        res: &ReturnValue,
+        state: &StateRef,
+    ) -> Vec<RedisValueRef> {
+        if res.is_error() {
+            return Vec::new();
+        }
+        // Inline commands arrive as a single bulk string.
+        let command = match command {
+            array @ RedisValueRef::Array(_) => array,
+            other => RedisValueRef::Array(vec![other]),
+        };
+        match self {
+            Record::Verbatim => vec![command],
+            Record::Pop(pop) => match res {
+                ReturnValue::MultiStringRes(popped) if !popped.is_empty() => {
+                    vec![self::command(vec![
+                        Bytes::from_static(pop),
+                        popped[0].clone(),
+                    ])]
+                }
+                _ => Vec::new(),
+            },
+            Record::Served => match res {
+                ReturnValue::Nil => Vec::new(),
+                _ => vec![command],
+            },
+            Record::SPop(key) => match res {
+                ReturnValue::MultiStringRes(members) if !members.is_empty() => {
+                    let mut args = vec![Bytes::from_static(b"SREM"), key];
+                    args.extend(members.iter().cloned());
+                    vec![self::command(args)]
+                }
+                _ => Vec::new(),
+            },
+            Record::Expire(key) => match state.expirations.get(&key) {
+                Some(deadline) => vec![pexpireat(key, deadline)],
+                // A deadline in the past deletes the key.
+                None if *res == ReturnValue::IntRes(1) => {
+                    vec![self::command(vec![Bytes::from_static(b"DEL"), key])]
+                }
+                None => Vec::new(),
+            },
+            Record::WithExpiry(key) => {
+                let mut commands = vec![command];
+                if let Some(deadline) = state.expirations.get(&key) {
+                    commands.push(pexpireat(key, deadline));
+                }
+                commands
+            }
+            Record::XAdd(pairs) => match (res, command) {
+                (ReturnValue::StringRes(id), RedisValueRef::Array(mut args)) => {
+                    if let Some(index) = args.len().checked_sub(2 * pairs + 1) {
+                        args[index] = RedisValueRef::BulkString(id.clone());
+                    }
+                    vec![RedisValueRef::Array(args)]
+                }
+                // NOMKSTREAM on a missing stream.
+                _ => Vec::new(),
+            },
+            Record::Claim(key, group, consumer, ids) => {
+                claim_commands(key, group, consumer, ids, res, state)
+            }
+        }
+    }
+}
+
+/// The commands replaying an XCLAIM or XAUTOCLAIM that replied `res`.
+fn claim_commands(
+    key: Key,
+    group: Key,
+    consumer: Key,
+    ids: Option<Vec<StreamId>>,
+    res: &ReturnValue,
+    state: &StateRef,
+) -> Vec<RedisValueRef> {
+    let stream = match state.streams.get(&key) {
+        Some(stream) => stream,
+        None => return Vec::new(),
+    };
+    let consumer_group = match stream.group(&group) {
+        Some(consumer_group) => consumer_group,
+        None => return Vec::new(),
+    };
+    let reply_ids = |reply: &ReturnValue| -> Vec<StreamId> {
+        let ids = match reply {
+            ReturnValue::Array(ids) => ids,
+            _ => return Vec::new(),
+        };
+        ids.iter()
+            .filter_map(|id| match id {
+                ReturnValue::StringRes(id) => StreamId::parse(id, 0),
+                ReturnValue::Array(entry) => match entry.first() {
+                    Some(ReturnValue::StringRes(id)) => StreamId::parse(id, 0),
+                    _ => None,
+                },
+                _ => None,
+            })
+            .collect()
+    };
+    let (claimed, deleted) = match (res, ids) {
+        (ReturnValue::Array(parts), None) if parts.len() == 3 => {
+            (reply_ids(&parts[1]), reply_ids(&parts[2]))
+        }
+        (res, Some(ids)) => {
+            let deleted = ids.into_iter().filter(|id| stream.get(id).is_none());
+            (reply_ids(res), deleted.collect())
+        }
+        _ => return Vec::new(),
+    };
+    let name = |name: &'static [u8]| Bytes::from_static(name);
+    // Claiming creates the consumer, even if nothing was claimed.
+    let mut commands = vec![command(vec![
+        name(b"XGROUP"),
+        name(b"CREATECONSUMER"),
+        key.clone(),
+        group.clone(),
+        consumer.clone(),
+    ])];
+    for id in claimed {
+        if let Some(pending) = consumer_group.pending.get(&id) {
+            commands.push(command(vec![
+                name(b"XCLAIM"),
+                key.clone(),
+                group.clone(),
+                consumer.clone(),
+                name(b"0"),
+                id.to_string().into(),
+                name(b"TIME"),
+                pending.delivered_at.to_string().into(),
+                name(b"RETRYCOUNT"),
+                pending.delivery_count.to_string().into(),
+                name(b"FORCE"),
+                name(b"JUSTID"),
+                name(b"LASTID"),
+                consumer_group.last_delivered.to_string().into(),
+            ]));
+        }
+    }
+    if !deleted.is_empty() {
+        let mut args = vec![name(b"XACK"), key, group];
+        args.extend(deleted.iter().map(|id| Bytes::from(id.to_string())));
+        commands.push(command(args));
+    }
+    commands
+}
+
+/// The key and payload of a value a rewrite logged whole, as
+/// `RESTORE key 0 payload`. Clients can't send RESTORE, so it's only
+/// understood here.
+fn restored_value(command: &RedisValueRef) -> Option<(Key, &[u8])> {
+    match command {
+        RedisValueRef::Array(args) => match &args[..] {
+            [RedisValueRef::BulkString(name), RedisValueRef::BulkString(key), _, RedisValueRef::BulkString(payload)]
+                if name.eq_ignore_ascii_case(b"RESTORE") =>
+            {
+                Some((key.clone(), payload))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Whether `command` is the bare command `name`, like MULTI or EXEC.
+fn is_command(command: &RedisValueRef, name: &[u8]) -> bool {
+    match command {
+        RedisValueRef::Array(args) => match &args[..] {
+            [RedisValueRef::BulkString(arg)] => arg.eq_ignore_ascii_case(name),
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Replay a single logged command against `state`.
+async fn replay_command(
+    command: RedisValueRef,
+    state_store: &StateStoreRef,
+    state: &mut StateRef,
+) -> io::Result<()> {
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    if let Some((key, payload)) = restored_value(&command) {
+        if !state.restore_key(key.clone(), payload) {
+            return Err(invalid(format!("Bad RESTORE payload for {:?}", key)));
+        }
+        state.track_memory(&[key], true);
+        return Ok(());
+    }
+    let op = translate(command, state_store.clone())
+        .map_err(|e| invalid(format!("Bad command in append only file: {:?}", e)))?;
+    match op {
+        Ops::Misc(MiscOps::Select(index)) => *state = state_store.get_or_create(index),
+        Ops::Misc(MiscOps::FlushAll()) => state_store.flush_all(),
+        Ops::Misc(MiscOps::FlushDB()) => state.clear(),
+        Ops::Misc(_) | Ops::PubSub(_) => {
+            return Err(invalid(format!("Unexpected command {:?}", op)));
+        }
+        op => {
+            op_interact(op.without_blocking(), state.clone()).await;
+        }
+    }
+    Ok(())
+}
+
+/// Replay the log at `path` into `state_store`.
+///
+/// Returns the number of commands replayed, and the length of the valid
+/// part of the file. An incomplete last command (e.g. from a crash
+/// halfway through a write) is ignored, and so is a transaction missing
+/// its EXEC.
+pub async fn replay(path: &Path, state_store: &StateStoreRef) -> io::Result<(usize, u64)> {
+    let contents = std::fs::read(path)?;
+    let mut buf = BytesMut::from(&contents[..]);
+    let mut decoder = RespParser::default();
+    let mut state = state_store.get_default();
+    let mut replayed = 0;
+    // The commands of a transaction, and where its MULTI starts.
+    let mut transaction: Option<(Vec<RedisValueRef>, u64)> = None;
+    let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+    loop {
+        let start = (contents.len() - buf.len()) as u64;
+        let command = match decoder
+            .decode(&mut buf)
+            .map_err(|e| invalid(format!("Bad command in append only file: {:?}", e)))?
+        {
+            Some(command) => command,
+            None => break,
+        };
+        if is_command(&command, b"MULTI") && transaction.is_none() {
+            transaction = Some((Vec::new(), start));
+        } else if is_command(&command, b"EXEC") {
+            let (queued, _) = transaction
+                .take()
+                .ok_or_else(|| invalid("EXEC without MULTI in append only file".to_string()))?;
+            for command in queued {
+                replay_command(command, state_store, &mut state).await?;
+                replayed += 1;
+            }
+        } else if let Some((queued, _)) = transaction.as_mut() {
+            queued.push(command);
+        } else {
+            replay_command(command, state_store, &mut state).await?;
+            replayed += 1;
+        }
+    }
+    let valid_len = match transaction {
+        Some((_, start)) => start,
+        None => (contents.len() - buf.len()) as u64,
+    };
+    Ok((replayed, valid_len))
+}
+
+fn emit(out: &mut BytesMut, args: Vec<Bytes>) -> io::Result<()> {
+    RespParser::default().encode(command(args), out)
+}
+
+fn emit_chunked(
+    out: &mut BytesMut,
+    cmd: &'static [u8],
+    key: &Key,
+    items: Vec<Bytes>,
+) -> io::Result<()> {
+    for chunk in items.chunks(REWRITE_ITEMS_PER_CMD) {
+        let mut args = vec![Bytes::from_static(cmd), key.clone()];
+        args.extend(chunk.iter().cloned());
+        emit(out, args)?;
+    }
+    Ok(())
+}
+
+/// The commands that rebuild a single db.
+fn rewrite_state(out: &mut BytesMut, state: &StateRef) -> io::Result<()> {
+    let mut keys = Vec::new();
+    for ent in state.kv.iter() {
+        emit(
+            out,
+            vec![
+                Bytes::from_static(b"SET"),
+                ent.key().clone(),
+                ent.value().clone(),
+            ],
+        )?;
+        keys.push(ent.key().clone());
+    }
+    for ent in state.sets.iter() {
+        let members = ent.value().iter().cloned().collect();
+        emit_chunked(out, b"SADD", ent.key(), members)?;
+        keys.push(ent.key().clone());
+    }
+    for ent in state.lists.iter() {
+        let items = ent.value().iter().collect();
+        emit_chunked(out, b"RPUSH", ent.key(), items)?;
+        keys.push(ent.key().clone());
+    }
+    for ent in state.hashes.iter() {
+        let fields = ent
+            .value()
+            .iter()
+            .flat_map(|(field, value)| vec![field.clone(), value.clone()])
+            .collect::<Vec<_>>();
+        // Keep field / value pairs together.
+        for chunk in fields.chunks(2 * REWRITE_ITEMS_PER_CMD) {
+            let mut args = vec![Bytes::from_static(b"HMSET"), ent.key().clone()];
+            args.extend(chunk.iter().cloned());
+            emit(out, args)?;
+        }
+        keys.push(ent.key().clone());
+    }
+    for ent in state.zsets.iter() {
+        let members = ent
+            .value()
+            .iter()
+            .map(|m| (m.score.to_string().into(), m.member.clone()))
+            .collect::<Vec<(Bytes, Bytes)>>();
+        for chunk in members.chunks(REWRITE_ITEMS_PER_CMD) {
+            let mut args = vec![Bytes::from_static(b"ZADD"), ent.key().clone()];
+            for (score, member) in chunk {
+                args.push(score.clone());
+                args.push(member.clone());
+            }
+            emit(out, args)?;
+        }
+        keys.push(ent.key().clone());
+    }
+    for ent in state.stacks.iter() {
+        for item in ent.value().iter() {
+            emit(
+                out,
+                vec![
+                    Bytes::from_static(b"STPUSH"),
+                    ent.key().clone(),
+                    item.clone(),
+                ],
+            )?;
+        }
+        keys.push(ent.key().clone());
+    }
+    // Blooms and hyperloglogs can't be rebuilt from their inputs, nor streams from
+    // their entries (they remember the IDs of deleted ones), so restore them whole.
+    let opaque: Vec<Key> = state
+        .blooms
+        .iter()
+        .map(|ent| ent.key().clone())
+        .chain(state.hyperloglogs.iter().map(|ent| ent.key().clone()))
+        .chain(state.streams.iter().map(|ent| ent.key().clone()))
+        .collect();
+    for key in opaque {
+        if let Some(payload) = state.dump_key(&key) {
+            emit(
+                out,
+                vec![
+                    Bytes::from_static(b"RESTORE"),
+                    key.clone(),
+                    Bytes::from_static(b"0"),
+                    payload.into(),
+                ],
+            )?;
+            keys.push(key);
+        }
+    }
+    for key in keys {
+        if let Some(deadline) = state.expirations.get(&key) {
+            RespParser::default().encode(pexpireat(key, deadline), out)?;
+        }
+    }
+    Ok(())
+}
+
+/// The commands that rebuild every db.
+fn rewrite_commands(state_store: &StateStoreRef) -> io::Result<BytesMut> {
+    let mut out = BytesMut::new();
+    let mut dbs: Vec<Index> = state_store.states.iter().map(|ent| *ent.key()).collect();
+    dbs.sort_unstable();
+    for db in dbs {
+        let state = match state_store.states.get(&db) {
+            Some(state) => state.clone(),
+            None => continue,
+        };
+        emit(
+            &mut out,
+            vec![Bytes::from_static(b"SELECT"), db.to_string().into()],
+        )?;
+        rewrite_state(&mut out, &state)?;
+    }
+    Ok(out)
+}
+
+async fn rewrite_into(
+    aof: &Aof,
+    temp_path: PathBuf,
+    state_store: &StateStoreRef,
+) -> io::Result<()> {
+    // Writes hold the log lock while they run, so the dataset can't
+    // change while it's being snapshotted.
+    let snapshot = {
+        let mut writer = aof.lock().await;
+        writer.rewrite_buffer = Some((BytesMut::new(), None));
+        rewrite_commands(state_store)?
+    };
+    let mut file = tokio::task::block_in_place(|| -> io::Result<File> {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(&snapshot)?;
+        file.sync_data()?;
+        Ok(file)
+    })?;
+    // Add what was logged in the meantime, then swap the files.
+    let mut writer = aof.lock().await;
+    let (buffer, _) = writer.rewrite_buffer.take().unwrap_or_default();
+    file.write_all(&buffer)?;
+    file.sync_data()?;
+    std::fs::rename(&temp_path, &aof.path)?;
+    writer.file = file;
+    writer.db = None;
+    writer.dirty = false;
+    Ok(())
+}
+
+/// Compact the log into the minimal set of commands that rebuild the
+/// current state.
+pub async fn rewrite(state_store: StateStoreRef) -> io::Result<()> {
+    let aof = match &state_store.aof {
+        Some(aof) => aof.clone(),
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "append only file is not enabled",
+            ))
+        }
+    };
+    if aof.rewriting.swap(true, Ordering::SeqCst) {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "a rewrite is already in progress",
+        ));
+    }
+    let mut temp_path = aof.path.clone().into_os_string();
+    temp_path.push(".rewrite");
+    let temp_path = PathBuf::from(temp_path);
+    let res = rewrite_into(&aof, temp_path.clone(), &state_store).await;
+    if res.is_err() {
+        aof.lock().await.rewrite_buffer = None;
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    aof.rewriting.store(false, Ordering::SeqCst);
+    res
+}
+
+/// Fsync the log every second, for the `everysec` policy.
+pub async fn fsync_aof_interval(aof: Arc<Aof>) {
+    let mut interval = interval(Duration::from_millis(FSYNC_PERIOD_MS));
+    loop {
+        interval.tick().await;
+        let mut writer = aof.lock().await;
+        if writer.dirty {
+            if let Err(e) = writer.file.sync_data() {
+                error!(LOGGER, "Failed to fsync the append only file! {:?}", e);
+                continue;
+            }
+            writer.dirty = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_aof {
+    use crate::aof::{replay, rewrite, Aof, FsyncPolicy, Record};
+    use crate::hyperloglog::HyperLogLogOps;
+    use crate::keys::{KeyOps, SetOptions};
+    use crate::lists::ListOps;
+    use crate::ops::{op_interact, translate, Ops};
+    use crate::types::{RedisValueRef, ReturnValue, StateStore};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "redis-proto-test-{}-{}.aof",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn command(args: &[&'static str]) -> RedisValueRef {
+        RedisValueRef::Array(
+            args.iter()
+                .map(|a| RedisValueRef::BulkString(Bytes::from_static(a.as_bytes())))
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_append_and_replay() {
+        let path = temp_path("replay");
+        let aof = Aof::open(path.clone(), FsyncPolicy::Always).unwrap();
+        {
+            let mut writer = aof.lock().await;
+            writer.append(0, vec![command(&["SET", "a", "1"])]).unwrap();
+            writer
+                .append(1, vec![command(&["RPUSH", "l", "x", "y"])])
+                .unwrap();
+        }
+        // Simulate a crash halfway through a write.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| std::io::Write::write_all(&mut f, b"*3\r\n$3\r\nSET\r\n"))
+            .unwrap();
+        let store = Arc::new(StateStore::default());
+        let (replayed, valid_len) = replay(&path, &store).await.unwrap();
+        // SET, SELECT, RPUSH (the first SELECT 0 included)
+        assert_eq!(replayed, 4);
+        assert!(valid_len < std::fs::metadata(&path).unwrap().len());
+        assert_eq!(
+            store
+                .get_default()
+                .kv
+                .get(&Bytes::from_static(b"a"))
+                .map(|v| v.clone()),
+            Some(Bytes::from_static(b"1"))
+        );
+        assert_eq!(
+            store
+                .get_or_create(1)
+                .lists
+                .get(&Bytes::from_static(b"l"))
+                .unwrap()
+                .len(),
+            2
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_transaction_replay() {
+        let path = temp_path("transaction");
+        let aof = Aof::open(path.clone(), FsyncPolicy::Always).unwrap();
+        {
+            let mut writer = aof.lock().await;
+            // Nothing is logged for a transaction that writes nothing.
+            writer.begin_transaction();
+            writer.end_transaction().unwrap();
+            writer.begin_transaction();
+            writer.append(0, vec![command(&["SET", "a", "1"])]).unwrap();
+            writer.append(0, vec![command(&["SET", "b", "2"])]).unwrap();
+            writer.end_transaction().unwrap();
+        }
+        let complete_len = std::fs::metadata(&path).unwrap().len();
+        {
+            // Simulate a crash in the middle of a transaction.
+            let mut writer = aof.lock().await;
+            writer.begin_transaction();
+            writer.append(0, vec![command(&["SET", "c", "3"])]).unwrap();
+        }
+        let contents = std::fs::read(&path).unwrap();
+        assert!(contents.starts_with(b"*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*1\r\n$5\r\nMULTI\r\n"));
+        assert!(contents[..complete_len as usize].ends_with(b"*1\r\n$4\r\nEXEC\r\n"));
+        let store = Arc::new(StateStore::default());
+        let (replayed, valid_len) = replay(&path, &store).await.unwrap();
+        // SELECT and the two SETs
+        assert_eq!(replayed, 3);
+        assert_eq!(valid_len, complete_len);
+        let state = store.get_default();
+        assert!(state.kv.contains_key(&Bytes::from_static(b"b")));
+        assert!(!state.kv.contains_key(&Bytes::from_static(b"c")));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_blocking_pop_is_logged_as_pop() {
+        let store = Arc::new(StateStore::default());
+        let state = store.get_default();
+        let key = Bytes::from_static(b"l");
+        let op = Ops::Lists(ListOps::BLPop(smallvec![key.clone()], Some(Duration::ZERO)));
+        let record = Record::of(&op).unwrap();
+        let res = ReturnValue::MultiStringRes(vec![Bytes::from_static(b"m"), key.clone()]);
+        assert_eq!(
+            record.commands(command(&["BLPOP", "l", "m", "0"]), &res, &state),
+            vec![command(&["LPOP", "m"])]
+        );
+        let record = Record::of(&op).unwrap();
+        assert!(record
+            .commands(
+                command(&["BLPOP", "l", "m", "0"]),
+                &ReturnValue::Nil,
+                &state
+            )
+            .is_empty());
+        assert!(Record::of(&Ops::Keys(KeyOps::Get(key))).is_none());
+    }
+
+    // Rewrites write the new file with `block_in_place`.
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rewrite() {
+        let path = temp_path("rewrite");
+        let mut store = StateStore::default();
+        store.aof = Some(Arc::new(Aof::open(path.clone(), FsyncPolicy::No).unwrap()));
+        let store = Arc::new(store);
+        let state = store.get_default();
+        let (key, value) = (Bytes::from_static(b"key"), Bytes::from_static(b"value"));
+        for _ in 0..3 {
+            let op = Ops::Keys(KeyOps::Set(
+                key.clone(),
+                value.clone(),
+                SetOptions::default(),
+            ));
+            op_interact(op, state.clone()).await;
+            let mut writer = store.aof.as_ref().unwrap().lock().await;
+            writer
+                .append(0, vec![command(&["SET", "key", "value"])])
+                .unwrap();
+        }
+        let before = std::fs::metadata(&path).unwrap().len();
+        rewrite(store.clone()).await.unwrap();
+        assert!(std::fs::metadata(&path).unwrap().len() < before);
+        // Appends keep going to the rewritten file.
+        store
+            .aof
+            .as_ref()
+            .unwrap()
+            .lock()
+            .await
+            .append(0, vec![command(&["SET", "other", "value"])])
+            .unwrap();
+
+        let replayed = Arc::new(StateStore::default());
+        replay(&path, &replayed).await.unwrap();
+        let state = replayed.get_default();
+        assert_eq!(state.kv.get(&key).map(|v| v.clone()), Some(value.clone()));
+        assert!(state.kv.contains_key(&Bytes::from_static(b"other")));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_rewrite_restores_sketches() {
+        let path = temp_path("restore");
+        let mut store = StateStore::default();
+        store.aof = Some(Arc::new(Aof::open(path.clone(), FsyncPolicy::No).unwrap()));
+        let store = Arc::new(store);
+        let key = Bytes::from_static(b"hll");
+        let add = HyperLogLogOps::PfAdd(key.clone(), smallvec![Bytes::from_static(b"a")]);
+        op_interact(Ops::HyperLogLogs(add), store.get_default()).await;
+        rewrite(store.clone()).await.unwrap();
+
+        let replayed = Arc::new(StateStore::default());
+        replay(&path, &replayed).await.unwrap();
+        assert!(replayed.get_default().hyperloglogs.contains_key(&key));
+        // Only rewrites can restore values.
+        let restore = command(&["RESTORE", "hll", "0", "payload"]);
+        assert!(translate(restore, replayed.clone()).is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
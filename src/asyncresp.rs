@@ -2,7 +2,7 @@ use memchr::memchr;
 use std::convert::From;
 use std::str;
 
-use crate::types::{RedisValueRef, NULL_ARRAY, NULL_BULK_STRING};
+use crate::types::{RedisValueRef, NULL, NULL_ARRAY, NULL_BULK_STRING};
 
 use bytes::{Bytes, BytesMut};
 use tokio_util::codec::{Decoder, Encoder};
@@ -15,6 +15,10 @@ pub enum RESPError {
     IntParseFailure,
     BadBulkStringSize(i64),
     BadArraySize(i64),
+    DoubleParseFailure,
+    BadBoolean,
+    BadNull,
+    BadVerbatimString,
 }
 
 impl From<std::io::Error> for RESPError {
@@ -30,10 +34,43 @@ enum RedisBufSplit {
     Array(Vec<RedisBufSplit>),
     NullArray,
     NullBulkString,
+    Map(BufSplitPairs),
+    Set(Vec<RedisBufSplit>),
+    Double(f64),
+    Boolean(bool),
+    Null,
+    BigNumber(BufSplit),
+    VerbatimString(BufSplit, BufSplit),
+    Attribute(BufSplitPairs, Box<RedisBufSplit>),
+    Push(Vec<RedisBufSplit>),
 }
 
+/// The protocol spoken by a connection. Switched with HELLO.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+/// RESP codec.
+///
+/// Decoding accepts both RESP2 and RESP3. Encoding downgrades RESP3
+/// types to their RESP2 equivalents unless the connection negotiated RESP3.
 #[derive(Default)]
-pub struct RespParser;
+pub struct RespParser {
+    protocol: ProtocolVersion,
+}
+
+impl RespParser {
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: ProtocolVersion) {
+        self.protocol = protocol;
+    }
+}
 
 impl RedisBufSplit {
     fn redis_value(self, buf: &Bytes) -> RedisValueRef {
@@ -47,11 +84,36 @@ impl RedisBufSplit {
             RedisBufSplit::NullArray => RedisValueRef::NullArray,
             RedisBufSplit::NullBulkString => RedisValueRef::NullBulkString,
             RedisBufSplit::Int(i) => RedisValueRef::Int(i),
+            RedisBufSplit::Map(pairs) => RedisValueRef::Map(pairs_value(pairs, buf)),
+            RedisBufSplit::Set(arr) => {
+                RedisValueRef::Set(arr.into_iter().map(|bfs| bfs.redis_value(buf)).collect())
+            }
+            RedisBufSplit::Double(d) => RedisValueRef::Double(d),
+            RedisBufSplit::Boolean(b) => RedisValueRef::Boolean(b),
+            RedisBufSplit::Null => RedisValueRef::Null,
+            RedisBufSplit::BigNumber(bfs) => RedisValueRef::BigNumber(bfs.as_bytes(buf)),
+            RedisBufSplit::VerbatimString(format, bfs) => {
+                RedisValueRef::VerbatimString(format.as_bytes(buf), bfs.as_bytes(buf))
+            }
+            RedisBufSplit::Attribute(attrs, value) => {
+                RedisValueRef::Attribute(pairs_value(attrs, buf), Box::new(value.redis_value(buf)))
+            }
+            RedisBufSplit::Push(arr) => {
+                RedisValueRef::Push(arr.into_iter().map(|bfs| bfs.redis_value(buf)).collect())
+            }
         }
     }
 }
 
+fn pairs_value(pairs: BufSplitPairs, buf: &Bytes) -> Vec<(RedisValueRef, RedisValueRef)> {
+    pairs
+        .into_iter()
+        .map(|(k, v)| (k.redis_value(buf), v.redis_value(buf)))
+        .collect()
+}
+
 type RedisResult = Result<Option<(usize, RedisBufSplit)>, RESPError>;
+type BufSplitPairs = Vec<(RedisBufSplit, RedisBufSplit)>;
 
 /// Fundamental struct for viewing byte slices
 ///
@@ -179,8 +241,130 @@ fn array(buf: &BytesMut, pos: usize) -> RedisResult {
     }
 }
 
+/// Parse `num_elements` consecutive values starting at `pos`.
+fn values(
+    buf: &BytesMut,
+    pos: usize,
+    num_elements: i64,
+) -> Result<Option<(usize, Vec<RedisBufSplit>)>, RESPError> {
+    // Every value takes at least a byte, so don't trust the size past that.
+    let mut values = Vec::with_capacity((num_elements as usize).min(buf.len() - pos));
+    let mut curr_pos = pos;
+    for _ in 0..num_elements {
+        match parse(buf, curr_pos)? {
+            Some((new_pos, value)) => {
+                curr_pos = new_pos;
+                values.push(value);
+            }
+            None => return Ok(None),
+        }
+    }
+    Ok(Some((curr_pos, values)))
+}
+
+/// Parse an aggregate of `2 * size` values as key-value pairs.
+fn pairs(buf: &BytesMut, pos: usize) -> Result<Option<(usize, BufSplitPairs)>, RESPError> {
+    match int(buf, pos)? {
+        None => Ok(None),
+        Some((pos, size)) if size >= 0 => {
+            let num_elements = size.checked_mul(2).ok_or(RESPError::BadArraySize(size))?;
+            Ok(values(buf, pos, num_elements)?.map(|(pos, values)| {
+                let mut pairs = Vec::with_capacity(values.len() / 2);
+                let mut values = values.into_iter();
+                while let (Some(k), Some(v)) = (values.next(), values.next()) {
+                    pairs.push((k, v));
+                }
+                (pos, pairs)
+            }))
+        }
+        Some((_pos, bad_size)) => Err(RESPError::BadArraySize(bad_size)),
+    }
+}
+
+/// Parse a RESP3 aggregate that can't be null (set or push).
+fn aggregate(buf: &BytesMut, pos: usize) -> Result<Option<(usize, Vec<RedisBufSplit>)>, RESPError> {
+    match int(buf, pos)? {
+        None => Ok(None),
+        Some((pos, size)) if size >= 0 => values(buf, pos, size),
+        Some((_pos, bad_size)) => Err(RESPError::BadArraySize(bad_size)),
+    }
+}
+
+fn map(buf: &BytesMut, pos: usize) -> RedisResult {
+    Ok(pairs(buf, pos)?.map(|(pos, pairs)| (pos, RedisBufSplit::Map(pairs))))
+}
+
+fn set(buf: &BytesMut, pos: usize) -> RedisResult {
+    Ok(aggregate(buf, pos)?.map(|(pos, values)| (pos, RedisBufSplit::Set(values))))
+}
+
+fn push(buf: &BytesMut, pos: usize) -> RedisResult {
+    Ok(aggregate(buf, pos)?.map(|(pos, values)| (pos, RedisBufSplit::Push(values))))
+}
+
+fn attribute(buf: &BytesMut, pos: usize) -> RedisResult {
+    // Attributes are followed by the value they describe.
+    match pairs(buf, pos)? {
+        Some((pos, attrs)) => Ok(parse(buf, pos)?
+            .map(|(pos, value)| (pos, RedisBufSplit::Attribute(attrs, Box::new(value))))),
+        None => Ok(None),
+    }
+}
+
+fn double(buf: &BytesMut, pos: usize) -> RedisResult {
+    match word(buf, pos) {
+        Some((pos, word)) => {
+            let s =
+                str::from_utf8(word.as_slice(buf)).map_err(|_| RESPError::DoubleParseFailure)?;
+            let d = s.parse().map_err(|_| RESPError::DoubleParseFailure)?;
+            Ok(Some((pos, RedisBufSplit::Double(d))))
+        }
+        None => Ok(None),
+    }
+}
+
+fn boolean(buf: &BytesMut, pos: usize) -> RedisResult {
+    match word(buf, pos) {
+        Some((pos, word)) => match word.as_slice(buf) {
+            b"t" => Ok(Some((pos, RedisBufSplit::Boolean(true)))),
+            b"f" => Ok(Some((pos, RedisBufSplit::Boolean(false)))),
+            _ => Err(RESPError::BadBoolean),
+        },
+        None => Ok(None),
+    }
+}
+
+fn null(buf: &BytesMut, pos: usize) -> RedisResult {
+    match word(buf, pos) {
+        Some((pos, word)) if word.as_slice(buf).is_empty() => Ok(Some((pos, RedisBufSplit::Null))),
+        Some(_) => Err(RESPError::BadNull),
+        None => Ok(None),
+    }
+}
+
+#[allow(clippy::unnecessary_wraps)]
+fn big_number(buf: &BytesMut, pos: usize) -> RedisResult {
+    Ok(word(buf, pos).map(|(pos, word)| (pos, RedisBufSplit::BigNumber(word))))
+}
+
+fn verbatim_string(buf: &BytesMut, pos: usize) -> RedisResult {
+    // Same framing as a bulk string, with a `fmt:` prefix in the contents.
+    match bulk_string(buf, pos)? {
+        Some((pos, RedisBufSplit::String(BufSplit(start, end)))) => {
+            if end - start < 4 || buf[start + 3] != b':' {
+                return Err(RESPError::BadVerbatimString);
+            }
+            let format = BufSplit(start, start + 3);
+            let contents = BufSplit(start + 4, end);
+            Ok(Some((pos, RedisBufSplit::VerbatimString(format, contents))))
+        }
+        Some(_) => Err(RESPError::BadVerbatimString),
+        None => Ok(None),
+    }
+}
+
 fn parse(buf: &BytesMut, pos: usize) -> RedisResult {
-    if buf.is_empty() {
+    if buf.len() <= pos {
         return Ok(None);
     }
 
@@ -190,6 +374,15 @@ fn parse(buf: &BytesMut, pos: usize) -> RedisResult {
         b'$' => bulk_string(buf, pos + 1),
         b':' => resp_int(buf, pos + 1),
         b'*' => array(buf, pos + 1),
+        b'%' => map(buf, pos + 1),
+        b'~' => set(buf, pos + 1),
+        b',' => double(buf, pos + 1),
+        b'#' => boolean(buf, pos + 1),
+        b'_' => null(buf, pos + 1),
+        b'(' => big_number(buf, pos + 1),
+        b'=' => verbatim_string(buf, pos + 1),
+        b'|' => attribute(buf, pos + 1),
+        b'>' => push(buf, pos + 1),
         _ => Err(RESPError::UnknownStartingByte),
     }
 }
@@ -217,12 +410,36 @@ impl Encoder<RedisValueRef> for RespParser {
     type Error = std::io::Error;
 
     fn encode(&mut self, item: RedisValueRef, dst: &mut BytesMut) -> std::io::Result<()> {
-        write_redis_value(item, dst);
+        write_redis_value(item, dst, self.protocol);
         Ok(())
     }
 }
 
-fn write_redis_value(item: RedisValueRef, dst: &mut BytesMut) {
+/// Format a double the way redis does.
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+fn write_header(prefix: &[u8], len: usize, dst: &mut BytesMut) {
+    dst.extend_from_slice(prefix);
+    dst.extend_from_slice(len.to_string().as_bytes());
+    dst.extend_from_slice(b"\r\n");
+}
+
+fn write_line(prefix: &[u8], line: &[u8], dst: &mut BytesMut) {
+    dst.extend_from_slice(prefix);
+    dst.extend_from_slice(line);
+    dst.extend_from_slice(b"\r\n");
+}
+
+fn write_redis_value(item: RedisValueRef, dst: &mut BytesMut, protocol: ProtocolVersion) {
+    let resp3 = protocol == ProtocolVersion::Resp3;
     match item {
         RedisValueRef::Error(e) => {
             dst.extend_from_slice(b"-");
@@ -251,7 +468,7 @@ fn write_redis_value(item: RedisValueRef, dst: &mut BytesMut) {
             dst.extend_from_slice(array.len().to_string().as_bytes());
             dst.extend_from_slice(b"\r\n");
             for redis_value in array {
-                write_redis_value(redis_value, dst);
+                write_redis_value(redis_value, dst, protocol);
             }
         }
         RedisValueRef::Int(i) => {
@@ -259,14 +476,79 @@ fn write_redis_value(item: RedisValueRef, dst: &mut BytesMut) {
             dst.extend_from_slice(i.to_string().as_bytes());
             dst.extend_from_slice(b"\r\n");
         }
-        RedisValueRef::NullBulkString => dst.extend_from_slice(NULL_BULK_STRING.as_bytes()),
+        RedisValueRef::NullBulkString | RedisValueRef::NullArray | RedisValueRef::Null if resp3 => {
+            dst.extend_from_slice(NULL.as_bytes())
+        }
+        RedisValueRef::NullBulkString | RedisValueRef::Null => {
+            dst.extend_from_slice(NULL_BULK_STRING.as_bytes())
+        }
         RedisValueRef::NullArray => dst.extend_from_slice(NULL_ARRAY.as_bytes()),
+        RedisValueRef::Map(pairs) => {
+            // RESP2 has no maps, so send a flat array of keys and values.
+            if resp3 {
+                write_header(b"%", pairs.len(), dst);
+            } else {
+                write_header(b"*", pairs.len() * 2, dst);
+            }
+            for (k, v) in pairs {
+                write_redis_value(k, dst, protocol);
+                write_redis_value(v, dst, protocol);
+            }
+        }
+        RedisValueRef::Set(items) | RedisValueRef::Push(items) if !resp3 => {
+            write_redis_value(RedisValueRef::Array(items), dst, protocol)
+        }
+        RedisValueRef::Set(items) => {
+            write_header(b"~", items.len(), dst);
+            for redis_value in items {
+                write_redis_value(redis_value, dst, protocol);
+            }
+        }
+        RedisValueRef::Push(items) => {
+            write_header(b">", items.len(), dst);
+            for redis_value in items {
+                write_redis_value(redis_value, dst, protocol);
+            }
+        }
+        RedisValueRef::Double(d) if resp3 => write_line(b",", format_double(d).as_bytes(), dst),
+        RedisValueRef::Double(d) => write_redis_value(
+            RedisValueRef::BulkString(format_double(d).into()),
+            dst,
+            protocol,
+        ),
+        RedisValueRef::Boolean(b) if resp3 => write_line(b"#", if b { b"t" } else { b"f" }, dst),
+        RedisValueRef::Boolean(b) => write_redis_value(RedisValueRef::Int(b as i64), dst, protocol),
+        RedisValueRef::BigNumber(n) if resp3 => write_line(b"(", &n, dst),
+        RedisValueRef::BigNumber(n) => {
+            write_redis_value(RedisValueRef::BulkString(n), dst, protocol)
+        }
+        RedisValueRef::VerbatimString(format, s) if resp3 => {
+            write_header(b"=", format.len() + 1 + s.len(), dst);
+            dst.extend_from_slice(&format);
+            dst.extend_from_slice(b":");
+            dst.extend_from_slice(&s);
+            dst.extend_from_slice(b"\r\n");
+        }
+        RedisValueRef::VerbatimString(_, s) => {
+            write_redis_value(RedisValueRef::BulkString(s), dst, protocol)
+        }
+        RedisValueRef::Attribute(attrs, value) => {
+            // RESP2 clients just get the value.
+            if resp3 {
+                write_header(b"|", attrs.len(), dst);
+                for (k, v) in attrs {
+                    write_redis_value(k, dst, protocol);
+                    write_redis_value(v, dst, protocol);
+                }
+            }
+            write_redis_value(*value, dst, protocol);
+        }
     }
 }
 
 #[cfg(test)]
 mod resp_parser_tests {
-    use crate::asyncresp::RespParser;
+    use crate::asyncresp::{ProtocolVersion, RespParser};
     use crate::types::{RedisValueRef, Value};
     use bytes::{Bytes, BytesMut};
     use tokio_util::codec::{Decoder, Encoder};
@@ -294,6 +576,25 @@ mod resp_parser_tests {
         );
     }
 
+    /// Decode `input`, and check it encodes back to `input` with RESP3
+    /// and to `resp2` with RESP2.
+    fn resp3_test(input: &'static str, output: RedisValueRef, resp2: &'static str) {
+        let mut decoder = RespParser::default();
+        let result_read = decoder.decode(&mut BytesMut::from(input));
+        assert_eq!(result_read.unwrap(), Some(output.clone()));
+
+        let mut encoder = RespParser::default();
+        encoder.set_protocol(ProtocolVersion::Resp3);
+        let mut buf = BytesMut::new();
+        encoder.encode(output.clone(), &mut buf).unwrap();
+        assert_eq!(input.as_bytes(), buf.as_ref());
+
+        let mut encoder = RespParser::default();
+        let mut buf = BytesMut::new();
+        encoder.encode(output, &mut buf).unwrap();
+        assert_eq!(resp2.as_bytes(), buf.as_ref());
+    }
+
     fn generic_test_arr(input: &str, output: Vec<RedisValueRef>) {
         // TODO: Try to make this occur randomly
         let first: usize = input.len() / 2;
@@ -439,4 +740,78 @@ mod resp_parser_tests {
         let s = "*-1\r\n";
         generic_test(s, t);
     }
+
+    #[test]
+    fn test_parse_resp3_scalars() {
+        resp3_test("_\r\n", RedisValueRef::Null, "$-1\r\n");
+        resp3_test("#t\r\n", RedisValueRef::Boolean(true), ":1\r\n");
+        resp3_test("#f\r\n", RedisValueRef::Boolean(false), ":0\r\n");
+        resp3_test(",1.5\r\n", RedisValueRef::Double(1.5), "$3\r\n1.5\r\n");
+        resp3_test(
+            ",inf\r\n",
+            RedisValueRef::Double(f64::INFINITY),
+            "$3\r\ninf\r\n",
+        );
+        resp3_test(
+            "(3492890328409238509324850943850943825024385\r\n",
+            RedisValueRef::BigNumber(Bytes::from_static(
+                b"3492890328409238509324850943850943825024385",
+            )),
+            "$43\r\n3492890328409238509324850943850943825024385\r\n",
+        );
+        resp3_test(
+            "=9\r\ntxt:hello\r\n",
+            RedisValueRef::VerbatimString(Bytes::from_static(b"txt"), ezs()),
+            "$5\r\nhello\r\n",
+        );
+    }
+
+    #[test]
+    fn test_parse_resp3_aggregates() {
+        resp3_test(
+            "%1\r\n$5\r\nhello\r\n:1\r\n",
+            RedisValueRef::Map(vec![(
+                RedisValueRef::BulkString(ezs()),
+                RedisValueRef::Int(1),
+            )]),
+            "*2\r\n$5\r\nhello\r\n:1\r\n",
+        );
+        resp3_test(
+            "~2\r\n:1\r\n:2\r\n",
+            RedisValueRef::Set(vec![RedisValueRef::Int(1), RedisValueRef::Int(2)]),
+            "*2\r\n:1\r\n:2\r\n",
+        );
+        resp3_test(
+            ">2\r\n$5\r\nhello\r\n_\r\n",
+            RedisValueRef::Push(vec![RedisValueRef::BulkString(ezs()), RedisValueRef::Null]),
+            "*2\r\n$5\r\nhello\r\n$-1\r\n",
+        );
+        resp3_test(
+            "|1\r\n$5\r\nhello\r\n#t\r\n:7\r\n",
+            RedisValueRef::Attribute(
+                vec![(
+                    RedisValueRef::BulkString(ezs()),
+                    RedisValueRef::Boolean(true),
+                )],
+                Box::new(RedisValueRef::Int(7)),
+            ),
+            ":7\r\n",
+        );
+    }
+
+    #[test]
+    fn test_parse_incomplete() {
+        let mut decoder = RespParser::default();
+        let mut buf = BytesMut::from("%1\r\n$5\r\nhello\r\n");
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        let mut buf = BytesMut::from("|1\r\n+a\r\n+b\r\n");
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+        assert!(decoder.decode(&mut BytesMut::from("#x\r\n")).is_err());
+        assert!(decoder.decode(&mut BytesMut::from("=2\r\nab\r\n")).is_err());
+        // Huge sizes are refused or wait for more bytes, without allocating.
+        let mut buf = BytesMut::from("%9223372036854775807\r\n");
+        assert!(decoder.decode(&mut buf).is_err());
+        let mut buf = BytesMut::from("%4611686018427387903\r\n+a\r\n");
+        assert_eq!(decoder.decode(&mut buf).unwrap(), None);
+    }
 }
@@ -0,0 +1,617 @@
+/// Bitmaps: bit level access to the strings in `State::kv`.
+///
+/// Bits are numbered from the most significant bit of the first byte,
+/// like redis. Writes past the end zero pad the string.
+use crate::ops::RVec;
+use crate::types::{Count, Index, Key, ReturnValue, StateRef, Value, ValueType, WRONGTYPE};
+use bytes::BytesMut;
+use std::str::FromStr;
+
+/// Strings can be up to 512MB, so bit offsets are below 2^32.
+pub const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+impl FromStr for BitOperation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "and" => Ok(BitOperation::And),
+            "or" => Ok(BitOperation::Or),
+            "xor" => Ok(BitOperation::Xor),
+            "not" => Ok(BitOperation::Not),
+            _ => Err(()),
+        }
+    }
+}
+
+/// BYTE / BIT: what the start and end of a range count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangeUnit {
+    Byte,
+    Bit,
+}
+
+/// The range of BITCOUNT and BITPOS. Negative indices count from the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitRange {
+    pub start: Index,
+    /// None for the end of the string.
+    pub end: Option<Index>,
+    pub unit: RangeUnit,
+}
+
+impl BitRange {
+    /// The first and last bit of the range in a string of `len` bytes,
+    /// or None if it's empty.
+    fn resolve(&self, len: usize) -> Option<(usize, usize)> {
+        let total = match self.unit {
+            RangeUnit::Byte => len as Index,
+            RangeUnit::Bit => len as Index * 8,
+        };
+        let mut start = self.start;
+        let mut end = self.end.unwrap_or(-1);
+        if start < 0 {
+            start += total;
+        }
+        if end < 0 {
+            end += total;
+        }
+        let start = start.max(0);
+        let end = end.max(0).min(total - 1);
+        if total == 0 || start > end {
+            return None;
+        }
+        let (start, end) = (start as usize, end as usize);
+        match self.unit {
+            RangeUnit::Byte => Some((start * 8, end * 8 + 7)),
+            RangeUnit::Bit => Some((start, end)),
+        }
+    }
+}
+
+/// A BITFIELD integer type: i1 to i64, or u1 to u63.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldType {
+    pub signed: bool,
+    pub bits: u8,
+}
+
+impl FieldType {
+    fn bounds(&self) -> (i128, i128) {
+        if self.signed {
+            let half = 1i128 << (self.bits - 1);
+            (-half, half - 1)
+        } else {
+            (0, (1i128 << self.bits) - 1)
+        }
+    }
+
+    /// Fit `value` into this type, or None if it overflows under FAIL.
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<Count> {
+        let (min, max) = self.bounds();
+        if (min..=max).contains(&value) {
+            return Some(value as Count);
+        }
+        match overflow {
+            Overflow::Fail => None,
+            Overflow::Sat => Some(value.clamp(min, max) as Count),
+            Overflow::Wrap => {
+                let low = value & ((1i128 << self.bits) - 1);
+                let wrapped = if low > max {
+                    low - (1i128 << self.bits)
+                } else {
+                    low
+                };
+                Some(wrapped as Count)
+            }
+        }
+    }
+}
+
+impl FromStr for FieldType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let signed = match s.as_bytes().first() {
+            Some(b'i') | Some(b'I') => true,
+            Some(b'u') | Some(b'U') => false,
+            _ => return Err(()),
+        };
+        let bits: u8 = s[1..].parse().map_err(|_| ())?;
+        let max_bits = if signed { 64 } else { 63 };
+        if bits == 0 || bits > max_bits {
+            return Err(());
+        }
+        Ok(FieldType { signed, bits })
+    }
+}
+
+/// BITFIELD overflow behaviour of SET and INCRBY.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+impl FromStr for Overflow {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "wrap" => Ok(Overflow::Wrap),
+            "sat" => Ok(Overflow::Sat),
+            "fail" => Ok(Overflow::Fail),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A single BITFIELD subcommand. Offsets are in bits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FieldOp {
+    Get(FieldType, u64),
+    Set(FieldType, u64, Count),
+    IncrBy(FieldType, u64, Count),
+    Overflow(Overflow),
+}
+
+op_variants! {
+    BitmapOps,
+    SetBit(Key, u64, bool),
+    GetBit(Key, u64),
+    BitCount(Key, Option<BitRange>),
+    BitPos(Key, bool, Option<BitRange>),
+    BitOp(BitOperation, Key, RVec<Key>),
+    BitField(Key, Vec<FieldOp>)
+}
+
+impl BitmapOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            BitmapOps::SetBit(key, ..)
+            | BitmapOps::GetBit(key, _)
+            | BitmapOps::BitCount(key, _)
+            | BitmapOps::BitPos(key, ..)
+            | BitmapOps::BitField(key, _) => vec![key.clone()],
+            BitmapOps::BitOp(_, dest, sources) => std::iter::once(dest)
+                .chain(sources.iter())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        match self {
+            BitmapOps::SetBit(..) | BitmapOps::BitOp(..) => true,
+            BitmapOps::BitField(_, ops) => ops
+                .iter()
+                .any(|op| matches!(op, FieldOp::Set(..) | FieldOp::IncrBy(..))),
+            _ => false,
+        }
+    }
+
+    /// The type the keys must hold, or None if any type will do.
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            // BITOP overwrites its destination, whatever it holds.
+            BitmapOps::BitOp(..) => None,
+            _ => Some(ValueType::String),
+        }
+    }
+}
+
+fn get_bit(value: &[u8], offset: u64) -> bool {
+    let byte = (offset / 8) as usize;
+    byte < value.len() && value[byte] & (0x80 >> (offset % 8)) != 0
+}
+
+/// Set a bit, growing `value` if needed. Returns the old bit.
+fn set_bit(value: &mut BytesMut, offset: u64, bit: bool) -> bool {
+    let byte = (offset / 8) as usize;
+    if value.len() <= byte {
+        value.resize(byte + 1, 0);
+    }
+    let mask = 0x80 >> (offset % 8);
+    let old = value[byte] & mask != 0;
+    if bit {
+        value[byte] |= mask;
+    } else {
+        value[byte] &= !mask;
+    }
+    old
+}
+
+/// Number of set bits from `first` to `last`, inclusive.
+fn count_ones(value: &[u8], first: usize, last: usize) -> usize {
+    let (first_byte, last_byte) = (first / 8, last / 8);
+    let whole: usize = value[first_byte..=last_byte]
+        .iter()
+        .map(|byte| byte.count_ones() as usize)
+        .sum();
+    // Leave out the bits of the edge bytes outside the range.
+    let before = (value[first_byte] as u16 >> (8 - first % 8)) as u8;
+    let after = value[last_byte] & (0xFFu16 >> (last % 8 + 1)) as u8;
+    whole - before.count_ones() as usize - after.count_ones() as usize
+}
+
+/// Position of the first `bit` from `first` to `last`, inclusive.
+fn find_bit(value: &[u8], bit: bool, first: usize, last: usize) -> Option<usize> {
+    let skip = if bit { 0x00 } else { 0xFF };
+    let mut pos = first;
+    while pos <= last {
+        if pos & 7 == 0 && pos + 7 <= last && value[pos / 8] == skip {
+            pos += 8;
+            continue;
+        }
+        if get_bit(value, pos as u64) == bit {
+            return Some(pos);
+        }
+        pos += 1;
+    }
+    None
+}
+
+/// Read a BITFIELD field, most significant bit first.
+fn read_field(value: &[u8], offset: u64, ty: FieldType) -> Count {
+    let raw = (0..ty.bits as u64).fold(0u64, |acc, i| {
+        (acc << 1) | get_bit(value, offset + i) as u64
+    });
+    if ty.signed && ty.bits < 64 && raw >> (ty.bits - 1) & 1 == 1 {
+        // Sign extend.
+        (raw | (u64::MAX << ty.bits)) as Count
+    } else {
+        raw as Count
+    }
+}
+
+fn write_field(value: &mut BytesMut, offset: u64, ty: FieldType, field: Count) {
+    for i in 0..ty.bits as u64 {
+        let bit = (field as u64) >> (ty.bits as u64 - 1 - i) & 1 == 1;
+        set_bit(value, offset + i, bit);
+    }
+}
+
+fn bit_op(state: &StateRef, op: BitOperation, dest: Key, sources: RVec<Key>) -> ReturnValue {
+    let mut values = Vec::with_capacity(sources.len());
+    for source in sources.iter() {
+        match state.kv.get(source) {
+            Some(value) => values.push(value.clone()),
+            None if state.contains_key(source) => return ReturnValue::Error(WRONGTYPE),
+            None => values.push(Value::new()),
+        }
+    }
+    let len = values.iter().map(|v| v.len()).max().unwrap_or(0);
+    let byte_at = |value: &Value, i: usize| value.get(i).copied().unwrap_or(0);
+    let result: Vec<u8> = (0..len)
+        .map(|i| {
+            let mut bytes = values.iter().map(|value| byte_at(value, i));
+            let first = bytes.next().unwrap_or(0);
+            match op {
+                BitOperation::And => bytes.fold(first, |acc, b| acc & b),
+                BitOperation::Or => bytes.fold(first, |acc, b| acc | b),
+                BitOperation::Xor => bytes.fold(first, |acc, b| acc ^ b),
+                BitOperation::Not => !first,
+            }
+        })
+        .collect();
+    state.remove_key(&dest);
+    if !result.is_empty() {
+        state.kv.insert(dest, result.into());
+    }
+    ReturnValue::IntRes(len as Count)
+}
+
+fn bit_field(state: &StateRef, key: Key, ops: Vec<FieldOp>) -> ReturnValue {
+    let writes = ops
+        .iter()
+        .any(|op| matches!(op, FieldOp::Set(..) | FieldOp::IncrBy(..)));
+    if !writes {
+        let value = state.kv.get(&key).map(|v| v.clone()).unwrap_or_default();
+        let results = ops.into_iter().filter_map(|op| match op {
+            FieldOp::Get(ty, offset) => Some(ReturnValue::IntRes(read_field(&value, offset, ty))),
+            _ => None,
+        });
+        return ReturnValue::Array(results.collect());
+    }
+    // Writes hold the entry throughout, so they're atomic.
+    let mut entry = state.kv.entry(key).or_default();
+    // Reuses the buffer when nothing else holds it, like APPEND.
+    let mut value = BytesMut::from(std::mem::take(&mut *entry));
+    let mut overflow = Overflow::Wrap;
+    let mut results = Vec::new();
+    for op in ops {
+        let result = match op {
+            FieldOp::Overflow(mode) => {
+                overflow = mode;
+                continue;
+            }
+            FieldOp::Get(ty, offset) => Some(read_field(&value, offset, ty)),
+            FieldOp::Set(ty, offset, new) => {
+                let old = read_field(&value, offset, ty);
+                ty.fit(new as i128, overflow).map(|new| {
+                    write_field(&mut value, offset, ty, new);
+                    old
+                })
+            }
+            FieldOp::IncrBy(ty, offset, increment) => {
+                let old = read_field(&value, offset, ty);
+                let new = ty.fit(old as i128 + increment as i128, overflow);
+                if let Some(new) = new {
+                    write_field(&mut value, offset, ty, new);
+                }
+                new
+            }
+        };
+        results.push(result.map_or(ReturnValue::Nil, ReturnValue::IntRes));
+    }
+    *entry = value.freeze();
+    ReturnValue::Array(results)
+}
+
+pub async fn bitmap_interact(bitmap_op: BitmapOps, state: StateRef) -> ReturnValue {
+    match bitmap_op {
+        BitmapOps::SetBit(key, offset, bit) => {
+            let mut entry = state.kv.entry(key).or_default();
+            // Reuses the buffer when nothing else holds it, like APPEND.
+            let mut value = BytesMut::from(std::mem::take(&mut *entry));
+            let old = set_bit(&mut value, offset, bit);
+            *entry = value.freeze();
+            ReturnValue::IntRes(old as Count)
+        }
+        BitmapOps::GetBit(key, offset) => {
+            let bit = state.kv.get(&key).is_some_and(|v| get_bit(&v, offset));
+            ReturnValue::IntRes(bit as Count)
+        }
+        BitmapOps::BitCount(key, range) => {
+            let value = match state.kv.get(&key) {
+                Some(value) => value.clone(),
+                None => return ReturnValue::IntRes(0),
+            };
+            let range = range.unwrap_or(BitRange {
+                start: 0,
+                end: None,
+                unit: RangeUnit::Byte,
+            });
+            match range.resolve(value.len()) {
+                Some((first, last)) => {
+                    ReturnValue::IntRes(count_ones(&value, first, last) as Count)
+                }
+                None => ReturnValue::IntRes(0),
+            }
+        }
+        BitmapOps::BitPos(key, bit, range) => {
+            let value = state.kv.get(&key).map(|v| v.clone()).unwrap_or_default();
+            if value.is_empty() {
+                return ReturnValue::IntRes(if bit { -1 } else { 0 });
+            }
+            let end_given = range.is_some_and(|range| range.end.is_some());
+            let range = range.unwrap_or(BitRange {
+                start: 0,
+                end: None,
+                unit: RangeUnit::Byte,
+            });
+            let (first, last) = match range.resolve(value.len()) {
+                Some(bits) => bits,
+                None => return ReturnValue::IntRes(-1),
+            };
+            match find_bit(&value, bit, first, last) {
+                Some(pos) => ReturnValue::IntRes(pos as Count),
+                // Without an end, the string counts as padded with clear bits.
+                None if !bit && !end_given => ReturnValue::IntRes(last as Count + 1),
+                None => ReturnValue::IntRes(-1),
+            }
+        }
+        BitmapOps::BitOp(op, dest, sources) => bit_op(&state, op, dest, sources),
+        BitmapOps::BitField(key, ops) => bit_field(&state, key, ops),
+    }
+}
+
+#[cfg(test)]
+mod test_bitmaps {
+    use crate::bitmaps::{
+        bitmap_interact, BitOperation, BitRange, BitmapOps, FieldOp, FieldType, Overflow, RangeUnit,
+    };
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_setbit_getbit() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let run = |op| bitmap_interact(op, eng.clone());
+        assert_eq!(
+            ReturnValue::IntRes(0),
+            run(BitmapOps::SetBit(key.clone(), 7, true)).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            run(BitmapOps::SetBit(key.clone(), 7, true)).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(0),
+            run(BitmapOps::SetBit(key.clone(), 17, true)).await
+        );
+        assert_eq!(eng.kv.get(&key).unwrap().as_ref(), &[0x01, 0x00, 0x40]);
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            run(BitmapOps::GetBit(key.clone(), 17)).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(0),
+            run(BitmapOps::GetBit(key.clone(), 1000)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bitcount_bitpos() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        eng.kv.insert(key.clone(), Bytes::from_static(b"foobar"));
+        let run = |op| bitmap_interact(op, eng.clone());
+        let range = |start, end, unit| {
+            Some(BitRange {
+                start,
+                end: Some(end),
+                unit,
+            })
+        };
+        assert_eq!(
+            ReturnValue::IntRes(26),
+            run(BitmapOps::BitCount(key.clone(), None)).await
+        );
+        let bytes = range(1, 1, RangeUnit::Byte);
+        assert_eq!(
+            ReturnValue::IntRes(6),
+            run(BitmapOps::BitCount(key.clone(), bytes)).await
+        );
+        let bits = range(5, 30, RangeUnit::Bit);
+        assert_eq!(
+            ReturnValue::IntRes(17),
+            run(BitmapOps::BitCount(key.clone(), bits)).await
+        );
+
+        eng.kv
+            .insert(key.clone(), Bytes::from_static(&[0xff, 0xf0, 0x00]));
+        assert_eq!(
+            ReturnValue::IntRes(12),
+            run(BitmapOps::BitPos(key.clone(), false, None)).await
+        );
+        eng.kv
+            .insert(key.clone(), Bytes::from_static(&[0xff, 0xff, 0xff]));
+        assert_eq!(
+            ReturnValue::IntRes(24),
+            run(BitmapOps::BitPos(key.clone(), false, None)).await
+        );
+        let bytes = range(0, -1, RangeUnit::Byte);
+        assert_eq!(
+            ReturnValue::IntRes(-1),
+            run(BitmapOps::BitPos(key.clone(), false, bytes)).await
+        );
+        eng.kv
+            .insert(key.clone(), Bytes::from_static(&[0x00, 0xff, 0xf0]));
+        let bits = range(7, 15, RangeUnit::Bit);
+        assert_eq!(
+            ReturnValue::IntRes(8),
+            run(BitmapOps::BitPos(key.clone(), true, bits)).await
+        );
+        let missing = Bytes::from_static(b"missing");
+        assert_eq!(
+            ReturnValue::IntRes(-1),
+            run(BitmapOps::BitPos(missing, true, None)).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bitop() {
+        let (a, b, dest) = (
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"dest"),
+        );
+        let eng = Arc::new(State::default());
+        eng.kv
+            .insert(a.clone(), Bytes::from_static(&[0b1100, 0xff]));
+        eng.kv.insert(b.clone(), Bytes::from_static(&[0b1010]));
+        let run =
+            |op, sources| bitmap_interact(BitmapOps::BitOp(op, dest.clone(), sources), eng.clone());
+        let expect = |bytes: &[u8]| assert_eq!(eng.kv.get(&dest).unwrap().as_ref(), bytes);
+        assert_eq!(
+            ReturnValue::IntRes(2),
+            run(BitOperation::And, smallvec![a.clone(), b.clone()]).await
+        );
+        expect(&[0b1000, 0x00]);
+        run(BitOperation::Or, smallvec![a.clone(), b.clone()]).await;
+        expect(&[0b1110, 0xff]);
+        run(BitOperation::Xor, smallvec![a.clone(), b.clone()]).await;
+        expect(&[0b0110, 0xff]);
+        run(BitOperation::Not, smallvec![b.clone()]).await;
+        expect(&[0b1111_0101]);
+        let missing = Bytes::from_static(b"missing");
+        assert_eq!(
+            ReturnValue::IntRes(0),
+            run(BitOperation::Not, smallvec![missing]).await
+        );
+        assert!(!eng.kv.contains_key(&dest));
+    }
+
+    #[tokio::test]
+    async fn test_bitfield() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let run = |ops| bitmap_interact(BitmapOps::BitField(key.clone(), ops), eng.clone());
+        let (u8_, i8_, u2) = (
+            "u8".parse::<FieldType>().unwrap(),
+            "i8".parse::<FieldType>().unwrap(),
+            "u2".parse::<FieldType>().unwrap(),
+        );
+        assert!("u64".parse::<FieldType>().is_err());
+        assert!("i65".parse::<FieldType>().is_err());
+        let res = run(vec![
+            FieldOp::Set(u8_, 0, 255),
+            FieldOp::Get(i8_, 0),
+            FieldOp::IncrBy(u8_, 0, 10),
+        ])
+        .await;
+        assert_eq!(
+            ReturnValue::Array(vec![
+                ReturnValue::IntRes(0),
+                ReturnValue::IntRes(-1),
+                ReturnValue::IntRes(9)
+            ]),
+            res
+        );
+        let res = run(vec![
+            FieldOp::Overflow(Overflow::Sat),
+            FieldOp::IncrBy(i8_, 0, 200),
+            FieldOp::Overflow(Overflow::Fail),
+            FieldOp::IncrBy(u2, 100, 5),
+            FieldOp::IncrBy(u2, 100, 3),
+        ])
+        .await;
+        assert_eq!(
+            ReturnValue::Array(vec![
+                ReturnValue::IntRes(127),
+                ReturnValue::Nil,
+                ReturnValue::IntRes(3)
+            ]),
+            res
+        );
+        assert_eq!(eng.kv.get(&key).unwrap().len(), 13);
+        let res = run(vec![
+            FieldOp::Overflow(Overflow::Wrap),
+            FieldOp::IncrBy(i8_, 0, 1),
+        ])
+        .await;
+        assert_eq!(ReturnValue::Array(vec![ReturnValue::IntRes(-128)]), res);
+    }
+
+    #[tokio::test]
+    async fn test_writes_reuse_the_buffer() {
+        let key = Bytes::from_static(b"key");
+        let state = Arc::new(State::default());
+        let buffer = |state: &Arc<State>| state.kv.get(&key).unwrap().as_ptr();
+        bitmap_interact(BitmapOps::SetBit(key.clone(), 63, true), state.clone()).await;
+        let first = buffer(&state);
+        bitmap_interact(BitmapOps::SetBit(key.clone(), 0, true), state.clone()).await;
+        assert_eq!(buffer(&state), first);
+        let set = FieldOp::Set("u8".parse::<FieldType>().unwrap(), 8, 255);
+        bitmap_interact(BitmapOps::BitField(key.clone(), vec![set]), state.clone()).await;
+        assert_eq!(buffer(&state), first);
+        assert_eq!(
+            state.kv.get(&key).unwrap().as_ref(),
+            &[0x80, 0xff, 0, 0, 0, 0, 0, 1]
+        );
+    }
+}
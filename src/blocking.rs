@@ -1,26 +1,78 @@
+use crate::aof::{Aof, AofWriter};
 use crate::data_structures::receipt_map::{KeyTypes, Receipt};
-use crate::types::{Key, ReturnValue, StateRef};
+use crate::types::{ReturnValue, StateRef};
 
+use parking_lot::Mutex;
 use std::future::Future;
 use std::pin::Pin;
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use tokio::sync::{OwnedMutexGuard, OwnedRwLockReadGuard, RwLock};
 
 pub type YieldingFn = Box<dyn Fn() -> Option<ReturnValue> + Send>;
+type Acquiring<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// The locks a blocked command can't hold while waiting, so its retries
+/// take them instead.
+pub struct Retries {
+    /// Held for writing while a transaction runs, so retries can't run
+    /// halfway through one.
+    exec_lock: Arc<RwLock<()>>,
+    /// The append only file, if the command is logged. The retry serving
+    /// the command keeps it locked until then, so no other write can be
+    /// logged first.
+    aof: Option<Arc<Aof>>,
+    served_writer: Mutex<Option<OwnedMutexGuard<AofWriter>>>,
+}
+
+impl Retries {
+    pub fn new(exec_lock: Arc<RwLock<()>>, aof: Option<Arc<Aof>>) -> Retries {
+        Retries {
+            exec_lock,
+            aof,
+            served_writer: Mutex::new(None),
+        }
+    }
+
+    /// The log, still locked by the retry that served the command.
+    pub fn take_writer(&self) -> Option<OwnedMutexGuard<AofWriter>> {
+        self.served_writer.lock().take()
+    }
+}
+
+tokio::task_local! {
+    /// Set while running a command that may block.
+    pub static RETRIES: Arc<Retries>;
+}
 
 pub struct KeyBlocking {
     f: Box<dyn Fn() -> Option<ReturnValue> + Send>,
     state: StateRef,
-    key: Key,
+    /// Woken by writes to any of these.
+    keys: Vec<KeyTypes>,
     receipt: Receipt,
+    served: bool,
+    retries: Option<Arc<Retries>>,
+    acquiring_exec: Option<Acquiring<OwnedRwLockReadGuard<()>>>,
+    acquiring_aof: Option<Acquiring<OwnedMutexGuard<AofWriter>>>,
 }
 
 impl KeyBlocking {
-    pub fn new(f: YieldingFn, state: StateRef, key: Key, receipt: Receipt) -> KeyBlocking {
+    pub fn new(
+        f: YieldingFn,
+        state: StateRef,
+        keys: Vec<KeyTypes>,
+        receipt: Receipt,
+    ) -> KeyBlocking {
         KeyBlocking {
             f,
-            key,
+            keys,
             state,
             receipt,
+            served: false,
+            retries: RETRIES.try_with(Arc::clone).ok(),
+            acquiring_exec: None,
+            acquiring_aof: None,
         }
     }
 }
@@ -28,17 +80,108 @@ impl KeyBlocking {
 impl Future for KeyBlocking {
     type Output = ReturnValue;
 
-    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.state.receipt_timed_out(self.receipt) {
-            return Poll::Ready(ReturnValue::Nil);
-        }
-        match (self.f)() {
-            Some(ret) => Poll::Ready(ret),
-            None => {
-                let mut rm = self.state.reciept_map.lock();
-                rm.insert(self.receipt, cx.waker().clone(), KeyTypes::list(&self.key));
-                Poll::Pending
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Register before trying, so a write landing in between still wakes us.
+        self.state
+            .reciept_map
+            .lock()
+            .insert(self.receipt, cx.waker().clone(), &self.keys);
+        let this = &mut *self;
+        let mut writer = None;
+        let _guard = match &this.retries {
+            Some(retries) => {
+                let acquiring = this
+                    .acquiring_exec
+                    .get_or_insert_with(|| Box::pin(retries.exec_lock.clone().read_owned()));
+                let guard = match acquiring.as_mut().poll(cx) {
+                    Poll::Ready(guard) => guard,
+                    Poll::Pending => {
+                        // The transaction may need the log, don't keep our place.
+                        this.acquiring_aof = None;
+                        return Poll::Pending;
+                    }
+                };
+                this.acquiring_exec = None;
+                if let Some(aof) = &retries.aof {
+                    let acquiring = this
+                        .acquiring_aof
+                        .get_or_insert_with(|| Box::pin(aof.lock()));
+                    match acquiring.as_mut().poll(cx) {
+                        Poll::Ready(locked) => writer = Some(locked),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                    this.acquiring_aof = None;
+                }
+                Some(guard)
+            }
+            None => None,
+        };
+        match (this.f)() {
+            Some(ret) => {
+                this.served = true;
+                if let (Some(retries), Some(writer)) = (&this.retries, writer) {
+                    *retries.served_writer.lock() = Some(writer);
+                }
+                Poll::Ready(ret)
             }
+            None => Poll::Pending,
         }
     }
 }
+
+/// Served, timed out or disconnected: stop waiting on the keys.
+impl Drop for KeyBlocking {
+    fn drop(&mut self) {
+        self.state
+            .reciept_map
+            .lock()
+            .remove(self.receipt, self.served);
+    }
+}
+
+#[cfg(test)]
+mod test_blocking {
+    use crate::aof::{Aof, FsyncPolicy};
+    use crate::blocking::{Retries, RETRIES};
+    use crate::lists::ListOps;
+    use crate::ops::{op_interact, Ops};
+    use crate::types::{ReturnValue, StateStore};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn test_served_retry_keeps_the_log_locked() {
+        let path = std::env::temp_dir().join(format!(
+            "redis-proto-test-retries-{}.aof",
+            std::process::id()
+        ));
+        let aof = Arc::new(Aof::open(path.clone(), FsyncPolicy::No).unwrap());
+        let state_store = StateStore::default();
+        let state = state_store.get_default();
+        let key = Bytes::from_static(b"l");
+        let push = Ops::Lists(ListOps::RPush(key.clone(), smallvec![key.clone()]));
+        op_interact(push, state.clone()).await;
+
+        let retries = Arc::new(Retries::new(
+            state_store.exec_lock.clone(),
+            Some(aof.clone()),
+        ));
+        let pop = Ops::Lists(ListOps::BLPop(smallvec![key.clone()], Some(Duration::ZERO)));
+        let res = RETRIES
+            .scope(retries.clone(), op_interact(pop, state.clone()))
+            .await;
+        assert_eq!(res, ReturnValue::MultiStringRes(vec![key.clone(), key]));
+        // Nothing else can be logged before the pop.
+        let writer = retries.take_writer();
+        assert!(writer.is_some());
+        assert!(timeout(Duration::from_millis(10), aof.lock())
+            .await
+            .is_err());
+        drop(writer);
+        assert!(timeout(Duration::from_millis(10), aof.lock()).await.is_ok());
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -8,6 +8,19 @@ op_variants! {
     BContains(Key, Value)
 }
 
+impl BloomOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            BloomOps::BInsert(key, _) | BloomOps::BContains(key, _) => vec![key.clone()],
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(self, BloomOps::BInsert(..))
+    }
+}
+
 const DESIRED_FAILURE_RATE: f64 = 0.05;
 const EST_INSERTS: usize = 10;
 
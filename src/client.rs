@@ -0,0 +1,101 @@
+/// Per-connection state.
+use crate::asyncresp::ProtocolVersion;
+use crate::pubsub::{PushSender, Subscriber};
+use crate::transaction::Transaction;
+use crate::types::{Count, Index, RedisValueRef, ReturnValue};
+use bytes::Bytes;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio_util::sync::CancellationToken;
+
+pub type ClientId = u64;
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Everything the server remembers about a single connection.
+///
+/// The default client can't receive pushed messages, like the one
+/// scripts use.
+#[derive(Default)]
+pub struct Client {
+    pub id: ClientId,
+    pub protocol: ProtocolVersion,
+    /// The selected db.
+    pub db: Index,
+    pub transaction: Transaction,
+    pub subscriber: Subscriber,
+    /// Cancelled when the connection closes, so blocked commands stop waiting.
+    pub disconnected: CancellationToken,
+    /// Set on the client every script runs its commands through.
+    pub scripted: bool,
+}
+
+impl Client {
+    /// A client whose pub/sub messages are sent through `push`.
+    pub fn new(push: PushSender) -> Self {
+        let id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed);
+        Client {
+            id,
+            protocol: ProtocolVersion::default(),
+            db: 0,
+            transaction: Transaction::default(),
+            subscriber: Subscriber::new(id, push),
+            disconnected: CancellationToken::new(),
+            scripted: false,
+        }
+    }
+
+    /// The client shared by scripts. Commands keeping state in the client
+    /// are refused, as the state would leak from one script to the next.
+    pub fn for_scripts() -> Self {
+        Client {
+            scripted: true,
+            ..Client::default()
+        }
+    }
+
+    /// Switch protocols, and describe the connection.
+    pub fn hello(&mut self, protocol: Option<Count>) -> ReturnValue {
+        self.protocol = match protocol {
+            None => self.protocol,
+            Some(2) => ProtocolVersion::Resp2,
+            Some(3) => ProtocolVersion::Resp3,
+            Some(_) => return ReturnValue::Error(b"NOPROTO unsupported protocol version"),
+        };
+        let proto = match self.protocol {
+            ProtocolVersion::Resp2 => 2,
+            ProtocolVersion::Resp3 => 3,
+        };
+        let string = |s: &'static str| ReturnValue::StringRes(Bytes::from_static(s.as_bytes()));
+        ReturnValue::Map(vec![
+            (string("server"), string("redis")),
+            (string("version"), string(env!("CARGO_PKG_VERSION"))),
+            (string("proto"), ReturnValue::IntRes(proto)),
+            (string("id"), ReturnValue::IntRes(self.id as Count)),
+            (string("mode"), string("standalone")),
+            (string("role"), string("master")),
+            (
+                string("modules"),
+                ReturnValue::Ident(RedisValueRef::Array(vec![])),
+            ),
+        ])
+    }
+}
+
+#[cfg(test)]
+mod test_client {
+    use crate::asyncresp::ProtocolVersion;
+    use crate::client::Client;
+
+    #[test]
+    fn test_hello() {
+        let mut client = Client::default();
+        assert!(!client.hello(None).is_error());
+        assert_eq!(client.protocol, ProtocolVersion::Resp2);
+        assert!(!client.hello(Some(3)).is_error());
+        assert_eq!(client.protocol, ProtocolVersion::Resp3);
+        assert!(client.hello(Some(4)).is_error());
+        assert_eq!(client.protocol, ProtocolVersion::Resp3);
+        client.hello(Some(2));
+        assert_eq!(client.protocol, ProtocolVersion::Resp2);
+    }
+}
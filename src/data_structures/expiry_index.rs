@@ -0,0 +1,174 @@
+use crate::data_structures::memory_tracker::random_u64;
+use crate::types::{Key, Timestamp};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeSet;
+
+/// Tracks key deadlines.
+///
+/// `deadlines` is used for the per-key lookups done on every access,
+/// while `queue` keeps the same deadlines ordered by time so the
+/// background task can find expired keys without scanning everything.
+#[derive(Default, Debug)]
+pub struct ExpiryIndex {
+    deadlines: DashMap<Key, Timestamp>,
+    queue: Mutex<BTreeSet<(Timestamp, Key)>>,
+}
+
+impl ExpiryIndex {
+    /// Get the deadline of a key, if it has one.
+    pub fn get(&self, key: &[u8]) -> Option<Timestamp> {
+        self.deadlines.get(key).map(|t| *t.value())
+    }
+
+    /// Set (or overwrite) the deadline of a key.
+    pub fn set(&self, key: Key, deadline: Timestamp) {
+        let mut queue = self.queue.lock();
+        if let Some(old) = self.deadlines.insert(key.clone(), deadline) {
+            queue.remove(&(old, key.clone()));
+        }
+        queue.insert((deadline, key));
+    }
+
+    /// Remove the deadline of a key. Returns the old deadline if there was one.
+    pub fn remove(&self, key: &[u8]) -> Option<Timestamp> {
+        let mut queue = self.queue.lock();
+        let (key, old) = self.deadlines.remove(key)?;
+        queue.remove(&(old, key));
+        Some(old)
+    }
+
+    /// Remove the deadline of a key only if it has passed.
+    pub fn remove_if_expired(&self, key: &[u8], now: Timestamp) -> bool {
+        let mut queue = self.queue.lock();
+        match self
+            .deadlines
+            .remove_if(key, |_, deadline| *deadline <= now)
+        {
+            Some((key, old)) => {
+                queue.remove(&(old, key));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Get up to `limit` keys whose deadline is at or before `now`.
+    pub fn due(&self, now: Timestamp, limit: usize) -> Vec<Key> {
+        self.queue
+            .lock()
+            .iter()
+            .take_while(|(deadline, _)| *deadline <= now)
+            .take(limit)
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+
+    /// Up to `n` keys with a deadline, picked at random.
+    ///
+    /// Each pick is the first key at or after a random time between the
+    /// earliest and latest deadline, so isolated deadlines are favoured.
+    pub fn sample(&self, n: usize) -> Vec<Key> {
+        let queue = self.queue.lock();
+        let (first, last) = match (queue.first(), queue.last()) {
+            (Some((first, _)), Some((last, _))) => (*first, *last),
+            _ => return Vec::new(),
+        };
+        let span = (last - first) as u64 + 1;
+        (0..n)
+            .filter_map(|_| {
+                let at = first + (random_u64() % span) as Timestamp;
+                queue
+                    .range((at, Key::new())..)
+                    .next()
+                    .map(|(_, key)| key.clone())
+            })
+            .collect()
+    }
+
+    /// Number of keys with a deadline.
+    pub fn len(&self) -> usize {
+        self.deadlines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deadlines.is_empty()
+    }
+
+    pub fn clear(&self) {
+        let mut queue = self.queue.lock();
+        self.deadlines.clear();
+        queue.clear();
+    }
+}
+
+// Only the deadlines are written to disk; the queue is rebuilt on load.
+impl Serialize for ExpiryIndex {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.deadlines.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ExpiryIndex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let deadlines: DashMap<Key, Timestamp> = DashMap::deserialize(deserializer)?;
+        let queue = deadlines
+            .iter()
+            .map(|ent| (*ent.value(), ent.key().clone()))
+            .collect();
+        Ok(ExpiryIndex {
+            deadlines,
+            queue: Mutex::new(queue),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_expiry_index {
+    use crate::data_structures::expiry_index::ExpiryIndex;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_set_overwrites() {
+        let idx = ExpiryIndex::default();
+        let key = Bytes::from_static(b"key");
+        idx.set(key.clone(), 10);
+        idx.set(key.clone(), 20);
+        assert_eq!(idx.get(&key), Some(20));
+        assert_eq!(idx.due(15, 10), Vec::<Bytes>::new());
+        assert_eq!(idx.due(20, 10), vec![key.clone()]);
+        assert_eq!(idx.remove(&key), Some(20));
+        assert!(idx.is_empty());
+        assert!(idx.sample(5).is_empty());
+        idx.set(key.clone(), 10);
+        idx.set(Bytes::from_static(b"other"), 1000);
+        assert_eq!(idx.sample(5).len(), 5);
+    }
+
+    #[test]
+    fn test_due_is_ordered() {
+        let idx = ExpiryIndex::default();
+        idx.set(Bytes::from_static(b"c"), 30);
+        idx.set(Bytes::from_static(b"a"), 10);
+        idx.set(Bytes::from_static(b"b"), 20);
+        assert_eq!(
+            idx.due(25, 10),
+            vec![Bytes::from_static(b"a"), Bytes::from_static(b"b")]
+        );
+        assert_eq!(idx.due(25, 1), vec![Bytes::from_static(b"a")]);
+        assert!(!idx.remove_if_expired(b"c", 25));
+        assert!(idx.remove_if_expired(b"a", 25));
+        assert_eq!(idx.len(), 2);
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let idx = ExpiryIndex::default();
+        idx.set(Bytes::from_static(b"a"), 10);
+        let bytes = rmp_serde::to_vec(&idx).unwrap();
+        let idx: ExpiryIndex = rmp_serde::decode::from_read(&bytes[..]).unwrap();
+        assert_eq!(idx.get(b"a"), Some(10));
+        assert_eq!(idx.due(10, 10), vec![Bytes::from_static(b"a")]);
+    }
+}
@@ -0,0 +1,63 @@
+use crate::types::Key;
+use tokio::sync::{Mutex, MutexGuard};
+
+/// Number of locks keys are spread over.
+const STRIPES: usize = 256;
+
+/// Locks that writes hold from checking the type of their keys until
+/// they're done, so two writes can't create one key as two types.
+///
+/// Keys are spread over a fixed number of locks, so unrelated keys
+/// may share one.
+#[derive(Debug)]
+pub struct KeyLocks {
+    stripes: Vec<Mutex<()>>,
+}
+
+impl Default for KeyLocks {
+    fn default() -> Self {
+        KeyLocks {
+            stripes: (0..STRIPES).map(|_| Mutex::new(())).collect(),
+        }
+    }
+}
+
+impl KeyLocks {
+    /// Lock every key in `keys`. Locks are always taken in the same
+    /// order, so writes sharing keys can't deadlock.
+    pub async fn lock(&self, keys: &[Key]) -> Vec<MutexGuard<'_, ()>> {
+        let mut stripes: Vec<usize> = keys.iter().map(|key| stripe(key)).collect();
+        stripes.sort_unstable();
+        stripes.dedup();
+        let mut guards = Vec::with_capacity(stripes.len());
+        for stripe in stripes {
+            guards.push(self.stripes[stripe].lock().await);
+        }
+        guards
+    }
+}
+
+fn stripe(key: &[u8]) -> usize {
+    (seahash::hash(key) % STRIPES as u64) as usize
+}
+
+#[cfg(test)]
+mod test_key_locks {
+    use crate::data_structures::key_locks::KeyLocks;
+    use bytes::Bytes;
+
+    #[tokio::test]
+    async fn test_shared_keys_wait() {
+        let locks = KeyLocks::default();
+        let a = Bytes::from_static(b"a");
+        let b = Bytes::from_static(b"b");
+        let held = locks.lock(&[a.clone(), b.clone(), a]).await;
+        assert!(!held.is_empty());
+        let keys = [b];
+        let waiting = locks.lock(&keys);
+        tokio::pin!(waiting);
+        assert!(futures::poll!(waiting.as_mut()).is_pending());
+        drop(held);
+        assert!(futures::poll!(waiting.as_mut()).is_ready());
+    }
+}
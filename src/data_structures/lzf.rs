@@ -0,0 +1,125 @@
+//! LZF, the small and fast compression redis uses for quicklist nodes.
+//!
+//! The output is a series of chunks, each starting with a control byte:
+//! - `000LLLLL`: a run of L + 1 literal bytes follows.
+//! - `LLLOOOOO OOOOOOOO`: copy L + 2 bytes from O + 1 bytes back.
+//!   L = 7 means the length continues in the next byte, before the offset.
+
+/// Bits of the hash table used to find matches.
+const HASH_LOG: u32 = 13;
+const MAX_LITERAL: usize = 1 << 5;
+const MAX_OFFSET: usize = 1 << 13;
+const MAX_MATCH: usize = (1 << 8) + (1 << 3);
+
+/// Compress `input`, or None if that wouldn't make it any smaller.
+pub fn compress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len());
+    // Last position + 1 of every hashed 3 byte sequence.
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut literals = 0;
+    let mut pos = 0;
+    while pos + 2 < input.len() {
+        let hash = hash(&input[pos..pos + 3]);
+        let candidate = std::mem::replace(&mut table[hash], pos + 1);
+        let offset = pos + 1 - candidate;
+        if candidate == 0 || offset > MAX_OFFSET || input[candidate - 1..][..3] != input[pos..][..3]
+        {
+            pos += 1;
+            continue;
+        }
+        let max_len = MAX_MATCH.min(input.len() - pos);
+        let len = (3..max_len)
+            .find(|&len| input[candidate - 1 + len] != input[pos + len])
+            .unwrap_or(max_len);
+        push_literals(&mut out, &input[literals..pos]);
+        let (len_code, offset_code) = (len - 2, offset - 1);
+        if len_code < 7 {
+            out.push((len_code << 5 | offset_code >> 8) as u8);
+        } else {
+            out.push((7 << 5 | offset_code >> 8) as u8);
+            out.push((len_code - 7) as u8);
+        }
+        out.push(offset_code as u8);
+        if out.len() >= input.len() {
+            return None;
+        }
+        pos += len;
+        literals = pos;
+    }
+    push_literals(&mut out, &input[literals..]);
+    (out.len() < input.len()).then_some(out)
+}
+
+/// Decompress the output of `compress`, which was `len` bytes long.
+pub fn decompress(input: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut pos = 0;
+    while pos < input.len() {
+        let control = input[pos] as usize;
+        pos += 1;
+        if control < MAX_LITERAL {
+            out.extend_from_slice(&input[pos..pos + control + 1]);
+            pos += control + 1;
+            continue;
+        }
+        let mut run = control >> 5;
+        if run == 7 {
+            run += input[pos] as usize;
+            pos += 1;
+        }
+        let start = out.len() - ((control & 0x1f) << 8 | input[pos] as usize) - 1;
+        pos += 1;
+        // Matches may overlap what they produce, so copy byte by byte.
+        for i in start..start + run + 2 {
+            out.push(out[i]);
+        }
+    }
+    out
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let v = u32::from(bytes[0]) << 16 | u32::from(bytes[1]) << 8 | u32::from(bytes[2]);
+    (v.wrapping_mul(2654435761) >> (32 - HASH_LOG)) as usize
+}
+
+fn push_literals(out: &mut Vec<u8>, literals: &[u8]) {
+    for chunk in literals.chunks(MAX_LITERAL) {
+        out.push((chunk.len() - 1) as u8);
+        out.extend_from_slice(chunk);
+    }
+}
+
+#[cfg(test)]
+mod test_lzf {
+    use crate::data_structures::lzf::{compress, decompress};
+
+    #[test]
+    fn test_round_trip() {
+        let repeated: Vec<u8> = b"quicklist node ".repeat(100);
+        let compressed = compress(&repeated).unwrap();
+        assert!(compressed.len() < repeated.len() / 10);
+        assert_eq!(decompress(&compressed, repeated.len()), repeated);
+        // Long runs overlap their own output.
+        let run = vec![b'a'; 5000];
+        assert_eq!(decompress(&compress(&run).unwrap(), run.len()), run);
+        let mixed: Vec<u8> = (0..4000u32).flat_map(|i| (i % 300).to_le_bytes()).collect();
+        let compressed = compress(&mixed).unwrap();
+        assert_eq!(decompress(&compressed, mixed.len()), mixed);
+    }
+
+    #[test]
+    fn test_incompressible() {
+        assert_eq!(compress(b""), None);
+        assert_eq!(compress(b"abc"), None);
+        let mut x: u64 = 0x9e3779b97f4a7c15;
+        let noise: Vec<u8> = (0..1000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        assert_eq!(compress(&noise), None);
+    }
+}
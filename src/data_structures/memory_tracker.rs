@@ -0,0 +1,206 @@
+use crate::types::{Key, Timestamp};
+use dashmap::DashMap;
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// LFU counter of new keys, so they aren't evicted straight away.
+const LFU_INIT: u8 = 5;
+/// Higher values make the LFU counter grow slower.
+const LFU_LOG_FACTOR: f64 = 10.0;
+/// The LFU counter drops by one per period without access.
+const LFU_DECAY_MS: Timestamp = 60_000;
+
+/// A cheap random number. Each `RandomState` is seeded differently.
+pub fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyUsage {
+    /// Approximate bytes used by the key and its value.
+    pub size: usize,
+    pub last_access: Timestamp,
+    /// Logarithmic access counter, like redis' LFU.
+    pub frequency: u8,
+}
+
+impl KeyUsage {
+    /// The access counter, after decaying for the time since the last access.
+    pub fn frequency_at(&self, now: Timestamp) -> u8 {
+        let periods = ((now - self.last_access) / LFU_DECAY_MS).clamp(0, 255);
+        self.frequency.saturating_sub(periods as u8)
+    }
+
+    fn access(&mut self, now: Timestamp) {
+        let frequency = self.frequency_at(now);
+        // The more accesses, the less likely the counter grows.
+        let p = 1.0 / (frequency.saturating_sub(LFU_INIT) as f64 * LFU_LOG_FACTOR + 1.0);
+        let r = random_u64() as f64 / u64::MAX as f64;
+        self.frequency = if frequency < u8::MAX && r < p {
+            frequency + 1
+        } else {
+            frequency
+        };
+        self.last_access = now;
+    }
+}
+
+/// Every tracked key, in a vec so random samples are cheap.
+#[derive(Default, Debug)]
+struct KeyPool {
+    keys: Vec<Key>,
+    positions: HashMap<Key, usize>,
+}
+
+impl KeyPool {
+    fn insert(&mut self, key: Key) {
+        if !self.positions.contains_key(&key) {
+            self.positions.insert(key.clone(), self.keys.len());
+            self.keys.push(key);
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(pos) = self.positions.remove(key) {
+            self.keys.swap_remove(pos);
+            if let Some(moved) = self.keys.get(pos) {
+                self.positions.insert(moved.clone(), pos);
+            }
+        }
+    }
+}
+
+/// Approximate memory use and access information of every key,
+/// for maxmemory and its eviction policies.
+#[derive(Default, Debug)]
+pub struct MemoryTracker {
+    /// Only set with a maxmemory, so nothing is tracked for nothing.
+    enabled: AtomicBool,
+    /// Sum of the sizes in `usage`.
+    used: AtomicUsize,
+    usage: DashMap<Key, KeyUsage>,
+    pool: Mutex<KeyPool>,
+}
+
+impl MemoryTracker {
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Approximate bytes used by every tracked key.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+
+    /// Set the size of a key that was written to. Counts as an access.
+    pub fn update(&self, key: &Key, size: usize, now: Timestamp) {
+        let resize = |usage: &mut KeyUsage| {
+            self.used.fetch_add(size, Ordering::SeqCst);
+            self.used.fetch_sub(usage.size, Ordering::SeqCst);
+            usage.size = size;
+            usage.access(now);
+        };
+        if let Some(mut usage) = self.usage.get_mut(key) {
+            return resize(&mut usage);
+        }
+        // New keys change the pool, which is always locked first.
+        let mut pool = self.pool.lock();
+        let mut usage = self.usage.entry(key.clone()).or_insert_with(|| {
+            pool.insert(key.clone());
+            KeyUsage {
+                size: 0,
+                last_access: now,
+                frequency: LFU_INIT,
+            }
+        });
+        resize(&mut usage);
+    }
+
+    /// Record a read of `key`.
+    pub fn touch(&self, key: &[u8], now: Timestamp) {
+        if let Some(mut usage) = self.usage.get_mut(key) {
+            usage.access(now);
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<KeyUsage> {
+        self.usage.get(key).map(|usage| *usage)
+    }
+
+    /// Stop tracking a removed key.
+    pub fn remove(&self, key: &[u8]) {
+        let mut pool = self.pool.lock();
+        if let Some((_, usage)) = self.usage.remove(key) {
+            self.used.fetch_sub(usage.size, Ordering::SeqCst);
+            pool.remove(key);
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut pool = self.pool.lock();
+        self.usage.clear();
+        *pool = KeyPool::default();
+        self.used.store(0, Ordering::SeqCst);
+    }
+
+    /// Up to `n` random keys, with their usage.
+    pub fn sample(&self, n: usize) -> Vec<(Key, KeyUsage)> {
+        let keys: Vec<Key> = {
+            let pool = self.pool.lock();
+            if pool.keys.is_empty() {
+                return Vec::new();
+            }
+            (0..n)
+                .map(|_| pool.keys[random_u64() as usize % pool.keys.len()].clone())
+                .collect()
+        };
+        keys.into_iter()
+            .filter_map(|key| self.get(&key).map(|usage| (key, usage)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test_memory_tracker {
+    use crate::data_structures::memory_tracker::MemoryTracker;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_accounting() {
+        let tracker = MemoryTracker::default();
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        tracker.update(&a, 100, 0);
+        tracker.update(&b, 50, 0);
+        assert_eq!(tracker.used(), 150);
+        tracker.update(&a, 10, 0);
+        assert_eq!(tracker.used(), 60);
+        tracker.remove(&a);
+        tracker.remove(&a);
+        assert_eq!(tracker.used(), 50);
+        assert_eq!(tracker.sample(3).len(), 3);
+        assert!(tracker.sample(3).iter().all(|(key, _)| key == &b));
+        tracker.clear();
+        assert_eq!(tracker.used(), 0);
+        assert!(tracker.sample(3).is_empty());
+    }
+
+    #[test]
+    fn test_frequency_decays() {
+        let tracker = MemoryTracker::default();
+        let key = Bytes::from_static(b"key");
+        tracker.update(&key, 1, 0);
+        for _ in 0..1000 {
+            tracker.touch(&key, 0);
+        }
+        let usage = tracker.get(&key).unwrap();
+        assert!(usage.frequency > 5);
+        assert!(usage.frequency_at(10 * 60_000) < usage.frequency);
+    }
+}
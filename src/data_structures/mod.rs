@@ -1,3 +1,12 @@
+pub mod expiry_index;
+pub mod key_locks;
+pub mod lzf;
+pub mod memory_tracker;
+pub mod quicklist;
 pub mod receipt_map;
+pub mod scan_index;
+pub mod skiplist;
 pub mod sorted_set;
 pub mod stack;
+pub mod stream;
+pub mod watch_map;
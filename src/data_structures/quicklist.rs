@@ -0,0 +1,896 @@
+//! A quicklist, the compact encoding behind redis' lists.
+//!
+//! Values are packed back to back into nodes of a few kilobytes, instead of
+//! each getting its own allocation. An entry is its length as a varint, the
+//! value, then the length again with its varint bytes reversed, so nodes can
+//! be walked from either end. Lists are mostly worked on at their ends, so
+//! nodes further than `compress_depth` from both ends may be LZF compressed.
+use crate::data_structures::lzf;
+use crate::types::Value;
+use bytes::Bytes;
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fmt;
+use std::mem::size_of;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+
+/// Nodes filled by entry count still stop growing past this many bytes.
+const SIZE_SAFETY_LIMIT: usize = 8192;
+/// Nodes smaller than this aren't worth compressing.
+const MIN_COMPRESS_SIZE: usize = 48;
+
+/// Settings for new lists, from the command line.
+static DEFAULT_FILL: AtomicI64 = AtomicI64::new(-2);
+static DEFAULT_COMPRESS_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+/// How full nodes get, like redis' list-max-listpack-size: a positive
+/// number of entries, or -1 to -5 for 4, 8, 16, 32 or 64 kilobytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fill(i64);
+
+impl Fill {
+    /// Whether a node of `count` entries and `size` bytes is within the fill.
+    /// A single entry always is, however large.
+    fn allows(self, count: usize, size: usize) -> bool {
+        if count <= 1 {
+            return true;
+        }
+        match self.0 {
+            entries if entries > 0 => count <= entries as usize && size <= SIZE_SAFETY_LIMIT,
+            size_class => size <= 4096 << (-size_class - 1),
+        }
+    }
+}
+
+impl Default for Fill {
+    fn default() -> Self {
+        Fill(-2)
+    }
+}
+
+impl FromStr for Fill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse() {
+            Ok(fill) if fill > 0 || (-5..=-1).contains(&fill) => Ok(Fill(fill)),
+            _ => Err(format!(
+                "Invalid list fill {}, expected a positive number of entries or -1 to -5",
+                s
+            )),
+        }
+    }
+}
+
+/// Set the fill and compress depth of lists created from now on.
+pub fn set_defaults(fill: Fill, compress_depth: usize) {
+    DEFAULT_FILL.store(fill.0, Ordering::Relaxed);
+    DEFAULT_COMPRESS_DEPTH.store(compress_depth, Ordering::Relaxed);
+}
+
+#[derive(Clone, Default)]
+struct Node {
+    count: usize,
+    /// The packed entries, or their compression.
+    data: Vec<u8>,
+    /// Length of the packed entries, if `data` is compressed.
+    compressed: Option<usize>,
+    /// Set once compression didn't pay off for the current entries.
+    incompressible: bool,
+}
+
+impl Node {
+    fn packed_len(&self) -> usize {
+        self.compressed.unwrap_or(self.data.len())
+    }
+
+    fn packed(&self) -> Cow<'_, [u8]> {
+        match self.compressed {
+            Some(len) => Cow::Owned(lzf::decompress(&self.data, len)),
+            None => Cow::Borrowed(&self.data),
+        }
+    }
+
+    fn to_bytes(&self) -> Bytes {
+        match self.packed() {
+            Cow::Owned(packed) => Bytes::from(packed),
+            Cow::Borrowed(packed) => Bytes::copy_from_slice(packed),
+        }
+    }
+
+    fn decompress(&mut self) {
+        if let Some(len) = self.compressed.take() {
+            self.data = lzf::decompress(&self.data, len);
+            self.incompressible = false;
+        }
+    }
+
+    /// The packed entries, to be modified.
+    fn packed_mut(&mut self) -> &mut Vec<u8> {
+        self.decompress();
+        self.incompressible = false;
+        &mut self.data
+    }
+
+    fn compress(&mut self) {
+        if self.compressed.is_some() || self.incompressible || self.data.len() < MIN_COMPRESS_SIZE {
+            return;
+        }
+        match lzf::compress(&self.data) {
+            Some(compressed) => {
+                self.compressed = Some(self.data.len());
+                self.data = compressed;
+                self.data.shrink_to_fit();
+            }
+            None => self.incompressible = true,
+        }
+    }
+}
+
+fn varint_len(mut n: usize) -> usize {
+    let mut len = 1;
+    while n >= 0x80 {
+        n >>= 7;
+        len += 1;
+    }
+    len
+}
+
+fn entry_size(value: &[u8]) -> usize {
+    value.len() + 2 * varint_len(value.len())
+}
+
+fn encode(value: &[u8]) -> Vec<u8> {
+    let mut entry = Vec::with_capacity(entry_size(value));
+    let mut len = value.len();
+    while len >= 0x80 {
+        entry.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    entry.push(len as u8);
+    let header = entry.len();
+    entry.extend_from_slice(value);
+    entry.extend_from_within(..header);
+    entry[header + value.len()..].reverse();
+    entry
+}
+
+/// The value of the entry starting at `pos`, and where the next one starts.
+fn entry_at(packed: &[u8], pos: usize) -> (std::ops::Range<usize>, usize) {
+    let (mut len, mut shift, mut start) = (0, 0, pos);
+    loop {
+        let byte = packed[start];
+        start += 1;
+        len |= usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let header = start - pos;
+    (start..start + len, start + len + header)
+}
+
+/// The value of the entry ending at `end`, and where it starts.
+fn entry_before(packed: &[u8], end: usize) -> (std::ops::Range<usize>, usize) {
+    let (mut len, mut shift, mut stop) = (0, 0, end);
+    loop {
+        stop -= 1;
+        let byte = packed[stop];
+        len |= usize::from(byte & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    let header = end - stop;
+    (stop - len..stop, stop - len - header)
+}
+
+/// Where the entry at `index` starts, or the end of the node.
+fn offset_of(packed: &[u8], count: usize, index: usize) -> usize {
+    if index <= count / 2 {
+        (0..index).fold(0, |pos, _| entry_at(packed, pos).1)
+    } else {
+        (index..count).fold(packed.len(), |end, _| entry_before(packed, end).1)
+    }
+}
+
+pub struct QuickList {
+    nodes: VecDeque<Node>,
+    len: usize,
+    /// Bytes allocated by the nodes' data, kept up to date as they change.
+    allocated: usize,
+    fill: Fill,
+    /// Nodes left uncompressed at each end, or 0 to never compress.
+    compress_depth: usize,
+}
+
+impl Default for QuickList {
+    fn default() -> Self {
+        QuickList::new(
+            Fill(DEFAULT_FILL.load(Ordering::Relaxed)),
+            DEFAULT_COMPRESS_DEPTH.load(Ordering::Relaxed),
+        )
+    }
+}
+
+// Cloned nodes only allocate what they hold.
+impl Clone for QuickList {
+    fn clone(&self) -> Self {
+        let nodes: VecDeque<Node> = self.nodes.clone();
+        QuickList {
+            allocated: nodes.iter().map(|node| node.data.capacity()).sum(),
+            nodes,
+            ..*self
+        }
+    }
+}
+
+impl QuickList {
+    pub fn new(fill: Fill, compress_depth: usize) -> Self {
+        QuickList {
+            nodes: VecDeque::new(),
+            len: 0,
+            allocated: 0,
+            fill,
+            compress_depth,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Approximate bytes allocated by the list.
+    pub fn memory_usage(&self) -> usize {
+        let nodes = self.nodes.capacity() * size_of::<Node>();
+        size_of::<Self>() + nodes + self.allocated
+    }
+
+    pub fn push_front(&mut self, value: &[u8]) {
+        let entry = encode(value);
+        if !self.has_room(self.nodes.front(), entry.len()) {
+            if !self.nodes.is_empty() {
+                self.with_node(0, |full| full.data.shrink_to_fit());
+            }
+            self.nodes.push_front(Node::default());
+        }
+        self.with_node(0, |node| {
+            node.packed_mut().splice(..0, entry);
+            node.count += 1;
+        });
+        self.len += 1;
+        self.settle_ends();
+    }
+
+    pub fn push_back(&mut self, value: &[u8]) {
+        let entry = encode(value);
+        if !self.has_room(self.nodes.back(), entry.len()) {
+            if let Some(last) = self.nodes.len().checked_sub(1) {
+                self.with_node(last, |full| full.data.shrink_to_fit());
+            }
+            self.nodes.push_back(Node::default());
+        }
+        self.with_node(self.nodes.len() - 1, |node| {
+            node.packed_mut().extend_from_slice(&entry);
+            node.count += 1;
+        });
+        self.len += 1;
+        self.settle_ends();
+    }
+
+    pub fn pop_front(&mut self) -> Option<Value> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let value = self.with_node(0, |node| {
+            let packed = node.packed_mut();
+            let (value, next) = entry_at(packed, 0);
+            let value = Bytes::copy_from_slice(&packed[value]);
+            packed.drain(..next);
+            node.count -= 1;
+            value
+        });
+        if self.nodes[0].count == 0 {
+            self.remove_node(0);
+        }
+        self.len -= 1;
+        self.settle_ends();
+        Some(value)
+    }
+
+    pub fn pop_back(&mut self) -> Option<Value> {
+        let last = self.nodes.len().checked_sub(1)?;
+        let value = self.with_node(last, |node| {
+            let packed = node.packed_mut();
+            let (value, start) = entry_before(packed, packed.len());
+            let value = Bytes::copy_from_slice(&packed[value]);
+            packed.truncate(start);
+            node.count -= 1;
+            value
+        });
+        if self.nodes[last].count == 0 {
+            self.remove_node(last);
+        }
+        self.len -= 1;
+        self.settle_ends();
+        Some(value)
+    }
+
+    pub fn get(&self, index: usize) -> Option<Value> {
+        let (node, index) = self.locate(index)?;
+        let node = &self.nodes[node];
+        let packed = node.packed();
+        let (value, _) = entry_at(&packed, offset_of(&packed, node.count, index));
+        Some(Bytes::copy_from_slice(&packed[value]))
+    }
+
+    /// Replace the value at `index`. Returns false if it's out of range.
+    pub fn set(&mut self, index: usize, value: &[u8]) -> bool {
+        let (node_index, index) = match self.locate(index) {
+            Some(found) => found,
+            None => return false,
+        };
+        self.with_node(node_index, |node| {
+            let count = node.count;
+            let packed = node.packed_mut();
+            let start = offset_of(packed, count, index);
+            let (_, end) = entry_at(packed, start);
+            packed.splice(start..end, encode(value));
+        });
+        self.split_full(node_index);
+        self.settle_ends();
+        true
+    }
+
+    /// Insert `value` so it ends up at `index`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index` is greater than the list's length.
+    pub fn insert(&mut self, index: usize, value: &[u8]) {
+        assert!(index <= self.len, "index out of bounds");
+        if index == 0 {
+            return self.push_front(value);
+        }
+        if index == self.len {
+            return self.push_back(value);
+        }
+        let (node_index, index) = self.locate(index).unwrap();
+        self.with_node(node_index, |node| {
+            let count = node.count;
+            let packed = node.packed_mut();
+            let pos = offset_of(packed, count, index);
+            packed.splice(pos..pos, encode(value));
+            node.count += 1;
+        });
+        self.len += 1;
+        self.split_full(node_index);
+        self.settle_ends();
+    }
+
+    /// Keep the first `len` values.
+    pub fn truncate(&mut self, len: usize) {
+        while self.len > len {
+            let last = self.nodes.len() - 1;
+            let excess = self.len - len;
+            if excess >= self.nodes[last].count {
+                self.len -= self.nodes[last].count;
+                self.remove_node(last);
+                continue;
+            }
+            self.with_node(last, |node| {
+                let keep = node.count - excess;
+                let packed = node.packed_mut();
+                packed.truncate(offset_of(packed, keep + excess, keep));
+                node.count = keep;
+            });
+            self.len = len;
+        }
+        self.settle_ends();
+    }
+
+    /// Remove the first `count` values.
+    pub fn remove_front(&mut self, count: usize) {
+        let len = self.len.saturating_sub(count);
+        while self.len > len {
+            let excess = self.len - len;
+            if excess >= self.nodes[0].count {
+                self.len -= self.nodes[0].count;
+                self.remove_node(0);
+                continue;
+            }
+            self.with_node(0, |node| {
+                let count = node.count;
+                let packed = node.packed_mut();
+                packed.drain(..offset_of(packed, count, excess));
+                node.count = count - excess;
+            });
+            self.len = len;
+        }
+        self.settle_ends();
+    }
+
+    /// Keep only the values `f` returns true for.
+    /// Returns how many values were removed.
+    pub fn retain(&mut self, mut f: impl FnMut(&[u8]) -> bool) -> usize {
+        let before = self.len;
+        let mut nodes = VecDeque::with_capacity(self.nodes.len());
+        let mut current = Node::default();
+        for node in std::mem::take(&mut self.nodes) {
+            let packed = node.packed();
+            let mut pos = 0;
+            while pos < packed.len() {
+                let (value, next) = entry_at(&packed, pos);
+                if !f(&packed[value]) {
+                    self.len -= 1;
+                } else {
+                    let entry = &packed[pos..next];
+                    if !self.has_room(Some(&current), entry.len()) {
+                        current.data.shrink_to_fit();
+                        nodes.push_back(std::mem::take(&mut current));
+                    }
+                    current.data.extend_from_slice(entry);
+                    current.count += 1;
+                }
+                pos = next;
+            }
+        }
+        if current.count > 0 {
+            current.data.shrink_to_fit();
+            nodes.push_back(current);
+        }
+        self.allocated = nodes.iter().map(|node| node.data.capacity()).sum();
+        self.nodes = nodes;
+        for index in 0..self.nodes.len() {
+            self.settle(index);
+        }
+        before - self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.len = 0;
+        self.allocated = 0;
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            list: self,
+            front: Cursor::default(),
+            back: Cursor {
+                node: self.nodes.len(),
+                ..Default::default()
+            },
+            remaining: self.len,
+        }
+    }
+
+    fn has_room(&self, node: Option<&Node>, entry_size: usize) -> bool {
+        node.is_some_and(|node| {
+            self.fill
+                .allows(node.count + 1, node.packed_len() + entry_size)
+        })
+    }
+
+    /// The node holding `index`, and its index in that node.
+    fn locate(&self, mut index: usize) -> Option<(usize, usize)> {
+        if index >= self.len {
+            return None;
+        }
+        if index < self.len / 2 {
+            for (i, node) in self.nodes.iter().enumerate() {
+                if index < node.count {
+                    return Some((i, index));
+                }
+                index -= node.count;
+            }
+        } else {
+            let mut from_back = self.len - index;
+            for (i, node) in self.nodes.iter().enumerate().rev() {
+                if from_back <= node.count {
+                    return Some((i, node.count - from_back));
+                }
+                from_back -= node.count;
+            }
+        }
+        unreachable!("list length out of sync with its nodes")
+    }
+
+    /// Run `f` on the node at `index`, accounting for what it allocates.
+    fn with_node<R>(&mut self, index: usize, f: impl FnOnce(&mut Node) -> R) -> R {
+        let node = &mut self.nodes[index];
+        let before = node.data.capacity();
+        let res = f(node);
+        self.allocated = self.allocated + node.data.capacity() - before;
+        res
+    }
+
+    fn remove_node(&mut self, index: usize) {
+        if let Some(node) = self.nodes.remove(index) {
+            self.allocated -= node.data.capacity();
+        }
+    }
+
+    /// Split the node at `index` until its halves are within the fill.
+    fn split_full(&mut self, index: usize) {
+        let node = &self.nodes[index];
+        if self.fill.allows(node.count, node.packed_len()) {
+            return self.settle(index);
+        }
+        let rest = self.with_node(index, |node| {
+            let count = node.count;
+            let packed = node.packed_mut();
+            let rest = packed.split_off(offset_of(packed, count, count / 2));
+            packed.shrink_to_fit();
+            node.count = count / 2;
+            Node {
+                count: count - count / 2,
+                data: rest,
+                ..Default::default()
+            }
+        });
+        self.allocated += rest.data.capacity();
+        self.nodes.insert(index + 1, rest);
+        self.split_full(index + 1);
+        self.split_full(index);
+    }
+
+    /// Compress or decompress the node at `index`, depending on how far it is
+    /// from the ends of the list.
+    fn settle(&mut self, index: usize) {
+        let depth = self.compress_depth;
+        if depth == 0 {
+            return;
+        }
+        let len = self.nodes.len();
+        if index < depth || index + depth >= len {
+            self.with_node(index, Node::decompress);
+        } else {
+            self.with_node(index, Node::compress);
+        }
+    }
+
+    /// Settle the nodes whose distance from an end may have just changed.
+    fn settle_ends(&mut self) {
+        let depth = self.compress_depth;
+        if depth == 0 {
+            return;
+        }
+        let len = self.nodes.len();
+        let back = len.saturating_sub(depth + 1).max(depth + 1);
+        for index in (0..=depth).chain(back..len) {
+            if index < len {
+                self.settle(index);
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Cursor {
+    /// The next node to load.
+    node: usize,
+    packed: Bytes,
+    /// Where the next entry starts, or the previous one ends when going back.
+    pos: usize,
+    /// Entries of `packed` not yet returned.
+    left: usize,
+}
+
+/// Iterates over a `QuickList`, decompressing one node at a time.
+/// Values share their node's buffer.
+pub struct Iter<'a> {
+    list: &'a QuickList,
+    front: Cursor,
+    back: Cursor,
+    remaining: usize,
+}
+
+impl<'a> Iter<'a> {
+    fn load_front(&mut self) {
+        let node = &self.list.nodes[self.front.node];
+        self.front.packed = node.to_bytes();
+        self.front.pos = 0;
+        self.front.left = node.count;
+        self.front.node += 1;
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = Value;
+
+    fn next(&mut self) -> Option<Value> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        if self.front.left == 0 {
+            self.load_front();
+        }
+        let (value, next) = entry_at(&self.front.packed, self.front.pos);
+        self.front.pos = next;
+        self.front.left -= 1;
+        Some(self.front.packed.slice(value))
+    }
+
+    /// Skips whole nodes without decompressing them.
+    fn nth(&mut self, mut n: usize) -> Option<Value> {
+        if n >= self.remaining {
+            self.remaining = 0;
+            return None;
+        }
+        self.remaining -= n;
+        if n >= self.front.left {
+            n -= self.front.left;
+            self.front.left = 0;
+            while n >= self.list.nodes[self.front.node].count {
+                n -= self.list.nodes[self.front.node].count;
+                self.front.node += 1;
+            }
+            self.load_front();
+        }
+        for _ in 0..n {
+            self.front.pos = entry_at(&self.front.packed, self.front.pos).1;
+            self.front.left -= 1;
+        }
+        self.next()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> DoubleEndedIterator for Iter<'a> {
+    fn next_back(&mut self) -> Option<Value> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        if self.back.left == 0 {
+            self.back.node -= 1;
+            let node = &self.list.nodes[self.back.node];
+            self.back.packed = node.to_bytes();
+            self.back.pos = self.back.packed.len();
+            self.back.left = node.count;
+        }
+        let (value, start) = entry_before(&self.back.packed, self.back.pos);
+        self.back.pos = start;
+        self.back.left -= 1;
+        Some(self.back.packed.slice(value))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
+
+impl<V: AsRef<[u8]>> FromIterator<V> for QuickList {
+    fn from_iter<I: IntoIterator<Item = V>>(iter: I) -> Self {
+        let mut list = QuickList::default();
+        for value in iter {
+            list.push_back(value.as_ref());
+        }
+        list
+    }
+}
+
+impl From<Vec<Value>> for QuickList {
+    fn from(values: Vec<Value>) -> Self {
+        values.into_iter().collect()
+    }
+}
+
+impl PartialEq for QuickList {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for QuickList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// Serialized as a plain sequence of values, like the VecDeque lists used to be.
+impl Serialize for QuickList {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for QuickList {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values: Vec<Value> = Vec::deserialize(deserializer)?;
+        Ok(values.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test_quicklist {
+    use crate::data_structures::quicklist::{Fill, QuickList};
+    use bytes::Bytes;
+    use rmp_serde as rmps;
+    use std::collections::VecDeque;
+
+    fn value(i: usize) -> Bytes {
+        // Some values need two byte lengths.
+        Bytes::from(format!("value {}", i).repeat(1 + i % 30))
+    }
+
+    /// Check every value and node against a VecDeque holding the same values.
+    fn check(list: &QuickList, expected: &VecDeque<Bytes>) {
+        assert_eq!(list.len(), expected.len());
+        assert!(list.iter().eq(expected.iter().cloned()));
+        assert!(list.iter().rev().eq(expected.iter().rev().cloned()));
+        for (i, v) in expected.iter().enumerate() {
+            assert_eq!(list.get(i).as_ref(), Some(v));
+        }
+        assert_eq!(list.get(expected.len()), None);
+        assert_eq!(
+            list.nodes.iter().map(|n| n.count).sum::<usize>(),
+            list.len()
+        );
+        let allocated: usize = list.nodes.iter().map(|n| n.data.capacity()).sum();
+        assert_eq!(list.allocated, allocated);
+        let depth = list.compress_depth;
+        for (i, node) in list.nodes.iter().enumerate() {
+            assert!(node.count > 0);
+            assert!(list.fill.allows(node.count, node.packed_len()));
+            if depth == 0 || i < depth || i + depth >= list.nodes.len() {
+                assert_eq!(node.compressed, None);
+            }
+        }
+    }
+
+    #[test]
+    fn test_push_pop() {
+        for (fill, depth) in [(Fill(4), 0), (Fill(4), 1), (Fill(-1), 2)] {
+            let mut list = QuickList::new(fill, depth);
+            let mut expected = VecDeque::new();
+            for i in 0..300 {
+                match i % 5 {
+                    0 | 1 => {
+                        list.push_back(&value(i));
+                        expected.push_back(value(i));
+                    }
+                    2 | 3 => {
+                        list.push_front(&value(i));
+                        expected.push_front(value(i));
+                    }
+                    _ => {
+                        assert_eq!(list.pop_front(), expected.pop_front());
+                    }
+                }
+            }
+            check(&list, &expected);
+            while !expected.is_empty() {
+                assert_eq!(list.pop_back(), expected.pop_back());
+                assert_eq!(list.pop_front(), expected.pop_front());
+            }
+            check(&list, &expected);
+            assert_eq!(list.pop_back(), None);
+            assert!(list.nodes.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_insert_set() {
+        let mut list = QuickList::new(Fill(-1), 1);
+        let mut expected = VecDeque::new();
+        // A deterministic shuffle of insert positions.
+        for i in 0..400 {
+            let index = (i * 7919) % (expected.len() + 1);
+            list.insert(index, &value(i));
+            expected.insert(index, value(i));
+        }
+        check(&list, &expected);
+        for i in (0..400).step_by(7) {
+            // Setting may need to split the node.
+            let big = Bytes::from(vec![b'x'; 1000 + i]);
+            assert!(list.set(i, &big));
+            expected[i] = big;
+        }
+        check(&list, &expected);
+        assert!(!list.set(400, b"x"));
+        let huge = Bytes::from(vec![b'y'; 10000]);
+        list.insert(200, &huge);
+        expected.insert(200, huge);
+        check(&list, &expected);
+    }
+
+    #[test]
+    fn test_trim_retain() {
+        let mut list = QuickList::new(Fill(8), 1);
+        let mut expected: VecDeque<Bytes> = (0..200).map(value).collect();
+        for v in &expected {
+            list.push_back(v);
+        }
+        list.truncate(150);
+        expected.truncate(150);
+        list.remove_front(13);
+        expected.drain(..13);
+        check(&list, &expected);
+        let removed = list.retain(|v| v.len() % 3 != 0);
+        let before = expected.len();
+        expected.retain(|v| v.len() % 3 != 0);
+        assert_eq!(removed, before - expected.len());
+        check(&list, &expected);
+        list.remove_front(1000);
+        assert!(list.is_empty());
+        assert!(list.nodes.is_empty());
+    }
+
+    #[test]
+    fn test_compression() {
+        let values: Vec<Bytes> = (0..2000).map(value).collect();
+        let plain: QuickList = {
+            let mut list = QuickList::new(Fill(-2), 0);
+            values.iter().for_each(|v| list.push_back(v));
+            list
+        };
+        let mut compressed = QuickList::new(Fill(-2), 1);
+        values.iter().for_each(|v| compressed.push_back(v));
+        assert!(compressed.nodes.len() > 3);
+        let mut interior = compressed.nodes.range(1..compressed.nodes.len() - 1);
+        assert!(interior.all(|node| node.compressed.is_some()));
+        assert!(compressed.memory_usage() < plain.memory_usage() / 2);
+        check(&compressed, &values.iter().cloned().collect());
+        check(&compressed.clone(), &values.iter().cloned().collect());
+        // Popping brings compressed nodes back to the ends.
+        let mut expected: VecDeque<Bytes> = values.into_iter().collect();
+        for _ in 0..1500 {
+            assert_eq!(compressed.pop_front(), expected.pop_front());
+        }
+        check(&compressed, &expected);
+    }
+
+    #[test]
+    fn test_iter_nth() {
+        let list: QuickList = {
+            let mut list = QuickList::new(Fill(5), 1);
+            (0..100).for_each(|i| list.push_back(&value(i)));
+            list
+        };
+        for skip in [0, 3, 5, 17, 99, 100] {
+            let expected: Vec<Bytes> = (skip..100).map(value).collect();
+            assert_eq!(list.iter().skip(skip).collect::<Vec<_>>(), expected);
+        }
+        let mut iter = list.iter();
+        assert_eq!(iter.next_back(), Some(value(99)));
+        assert_eq!(iter.nth(97), Some(value(97)));
+        assert_eq!(iter.next(), Some(value(98)));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next_back(), None);
+    }
+
+    #[test]
+    fn test_serialized_like_a_vecdeque() {
+        let values: VecDeque<Bytes> = (0..100).map(value).collect();
+        let list: QuickList = QuickList::from(values.iter().cloned().collect::<Vec<_>>());
+        let serialized = rmps::to_vec(&list).unwrap();
+        assert_eq!(serialized, rmps::to_vec(&values).unwrap());
+        let deserialized: QuickList = rmps::from_slice(&serialized).unwrap();
+        assert_eq!(deserialized, list);
+    }
+
+    #[test]
+    fn test_fill() {
+        assert_eq!("-2".parse(), Ok(Fill(-2)));
+        assert_eq!("128".parse(), Ok(Fill(128)));
+        assert!("0".parse::<Fill>().is_err());
+        assert!("-6".parse::<Fill>().is_err());
+        assert!(Fill(-1).allows(2, 4096));
+        assert!(!Fill(-1).allows(2, 4097));
+        assert!(Fill(-1).allows(1, 100_000));
+        assert!(!Fill(3).allows(4, 10));
+    }
+}
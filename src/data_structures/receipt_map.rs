@@ -1,69 +1,203 @@
 use seahash::hash;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, VecDeque},
     task::Waker,
 };
 
 pub type Receipt = u32;
 
-#[derive(Hash, Debug, PartialEq, Eq)]
+#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum KeyTypes {
     List(u64),
+    Stream(u64),
 }
 
 impl KeyTypes {
     pub fn list(key: &[u8]) -> KeyTypes {
         KeyTypes::List(hash(key))
     }
+
+    pub fn stream(key: &[u8]) -> KeyTypes {
+        KeyTypes::Stream(hash(key))
+    }
 }
 
+/// Clients blocked on keys, queued per key in the order they blocked.
+///
+/// A receipt stays queued until it's removed, so a client keeps its place
+/// while it's woken and retries. Waking takes the receipt's waker; a receipt
+/// without one has been woken and hasn't retried yet.
 #[derive(Default, Debug)]
 pub struct RecieptMap {
     counter: Receipt,
     wakers: HashMap<Receipt, Waker>,
-    timed_out: HashSet<Receipt>,
-    keys: HashMap<KeyTypes, Vec<Receipt>>,
+    keys: HashMap<KeyTypes, VecDeque<Receipt>>,
+    receipt_keys: HashMap<Receipt, Vec<KeyTypes>>,
 }
 
 impl RecieptMap {
     pub fn get_receipt(&mut self) -> Receipt {
-        self.counter += 1;
+        self.counter = self.counter.wrapping_add(1);
         self.counter
     }
 
-    pub fn insert(&mut self, receipt: Receipt, item: Waker, key: KeyTypes) {
+    // Method for (re)registering the waker of a receipt blocked on `keys`.
+    // The receipt is queued behind the others on its first registration only.
+    pub fn insert(&mut self, receipt: Receipt, item: Waker, keys: &[KeyTypes]) {
         self.wakers.insert(receipt, item);
-        self.keys.entry(key).or_default().push(receipt);
+        if self.receipt_keys.contains_key(&receipt) {
+            return;
+        }
+        for key in keys {
+            self.keys.entry(*key).or_default().push_back(receipt);
+        }
+        self.receipt_keys.insert(receipt, keys.to_vec());
     }
 
-    // Method for checking if a receipt has timed out
-    pub fn receipt_timed_out(&self, receipt: Receipt) -> bool {
-        self.timed_out.contains(&receipt)
+    // Method for forgetting a receipt, once its client was served, timed out
+    // or went away. A served client may have left data for the next one, and
+    // a client woken but gone before retrying leaves its wake unused, so in
+    // both cases the next client blocked on each of its keys is woken.
+    pub fn remove(&mut self, receipt: Receipt, served: bool) {
+        let keys = match self.receipt_keys.remove(&receipt) {
+            Some(keys) => keys,
+            None => return,
+        };
+        let woken = self.wakers.remove(&receipt).is_none() || served;
+        for key in keys {
+            if let Some(receipts) = self.keys.get_mut(&key) {
+                receipts.retain(|r| *r != receipt);
+                if receipts.is_empty() {
+                    self.keys.remove(&key);
+                }
+            }
+            if woken {
+                self.wake_with_key(key);
+            }
+        }
     }
 
-    // Method for waking up wakers associated with a specific key
+    // Method for waking up the longest blocked waker on a specific key
     pub fn wake_with_key(&mut self, key: KeyTypes) {
-        let v = self.keys.get_mut(&key);
-        if v.is_none() {
-            return;
+        let receipts = match self.keys.get(&key) {
+            Some(receipts) => receipts,
+            None => return,
+        };
+        let waker = receipts
+            .iter()
+            .find_map(|receipt| self.wakers.remove(receipt));
+        if let Some(waker) = waker {
+            waker.wake();
         }
-        let v = v.unwrap();
-        while let Some(receipt) = v.pop() {
-            match self.wakers.remove(&receipt) {
-                Some(waker) => {
-                    waker.wake();
-                    break;
-                }
-                None => continue,
-            };
+    }
+
+    // Method for waking up every waker associated with a specific key.
+    // Used when a write can serve all of them, like XADD to XREAD readers.
+    pub fn wake_all_with_key(&mut self, key: KeyTypes) {
+        let receipts = match self.keys.get(&key) {
+            Some(receipts) => receipts,
+            None => return,
+        };
+        for receipt in receipts {
+            if let Some(waker) = self.wakers.remove(receipt) {
+                waker.wake();
+            }
         }
     }
 
-    // Method for handling a timed out receipt
-    pub fn timeout_receipt(&mut self, receipt: Receipt) {
-        self.timed_out.insert(receipt);
-        if let Some(waker) = self.wakers.remove(&receipt) {
-            waker.wake();
+    /// The number of receipts still tracked.
+    pub fn len(&self) -> usize {
+        self.receipt_keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.receipt_keys.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod test_receipt_map {
+    use crate::data_structures::receipt_map::{KeyTypes, RecieptMap};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
         }
     }
+
+    fn waker() -> (Arc<CountingWaker>, Waker) {
+        let counter = Arc::new(CountingWaker::default());
+        (counter.clone(), Waker::from(counter))
+    }
+
+    fn wakes(counter: &CountingWaker) -> usize {
+        counter.0.load(Ordering::SeqCst)
+    }
+
+    #[test]
+    fn test_fifo_wakeups() {
+        let mut rm = RecieptMap::default();
+        let key = KeyTypes::list(b"l");
+        let counters: Vec<_> = (0..3)
+            .map(|_| {
+                let (counter, waker) = waker();
+                let receipt = rm.get_receipt();
+                rm.insert(receipt, waker, &[key]);
+                (receipt, counter)
+            })
+            .collect();
+        rm.wake_with_key(key);
+        assert_eq!(
+            counters.iter().map(|(_, c)| wakes(c)).collect::<Vec<_>>(),
+            vec![1, 0, 0]
+        );
+        // Retrying keeps the first client's place in the queue.
+        let (first, counter) = &counters[0];
+        rm.insert(*first, Waker::from(counter.clone()), &[key]);
+        rm.wake_with_key(key);
+        assert_eq!(wakes(counter), 2);
+        // Woken clients that go away pass the wake on.
+        rm.remove(*first, false);
+        assert_eq!(
+            counters.iter().map(|(_, c)| wakes(c)).collect::<Vec<_>>(),
+            vec![2, 1, 0]
+        );
+        // As do served ones.
+        let (second, counter) = &counters[1];
+        rm.insert(*second, Waker::from(counter.clone()), &[key]);
+        rm.remove(*second, true);
+        assert_eq!(wakes(&counters[2].1), 1);
+        // Unlike clients that weren't woken.
+        let (third, counter) = &counters[2];
+        rm.insert(*third, Waker::from(counter.clone()), &[key]);
+        let (fourth, waker) = waker();
+        let receipt = rm.get_receipt();
+        rm.insert(receipt, waker, &[key]);
+        rm.remove(*third, false);
+        assert_eq!(wakes(&fourth), 0);
+        rm.remove(receipt, false);
+        assert!(rm.is_empty());
+        assert!(rm.keys.is_empty() && rm.wakers.is_empty());
+    }
+
+    #[test]
+    fn test_many_keys() {
+        let mut rm = RecieptMap::default();
+        let (a, b) = (KeyTypes::list(b"a"), KeyTypes::list(b"b"));
+        let (counter, waker) = waker();
+        let receipt = rm.get_receipt();
+        rm.insert(receipt, waker, &[a, b]);
+        rm.wake_with_key(b);
+        // Already woken, so there's nothing to wake until it retries.
+        rm.wake_with_key(a);
+        assert_eq!(wakes(&counter), 1);
+        rm.remove(receipt, true);
+        assert!(rm.keys.is_empty());
+    }
 }
@@ -0,0 +1,477 @@
+use crate::types::{Key, Value};
+use dashmap::DashMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::hash::BuildHasher;
+
+/// Where `item` sits in scan order.
+pub fn position(item: &[u8]) -> u64 {
+    seahash::hash(item)
+}
+
+/// Take at least `count` of `items`, which are in position order, plus
+/// any sharing the position of the last one, so none are skipped.
+///
+/// Returns the cursor to continue from (0 once done), and the batch.
+fn scan_ordered<T>(items: impl Iterator<Item = (u64, T)>, count: usize) -> (u64, Vec<T>) {
+    let count = count.max(1);
+    let mut batch = Vec::with_capacity(count);
+    let mut last = None;
+    for (position, item) in items {
+        if batch.len() >= count && last != Some(position) {
+            return (position, batch);
+        }
+        last = Some(position);
+        batch.push(item);
+    }
+    (0, batch)
+}
+
+/// A set kept in hash order, so a scan can resume from a cursor
+/// without looking at anything before it.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct ScanSet {
+    members: BTreeSet<(u64, Value)>,
+}
+
+impl ScanSet {
+    pub fn insert(&mut self, member: Value) -> bool {
+        self.members.insert((position(&member), member))
+    }
+
+    pub fn remove(&mut self, member: &[u8]) -> bool {
+        self.take(member).is_some()
+    }
+
+    pub fn take(&mut self, member: &[u8]) -> Option<Value> {
+        let member = self.get(member)?.clone();
+        self.members
+            .take(&(position(&member), member))
+            .map(|(_, member)| member)
+    }
+
+    pub fn get(&self, member: &[u8]) -> Option<&Value> {
+        let position = position(member);
+        self.members
+            .range((position, Value::new())..)
+            .take_while(|(other, _)| *other == position)
+            .map(|(_, other)| other)
+            .find(|other| other[..] == *member)
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.get(member).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Value> + Clone {
+        self.members.iter().map(|(_, member)| member)
+    }
+
+    /// Take at least `count` members at or after `cursor`.
+    ///
+    /// Returns the cursor to continue from (0 once done), and the batch.
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<Value>) {
+        let members = self
+            .members
+            .range((cursor, Value::new())..)
+            .map(|(position, member)| (*position, member.clone()));
+        scan_ordered(members, count)
+    }
+}
+
+impl FromIterator<Value> for ScanSet {
+    fn from_iter<I: IntoIterator<Item = Value>>(members: I) -> Self {
+        ScanSet {
+            members: members.into_iter().map(|m| (position(&m), m)).collect(),
+        }
+    }
+}
+
+impl Extend<Value> for ScanSet {
+    fn extend<I: IntoIterator<Item = Value>>(&mut self, members: I) {
+        members.into_iter().for_each(|member| {
+            self.insert(member);
+        });
+    }
+}
+
+/// A map kept in hash order of its keys, like [`ScanSet`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanMap<V> {
+    entries: BTreeMap<(u64, Key), V>,
+}
+
+impl<V> Default for ScanMap<V> {
+    fn default() -> Self {
+        ScanMap {
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl<V> ScanMap<V> {
+    pub fn insert(&mut self, key: Key, value: V) -> Option<V> {
+        self.entries.insert((position(&key), key), value)
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let key = self.get_key_value(key)?.0.clone();
+        self.entries.remove(&(position(&key), key))
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        self.get_key_value(key).map(|(_, value)| value)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        let position = position(key);
+        self.entries
+            .range_mut((position, Key::new())..)
+            .take_while(|((other, _), _)| *other == position)
+            .find(|((_, other), _)| other[..] == *key)
+            .map(|(_, value)| value)
+    }
+
+    pub fn get_key_value(&self, key: &[u8]) -> Option<(&Key, &V)> {
+        let position = position(key);
+        self.entries
+            .range((position, Key::new())..)
+            .take_while(|((other, _), _)| *other == position)
+            .find(|((_, other), _)| other[..] == *key)
+            .map(|((_, key), value)| (key, value))
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get_key_value(key).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&Key, &V)> {
+        self.entries.iter().map(|((_, key), value)| (key, value))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.entries.keys().map(|(_, key)| key)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.entries.values()
+    }
+
+    /// Take at least `count` entries at or after `cursor`, see [`ScanSet::scan`].
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(Key, &V)>) {
+        let entries = self
+            .entries
+            .range((cursor, Key::new())..)
+            .map(|((position, key), value)| (*position, (key.clone(), value)));
+        scan_ordered(entries, count)
+    }
+}
+
+impl<V> FromIterator<(Key, V)> for ScanMap<V> {
+    fn from_iter<I: IntoIterator<Item = (Key, V)>>(entries: I) -> Self {
+        ScanMap {
+            entries: entries
+                .into_iter()
+                .map(|(key, value)| ((position(&key), key), value))
+                .collect(),
+        }
+    }
+}
+
+impl<V> Extend<(Key, V)> for ScanMap<V> {
+    fn extend<I: IntoIterator<Item = (Key, V)>>(&mut self, entries: I) {
+        entries.into_iter().for_each(|(key, value)| {
+            self.insert(key, value);
+        });
+    }
+}
+
+/// Bits of a SCAN cursor for the map a key is in, and for the shard of
+/// that map. The rest are for the key's position in the shard.
+const MAP_BITS: u32 = 4;
+const SHARD_BITS: u32 = 16;
+const POSITION_BITS: u32 = 64 - MAP_BITS - SHARD_BITS;
+
+/// Where a SCAN over several DashMaps resumes: a map, a shard of it,
+/// and a position in hash order within the shard.
+///
+/// A key stays in the same shard for as long as it exists, so nothing
+/// needs to be kept between calls.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct KeyCursor {
+    map: usize,
+    shard: usize,
+    position: u64,
+}
+
+impl From<u64> for KeyCursor {
+    fn from(cursor: u64) -> Self {
+        KeyCursor {
+            map: (cursor >> (SHARD_BITS + POSITION_BITS)) as usize,
+            shard: ((cursor >> POSITION_BITS) & ((1 << SHARD_BITS) - 1)) as usize,
+            position: cursor & ((1 << POSITION_BITS) - 1),
+        }
+    }
+}
+
+impl From<KeyCursor> for u64 {
+    fn from(cursor: KeyCursor) -> Self {
+        (cursor.map as u64) << (SHARD_BITS + POSITION_BITS)
+            | (cursor.shard as u64) << POSITION_BITS
+            | cursor.position
+    }
+}
+
+/// Where `key` sits in scan order within its shard.
+fn key_position(key: &[u8]) -> u64 {
+    position(key) >> (64 - POSITION_BITS)
+}
+
+/// Keys that can be scanned a shard at a time.
+pub trait ShardedKeys {
+    fn shard_count(&self) -> usize;
+
+    /// Take at least `count` keys of `shard` at or after `from`, plus
+    /// any sharing the position of the last one.
+    ///
+    /// Returns the position to continue from, if any, and the batch.
+    fn scan_shard(&self, shard: usize, from: u64, count: usize) -> (Option<u64>, Vec<Key>);
+}
+
+impl<V, S: BuildHasher + Clone> ShardedKeys for DashMap<Key, V, S> {
+    fn shard_count(&self) -> usize {
+        self.shards().len()
+    }
+
+    fn scan_shard(&self, shard: usize, from: u64, count: usize) -> (Option<u64>, Vec<Key>) {
+        let shard = self.shards()[shard].read();
+        let mut keys: Vec<(u64, &Key)> = shard
+            .keys()
+            .map(|key| (key_position(key), key))
+            .filter(|(position, _)| *position >= from)
+            .collect();
+        let count = count.max(1);
+        let mut next = None;
+        if keys.len() > count {
+            // Only the first `count` positions are needed, not a full sort.
+            let (_, last, _) =
+                keys.select_nth_unstable_by_key(count - 1, |(position, _)| *position);
+            let last = last.0;
+            next = keys
+                .iter()
+                .map(|(position, _)| *position)
+                .filter(|p| *p > last)
+                .min();
+            keys.retain(|(position, _)| *position <= last);
+        }
+        (next, keys.into_iter().map(|(_, key)| key.clone()).collect())
+    }
+}
+
+/// Take at least `count` keys of `maps` from `cursor` on, a shard at a
+/// time. Only the shard being read is locked.
+///
+/// Returns the cursor to continue from (0 once done), and the batch.
+pub fn scan_keys(maps: &[&dyn ShardedKeys], cursor: u64, count: usize) -> (u64, Vec<Key>) {
+    let count = count.max(1);
+    let mut cursor = KeyCursor::from(cursor);
+    let mut batch = Vec::new();
+    while let Some(keys) = maps.get(cursor.map) {
+        if cursor.shard >= keys.shard_count() {
+            cursor = KeyCursor {
+                map: cursor.map + 1,
+                shard: 0,
+                position: 0,
+            };
+            continue;
+        }
+        if batch.len() >= count {
+            return (cursor.into(), batch);
+        }
+        let (next, found) = keys.scan_shard(cursor.shard, cursor.position, count - batch.len());
+        batch.extend(found);
+        cursor = match next {
+            Some(position) => return (KeyCursor { position, ..cursor }.into(), batch),
+            None => KeyCursor {
+                shard: cursor.shard + 1,
+                position: 0,
+                ..cursor
+            },
+        };
+    }
+    (0, batch)
+}
+
+// Written to disk as plain collections, so older dumps still load.
+impl Serialize for ScanSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for ScanSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashSet::<Value>::deserialize(deserializer).map(|members| members.into_iter().collect())
+    }
+}
+
+impl<V: Serialize> Serialize for ScanMap<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.iter())
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for ScanMap<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        HashMap::<Key, V>::deserialize(deserializer).map(|entries| entries.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test_scan_index {
+    use crate::data_structures::scan_index::{scan_keys, ScanMap, ScanSet, ShardedKeys};
+    use bytes::Bytes;
+    use dashmap::DashMap;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_scan_visits_everything_once() {
+        let items: ScanSet = (0..1000)
+            .map(|i| Bytes::from(format!("item_{}", i)))
+            .collect();
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut calls = 0;
+        loop {
+            let (next, batch) = items.scan(cursor, 7);
+            assert!(batch.len() <= 7);
+            for item in batch {
+                assert!(seen.insert(item));
+            }
+            calls += 1;
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(seen.len(), items.len());
+        assert!(calls >= 1000 / 7);
+    }
+
+    #[test]
+    fn test_scan_survives_changes() {
+        let mut set = ScanSet::default();
+        set.extend((0..100).map(|i| Bytes::from(format!("m{}", i))));
+        let (cursor, first) = set.scan(0, 10);
+        // Remove what was returned and add new members midway.
+        for member in first.iter() {
+            assert!(set.remove(member));
+        }
+        set.extend((100..150).map(|i| Bytes::from(format!("m{}", i))));
+        let mut seen: HashSet<Bytes> = first.into_iter().collect();
+        let mut cursor = cursor;
+        while cursor != 0 {
+            let (next, batch) = set.scan(cursor, 10);
+            seen.extend(batch);
+            cursor = next;
+        }
+        // Everything there the whole time was returned.
+        for i in 0..100 {
+            assert!(seen.contains(&Bytes::from(format!("m{}", i))));
+        }
+        assert_eq!(set.len(), 140);
+    }
+
+    #[test]
+    fn test_set_lookups() {
+        let mut set = ScanSet::default();
+        assert!(set.insert(Bytes::from_static(b"a")));
+        assert!(!set.insert(Bytes::from_static(b"a")));
+        assert!(set.contains(b"a"));
+        assert!(!set.contains(b"b"));
+        assert_eq!(set.take(b"a"), Some(Bytes::from_static(b"a")));
+        assert!(set.is_empty());
+    }
+
+    #[test]
+    fn test_map_lookups() {
+        let mut map = ScanMap::default();
+        map.insert(Bytes::from_static(b"a"), 1);
+        assert_eq!(map.insert(Bytes::from_static(b"a"), 2), Some(1));
+        map.insert(Bytes::from_static(b"b"), 0);
+        *map.get_mut(b"b").unwrap() += 3;
+        assert_eq!(map.remove(b"c"), None);
+        let (cursor, mut entries) = map.scan(0, 10);
+        entries.sort();
+        assert_eq!(cursor, 0);
+        assert_eq!(
+            entries,
+            vec![
+                (Bytes::from_static(b"a"), &2),
+                (Bytes::from_static(b"b"), &3)
+            ]
+        );
+        assert_eq!(map.remove(b"a"), Some(2));
+        assert_eq!(map.scan(0, 10).1, vec![(Bytes::from_static(b"b"), &3)]);
+    }
+
+    #[test]
+    fn test_serde_roundtrip() {
+        let mut set = ScanSet::default();
+        set.insert(Bytes::from_static(b"a"));
+        let bytes = rmp_serde::to_vec(&set).unwrap();
+        // Written as a plain set.
+        let plain: HashSet<Bytes> = rmp_serde::decode::from_read(&bytes[..]).unwrap();
+        assert_eq!(plain.len(), 1);
+        let set: ScanSet = rmp_serde::decode::from_read(&bytes[..]).unwrap();
+        assert_eq!(set.scan(0, 10), (0, vec![Bytes::from_static(b"a")]));
+    }
+
+    #[test]
+    fn test_scan_keys_across_maps() {
+        let strings: DashMap<Bytes, ()> = DashMap::new();
+        let lists: DashMap<Bytes, u8> = DashMap::new();
+        for i in 0..500 {
+            strings.insert(Bytes::from(format!("s{}", i)), ());
+            lists.insert(Bytes::from(format!("l{}", i)), 0);
+        }
+        let maps: [&dyn ShardedKeys; 2] = [&strings, &lists];
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        loop {
+            let (next, batch) = scan_keys(&maps, cursor, 10);
+            // Ties can add a few, but not whole shards.
+            assert!(batch.len() < 20);
+            for key in batch {
+                assert!(seen.insert(key));
+            }
+            // Changes midway don't affect keys there the whole time.
+            lists.remove(&Bytes::from(format!("l{}", seen.len() % 500)));
+            lists.insert(Bytes::from(format!("new{}", seen.len())), 0);
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        for i in 0..500 {
+            assert!(seen.contains(&Bytes::from(format!("s{}", i))));
+        }
+        assert!(seen.len() >= 500);
+    }
+}
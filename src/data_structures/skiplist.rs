@@ -0,0 +1,444 @@
+/// An indexable skiplist, like the one behind redis' sorted sets.
+///
+/// Every link records how many nodes it skips over (its span), so the rank
+/// of a value and the value at a rank are both found in O(log n).
+/// Nodes live in an arena and link to each other by index.
+use crate::data_structures::memory_tracker::random_u64;
+use serde::de::{Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::fmt;
+
+/// Enough levels for 4^32 elements.
+const MAX_LEVEL: usize = 32;
+/// The head of the list is always the first node of the arena.
+const HEAD: usize = 0;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Link {
+    next: Option<usize>,
+    /// Number of nodes between this node and `next`, counting `next`.
+    span: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Node<T> {
+    /// None for the head and for free nodes.
+    value: Option<T>,
+    levels: Vec<Link>,
+    prev: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct SkipList<T> {
+    nodes: Vec<Node<T>>,
+    /// Indices of removed nodes, reused by later inserts.
+    free: Vec<usize>,
+    tail: Option<usize>,
+    /// Levels in use by the head.
+    level: usize,
+    len: usize,
+    /// xorshift state, to pick the level of new nodes.
+    rng: u64,
+}
+
+impl<T> Default for SkipList<T> {
+    fn default() -> Self {
+        let head = Node {
+            value: None,
+            levels: vec![Link::default(); MAX_LEVEL],
+            prev: None,
+        };
+        SkipList {
+            nodes: vec![head],
+            free: Vec::new(),
+            tail: None,
+            level: 1,
+            len: 0,
+            // xorshift gets stuck on 0.
+            rng: random_u64() | 1,
+        }
+    }
+}
+
+impl<T: Ord> SkipList<T> {
+    pub fn new() -> Self {
+        SkipList::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn value(&self, node: usize) -> &T {
+        self.nodes[node]
+            .value
+            .as_ref()
+            .expect("linked nodes hold a value")
+    }
+
+    /// A level of at least 1, each further level with a 1/4 chance.
+    fn random_level(&mut self) -> usize {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (1 + self.rng.trailing_ones() as usize / 2).min(MAX_LEVEL)
+    }
+
+    /// Insert `value`, which must not already be in the list.
+    pub fn insert(&mut self, value: T) {
+        // The last node before `value` on each level, and its rank.
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut rank = [0; MAX_LEVEL];
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            rank[i] = if i + 1 == self.level { 0 } else { rank[i + 1] };
+            while let Some(next) = self.nodes[node].levels[i].next {
+                if *self.value(next) >= value {
+                    break;
+                }
+                rank[i] += self.nodes[node].levels[i].span;
+                node = next;
+            }
+            update[i] = node;
+        }
+        let level = self.random_level();
+        for i in self.level..level {
+            rank[i] = 0;
+            update[i] = HEAD;
+            self.nodes[HEAD].levels[i].span = self.len;
+        }
+        self.level = self.level.max(level);
+
+        let new = Node {
+            value: Some(value),
+            levels: vec![Link::default(); level],
+            prev: if update[0] == HEAD {
+                None
+            } else {
+                Some(update[0])
+            },
+        };
+        let new_index = match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = new;
+                index
+            }
+            None => {
+                self.nodes.push(new);
+                self.nodes.len() - 1
+            }
+        };
+        for i in 0..level {
+            let before = &mut self.nodes[update[i]].levels[i];
+            let link = Link {
+                next: before.next,
+                span: before.span - (rank[0] - rank[i]),
+            };
+            before.next = Some(new_index);
+            before.span = rank[0] - rank[i] + 1;
+            self.nodes[new_index].levels[i] = link;
+        }
+        for (i, &before) in update.iter().enumerate().take(self.level).skip(level) {
+            self.nodes[before].levels[i].span += 1;
+        }
+        match self.nodes[new_index].levels[0].next {
+            Some(next) => self.nodes[next].prev = Some(new_index),
+            None => self.tail = Some(new_index),
+        }
+        self.len += 1;
+    }
+
+    /// Remove `value` from the list, returning it if it was there.
+    pub fn remove(&mut self, value: &T) -> Option<T> {
+        let mut update = [HEAD; MAX_LEVEL];
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[i].next {
+                if self.value(next) >= value {
+                    break;
+                }
+                node = next;
+            }
+            update[i] = node;
+        }
+        let found = self.nodes[node].levels[0].next?;
+        if self.value(found) != value {
+            return None;
+        }
+        for (i, &before) in update.iter().enumerate().take(self.level) {
+            let found_link = self.nodes[found].levels.get(i).copied();
+            let before = &mut self.nodes[before].levels[i];
+            match found_link {
+                Some(link) if before.next == Some(found) => {
+                    before.span += link.span;
+                    before.span -= 1;
+                    before.next = link.next;
+                }
+                _ => before.span -= 1,
+            }
+        }
+        let prev = self.nodes[found].prev;
+        match self.nodes[found].levels[0].next {
+            Some(next) => self.nodes[next].prev = prev,
+            None => self.tail = prev,
+        }
+        while self.level > 1 && self.nodes[HEAD].levels[self.level - 1].next.is_none() {
+            self.level -= 1;
+        }
+        self.len -= 1;
+        self.free.push(found);
+        let node = &mut self.nodes[found];
+        node.levels = Vec::new();
+        node.prev = None;
+        node.value.take()
+    }
+
+    /// The 0-based rank of `value`, if it's in the list.
+    pub fn rank(&self, value: &T) -> Option<usize> {
+        let mut rank = 0;
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[i].next {
+                if self.value(next) > value {
+                    break;
+                }
+                rank += self.nodes[node].levels[i].span;
+                node = next;
+            }
+            if node != HEAD && self.value(node) == value {
+                return Some(rank - 1);
+            }
+        }
+        None
+    }
+
+    /// The node at the 0-based `rank`.
+    fn node_at(&self, rank: usize) -> Option<usize> {
+        if rank >= self.len {
+            return None;
+        }
+        let mut traversed = 0;
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[i].next {
+                let span = self.nodes[node].levels[i].span;
+                if traversed + span > rank + 1 {
+                    break;
+                }
+                traversed += span;
+                node = next;
+            }
+            if traversed == rank + 1 {
+                return Some(node);
+            }
+        }
+        None
+    }
+
+    /// The value at the 0-based `rank`.
+    pub fn get(&self, rank: usize) -> Option<&T> {
+        self.node_at(rank).map(|node| self.value(node))
+    }
+
+    pub fn first(&self) -> Option<&T> {
+        self.nodes[HEAD].levels[0].next.map(|node| self.value(node))
+    }
+
+    pub fn last(&self) -> Option<&T> {
+        self.tail.map(|node| self.value(node))
+    }
+
+    /// Iterate in ascending order.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            list: self,
+            node: self.nodes[HEAD].levels[0].next,
+        }
+    }
+
+    /// Iterate in ascending order, starting at the 0-based `rank`.
+    pub fn iter_from_rank(&self, rank: usize) -> Iter<'_, T> {
+        Iter {
+            list: self,
+            node: self.node_at(rank),
+        }
+    }
+
+    /// Iterate in ascending order, starting at the first value >= `value`.
+    pub fn iter_from(&self, value: &T) -> Iter<'_, T> {
+        let mut node = HEAD;
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.nodes[node].levels[i].next {
+                if self.value(next) >= value {
+                    break;
+                }
+                node = next;
+            }
+        }
+        Iter {
+            list: self,
+            node: self.nodes[node].levels[0].next,
+        }
+    }
+
+    /// Iterate in descending order.
+    pub fn rev_iter(&self) -> RevIter<'_, T> {
+        RevIter {
+            list: self,
+            node: self.tail,
+        }
+    }
+
+    /// Iterate in descending order, starting at the 0-based (ascending) `rank`.
+    pub fn rev_iter_from_rank(&self, rank: usize) -> RevIter<'_, T> {
+        RevIter {
+            list: self,
+            node: self.node_at(rank),
+        }
+    }
+}
+
+pub struct Iter<'a, T> {
+    list: &'a SkipList<T>,
+    node: Option<usize>,
+}
+
+impl<'a, T: Ord> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let node = self.node?;
+        self.node = self.list.nodes[node].levels[0].next;
+        Some(self.list.value(node))
+    }
+}
+
+pub struct RevIter<'a, T> {
+    list: &'a SkipList<T>,
+    node: Option<usize>,
+}
+
+impl<'a, T: Ord> Iterator for RevIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        let node = self.node?;
+        self.node = self.list.nodes[node].prev;
+        Some(self.list.value(node))
+    }
+}
+
+impl<T: Ord + fmt::Debug> fmt::Debug for SkipList<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: Ord> FromIterator<T> for SkipList<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut list = SkipList::new();
+        for value in iter {
+            list.insert(value);
+        }
+        list
+    }
+}
+
+// Serialized as a plain sequence, the same as the BTreeSet it replaced.
+impl<T: Ord + Serialize> Serialize for SkipList<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter())
+    }
+}
+
+impl<'de, T: Ord + Deserialize<'de>> Deserialize<'de> for SkipList<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values: Vec<T> = Vec::deserialize(deserializer)?;
+        Ok(values.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod test_skiplist {
+    use crate::data_structures::skiplist::SkipList;
+    use std::collections::BTreeSet;
+
+    /// Check every rank and link against a BTreeSet holding the same values.
+    fn check(list: &SkipList<u64>, expected: &BTreeSet<u64>) {
+        assert_eq!(list.len(), expected.len());
+        let values: Vec<u64> = expected.iter().copied().collect();
+        assert_eq!(list.iter().copied().collect::<Vec<_>>(), values);
+        let mut reversed: Vec<u64> = list.rev_iter().copied().collect();
+        reversed.reverse();
+        assert_eq!(reversed, values);
+        for (rank, value) in values.iter().enumerate() {
+            assert_eq!(list.rank(value), Some(rank));
+            assert_eq!(list.get(rank), Some(value));
+        }
+        assert_eq!(list.get(values.len()), None);
+        assert_eq!(list.first(), values.first());
+        assert_eq!(list.last(), values.last());
+    }
+
+    #[test]
+    fn test_insert_remove() {
+        let mut list = SkipList::new();
+        let mut expected = BTreeSet::new();
+        // A deterministic shuffle of 0..500.
+        for i in 0..500u64 {
+            let value = (i * 7919) % 500;
+            list.insert(value);
+            expected.insert(value);
+        }
+        check(&list, &expected);
+        for i in (0..500u64).step_by(3) {
+            assert_eq!(list.remove(&i), Some(i));
+            assert_eq!(list.remove(&i), None);
+            expected.remove(&i);
+        }
+        check(&list, &expected);
+        assert_eq!(list.rank(&0), None);
+        // Freed nodes are reused.
+        for i in (0..500u64).step_by(3) {
+            list.insert(i);
+            expected.insert(i);
+        }
+        check(&list, &expected);
+        assert_eq!(list.nodes.len(), 501);
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let list: SkipList<u64> = (0..100).map(|i| i * 2).collect();
+        assert_eq!(list.iter_from(&51).next(), Some(&52));
+        assert_eq!(list.iter_from(&52).next(), Some(&52));
+        assert_eq!(list.iter_from(&500).next(), None);
+        assert_eq!(
+            list.iter_from_rank(98).copied().collect::<Vec<_>>(),
+            vec![196, 198]
+        );
+        assert_eq!(
+            list.rev_iter_from_rank(1).copied().collect::<Vec<_>>(),
+            vec![2, 0]
+        );
+        assert_eq!(list.iter_from_rank(100).next(), None);
+    }
+
+    #[test]
+    fn test_empty() {
+        let mut list: SkipList<u64> = SkipList::new();
+        assert!(list.is_empty());
+        assert_eq!(list.first(), None);
+        assert_eq!(list.remove(&1), None);
+        list.insert(1);
+        list.remove(&1);
+        assert!(list.is_empty());
+        assert_eq!(list.iter().next(), None);
+        assert_eq!(list.rev_iter().next(), None);
+        assert_eq!(list.level, 1);
+    }
+}
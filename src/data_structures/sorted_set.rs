@@ -1,36 +1,170 @@
 // Clippy does not like SortedSet. TODO: Figure out if we can fix this.
 #![allow(clippy::mutable_key_type)]
 
+use crate::data_structures::scan_index::ScanMap;
+use crate::data_structures::skiplist::SkipList;
 use crate::ops::RVec;
 use crate::types::{Count, Index, Key, Score};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
-use std::collections::hash_map::Entry;
-use std::collections::{BTreeSet, HashMap};
-
-// TODO: Use convenient-skiplist
 
 // TODO: Why doesn't this actually allow it?
 #[allow(clippy::mutable_key_type)]
-#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct SortedSetMember {
     pub score: Score,
-    pub member: String,
+    pub member: Key,
+}
+
+// Scores are never NaN, so comparing them is a total order. -0 and 0 are
+// equal, and members with equal scores are ordered byte-wise, like redis.
+impl Ord for SortedSetMember {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let score_cmp = self
+            .score
+            .partial_cmp(&other.score)
+            .unwrap_or_else(|| self.score.total_cmp(&other.score));
+        score_cmp.then_with(|| self.member.cmp(&other.member))
+    }
 }
 
 impl PartialOrd<SortedSetMember> for SortedSetMember {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        let score_cmp = self.score.cmp(&other.score);
-        if let Ordering::Equal = score_cmp {
-            return Some(self.member.cmp(&other.member));
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for SortedSetMember {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for SortedSetMember {}
+
+/// ZADD NX / XX: only add new members, or only update existing ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddCondition {
+    IfAbsent,
+    IfPresent,
+}
+
+/// ZADD GT / LT: only update a score if the new one is greater / less.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateCondition {
+    GreaterThan,
+    LessThan,
+}
+
+/// The modifiers of ZADD.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AddOptions {
+    pub condition: Option<AddCondition>,
+    pub update: Option<UpdateCondition>,
+    /// CH: count updated members as well as added ones.
+    pub changed: bool,
+    /// INCR: add to the score instead of replacing it.
+    pub incr: bool,
+}
+
+/// What adding a single member did, along with its resulting score.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddOutcome {
+    Added(Score),
+    Updated(Score),
+    /// The member already had this score.
+    Unchanged(Score),
+    /// The options prevented the add or update.
+    Skipped,
+    /// Incrementing would have made the score NaN.
+    NotANumber,
+}
+
+/// One end of a score range. `(` in a command makes it exclusive.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreBound {
+    pub score: Score,
+    pub exclusive: bool,
+}
+
+/// The scores between `min` and `max`, as used by ZRANGEBYSCORE and co.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ScoreRange {
+    pub min: ScoreBound,
+    pub max: ScoreBound,
+}
+
+impl ScoreRange {
+    /// The range from `min` to `max`, both included.
+    pub fn inclusive(min: Score, max: Score) -> Self {
+        ScoreRange {
+            min: ScoreBound {
+                score: min,
+                exclusive: false,
+            },
+            max: ScoreBound {
+                score: max,
+                exclusive: false,
+            },
+        }
+    }
+
+    fn above_min(&self, score: Score) -> bool {
+        match self.min.exclusive {
+            true => score > self.min.score,
+            false => score >= self.min.score,
         }
-        Some(score_cmp)
+    }
+
+    fn below_max(&self, score: Score) -> bool {
+        match self.max.exclusive {
+            true => score < self.max.score,
+            false => score <= self.max.score,
+        }
+    }
+
+    pub fn contains(&self, score: Score) -> bool {
+        self.above_min(score) && self.below_max(score)
     }
 }
 
-impl Ord for SortedSetMember {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.partial_cmp(other).unwrap()
+/// One end of a lexicographic range: `-`, `+`, `[member` or `(member`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(Key),
+    Exclusive(Key),
+}
+
+/// The members between `min` and `max`, as used by ZRANGEBYLEX and co.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+impl LexRange {
+    fn above_min(&self, member: &[u8]) -> bool {
+        match &self.min {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(min) => member >= &min[..],
+            LexBound::Exclusive(min) => member > &min[..],
+        }
+    }
+
+    fn below_max(&self, member: &[u8]) -> bool {
+        match &self.max {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(max) => member <= &max[..],
+            LexBound::Exclusive(max) => member < &max[..],
+        }
+    }
+
+    pub fn contains(&self, member: &[u8]) -> bool {
+        self.above_min(member) && self.below_max(member)
     }
 }
 // TODO: Look into using RangeBounds properly
@@ -79,15 +213,15 @@ impl SortedSetMember {
     fn new(key: &[u8], score: Score) -> Self {
         SortedSetMember {
             score,
-            member: String::from_utf8_lossy(key).to_string(),
+            member: Key::copy_from_slice(key),
         }
     }
 }
 
 #[derive(Debug, Serialize, Deserialize, Default)]
 pub struct SortedSet {
-    members_hash: HashMap<Key, Score>,
-    scores: BTreeSet<SortedSetMember>,
+    members_hash: ScanMap<Score>,
+    scores: SkipList<SortedSetMember>,
 }
 
 #[allow(unused)]
@@ -97,19 +231,60 @@ impl SortedSet {
         SortedSet::default()
     }
 
-    /// Add the following keys and scores to the sorted set
+    /// Add the following keys and scores to the sorted set, updating the
+    /// scores of existing keys. Returns how many keys were added.
     pub fn add(&mut self, key_scores: RVec<(Score, Key)>) -> Count {
+        let options = AddOptions::default();
         key_scores
             .into_iter()
-            .map(|(score, key)| match self.members_hash.entry(key) {
-                Entry::Vacant(ent) => {
-                    self.scores.insert(SortedSetMember::new(ent.key(), score));
-                    ent.insert(score);
-                    1
-                }
-                Entry::Occupied(_) => 0,
+            .filter(|(score, key)| {
+                matches!(
+                    self.add_with(key.clone(), *score, &options),
+                    AddOutcome::Added(_)
+                )
             })
-            .sum()
+            .count() as Count
+    }
+
+    /// Add `key` with `score`, or update its score, as allowed by `options`.
+    pub fn add_with(&mut self, key: Key, score: Score, options: &AddOptions) -> AddOutcome {
+        let old = self.members_hash.get(&key).copied();
+        match (old, options.condition) {
+            (Some(_), Some(AddCondition::IfAbsent)) | (None, Some(AddCondition::IfPresent)) => {
+                return AddOutcome::Skipped
+            }
+            _ => {}
+        }
+        let new = match old {
+            Some(old) if options.incr => old + score,
+            _ => score,
+        };
+        if new.is_nan() {
+            return AddOutcome::NotANumber;
+        }
+        let old = match old {
+            Some(old) => old,
+            None => {
+                self.scores.insert(SortedSetMember::new(&key, new));
+                self.members_hash.insert(key, new);
+                return AddOutcome::Added(new);
+            }
+        };
+        let allowed = match options.update {
+            None => true,
+            Some(UpdateCondition::GreaterThan) => new > old,
+            Some(UpdateCondition::LessThan) => new < old,
+        };
+        if !allowed {
+            return AddOutcome::Skipped;
+        }
+        if new == old {
+            return AddOutcome::Unchanged(new);
+        }
+        self.scores.remove(&SortedSetMember::new(&key, old));
+        self.scores.insert(SortedSetMember::new(&key, new));
+        self.members_hash.insert(key, new);
+        AddOutcome::Updated(new)
     }
 
     /// Remove the following keys from the sorted set
@@ -141,25 +316,155 @@ impl SortedSet {
         self.members_hash.get(&key).cloned()
     }
 
+    /// Iterate over members, lowest score first.
+    pub fn iter(&self) -> impl Iterator<Item = &SortedSetMember> {
+        self.scores.iter()
+    }
+
     /// Get all members between (lower, upper) scores
     pub fn range(&self, range: (Score, Score)) -> RVec<SortedSetMember> {
-        // TODO: Use a more efficient method. I should use a skiplist or an AVL tree.
-        // Another option is to retackle the rangebounds stuff, but the semantics are different.
-        // I want to be able to compare by score AND member when inserting/removing,
-        // but only by score in this case. Need to figure out how to encode that.
-        self.scores
-            .iter()
-            .filter(|mem| range.0 <= mem.score && mem.score <= range.1)
+        self.range_by_score(&ScoreRange::inclusive(range.0, range.1))
             .cloned()
             .collect()
     }
 
+    /// Iterate over the members with a score in `range`, lowest score first.
+    pub fn range_by_score<'a>(
+        &'a self,
+        range: &'a ScoreRange,
+    ) -> impl Iterator<Item = &'a SortedSetMember> + 'a {
+        // No member sorts before an empty one with the same score.
+        let start = SortedSetMember {
+            score: range.min.score,
+            member: Key::new(),
+        };
+        self.scores
+            .iter_from(&start)
+            .skip_while(move |m| !range.above_min(m.score))
+            .take_while(move |m| range.below_max(m.score))
+    }
+
+    /// Number of members with a score in `range`.
+    pub fn count(&self, range: &ScoreRange) -> Count {
+        self.range_by_score(range).count() as Count
+    }
+
+    /// Iterate over the members in the lexicographic `range`.
+    ///
+    /// Like redis, this assumes every member has the same score.
+    pub fn range_by_lex<'a>(
+        &'a self,
+        range: &'a LexRange,
+    ) -> impl Iterator<Item = &'a SortedSetMember> + 'a {
+        let start = SortedSetMember {
+            score: self.scores.first().map(|m| m.score).unwrap_or_default(),
+            member: match &range.min {
+                LexBound::Inclusive(min) | LexBound::Exclusive(min) => min.clone(),
+                LexBound::NegInf | LexBound::PosInf => Key::new(),
+            },
+        };
+        self.scores
+            .iter_from(&start)
+            .skip_while(move |m| !range.above_min(&m.member))
+            .take_while(move |m| range.below_max(&m.member))
+    }
+
+    /// Number of members in the lexicographic `range`.
+    pub fn lex_count(&self, range: &LexRange) -> Count {
+        self.range_by_lex(range).count() as Count
+    }
+
+    /// Resolve `start` and `stop` ranks, which count from the end if negative.
+    fn rank_range(&self, start: Index, stop: Index) -> Option<(usize, usize)> {
+        let len = self.card();
+        let start = if start < 0 {
+            (start + len).max(0)
+        } else {
+            start
+        };
+        let stop = if stop < 0 {
+            stop + len
+        } else {
+            stop.min(len - 1)
+        };
+        if start > stop || start >= len {
+            return None;
+        }
+        Some((start as usize, stop as usize))
+    }
+
+    /// Members from rank `start` to `stop` inclusive, lowest score first.
+    pub fn range_by_rank(&self, start: Index, stop: Index) -> Vec<SortedSetMember> {
+        match self.rank_range(start, stop) {
+            Some((start, stop)) => self
+                .scores
+                .iter_from_rank(start)
+                .take(stop - start + 1)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Members from rank `start` to `stop` inclusive, ranked from the highest score.
+    pub fn rev_range_by_rank(&self, start: Index, stop: Index) -> Vec<SortedSetMember> {
+        match self.rank_range(start, stop) {
+            Some((start, stop)) => self
+                .scores
+                .rev_iter_from_rank(self.scores.len() - 1 - start)
+                .take(stop - start + 1)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Add `delta` to the score of `key`, adding the member if needed.
+    /// Returns the new score, or None if it would be NaN.
+    pub fn incr(&mut self, key: Key, delta: Score) -> Option<Score> {
+        let options = AddOptions {
+            incr: true,
+            ..Default::default()
+        };
+        match self.add_with(key, delta, &options) {
+            AddOutcome::Added(score)
+            | AddOutcome::Updated(score)
+            | AddOutcome::Unchanged(score) => Some(score),
+            AddOutcome::Skipped | AddOutcome::NotANumber => None,
+        }
+    }
+
+    /// Remove the members with a score in `range`. Returns how many were removed.
+    pub fn remove_range_by_score(&mut self, range: &ScoreRange) -> Count {
+        let keys: Vec<Key> = self
+            .range_by_score(range)
+            .map(|m| m.member.clone())
+            .collect();
+        self.remove(&keys)
+    }
+
+    /// Remove the members in the lexicographic `range`. Returns how many were removed.
+    pub fn remove_range_by_lex(&mut self, range: &LexRange) -> Count {
+        let keys: Vec<Key> = self.range_by_lex(range).map(|m| m.member.clone()).collect();
+        self.remove(&keys)
+    }
+
+    /// Remove the members from rank `start` to `stop`. Returns how many were removed.
+    pub fn remove_range_by_rank(&mut self, start: Index, stop: Index) -> Count {
+        let keys: Vec<Key> = self
+            .range_by_rank(start, stop)
+            .into_iter()
+            .map(|m| m.member)
+            .collect();
+        self.remove(&keys)
+    }
+
     /// Remove count (default: 1) maximum members from the sorted set
     pub fn pop_max(&mut self, count: Count) -> Vec<SortedSetMember> {
         let count = count as usize; // TODO: What if it's negative?
-        let ret: Vec<SortedSetMember> = self.scores.iter().rev().take(count).cloned().collect();
+        let ret: Vec<SortedSetMember> = self.scores.rev_iter().take(count).cloned().collect();
         for key in ret.iter().map(|s| s.member.clone()) {
-            self.remove(&[key.into()]);
+            self.remove(&[key]);
         }
         ret
     }
@@ -169,28 +474,40 @@ impl SortedSet {
         let count = count as usize; // TODO: What if it's negative?
         let ret: Vec<SortedSetMember> = self.scores.iter().take(count).cloned().collect();
         for key in ret.iter().map(|s| s.member.clone()) {
-            self.remove(&[key.into()]);
+            self.remove(&[key]);
         }
         ret
     }
 
+    /// The members from `cursor` on and their scores, see [`ScanMap::scan`].
+    pub fn scan(&self, cursor: u64, count: usize) -> (u64, Vec<(Key, Score)>) {
+        let (cursor, members) = self.members_hash.scan(cursor, count);
+        let members = members
+            .into_iter()
+            .map(|(member, score)| (member, *score))
+            .collect();
+        (cursor, members)
+    }
+
     // /// Get the maximum score in the sorted set
     // pub fn max_score(&self) -> Option<Score> {
-    //     self.scores.iter().rev().next().cloned().map(|m| m.score)
+    //     self.scores.last().map(|m| m.score)
     // }
 
     /// Get the rank of a given key in a sorted set
     pub fn rank(&self, key: Key) -> Option<Index> {
-        self.scores
-            .iter()
-            .position(|s| s.member.as_bytes() == &*key)
-            .map(|pos| pos as Index)
+        let score = *self.members_hash.get(&key)?;
+        let member = SortedSetMember { score, member: key };
+        self.scores.rank(&member).map(|rank| rank as Index)
     }
 }
 
 #[cfg(test)]
 mod test_sorted_sets_ds {
-    use crate::data_structures::sorted_set::{SortedSet, SortedSetMember};
+    use crate::data_structures::sorted_set::{
+        AddCondition, AddOptions, AddOutcome, LexBound, LexRange, ScoreBound, ScoreRange,
+        SortedSet, SortedSetMember, UpdateCondition,
+    };
     use crate::ops::RVec;
     use crate::types::{Key, Score};
     use bytes::Bytes;
@@ -198,9 +515,9 @@ mod test_sorted_sets_ds {
 
     fn get_multiple_entries() -> RVec<(Score, Key)> {
         smallvec![
-            (1, Bytes::from_static(b"hi_0")),
-            (3, Bytes::from_static(b"hi_1")),
-            (5, Bytes::from_static(b"hi_2")),
+            (1.0, Bytes::from_static(b"hi_0")),
+            (3.0, Bytes::from_static(b"hi_1")),
+            (5.0, Bytes::from_static(b"hi_2")),
         ]
     }
 
@@ -215,7 +532,7 @@ mod test_sorted_sets_ds {
     #[test]
     fn test_add() {
         let mut ss = SortedSet::new();
-        assert_eq!(1, ss.add(smallvec![(2, Bytes::from_static(b"hi"))]));
+        assert_eq!(1, ss.add(smallvec![(2.0, Bytes::from_static(b"hi"))]));
         assert_eq!(
             get_multiple_entries().len() as i64,
             ss.add(get_multiple_entries())
@@ -228,20 +545,21 @@ mod test_sorted_sets_ds {
         let mut ss = SortedSet::new();
 
         ss.add(smallvec![
-            (1, Bytes::from_static(b"hi_0")),
-            (3, Bytes::from_static(b"hi_1")),
-            (5, Bytes::from_static(b"hi_2")),
+            (1.0, Bytes::from_static(b"hi_0")),
+            (3.0, Bytes::from_static(b"hi_1")),
+            (5.0, Bytes::from_static(b"hi_2")),
         ]);
         let expected: RVec<SortedSetMember> = smallvec![
-            SortedSetMember::new(&Bytes::from_static(b"hi_0"), 1),
-            SortedSetMember::new(&Bytes::from_static(b"hi_1"), 3),
-            SortedSetMember::new(&Bytes::from_static(b"hi_2"), 5),
+            SortedSetMember::new(&Bytes::from_static(b"hi_0"), 1.0),
+            SortedSetMember::new(&Bytes::from_static(b"hi_1"), 3.0),
+            SortedSetMember::new(&Bytes::from_static(b"hi_2"), 5.0),
         ];
-        assert_eq!(ss.range((1, 5)), expected);
-        let expected: RVec<SortedSetMember> = smallvec![SortedSetMember::new(&b"hi_1".to_vec(), 3)];
-        assert_eq!(ss.range((2, 4)), expected);
+        assert_eq!(ss.range((1.0, 5.0)), expected);
+        let expected: RVec<SortedSetMember> =
+            smallvec![SortedSetMember::new(&b"hi_1".to_vec(), 3.0)];
+        assert_eq!(ss.range((2.0, 4.0)), expected);
         let empty_vec: RVec<SortedSetMember> = RVec::new();
-        assert_eq!(ss.range((20, 40)), empty_vec);
+        assert_eq!(ss.range((20.0, 40.0)), empty_vec);
     }
 
     #[test]
@@ -281,4 +599,160 @@ mod test_sorted_sets_ds {
         assert_eq!(ss.pop_min(2).as_slice(), &last_two[..2]);
         assert_eq!(ss.pop_min(2).as_slice(), &[last_two[2].clone()]);
     }
+
+    fn members(ss_members: Vec<SortedSetMember>) -> Vec<Key> {
+        ss_members.into_iter().map(|m| m.member).collect()
+    }
+
+    #[test]
+    fn test_float_ordering() {
+        let mut ss = SortedSet::new();
+        ss.add(smallvec![
+            (Score::INFINITY, Bytes::from_static(b"top")),
+            (-0.5, Bytes::from_static(b"b")),
+            (-0.5, Bytes::from_static(b"a")),
+            (Score::NEG_INFINITY, Bytes::from_static(b"bottom")),
+            (1e-3, Bytes::from_static(b"c")),
+        ]);
+        assert_eq!(
+            members(ss.range_by_rank(0, -1)),
+            vec!["bottom", "a", "b", "c", "top"]
+        );
+        assert_eq!(members(ss.rev_range_by_rank(0, 1)), vec!["top", "c"]);
+        assert_eq!(members(ss.range_by_rank(-2, 100)), vec!["c", "top"]);
+        assert!(ss.range_by_rank(3, 1).is_empty());
+        assert_eq!(ss.rank(Bytes::from_static(b"top")), Some(4));
+    }
+
+    #[test]
+    fn test_range_by_score() {
+        let mut ss = SortedSet::new();
+        ss.add(get_multiple_entries());
+        let exclusive = ScoreRange {
+            min: ScoreBound {
+                score: 1.0,
+                exclusive: true,
+            },
+            max: ScoreBound {
+                score: 5.0,
+                exclusive: true,
+            },
+        };
+        let in_range: Vec<Key> = ss
+            .range_by_score(&exclusive)
+            .map(|m| m.member.clone())
+            .collect();
+        assert_eq!(in_range, vec!["hi_1"]);
+        let everything = ScoreRange::inclusive(Score::NEG_INFINITY, Score::INFINITY);
+        assert_eq!(ss.count(&everything), 3);
+        assert_eq!(ss.count(&ScoreRange::inclusive(5.0, 1.0)), 0);
+        assert_eq!(
+            ss.remove_range_by_score(&ScoreRange::inclusive(3.0, 10.0)),
+            2
+        );
+        assert_eq!(ss.card(), 1);
+    }
+
+    #[test]
+    fn test_incr() {
+        let mut ss = SortedSet::new();
+        let key = Bytes::from_static(b"hi");
+        assert_eq!(ss.incr(key.clone(), 2.5), Some(2.5));
+        assert_eq!(ss.incr(key.clone(), -1.0), Some(1.5));
+        assert_eq!(ss.incr(key.clone(), Score::INFINITY), Some(Score::INFINITY));
+        assert_eq!(ss.incr(key.clone(), Score::NEG_INFINITY), None);
+        assert_eq!(ss.score(key), Some(Score::INFINITY));
+        assert_eq!(ss.iter().count(), 1);
+    }
+
+    #[test]
+    fn test_remove_range_by_rank() {
+        let mut ss = SortedSet::new();
+        ss.add(get_multiple_entries());
+        assert_eq!(ss.remove_range_by_rank(0, -2), 2);
+        assert_eq!(members(ss.range_by_rank(0, -1)), vec!["hi_2"]);
+    }
+
+    #[test]
+    fn test_add_updates_scores() {
+        let mut ss = SortedSet::new();
+        ss.add(get_multiple_entries());
+        assert_eq!(0, ss.add(smallvec![(10.0, Bytes::from_static(b"hi_0"))]));
+        assert_eq!(ss.score(Bytes::from_static(b"hi_0")), Some(10.0));
+        assert_eq!(
+            members(ss.range_by_rank(0, -1)),
+            vec!["hi_1", "hi_2", "hi_0"]
+        );
+        assert_eq!(ss.iter().count(), 3);
+    }
+
+    #[test]
+    fn test_add_with_options() {
+        let mut ss = SortedSet::new();
+        let key = Bytes::from_static(b"hi");
+        let with = |condition, update| AddOptions {
+            condition,
+            update,
+            ..Default::default()
+        };
+        let xx = with(Some(AddCondition::IfPresent), None);
+        let nx = with(Some(AddCondition::IfAbsent), None);
+        let gt = with(None, Some(UpdateCondition::GreaterThan));
+        let lt = with(None, Some(UpdateCondition::LessThan));
+        assert_eq!(ss.add_with(key.clone(), 1.0, &xx), AddOutcome::Skipped);
+        assert_eq!(ss.add_with(key.clone(), 1.0, &nx), AddOutcome::Added(1.0));
+        assert_eq!(ss.add_with(key.clone(), 2.0, &nx), AddOutcome::Skipped);
+        assert_eq!(ss.add_with(key.clone(), 0.0, &gt), AddOutcome::Skipped);
+        assert_eq!(ss.add_with(key.clone(), 3.0, &gt), AddOutcome::Updated(3.0));
+        assert_eq!(ss.add_with(key.clone(), 4.0, &lt), AddOutcome::Skipped);
+        assert_eq!(
+            ss.add_with(key.clone(), 3.0, &xx),
+            AddOutcome::Unchanged(3.0)
+        );
+        // GT and LT don't stop new members from being added.
+        let other = Bytes::from_static(b"other");
+        assert_eq!(ss.add_with(other, -1.0, &gt), AddOutcome::Added(-1.0));
+        let incr = AddOptions { incr: true, ..gt };
+        assert_eq!(ss.add_with(key.clone(), -1.0, &incr), AddOutcome::Skipped);
+        assert_eq!(
+            ss.add_with(key.clone(), 1.5, &incr),
+            AddOutcome::Updated(4.5)
+        );
+        assert_eq!(members(ss.range_by_rank(0, -1)), vec!["other", "hi"]);
+        assert_eq!(ss.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_range_by_lex() {
+        let mut ss = SortedSet::new();
+        for member in ["b", "a", "ab", "c", "\u{ff}"] {
+            ss.add(smallvec![(0.0, Bytes::copy_from_slice(member.as_bytes()))]);
+        }
+        let lex = |min, max| LexRange { min, max };
+        let key = |k: &'static str| Bytes::from_static(k.as_bytes());
+        let everything = lex(LexBound::NegInf, LexBound::PosInf);
+        let in_range = |range: &LexRange| -> Vec<Key> {
+            ss.range_by_lex(range).map(|m| m.member.clone()).collect()
+        };
+        assert_eq!(in_range(&everything), vec!["a", "ab", "b", "c", "\u{ff}"]);
+        let from_a = lex(LexBound::Exclusive(key("a")), LexBound::Inclusive(key("b")));
+        assert_eq!(in_range(&from_a), vec!["ab", "b"]);
+        let prefix = lex(LexBound::Inclusive(key("a")), LexBound::Exclusive(key("b")));
+        assert_eq!(in_range(&prefix), vec!["a", "ab"]);
+        assert!(in_range(&lex(LexBound::PosInf, LexBound::NegInf)).is_empty());
+        assert_eq!(ss.lex_count(&everything), 5);
+        assert_eq!(ss.remove_range_by_lex(&prefix), 2);
+        assert_eq!(ss.card(), 3);
+    }
+
+    #[test]
+    fn test_binary_members() {
+        let mut ss = SortedSet::new();
+        let binary = Bytes::from_static(&[0xff, 0x00, 0xfe]);
+        ss.add(smallvec![(1.0, binary.clone())]);
+        assert_eq!(ss.rank(binary.clone()), Some(0));
+        assert_eq!(members(ss.range_by_rank(0, -1)), vec![binary.clone()]);
+        assert_eq!(ss.remove(&[binary]), 1);
+        assert_eq!(ss.iter().count(), 0);
+    }
 }
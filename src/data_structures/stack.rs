@@ -26,6 +26,11 @@ impl<T: Clone> Stack<T> {
     pub fn size(&self) -> Count {
         self.inner.len() as Count
     }
+
+    /// Iterate from the bottom of the stack to the top.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.inner.iter()
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,902 @@
+use crate::types::{Key, Value};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+/// Redis frees trimmed entries a whole node of its radix tree at a
+/// time, so approximate trims only remove entries in blocks this big.
+pub const STREAM_NODE_SIZE: usize = 100;
+/// The most entries an approximate trim removes without a LIMIT.
+pub const DEFAULT_TRIM_LIMIT: usize = 100 * STREAM_NODE_SIZE;
+
+/// The ID of a stream entry: the unix time in milliseconds it was added,
+/// and a sequence number for entries added in the same millisecond.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    pub fn new(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
+
+    /// Parse `ms-seq`, or `ms` alone with `missing_seq` as its sequence number.
+    pub fn parse(s: &[u8], missing_seq: u64) -> Option<StreamId> {
+        let s = std::str::from_utf8(s).ok()?;
+        let (ms, seq) = match s.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().ok()?),
+            None => (s, missing_seq),
+        };
+        Some(StreamId::new(ms.parse().ok()?, seq))
+    }
+
+    /// The smallest ID after this one, if any.
+    pub fn next(&self) -> Option<StreamId> {
+        match self.seq.checked_add(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_add(1).map(|ms| StreamId::new(ms, 0)),
+        }
+    }
+
+    /// The largest ID before this one, if any.
+    pub fn prev(&self) -> Option<StreamId> {
+        match self.seq.checked_sub(1) {
+            Some(seq) => Some(StreamId::new(self.ms, seq)),
+            None => self.ms.checked_sub(1).map(|ms| StreamId::new(ms, u64::MAX)),
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID asked for a new entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NewId {
+    /// `*`: the current time, or after the last entry if that's later.
+    Auto,
+    /// `ms-*`: the next sequence number in that millisecond.
+    Sequence(u64),
+    Exact(StreamId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddError {
+    /// The ID isn't greater than that of the last entry.
+    TooSmall,
+    /// The ID is 0-0, which is never valid.
+    Zero,
+    /// The last entry has the greatest possible ID.
+    Exhausted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimStrategy {
+    /// Keep at most this many entries.
+    MaxLen(usize),
+    /// Remove entries with smaller IDs.
+    MinId(StreamId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trim {
+    pub strategy: TrimStrategy,
+    /// Only remove whole blocks of `STREAM_NODE_SIZE` entries.
+    pub approximate: bool,
+    /// The most entries to remove, if limited.
+    pub limit: Option<usize>,
+}
+
+/// The field / value pairs of an entry.
+pub type StreamFields = Vec<(Key, Value)>;
+
+/// An entry delivered to a consumer of a group, but not yet acknowledged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingEntry {
+    pub consumer: Key,
+    /// Unix time in milliseconds of the last delivery.
+    pub delivered_at: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Consumer {
+    /// Unix time in milliseconds of the last attempted interaction.
+    pub seen_at: u64,
+    /// Unix time in milliseconds of the last successful read or claim.
+    pub active_at: Option<u64>,
+    /// The IDs of the entries pending for this consumer.
+    pub pending: BTreeSet<StreamId>,
+}
+
+/// Readers sharing a stream: each entry is delivered to a single consumer,
+/// and stays pending until it's acknowledged.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConsumerGroup {
+    /// The last entry delivered to any consumer.
+    pub last_delivered: StreamId,
+    /// How many entries of the stream the group has read, if known.
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<Key, Consumer>,
+}
+
+impl ConsumerGroup {
+    pub fn new(last_delivered: StreamId, entries_read: Option<u64>) -> ConsumerGroup {
+        ConsumerGroup {
+            last_delivered,
+            entries_read,
+            ..Default::default()
+        }
+    }
+
+    /// The consumer called `name`, created if needed, marked as seen at `now`.
+    pub fn consumer(&mut self, name: &Key, now: u64) -> &mut Consumer {
+        let consumer = self.consumers.entry(name.clone()).or_default();
+        consumer.seen_at = now;
+        consumer
+    }
+
+    /// Create a consumer. Returns false if it already exists.
+    pub fn create_consumer(&mut self, name: &Key, now: u64) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
+        }
+        self.consumer(name, now);
+        true
+    }
+
+    /// Remove a consumer and its pending entries.
+    /// Returns how many entries were pending for it, if it existed.
+    pub fn remove_consumer(&mut self, name: &Key) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+        for id in consumer.pending.iter() {
+            self.pending.remove(id);
+        }
+        Some(consumer.pending.len())
+    }
+
+    /// Record the delivery of entry `id` to `consumer`, taking it from
+    /// whichever consumer it was pending for.
+    fn deliver(&mut self, id: StreamId, consumer: &Key, delivered_at: u64, delivery_count: u64) {
+        let pending = PendingEntry {
+            consumer: consumer.clone(),
+            delivered_at,
+            delivery_count,
+        };
+        if let Some(previous) = self.pending.insert(id, pending) {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
+            }
+        }
+        self.consumers
+            .entry(consumer.clone())
+            .or_default()
+            .pending
+            .insert(id);
+    }
+
+    /// Acknowledge entry `id`. Returns false if it wasn't pending.
+    pub fn ack(&mut self, id: &StreamId) -> bool {
+        let pending = match self.pending.remove(id) {
+            Some(pending) => pending,
+            None => return false,
+        };
+        if let Some(consumer) = self.consumers.get_mut(&pending.consumer) {
+            consumer.pending.remove(id);
+        }
+        true
+    }
+}
+
+/// The options of XCLAIM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClaimOptions {
+    /// Only claim entries pending for at least this many milliseconds.
+    pub min_idle: u64,
+    /// Set the idle time of claimed entries.
+    pub idle: Option<u64>,
+    /// Set the delivery time of claimed entries, in unix milliseconds.
+    pub time: Option<u64>,
+    /// Set the delivery count of claimed entries.
+    pub retry_count: Option<u64>,
+    /// Claim entries that aren't pending, as long as they exist.
+    pub force: bool,
+    /// Reply with IDs only, without counting a delivery.
+    pub just_id: bool,
+    /// Move the group's last delivered ID up to this one.
+    pub last_id: Option<StreamId>,
+}
+
+/// What XAUTOCLAIM did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoClaimed {
+    /// Where to continue scanning the pending entries, or 0-0 if done.
+    pub cursor: StreamId,
+    pub claimed: Vec<(StreamId, StreamFields)>,
+    /// Pending entries no longer in the stream, which were acknowledged.
+    pub deleted: Vec<StreamId>,
+}
+
+/// An append only log of entries, each a list of field / value pairs.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, StreamFields>,
+    /// The ID of the last entry added, even if it has since been removed.
+    last_id: StreamId,
+    /// How many entries were ever added.
+    entries_added: u64,
+    /// The largest ID of a removed entry.
+    #[serde(default)]
+    max_deleted_id: StreamId,
+    #[serde(default)]
+    groups: BTreeMap<Key, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
+
+    pub fn first(&self) -> Option<(&StreamId, &StreamFields)> {
+        self.entries.iter().next()
+    }
+
+    pub fn last(&self) -> Option<(&StreamId, &StreamFields)> {
+        self.entries.iter().next_back()
+    }
+
+    /// The ID an entry added with `id` at `now_ms` would get.
+    pub fn next_id(&self, id: NewId, now_ms: u64) -> Result<StreamId, AddError> {
+        let last = self.last_id;
+        let id = match id {
+            NewId::Auto if now_ms > last.ms => StreamId::new(now_ms, 0),
+            NewId::Auto => last.next().ok_or(AddError::Exhausted)?,
+            NewId::Sequence(ms) if ms == last.ms => last.next().ok_or(AddError::TooSmall)?,
+            // 0-0 is never valid, so the first sequence number of 0 is 1.
+            NewId::Sequence(ms) => StreamId::new(ms, (ms == 0) as u64),
+            NewId::Exact(id) => id,
+        };
+        match id {
+            StreamId::MIN => Err(AddError::Zero),
+            id if id <= last => Err(AddError::TooSmall),
+            id => Ok(id),
+        }
+    }
+
+    /// Append an entry. Returns its ID.
+    pub fn add(
+        &mut self,
+        id: NewId,
+        fields: Vec<(Key, Value)>,
+        now_ms: u64,
+    ) -> Result<StreamId, AddError> {
+        let id = self.next_id(id, now_ms)?;
+        self.entries.insert(id, fields);
+        self.last_id = id;
+        self.entries_added += 1;
+        Ok(id)
+    }
+
+    pub fn get(&self, id: &StreamId) -> Option<&Vec<(Key, Value)>> {
+        self.entries.get(id)
+    }
+
+    /// Entries with IDs from `start` to `end`, both included.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl DoubleEndedIterator<Item = (&StreamId, &Vec<(Key, Value)>)> {
+        // BTreeMap::range panics on inverted ranges.
+        let entries = (start <= end).then(|| self.entries.range(start..=end));
+        entries.into_iter().flatten()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&StreamId, &Vec<(Key, Value)>)> {
+        self.entries.iter()
+    }
+
+    /// Remove an entry. Returns false if there was none with `id`.
+    pub fn remove(&mut self, id: &StreamId) -> bool {
+        if self.entries.remove(id).is_none() {
+            return false;
+        }
+        self.max_deleted_id = self.max_deleted_id.max(*id);
+        true
+    }
+
+    /// Remove the oldest entries, as `trim` asks.
+    /// Returns the number of entries removed.
+    pub fn trim(&mut self, trim: &Trim) -> usize {
+        let mut removable = match trim.strategy {
+            TrimStrategy::MaxLen(max_len) => self.len().saturating_sub(max_len),
+            TrimStrategy::MinId(min_id) => self.entries.range(..min_id).count(),
+        };
+        if let Some(limit) = trim.limit {
+            removable = removable.min(limit);
+        }
+        if trim.approximate {
+            removable -= removable % STREAM_NODE_SIZE;
+        }
+        for _ in 0..removable {
+            if let Some((id, _)) = self.entries.pop_first() {
+                self.max_deleted_id = self.max_deleted_id.max(id);
+            }
+        }
+        removable
+    }
+
+    /// Whether an entry from `start` on was removed.
+    fn has_deleted_from(&self, start: StreamId) -> bool {
+        !self.is_empty() && self.max_deleted_id != StreamId::MIN && start <= self.max_deleted_id
+    }
+
+    /// How many entries were added up to `id`, if that can be told
+    /// despite removed entries. Like redis, this can't see past a gap
+    /// left by XDEL.
+    pub fn entries_read_until(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        if self.is_empty() && id <= self.last_id {
+            return Some(self.entries_added);
+        }
+        match id.cmp(&self.last_id) {
+            std::cmp::Ordering::Equal => return Some(self.entries_added),
+            std::cmp::Ordering::Greater => return None,
+            std::cmp::Ordering::Less => {}
+        }
+        let first_id = *self.first()?.0;
+        // Without removals after the first entry, the ones before it were trimmed.
+        if self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first_id {
+            let before_first = self.entries_added - self.len() as u64;
+            match id.cmp(&first_id) {
+                std::cmp::Ordering::Less => return Some(before_first),
+                std::cmp::Ordering::Equal => return Some(before_first + 1),
+                std::cmp::Ordering::Greater => {}
+            }
+        }
+        None
+    }
+
+    /// How many entries `group` has yet to read, if that can be told.
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+        let entries_read = match group.entries_read {
+            Some(read) if !self.has_deleted_from(group.last_delivered) => Some(read),
+            _ => self.entries_read_until(group.last_delivered),
+        };
+        entries_read.map(|read| self.entries_added.saturating_sub(read))
+    }
+
+    pub fn groups(&self) -> &BTreeMap<Key, ConsumerGroup> {
+        &self.groups
+    }
+
+    pub fn group(&self, name: &Key) -> Option<&ConsumerGroup> {
+        self.groups.get(name)
+    }
+
+    pub fn group_mut(&mut self, name: &Key) -> Option<&mut ConsumerGroup> {
+        self.groups.get_mut(name)
+    }
+
+    /// Create a group. Returns false if one already has that name.
+    pub fn create_group(&mut self, name: Key, group: ConsumerGroup) -> bool {
+        if self.groups.contains_key(&name) {
+            return false;
+        }
+        self.groups.insert(name, group);
+        true
+    }
+
+    pub fn remove_group(&mut self, name: &Key) -> bool {
+        self.groups.remove(name).is_some()
+    }
+
+    /// Deliver up to `count` entries the group hasn't delivered yet to
+    /// `consumer`. They become pending, unless `no_ack` is set.
+    /// Returns None if there's no such group.
+    pub fn read_group(
+        &mut self,
+        name: &Key,
+        consumer: &Key,
+        count: Option<usize>,
+        no_ack: bool,
+        now: u64,
+    ) -> Option<Vec<(StreamId, StreamFields)>> {
+        let group = self.groups.get(name)?;
+        let entries: Vec<(StreamId, StreamFields)> = match group.last_delivered.next() {
+            Some(start) => self
+                .range(start, StreamId::MAX)
+                .take(count.unwrap_or(usize::MAX))
+                .map(|(id, fields)| (*id, fields.clone()))
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut entries_read = group.entries_read;
+        for (id, _) in entries.iter() {
+            entries_read = match entries_read {
+                Some(read) if !self.has_deleted_from(*id) => Some(read + 1),
+                _ => self.entries_read_until(*id),
+            };
+        }
+        let group = self.groups.get_mut(name)?;
+        group.consumer(consumer, now);
+        if let Some((last, _)) = entries.last() {
+            group.last_delivered = *last;
+            group.entries_read = entries_read;
+            group.consumer(consumer, now).active_at = Some(now);
+        }
+        if !no_ack {
+            for (id, _) in entries.iter() {
+                group.deliver(*id, consumer, now, 1);
+            }
+        }
+        Some(entries)
+    }
+
+    /// Deliver again up to `count` of the entries pending for `consumer`,
+    /// with IDs after `after`. Entries removed from the stream have no fields.
+    /// Returns None if there's no such group.
+    pub fn read_pending(
+        &mut self,
+        name: &Key,
+        consumer: &Key,
+        after: StreamId,
+        count: Option<usize>,
+        now: u64,
+    ) -> Option<Vec<(StreamId, Option<StreamFields>)>> {
+        let group = self.groups.get_mut(name)?;
+        let ids: Vec<StreamId> = match after.next() {
+            Some(start) => group
+                .consumer(consumer, now)
+                .pending
+                .range(start..)
+                .take(count.unwrap_or(usize::MAX))
+                .copied()
+                .collect(),
+            None => Vec::new(),
+        };
+        let mut entries = Vec::with_capacity(ids.len());
+        for id in ids {
+            let fields = self.entries.get(&id).cloned();
+            if fields.is_some() {
+                if let Some(pending) = group.pending.get_mut(&id) {
+                    pending.delivered_at = now;
+                    pending.delivery_count += 1;
+                }
+            }
+            entries.push((id, fields));
+        }
+        Some(entries)
+    }
+
+    /// Take ownership of pending entries for `consumer`, as XCLAIM does.
+    /// Pending entries removed from the stream are acknowledged.
+    /// Returns None if there's no such group.
+    pub fn claim(
+        &mut self,
+        name: &Key,
+        consumer: &Key,
+        ids: &[StreamId],
+        options: &ClaimOptions,
+        now: u64,
+    ) -> Option<Vec<(StreamId, StreamFields)>> {
+        let group = self.groups.get_mut(name)?;
+        group.consumer(consumer, now);
+        if let Some(last_id) = options.last_id {
+            group.last_delivered = group.last_delivered.max(last_id);
+        }
+        let delivered_at = match (options.idle, options.time) {
+            (Some(idle), _) => now.saturating_sub(idle),
+            (None, Some(time)) => time,
+            (None, None) => now,
+        };
+        let mut claimed = Vec::new();
+        for id in ids {
+            let fields = self.entries.get(id);
+            if !group.pending.contains_key(id) {
+                if !options.force || fields.is_none() {
+                    continue;
+                }
+                group.deliver(*id, consumer, now, 1);
+            }
+            let fields = match fields {
+                Some(fields) => fields.clone(),
+                None => {
+                    group.ack(id);
+                    continue;
+                }
+            };
+            let pending = &group.pending[id];
+            if now.saturating_sub(pending.delivered_at) < options.min_idle {
+                continue;
+            }
+            let delivery_count = match options.retry_count {
+                Some(count) => count,
+                None if options.just_id => pending.delivery_count,
+                None => pending.delivery_count + 1,
+            };
+            group.deliver(*id, consumer, delivered_at, delivery_count);
+            claimed.push((*id, fields));
+        }
+        if !claimed.is_empty() {
+            group.consumer(consumer, now).active_at = Some(now);
+        }
+        Some(claimed)
+    }
+
+    /// Claim up to `count` entries pending for at least `min_idle`
+    /// milliseconds of `options`, scanning the pending entries from
+    /// `start`, as XAUTOCLAIM does. Returns None if there's no such group.
+    pub fn auto_claim(
+        &mut self,
+        name: &Key,
+        consumer: &Key,
+        start: StreamId,
+        count: usize,
+        options: &ClaimOptions,
+        now: u64,
+    ) -> Option<AutoClaimed> {
+        let group = self.groups.get_mut(name)?;
+        group.consumer(consumer, now);
+        // Like redis, bound the work done when few entries are idle enough.
+        let mut attempts = count.saturating_mul(10);
+        let mut claimed = Vec::new();
+        let mut deleted = Vec::new();
+        let mut cursor = StreamId::MIN;
+        for id in group.pending.range(start..).map(|(id, _)| *id) {
+            if attempts == 0 || claimed.len() == count {
+                cursor = id;
+                break;
+            }
+            attempts -= 1;
+            let fields = match self.entries.get(&id) {
+                Some(fields) => fields.clone(),
+                None => {
+                    deleted.push(id);
+                    continue;
+                }
+            };
+            let pending = &group.pending[&id];
+            if now.saturating_sub(pending.delivered_at) < options.min_idle {
+                continue;
+            }
+            let delivery_count = pending.delivery_count + (!options.just_id) as u64;
+            claimed.push((id, fields, delivery_count));
+        }
+        for id in deleted.iter() {
+            group.ack(id);
+        }
+        let claimed = claimed
+            .into_iter()
+            .map(|(id, fields, delivery_count)| {
+                group.deliver(id, consumer, now, delivery_count);
+                (id, fields)
+            })
+            .collect::<Vec<_>>();
+        if !claimed.is_empty() {
+            group.consumer(consumer, now).active_at = Some(now);
+        }
+        Some(AutoClaimed {
+            cursor,
+            claimed,
+            deleted,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_stream {
+    use crate::data_structures::stream::{
+        AddError, ClaimOptions, ConsumerGroup, NewId, Stream, StreamId, Trim, TrimStrategy,
+        STREAM_NODE_SIZE,
+    };
+    use bytes::Bytes;
+
+    fn fields() -> Vec<(Bytes, Bytes)> {
+        vec![(Bytes::from_static(b"f"), Bytes::from_static(b"v"))]
+    }
+
+    #[test]
+    fn test_parse_id() {
+        assert_eq!(StreamId::parse(b"5-3", 0), Some(StreamId::new(5, 3)));
+        assert_eq!(
+            StreamId::parse(b"5", u64::MAX),
+            Some(StreamId::new(5, u64::MAX))
+        );
+        assert_eq!(StreamId::parse(b"5-", 0), None);
+        assert_eq!(StreamId::parse(b"-5", 0), None);
+        assert_eq!(StreamId::parse(b"a-1", 0), None);
+        assert_eq!(StreamId::new(5, 3).to_string(), "5-3");
+        assert_eq!(StreamId::new(5, u64::MAX).next(), Some(StreamId::new(6, 0)));
+        assert_eq!(StreamId::new(6, 0).prev(), Some(StreamId::new(5, u64::MAX)));
+        assert_eq!(StreamId::MAX.next(), None);
+        assert_eq!(StreamId::MIN.prev(), None);
+    }
+
+    #[test]
+    fn test_add_ids() {
+        let mut stream = Stream::default();
+        assert_eq!(
+            stream.add(NewId::Exact(StreamId::MIN), fields(), 0),
+            Err(AddError::Zero)
+        );
+        assert_eq!(
+            stream.add(NewId::Sequence(0), fields(), 0),
+            Ok(StreamId::new(0, 1))
+        );
+        assert_eq!(
+            stream.add(NewId::Auto, fields(), 10),
+            Ok(StreamId::new(10, 0))
+        );
+        // The clock went backwards.
+        assert_eq!(
+            stream.add(NewId::Auto, fields(), 5),
+            Ok(StreamId::new(10, 1))
+        );
+        assert_eq!(
+            stream.add(NewId::Sequence(10), fields(), 0),
+            Ok(StreamId::new(10, 2))
+        );
+        assert_eq!(
+            stream.add(NewId::Sequence(9), fields(), 0),
+            Err(AddError::TooSmall)
+        );
+        assert_eq!(
+            stream.add(NewId::Exact(StreamId::new(10, 2)), fields(), 0),
+            Err(AddError::TooSmall)
+        );
+        assert_eq!(
+            stream.add(NewId::Exact(StreamId::new(20, 7)), fields(), 0),
+            Ok(StreamId::new(20, 7))
+        );
+        assert_eq!(stream.len(), 5);
+        // Removing the last entry doesn't allow reusing its ID.
+        assert!(stream.remove(&StreamId::new(20, 7)));
+        assert!(!stream.remove(&StreamId::new(20, 7)));
+        assert_eq!(stream.last_id(), StreamId::new(20, 7));
+        assert_eq!(
+            stream.add(NewId::Exact(StreamId::new(20, 7)), fields(), 0),
+            Err(AddError::TooSmall)
+        );
+        stream
+            .add(NewId::Exact(StreamId::MAX), fields(), 0)
+            .unwrap();
+        assert_eq!(
+            stream.add(NewId::Auto, fields(), 0),
+            Err(AddError::Exhausted)
+        );
+        assert_eq!(stream.entries_added(), 6);
+    }
+
+    #[test]
+    fn test_range() {
+        let mut stream = Stream::default();
+        for ms in 1..=5 {
+            stream.add(NewId::Sequence(ms), fields(), 0).unwrap();
+        }
+        let ids = |start, end| {
+            stream
+                .range(StreamId::new(start, 0), StreamId::new(end, 0))
+                .map(|(id, _)| id.ms)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(ids(2, 4), vec![2, 3, 4]);
+        assert_eq!(ids(4, 2), Vec::<u64>::new());
+        assert_eq!(ids(0, 100), vec![1, 2, 3, 4, 5]);
+        let last_two = stream
+            .range(StreamId::MIN, StreamId::MAX)
+            .rev()
+            .take(2)
+            .map(|(id, _)| id.ms)
+            .collect::<Vec<_>>();
+        assert_eq!(last_two, vec![5, 4]);
+    }
+
+    #[test]
+    fn test_trim() {
+        let mut stream = Stream::default();
+        for ms in 1..=250 {
+            stream.add(NewId::Sequence(ms), fields(), 0).unwrap();
+        }
+        let trim = |strategy, approximate, limit| Trim {
+            strategy,
+            approximate,
+            limit,
+        };
+        // Approximate trims keep whole nodes.
+        let approx = trim(TrimStrategy::MaxLen(10), true, None);
+        assert_eq!(stream.trim(&approx), 2 * STREAM_NODE_SIZE);
+        assert_eq!(stream.len(), 50);
+        let limited = trim(TrimStrategy::MaxLen(10), false, Some(5));
+        assert_eq!(stream.trim(&limited), 5);
+        assert_eq!(stream.len(), 45);
+        let min_id = trim(TrimStrategy::MinId(StreamId::new(240, 0)), false, None);
+        assert_eq!(stream.trim(&min_id), 34);
+        assert_eq!(
+            stream
+                .range(StreamId::MIN, StreamId::MAX)
+                .next()
+                .unwrap()
+                .0
+                .ms,
+            240
+        );
+        assert_eq!(stream.trim(&min_id), 0);
+    }
+
+    #[test]
+    fn test_read_group() {
+        let mut stream = Stream::default();
+        for ms in 1..=3 {
+            stream.add(NewId::Sequence(ms), fields(), 0).unwrap();
+        }
+        let (group, alice, bob) = (
+            Bytes::from_static(b"g"),
+            Bytes::from_static(b"alice"),
+            Bytes::from_static(b"bob"),
+        );
+        assert!(stream.create_group(group.clone(), ConsumerGroup::new(StreamId::MIN, None)));
+        assert!(!stream.create_group(group.clone(), ConsumerGroup::new(StreamId::MIN, None)));
+        let ids =
+            |entries: Vec<(StreamId, _)>| entries.iter().map(|(id, _)| id.ms).collect::<Vec<_>>();
+        let read = stream
+            .read_group(&group, &alice, Some(2), false, 100)
+            .unwrap();
+        assert_eq!(ids(read), vec![1, 2]);
+        let read = stream.read_group(&group, &bob, None, false, 100).unwrap();
+        assert_eq!(ids(read), vec![3]);
+        assert!(stream
+            .read_group(&group, &bob, None, false, 100)
+            .unwrap()
+            .is_empty());
+        assert!(stream
+            .read_group(&Bytes::from_static(b"nope"), &bob, None, false, 100)
+            .is_none());
+        // Reading the history redelivers the consumer's own pending entries.
+        let history = stream
+            .read_pending(&group, &alice, StreamId::MIN, None, 200)
+            .unwrap();
+        assert_eq!(
+            history.iter().map(|(id, _)| id.ms).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+        let group_ref = stream.group(&group).unwrap();
+        assert_eq!(group_ref.pending[&StreamId::new(1, 0)].delivery_count, 2);
+        assert_eq!(group_ref.pending[&StreamId::new(1, 0)].delivered_at, 200);
+        assert_eq!(group_ref.entries_read, Some(3));
+        assert_eq!(stream.lag(group_ref), Some(0));
+        // Deleted entries stay pending, without their fields.
+        stream.remove(&StreamId::new(2, 0));
+        let history = stream
+            .read_pending(&group, &alice, StreamId::new(1, 0), None, 200)
+            .unwrap();
+        assert_eq!(history, vec![(StreamId::new(2, 0), None)]);
+        let group_mut = stream.group_mut(&group).unwrap();
+        assert!(group_mut.ack(&StreamId::new(2, 0)));
+        assert!(!group_mut.ack(&StreamId::new(2, 0)));
+        assert_eq!(group_mut.remove_consumer(&alice), Some(1));
+        assert_eq!(group_mut.pending.len(), 1);
+        assert_eq!(group_mut.remove_consumer(&alice), None);
+    }
+
+    #[test]
+    fn test_claim() {
+        let mut stream = Stream::default();
+        for ms in 1..=4 {
+            stream.add(NewId::Sequence(ms), fields(), 0).unwrap();
+        }
+        let (group, alice, bob) = (
+            Bytes::from_static(b"g"),
+            Bytes::from_static(b"alice"),
+            Bytes::from_static(b"bob"),
+        );
+        stream.create_group(group.clone(), ConsumerGroup::new(StreamId::MIN, None));
+        stream.read_group(&group, &alice, None, false, 0).unwrap();
+        let ids: Vec<_> = (1..=4).map(|ms| StreamId::new(ms, 0)).collect();
+        let min_idle = ClaimOptions {
+            min_idle: 100,
+            ..Default::default()
+        };
+        // Not idle for long enough yet.
+        let claimed = stream
+            .claim(&group, &bob, &ids[..2], &min_idle, 50)
+            .unwrap();
+        assert!(claimed.is_empty());
+        let claimed = stream
+            .claim(&group, &bob, &ids[..2], &min_idle, 150)
+            .unwrap();
+        assert_eq!(claimed.len(), 2);
+        let group_ref = stream.group(&group).unwrap();
+        let pending = &group_ref.pending[&ids[0]];
+        assert_eq!(
+            (&pending.consumer[..], pending.delivery_count),
+            (&b"bob"[..], 2)
+        );
+        assert_eq!(group_ref.consumers[&alice].pending.len(), 2);
+        assert_eq!(group_ref.consumers[&bob].pending.len(), 2);
+        // Deleted entries are acknowledged instead.
+        stream.remove(&ids[2]);
+        let claimed = stream
+            .auto_claim(&group, &bob, StreamId::MIN, 1, &min_idle, 300)
+            .unwrap();
+        assert_eq!(
+            claimed
+                .claimed
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>(),
+            vec![ids[0]]
+        );
+        assert_eq!(claimed.cursor, ids[1]);
+        let claimed = stream
+            .auto_claim(&group, &bob, claimed.cursor, 10, &min_idle, 300)
+            .unwrap();
+        assert_eq!(
+            claimed
+                .claimed
+                .iter()
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>(),
+            vec![ids[1], ids[3]]
+        );
+        assert_eq!(claimed.deleted, vec![ids[2]]);
+        assert_eq!(claimed.cursor, StreamId::MIN);
+        assert_eq!(stream.group(&group).unwrap().pending.len(), 3);
+    }
+
+    #[test]
+    fn test_lag() {
+        let mut stream = Stream::default();
+        for ms in 1..=5 {
+            stream.add(NewId::Sequence(ms), fields(), 0).unwrap();
+        }
+        let group = Bytes::from_static(b"g");
+        let consumer = Bytes::from_static(b"c");
+        stream.create_group(group.clone(), ConsumerGroup::new(StreamId::MIN, None));
+        assert_eq!(stream.lag(stream.group(&group).unwrap()), Some(5));
+        stream
+            .read_group(&group, &consumer, Some(2), true, 0)
+            .unwrap();
+        assert_eq!(stream.lag(stream.group(&group).unwrap()), Some(3));
+        // A deletion after the group's position makes the lag unknowable.
+        stream.remove(&StreamId::new(4, 0));
+        assert_eq!(stream.lag(stream.group(&group).unwrap()), None);
+        stream.read_group(&group, &consumer, None, true, 0).unwrap();
+        assert_eq!(stream.lag(stream.group(&group).unwrap()), Some(0));
+    }
+}
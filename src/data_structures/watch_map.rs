@@ -0,0 +1,80 @@
+use crate::types::Key;
+use dashmap::DashMap;
+
+pub type Version = u64;
+
+/// Per-key versions used by WATCH.
+///
+/// Only keys that are currently watched are tracked, so writes to
+/// unwatched keys cost a single lookup.
+#[derive(Default, Debug)]
+pub struct WatchMap {
+    // key -> (version, number of watchers)
+    versions: DashMap<Key, (Version, usize)>,
+}
+
+impl WatchMap {
+    /// Start watching a key. Returns the current version of the key.
+    pub fn watch(&self, key: Key) -> Version {
+        let mut ent = self.versions.entry(key).or_default();
+        ent.1 += 1;
+        ent.0
+    }
+
+    /// Stop watching a key. The key is forgotten once nobody watches it.
+    pub fn unwatch(&self, key: &[u8]) {
+        if let Some(mut ent) = self.versions.get_mut(key) {
+            ent.1 = ent.1.saturating_sub(1);
+        }
+        self.versions
+            .remove_if(key, |_, (_, watchers)| *watchers == 0);
+    }
+
+    /// Current version of a watched key.
+    pub fn version(&self, key: &[u8]) -> Option<Version> {
+        self.versions.get(key).map(|ent| ent.0)
+    }
+
+    /// Mark a key as modified.
+    pub fn touch(&self, key: &[u8]) {
+        if let Some(mut ent) = self.versions.get_mut(key) {
+            ent.0 += 1;
+        }
+    }
+
+    /// Mark every watched key as modified.
+    pub fn touch_all(&self) {
+        for mut ent in self.versions.iter_mut() {
+            ent.0 += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_watch_map {
+    use crate::data_structures::watch_map::WatchMap;
+    use bytes::Bytes;
+
+    #[test]
+    fn test_touch() {
+        let wm = WatchMap::default();
+        let key = Bytes::from_static(b"key");
+        wm.touch(&key);
+        assert_eq!(wm.version(&key), None);
+        let version = wm.watch(key.clone());
+        wm.touch(&key);
+        assert_ne!(wm.version(&key), Some(version));
+    }
+
+    #[test]
+    fn test_unwatch_forgets() {
+        let wm = WatchMap::default();
+        let key = Bytes::from_static(b"key");
+        wm.watch(key.clone());
+        wm.watch(key.clone());
+        wm.unwatch(&key);
+        assert!(wm.version(&key).is_some());
+        wm.unwatch(&key);
+        assert_eq!(wm.version(&key), None);
+    }
+}
@@ -1,21 +1,25 @@
+use crate::aof::{self, Aof};
+use crate::data_structures::quicklist;
+use crate::expiry::now_millis;
 use crate::logger::LOGGER;
 use crate::startup::Config;
 use crate::types::{DumpFile, StateStore, StateStoreRef};
 use directories::ProjectDirs;
 use parking_lot::Mutex;
 use rmp_serde as rmps;
-use slog::{debug, error, info};
+use slog::{error, info, warn};
 use std::error::Error;
 use std::fs::{File, OpenOptions};
-use std::io::{Seek, SeekFrom};
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::{task, time::interval};
 
-const SAVE_STATE_PERIOD_SEC: u64 = 60;
-const SAVE_STATE_PERIOD: u64 = SAVE_STATE_PERIOD_SEC * 1000;
+/// How often the save points are checked.
+const SAVE_CHECK_PERIOD_MS: u64 = 1000;
 
 /// Convenience macro to panic with error messages.
 macro_rules! fatal_panic {
@@ -31,28 +35,258 @@ macro_rules! fatal_panic {
     }};
 }
 
+/// A redis style save point: save once at least `changes` writes
+/// happened, and `seconds` passed since the last save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavePoint {
+    pub seconds: u64,
+    pub changes: u64,
+}
+
+/// Save points, written like the redis `save` config (`"3600 1 300 100"`).
+/// An empty string disables automatic saves.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SavePoints(pub Vec<SavePoint>);
+
+impl FromStr for SavePoints {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let nums = s
+            .split_whitespace()
+            .map(|n| {
+                n.parse::<u64>()
+                    .map_err(|_| format!("Invalid save point value {}", n))
+            })
+            .collect::<Result<Vec<u64>, String>>()?;
+        if nums.len() % 2 != 0 {
+            return Err("Save points must be <seconds> <changes> pairs".to_string());
+        }
+        Ok(SavePoints(
+            nums.chunks(2)
+                .map(|pair| SavePoint {
+                    seconds: pair[0],
+                    changes: pair[1],
+                })
+                .collect(),
+        ))
+    }
+}
+
+impl SavePoints {
+    /// Whether a save is due, given the changes and seconds since the last save.
+    pub fn should_save(&self, changes: u64, elapsed_secs: u64) -> bool {
+        changes != 0
+            && self
+                .0
+                .iter()
+                .any(|p| changes >= p.changes && elapsed_secs >= p.seconds)
+    }
+}
+
+/// Reasons SAVE / BGSAVE can fail.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SaveError {
+    InProgress,
+    Failed(String),
+}
+
+/// Identifies redis-proto dump files.
+const DUMP_MAGIC: &[u8; 8] = b"REDISPRO";
+/// Bumped whenever the serialized layout of `StateStore` changes:
+///
+/// 1. the first dump with a header
+/// 2. sorted set scores became floats
+/// 3. streams, stored before the expirations
+/// 4. stream consumer groups
+const DUMP_FORMAT_VERSION: u16 = 4;
+/// magic, format version, payload length, payload checksum.
+const DUMP_HEADER_LEN: usize = 8 + 2 + 8 + 8;
+
+/// Reasons a dump file can't be loaded.
+#[derive(Debug)]
+pub enum DumpError {
+    BadMagic,
+    OutdatedVersion(u16),
+    UnsupportedVersion(u16),
+    Truncated,
+    BadChecksum,
+}
+
+impl std::fmt::Display for DumpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DumpError::BadMagic => write!(f, "not a redis-proto dump file (bad magic number)"),
+            DumpError::OutdatedVersion(v) => write!(
+                f,
+                "dump file format version {} is outdated and can't be migrated to version {}",
+                v, DUMP_FORMAT_VERSION
+            ),
+            DumpError::UnsupportedVersion(v) => write!(
+                f,
+                "dump file format version {} is not supported (expected {})",
+                v, DUMP_FORMAT_VERSION
+            ),
+            DumpError::Truncated => write!(f, "dump file is truncated"),
+            DumpError::BadChecksum => write!(f, "dump file is corrupt (checksum mismatch)"),
+        }
+    }
+}
+
+impl Error for DumpError {}
+
+/// Serialize the state, prefixed with the dump header.
+fn encode_dump(state: &StateStore) -> Result<Vec<u8>, Box<dyn Error>> {
+    let payload = rmps::encode::to_vec(state)?;
+    let mut dump = Vec::with_capacity(DUMP_HEADER_LEN + payload.len());
+    dump.extend_from_slice(DUMP_MAGIC);
+    dump.extend_from_slice(&DUMP_FORMAT_VERSION.to_le_bytes());
+    dump.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    dump.extend_from_slice(&seahash::hash(&payload).to_le_bytes());
+    dump.extend_from_slice(&payload);
+    Ok(dump)
+}
+
+/// Verify the dump header, and deserialize the state.
+///
+/// Dumps from before the header are the bare payload, in a layout the
+/// current one only adds trailing fields to, so they load as they are.
+/// Versions in between reordered fields and are refused.
+fn decode_dump(dump: &[u8]) -> Result<StateStore, Box<dyn Error>> {
+    if dump.len() < DUMP_MAGIC.len() || &dump[..DUMP_MAGIC.len()] != DUMP_MAGIC {
+        return decode_payload(dump).map_err(|_| DumpError::BadMagic.into());
+    }
+    if dump.len() < DUMP_HEADER_LEN {
+        return Err(DumpError::Truncated.into());
+    }
+    let u64_at = |pos: usize| u64::from_le_bytes(dump[pos..pos + 8].try_into().unwrap());
+    let version = u16::from_le_bytes([dump[8], dump[9]]);
+    if version < DUMP_FORMAT_VERSION {
+        return Err(DumpError::OutdatedVersion(version).into());
+    }
+    if version > DUMP_FORMAT_VERSION {
+        return Err(DumpError::UnsupportedVersion(version).into());
+    }
+    let payload = &dump[DUMP_HEADER_LEN..];
+    if payload.len() as u64 != u64_at(10) {
+        return Err(DumpError::Truncated.into());
+    }
+    if seahash::hash(payload) != u64_at(18) {
+        return Err(DumpError::BadChecksum.into());
+    }
+    decode_payload(payload)
+}
+
+fn decode_payload(payload: &[u8]) -> Result<StateStore, Box<dyn Error>> {
+    Ok(rmps::decode::from_read(payload)?)
+}
+
+/// Replace the file at `path` with `contents`.
+///
+/// The contents are written to a temp file which is fsynced, then
+/// renamed over `path`, so a crash leaves either the old or the new file.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let mut file = File::create(&temp_path)?;
+    if let Err(e) = file.write_all(contents).and_then(|_| file.sync_all()) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e);
+    }
+    std::fs::rename(&temp_path, path)?;
+    // Make the rename itself durable.
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
 /// Dump the current state to the dump_file
-fn dump_state(state: StateStoreRef, dump_file: &mut File) -> Result<(), Box<dyn Error>> {
-    dump_file.seek(SeekFrom::Start(0))?;
-    rmps::encode::write(dump_file, &state)
-        .map_err(|e| fatal_panic!("Could not write state!", e.to_string()))
-        .unwrap();
+fn dump_state(state: StateStoreRef, dump_file: &Path) -> Result<(), Box<dyn Error>> {
+    let dump = encode_dump(&state)?;
+    write_atomically(dump_file, &dump)?;
     Ok(())
 }
 
-/// Load state from the dump_file
-pub fn load_state(dump_file: DumpFile, config: &Config) -> Result<StateStoreRef, Box<dyn Error>> {
-    let mut contents = dump_file.lock(); // to prevent concurent access
-    if contents.metadata()?.len() == 0 {
-        return Ok(Arc::new(StateStore::default()));
+/// Read the snapshot in the dump_file
+fn read_dump(dump_file: DumpFile) -> Result<StateStore, Box<dyn Error>> {
+    let path = dump_file.lock(); // to prevent concurent access
+    let dump = match std::fs::read(&*path) {
+        Ok(dump) => dump,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(StateStore::default()),
+        Err(e) => return Err(e.into()),
+    };
+    if dump.is_empty() {
+        return Ok(StateStore::default());
     }
+    decode_dump(&dump).map_err(|e| format!("Could not load {:?}: {}", *path, e).into())
+}
 
-    contents.seek(SeekFrom::Start(0))?;
-    let mut state_store: StateStore = rmps::decode::from_read(&*contents)?;
-    state_store.commands_threshold = config.ops_until_save;
+/// Load state from the dump_file, or from the append only file if enabled.
+///
+/// The append only file is the more up to date of the two, so it
+/// wins when it has anything in it.
+pub async fn load_state(
+    dump_file: DumpFile,
+    config: &Config,
+) -> Result<StateStoreRef, Box<dyn Error>> {
+    let use_aof = config.append_only && !config.memory_only;
+    let aof_path = get_aof_path(config);
+    let replay_aof = use_aof && aof_path.exists() && std::fs::metadata(&aof_path)?.len() != 0;
+    // Before any list gets loaded.
+    quicklist::set_defaults(config.list_max_listpack_size, config.list_compress_depth);
+
+    let mut state_store = if replay_aof {
+        StateStore::default()
+    } else {
+        read_dump(dump_file)?
+    };
+    state_store.save_points = config.save.clone();
     state_store.memory_only = config.memory_only;
+    state_store.maxmemory = config.maxmemory;
+    state_store.maxmemory_policy = config.maxmemory_policy;
+    state_store
+        .last_save
+        .store(now_millis() / 1000, Ordering::SeqCst);
+    if use_aof {
+        info!(LOGGER, "Append Only File Location: {:?}", aof_path);
+        state_store.aof = Some(Arc::new(Aof::open(aof_path.clone(), config.append_fsync)?));
+    }
+    let state_store = Arc::new(state_store);
 
-    Ok(Arc::new(state_store))
+    if replay_aof {
+        let (replayed, valid_len) = aof::replay(&aof_path, &state_store).await?;
+        info!(
+            LOGGER,
+            "Replayed {} commands from the append only file", replayed
+        );
+        if valid_len < std::fs::metadata(&aof_path)?.len() {
+            warn!(
+                LOGGER,
+                "Append only file ends with an incomplete command, truncating it to {} bytes",
+                valid_len
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(&aof_path)?
+                .set_len(valid_len)?;
+        }
+    } else {
+        // Replayed commands are accounted for as they run, but loaded keys aren't.
+        if state_store.maxmemory > 0 {
+            for state in state_store.states.iter() {
+                state.memory.enable();
+                state.track_memory(&state.all_keys(), true);
+            }
+        }
+        if use_aof {
+            // Start the log off with the snapshot.
+            aof::rewrite(state_store.clone()).await?;
+        }
+    }
+
+    Ok(state_store)
 }
 
 /// Make the data directory (directory where the dump file lives)
@@ -83,7 +317,7 @@ fn default_data_dir() -> PathBuf {
     p
 }
 
-pub fn get_dump_file(config: &Config) -> DumpFile {
+fn get_data_dir(config: &Config) -> PathBuf {
     let data_dir = match &config.data_dir {
         Some(dir) => dir.to_path_buf(),
         None => default_data_dir(),
@@ -91,52 +325,209 @@ pub fn get_dump_file(config: &Config) -> DumpFile {
     if !data_dir.exists() {
         make_data_dir(&data_dir);
     }
+    data_dir
+}
 
-    let dump_file = data_dir.join("dump.rodb");
+/// The append only file lives next to the dump file.
+pub fn get_aof_path(config: &Config) -> PathBuf {
+    get_data_dir(config).join("appendonly.aof")
+}
+
+pub fn get_dump_file(config: &Config) -> DumpFile {
+    let dump_file = get_data_dir(config).join("dump.rodb");
     info!(LOGGER, "Dump File Location: {:?}", dump_file);
-    let opened_file = match OpenOptions::new()
-        .read(true)
-        .write(true)
-        .create(true)
-        .append(false)
-        .open(dump_file)
-    {
-        Ok(f) => f,
-        Err(e) => fatal_panic!(format!("Failed to open dump file! {}", e)),
-    };
     // TODO: Use tokio locks here
-    Arc::new(Mutex::new(opened_file))
-}
-
-pub fn save_state(state: StateStoreRef, dump_file: DumpFile) {
-    info!(
-        LOGGER,
-        "Saving state ({}s or >={} ops ran)...", SAVE_STATE_PERIOD_SEC, state.commands_threshold
-    );
-    match dump_file.try_lock() {
-        Some(mut file) => {
-            if let Err(e) = task::block_in_place(|| dump_state(state, &mut file)) {
-                fatal_panic!("FAILED TO DUMP STATE!", e.to_string());
-            }
+    Arc::new(Mutex::new(dump_file))
+}
+
+/// Save the state. The caller must have set `state.saving`.
+fn run_save(state: &StateStoreRef, dump_file: &DumpFile) -> Result<(), SaveError> {
+    info!(LOGGER, "Saving state...");
+    let changes = state.commands_ran_since_save.swap(0, Ordering::SeqCst);
+    let res = dump_state(state.clone(), &dump_file.lock());
+    state.saving.store(false, Ordering::SeqCst);
+    match res {
+        Ok(()) => {
+            state.last_save.store(now_millis() / 1000, Ordering::SeqCst);
+            Ok(())
+        }
+        Err(e) => {
+            // Try again at the next save point.
+            state
+                .commands_ran_since_save
+                .fetch_add(changes, Ordering::SeqCst);
+            error!(LOGGER, "Failed to save state! {}", e);
+            Err(SaveError::Failed(e.to_string()))
         }
-        None => debug!(
-            LOGGER,
-            "Failed to save state! Someone else is currently writing..."
-        ),
     }
 }
 
-/// Save the current State to DumpFile.
-///
-/// Panics if state fails to dump.
+/// Save the state, blocking until it's written.
+pub fn save_state(state: StateStoreRef, dump_file: DumpFile) -> Result<(), SaveError> {
+    if state.saving.swap(true, Ordering::SeqCst) {
+        return Err(SaveError::InProgress);
+    }
+    task::block_in_place(|| run_save(&state, &dump_file))
+}
+
+/// Save the state on a background thread.
+pub fn bgsave(state: StateStoreRef, dump_file: DumpFile) -> Result<(), SaveError> {
+    if state.saving.swap(true, Ordering::SeqCst) {
+        return Err(SaveError::InProgress);
+    }
+    task::spawn_blocking(move || run_save(&state, &dump_file));
+    Ok(())
+}
+
+/// Save the current State to DumpFile whenever a save point is reached.
 pub async fn save_state_interval(state: StateStoreRef, dump_file: DumpFile) {
-    let mut interval = interval(Duration::from_millis(SAVE_STATE_PERIOD));
+    let mut interval = interval(Duration::from_millis(SAVE_CHECK_PERIOD_MS));
     loop {
         interval.tick().await;
-        let commands_ran_since_save = state.commands_ran_since_save.load(Ordering::SeqCst);
-        if commands_ran_since_save != 0 {
-            state.commands_ran_since_save.store(0, Ordering::SeqCst);
-            save_state(state.clone(), dump_file.clone());
+        let changes = state.commands_ran_since_save.load(Ordering::SeqCst);
+        let elapsed = now_millis() / 1000 - state.last_save.load(Ordering::SeqCst);
+        if state
+            .save_points
+            .should_save(changes, elapsed.max(0) as u64)
+        {
+            let _ = bgsave(state.clone(), dump_file.clone());
         }
     }
 }
+
+#[cfg(test)]
+mod test_database {
+    use crate::database::{decode_dump, encode_dump, SavePoint, SavePoints, DUMP_HEADER_LEN};
+    use crate::types::{Index, StateStore};
+    use bytes::Bytes;
+    use rmp_serde as rmps;
+    use serde::Serialize;
+    use std::collections::{HashMap, VecDeque};
+
+    /// Dumps from before the header: no expirations or streams, integer
+    /// scores and string members.
+    #[derive(Serialize)]
+    struct LegacyStore {
+        states: HashMap<Index, LegacyState>,
+    }
+
+    type Unused = HashMap<Bytes, ()>;
+
+    #[derive(Serialize)]
+    struct LegacyState {
+        kv: HashMap<Bytes, Bytes>,
+        sets: Unused,
+        lists: HashMap<Bytes, VecDeque<Bytes>>,
+        hashes: Unused,
+        zsets: HashMap<Bytes, LegacySortedSet>,
+        blooms: Unused,
+        stacks: Unused,
+        hyperloglogs: Unused,
+    }
+
+    #[derive(Serialize)]
+    struct LegacySortedSet {
+        members_hash: HashMap<Bytes, i64>,
+        scores: Vec<LegacyMember>,
+    }
+
+    #[derive(Serialize)]
+    struct LegacyMember {
+        score: i64,
+        member: String,
+    }
+
+    fn sample_dump() -> Vec<u8> {
+        let store = StateStore::default();
+        let key = Bytes::from_static(b"key");
+        store.get_default().kv.insert(key.clone(), key);
+        encode_dump(&store).unwrap()
+    }
+
+    #[test]
+    fn test_dump_roundtrip() {
+        let store = decode_dump(&sample_dump()).unwrap();
+        assert!(store.get_default().kv.contains_key(&b"key"[..]));
+    }
+
+    #[test]
+    fn test_headerless_dumps_load() {
+        let (key, list, zkey) = (
+            Bytes::from_static(b"key"),
+            Bytes::from_static(b"list"),
+            Bytes::from_static(b"zset"),
+        );
+        let zset = LegacySortedSet {
+            members_hash: HashMap::from([(key.clone(), 3)]),
+            scores: vec![LegacyMember {
+                score: 3,
+                member: "key".into(),
+            }],
+        };
+        let state = LegacyState {
+            kv: HashMap::from([(key.clone(), key.clone())]),
+            sets: Unused::new(),
+            lists: HashMap::from([(list.clone(), VecDeque::from([key.clone()]))]),
+            hashes: Unused::new(),
+            zsets: HashMap::from([(zkey.clone(), zset)]),
+            blooms: Unused::new(),
+            stacks: Unused::new(),
+            hyperloglogs: Unused::new(),
+        };
+        let legacy = LegacyStore {
+            states: HashMap::from([(0, state)]),
+        };
+        let store = decode_dump(&rmps::to_vec(&legacy).unwrap()).unwrap();
+        let state = store.get_default();
+        assert!(state.kv.contains_key(&key));
+        assert_eq!(state.lists.get(&list).unwrap().len(), 1);
+        assert_eq!(
+            state.zsets.get(&zkey).unwrap().score(key.clone()),
+            Some(3.0)
+        );
+        assert_eq!(state.scan_keys(0, 10).1.len(), 3);
+    }
+
+    #[test]
+    fn test_corrupt_dumps_are_refused() {
+        let dump = sample_dump();
+        let err = |dump: &[u8]| decode_dump(dump).err().unwrap().to_string();
+        assert!(err(b"garbage").contains("magic"));
+        assert!(err(&dump[..dump.len() - 1]).contains("truncated"));
+        let mut flipped = dump.clone();
+        *flipped.last_mut().unwrap() ^= 0xff;
+        assert!(err(&flipped).contains("checksum"));
+        let mut future = dump.clone();
+        future[8] = 0xff;
+        assert!(err(&future).contains("not supported"));
+        let mut outdated = dump;
+        outdated[8..10].copy_from_slice(&1u16.to_le_bytes());
+        assert!(err(&outdated).contains("outdated"));
+        assert!(err(&sample_dump()[..DUMP_HEADER_LEN - 1]).contains("truncated"));
+    }
+
+    #[test]
+    fn test_save_points() {
+        let points: SavePoints = "3600 1 60 100".parse().unwrap();
+        assert_eq!(
+            points.0,
+            vec![
+                SavePoint {
+                    seconds: 3600,
+                    changes: 1
+                },
+                SavePoint {
+                    seconds: 60,
+                    changes: 100
+                }
+            ]
+        );
+        assert!(!points.should_save(0, 10_000));
+        assert!(!points.should_save(50, 100));
+        assert!(points.should_save(100, 60));
+        assert!(points.should_save(1, 3600));
+        assert!(!"".parse::<SavePoints>().unwrap().should_save(1000, 1000));
+        assert!("60".parse::<SavePoints>().is_err());
+        assert!("60 x".parse::<SavePoints>().is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use crate::logger::LOGGER;
+use crate::op_variants;
+use crate::types::{Count, Key, ReturnValue, StateRef, StateStoreRef, Timestamp};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time::interval;
+
+op_variants! {
+    ExpiryOps,
+    Expire(Key, Count),
+    PExpire(Key, Count),
+    ExpireAt(Key, Count),
+    PExpireAt(Key, Count),
+    Ttl(Key),
+    PTtl(Key),
+    Persist(Key)
+}
+
+impl ExpiryOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            ExpiryOps::Expire(key, _)
+            | ExpiryOps::PExpire(key, _)
+            | ExpiryOps::ExpireAt(key, _)
+            | ExpiryOps::PExpireAt(key, _)
+            | ExpiryOps::Ttl(key)
+            | ExpiryOps::PTtl(key)
+            | ExpiryOps::Persist(key) => vec![key.clone()],
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            ExpiryOps::Expire(..)
+                | ExpiryOps::PExpire(..)
+                | ExpiryOps::ExpireAt(..)
+                | ExpiryOps::PExpireAt(..)
+                | ExpiryOps::Persist(..)
+        )
+    }
+}
+
+/// How often the background task looks for expired keys.
+const ACTIVE_EXPIRE_PERIOD_MS: u64 = 100;
+
+/// Current unix time in milliseconds.
+pub fn now_millis() -> Timestamp {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as Timestamp)
+        .unwrap_or(0)
+}
+
+/// Set the deadline of `key` to `deadline`.
+///
+/// Keys with a deadline in the past are deleted straight away, like redis does.
+fn expire_at(state: &StateRef, key: Key, deadline: Option<Timestamp>) -> ReturnValue {
+    let deadline = match deadline {
+        Some(d) => d,
+        None => return ReturnValue::Error(b"invalid expire time"),
+    };
+    if !state.contains_key(&key) {
+        return ReturnValue::IntRes(0);
+    }
+    if deadline <= now_millis() {
+        state.remove_key(&key);
+    } else {
+        state.expirations.set(key, deadline);
+    }
+    ReturnValue::IntRes(1)
+}
+
+/// Remaining time to live of `key` in milliseconds, or the redis
+/// special values (-2 no key, -1 no expiry).
+fn ttl_millis(state: &StateRef, key: &Key) -> Count {
+    if !state.contains_key(key) {
+        return -2;
+    }
+    match state.expirations.get(key) {
+        Some(deadline) => std::cmp::max(deadline - now_millis(), 0),
+        None => -1,
+    }
+}
+
+pub async fn expiry_interact(expiry_op: ExpiryOps, state: StateRef) -> ReturnValue {
+    match expiry_op {
+        ExpiryOps::Expire(key, secs) => {
+            let deadline = secs
+                .checked_mul(1000)
+                .and_then(|ms| ms.checked_add(now_millis()));
+            expire_at(&state, key, deadline)
+        }
+        ExpiryOps::PExpire(key, ms) => expire_at(&state, key, ms.checked_add(now_millis())),
+        ExpiryOps::ExpireAt(key, unix_secs) => expire_at(&state, key, unix_secs.checked_mul(1000)),
+        ExpiryOps::PExpireAt(key, unix_ms) => expire_at(&state, key, Some(unix_ms)),
+        ExpiryOps::Ttl(key) => match ttl_millis(&state, &key) {
+            ms if ms < 0 => ReturnValue::IntRes(ms),
+            ms => ReturnValue::IntRes((ms + 500) / 1000),
+        },
+        ExpiryOps::PTtl(key) => ReturnValue::IntRes(ttl_millis(&state, &key)),
+        ExpiryOps::Persist(key) => {
+            if !state.contains_key(&key) {
+                return ReturnValue::IntRes(0);
+            }
+            ReturnValue::IntRes(state.expirations.remove(&key).is_some() as Count)
+        }
+    }
+}
+
+/// Actively remove expired keys.
+///
+/// Keys are also expired lazily when accessed; this task makes sure
+/// keys that are never touched again still get evicted.
+pub async fn expire_keys_interval(state_store: StateStoreRef) {
+    let mut interval = interval(Duration::from_millis(ACTIVE_EXPIRE_PERIOD_MS));
+    loop {
+        interval.tick().await;
+        for state in state_store.states.iter() {
+            let removed = state.remove_expired_keys();
+            if removed != 0 {
+                debug!(LOGGER, "Expired {} keys in db {}", removed, state.key());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_expiry {
+    use crate::expiry::{expiry_interact, now_millis, ExpiryOps};
+    use crate::keys::{key_interact, KeyOps, SetOptions};
+    use crate::ops::{op_interact, Ops};
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_expire_and_ttl() {
+        let (key, v) = (Bytes::from_static(b"key"), Bytes::from_static(b"v"));
+        let eng = Arc::new(State::default());
+        assert_eq!(
+            ReturnValue::IntRes(0),
+            expiry_interact(ExpiryOps::Expire(key.clone(), 10), eng.clone()).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(-2),
+            expiry_interact(ExpiryOps::Ttl(key.clone()), eng.clone()).await
+        );
+        key_interact(
+            KeyOps::Set(key.clone(), v, SetOptions::default()),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(
+            ReturnValue::IntRes(-1),
+            expiry_interact(ExpiryOps::Ttl(key.clone()), eng.clone()).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            expiry_interact(ExpiryOps::Expire(key.clone(), 10), eng.clone()).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(10),
+            expiry_interact(ExpiryOps::Ttl(key.clone()), eng.clone()).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            expiry_interact(ExpiryOps::Persist(key.clone()), eng.clone()).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(-1),
+            expiry_interact(ExpiryOps::PTtl(key), eng.clone()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lazy_expiry() {
+        let (key, v) = (Bytes::from_static(b"key"), Bytes::from_static(b"v"));
+        let eng = Arc::new(State::default());
+        key_interact(
+            KeyOps::Set(key.clone(), v, SetOptions::default()),
+            eng.clone(),
+        )
+        .await;
+        eng.expirations.set(key.clone(), now_millis() - 1);
+        assert_eq!(
+            ReturnValue::Nil,
+            op_interact(Ops::Keys(KeyOps::Get(key.clone())), eng.clone()).await
+        );
+        assert!(!eng.contains_key(&key));
+        assert!(eng.expirations.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_active_expiry() {
+        let eng = Arc::new(State::default());
+        for i in 0..100 {
+            let key = Bytes::from(format!("key_{}", i));
+            eng.kv.insert(key.clone(), key.clone());
+            let deadline = if i % 2 == 0 {
+                now_millis() - 1
+            } else {
+                now_millis() + 100_000
+            };
+            eng.expirations.set(key, deadline);
+        }
+        assert_eq!(eng.remove_expired_keys(), 50);
+        assert_eq!(eng.kv.len(), 50);
+        assert_eq!(eng.expirations.len(), 50);
+    }
+
+    #[tokio::test]
+    async fn test_expire_in_past_deletes() {
+        let (key, v) = (Bytes::from_static(b"key"), Bytes::from_static(b"v"));
+        let eng = Arc::new(State::default());
+        key_interact(
+            KeyOps::Set(key.clone(), v, SetOptions::default()),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            expiry_interact(ExpiryOps::PExpireAt(key.clone(), 1), eng.clone()).await
+        );
+        assert!(!eng.contains_key(&key));
+    }
+}
@@ -0,0 +1,670 @@
+/// Geospatial indexes, stored in sorted sets like redis does.
+///
+/// A member's score is its 52 bit geohash: 26 bits of latitude and 26 of
+/// longitude, interleaved. Nearby points share score prefixes, so a search
+/// looks up the score ranges of the few cells around its center, then
+/// filters them by exact distance.
+use crate::data_structures::sorted_set::{ScoreBound, ScoreRange, SortedSet, SortedSetMember};
+use crate::ops::RVec;
+use crate::types::{Count, Key, ReturnValue, Score, StateRef, ValueType, WRONGTYPE};
+use std::str::FromStr;
+
+pub const LON_MIN: f64 = -180.0;
+pub const LON_MAX: f64 = 180.0;
+/// The limits of web mercator, as used by redis.
+pub const LAT_MIN: f64 = -85.05112878;
+pub const LAT_MAX: f64 = 85.05112878;
+/// Bits per coordinate in a geohash.
+const GEO_STEP: u32 = 26;
+/// The earth radius redis uses, in meters.
+const EARTH_RADIUS: f64 = 6372797.560856;
+/// Half the circumference of the earth in web mercator, in meters.
+const MERCATOR_MAX: f64 = 20037726.37;
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    pub lon: f64,
+    pub lat: f64,
+}
+
+/// Spread the bits of `x` out to the even bits of the result.
+fn spread(x: u32) -> u64 {
+    let mut x = x as u64;
+    x = (x | (x << 16)) & 0x0000_FFFF_0000_FFFF;
+    x = (x | (x << 8)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x << 2)) & 0x3333_3333_3333_3333;
+    (x | (x << 1)) & 0x5555_5555_5555_5555
+}
+
+/// The inverse of `spread`: gather the even bits of `x`.
+fn squash(x: u64) -> u32 {
+    let mut x = x & 0x5555_5555_5555_5555;
+    x = (x | (x >> 1)) & 0x3333_3333_3333_3333;
+    x = (x | (x >> 2)) & 0x0F0F_0F0F_0F0F_0F0F;
+    x = (x | (x >> 4)) & 0x00FF_00FF_00FF_00FF;
+    x = (x | (x >> 8)) & 0x0000_FFFF_0000_FFFF;
+    (x | (x >> 16)) as u32
+}
+
+fn interleave(lat_cell: u32, lon_cell: u32) -> u64 {
+    spread(lat_cell) | (spread(lon_cell) << 1)
+}
+
+/// The cell of `value` when `min..max` is cut in 2^step cells.
+fn cell(value: f64, min: f64, max: f64, step: u32) -> u32 {
+    let cells = 1u64 << step;
+    let cell = ((value - min) / (max - min) * cells as f64) as u64;
+    cell.min(cells - 1) as u32
+}
+
+fn deg_rad(deg: f64) -> f64 {
+    deg * std::f64::consts::PI / 180.0
+}
+
+fn rad_deg(rad: f64) -> f64 {
+    rad * 180.0 / std::f64::consts::PI
+}
+
+impl GeoPoint {
+    /// None if the point is out of the range geohashes can encode.
+    pub fn new(lon: f64, lat: f64) -> Option<GeoPoint> {
+        let valid = (LON_MIN..=LON_MAX).contains(&lon) && (LAT_MIN..=LAT_MAX).contains(&lat);
+        valid.then_some(GeoPoint { lon, lat })
+    }
+
+    /// The cells of the point when each coordinate is cut in 2^step cells.
+    fn cells(&self, step: u32) -> (u32, u32) {
+        (
+            cell(self.lat, LAT_MIN, LAT_MAX, step),
+            cell(self.lon, LON_MIN, LON_MAX, step),
+        )
+    }
+
+    /// The geohash of the point, as a sorted set score.
+    pub fn score(&self) -> Score {
+        let (lat_cell, lon_cell) = self.cells(GEO_STEP);
+        // 52 bits, so exactly representable.
+        interleave(lat_cell, lon_cell) as Score
+    }
+
+    /// The center of the geohash cell `score`.
+    pub fn from_score(score: Score) -> GeoPoint {
+        let bits = score as u64;
+        let cells = (1u64 << GEO_STEP) as f64;
+        let center = |cell: u32, min: f64, max: f64| {
+            let size = (max - min) / cells;
+            (min + (cell as f64 + 0.5) * size).clamp(min, max)
+        };
+        GeoPoint {
+            lon: center(squash(bits >> 1), LON_MIN, LON_MAX),
+            lat: center(squash(bits), LAT_MIN, LAT_MAX),
+        }
+    }
+
+    /// Great circle distance in meters, using the haversine formula.
+    pub fn distance(&self, other: &GeoPoint) -> f64 {
+        let (lat1, lat2) = (deg_rad(self.lat), deg_rad(other.lat));
+        let u = ((lat2 - lat1) / 2.0).sin();
+        let v = (deg_rad(other.lon - self.lon) / 2.0).sin();
+        2.0 * EARTH_RADIUS * (u * u + lat1.cos() * lat2.cos() * v * v).sqrt().asin()
+    }
+}
+
+/// The standard 11 character geohash of the cell `score`, as GEOHASH replies.
+pub fn geohash_string(score: Score) -> String {
+    let point = GeoPoint::from_score(score);
+    // Standard geohashes take latitudes from -90 to 90, not web mercator's.
+    let bits = interleave(
+        cell(point.lat, -90.0, 90.0, GEO_STEP),
+        cell(point.lon, LON_MIN, LON_MAX, GEO_STEP),
+    );
+    (0..11)
+        .map(|i| {
+            // 52 bits fill 10 characters and a bit; the last is always '0'.
+            let index = match i {
+                10 => 0,
+                i => (bits >> (52 - (i + 1) * 5)) & 0x1f,
+            };
+            GEOHASH_ALPHABET[index as usize] as char
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum GeoUnit {
+    #[default]
+    Meters,
+    Kilometers,
+    Miles,
+    Feet,
+}
+
+impl GeoUnit {
+    pub fn meters(&self) -> f64 {
+        match self {
+            GeoUnit::Meters => 1.0,
+            GeoUnit::Kilometers => 1000.0,
+            GeoUnit::Miles => 1609.34,
+            GeoUnit::Feet => 0.3048,
+        }
+    }
+}
+
+impl FromStr for GeoUnit {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "m" => Ok(GeoUnit::Meters),
+            "km" => Ok(GeoUnit::Kilometers),
+            "mi" => Ok(GeoUnit::Miles),
+            "ft" => Ok(GeoUnit::Feet),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where a search is centered: FROMMEMBER or FROMLONLAT.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GeoFrom {
+    Member(Key),
+    Point(GeoPoint),
+}
+
+/// The area searched: BYRADIUS or BYBOX. Sizes are in meters.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GeoShape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+impl GeoShape {
+    /// The distance from `center` to `point`, if it's within the shape.
+    fn distance_if_inside(&self, center: &GeoPoint, point: &GeoPoint) -> Option<f64> {
+        match *self {
+            GeoShape::Radius(radius) => {
+                let distance = center.distance(point);
+                (distance <= radius).then_some(distance)
+            }
+            GeoShape::Box { width, height } => {
+                let lat_distance = EARTH_RADIUS * deg_rad(point.lat - center.lat).abs();
+                if lat_distance > height / 2.0 {
+                    return None;
+                }
+                // Measured along the point's parallel.
+                let on_parallel = GeoPoint {
+                    lon: center.lon,
+                    lat: point.lat,
+                };
+                if point.distance(&on_parallel) > width / 2.0 {
+                    return None;
+                }
+                Some(center.distance(point))
+            }
+        }
+    }
+
+    /// Half the width and height of the shape, in meters.
+    fn half_extents(&self) -> (f64, f64) {
+        match *self {
+            GeoShape::Radius(radius) => (radius, radius),
+            GeoShape::Box { width, height } => (width / 2.0, height / 2.0),
+        }
+    }
+
+    /// The south west and north east corners of a box holding the shape.
+    /// Longitudes may go past +-180.
+    fn bounding_box(&self, center: &GeoPoint) -> (GeoPoint, GeoPoint) {
+        let (half_width, half_height) = self.half_extents();
+        let lat_delta = rad_deg(half_height / EARTH_RADIUS);
+        // Parallels are shorter away from the equator.
+        let lon_delta = |lat: f64| rad_deg(half_width / EARTH_RADIUS / deg_rad(lat).cos());
+        let lon_delta = lon_delta(center.lat + lat_delta).max(lon_delta(center.lat - lat_delta));
+        (
+            GeoPoint {
+                lon: center.lon - lon_delta,
+                lat: (center.lat - lat_delta).max(LAT_MIN),
+            },
+            GeoPoint {
+                lon: center.lon + lon_delta,
+                lat: (center.lat + lat_delta).min(LAT_MAX),
+            },
+        )
+    }
+
+    /// The geohash step whose cells are about as big as the shape.
+    fn estimate_step(&self, lat: f64) -> u32 {
+        let mut range = match *self {
+            GeoShape::Radius(radius) => radius,
+            GeoShape::Box { width, height } => (width / 2.0).hypot(height / 2.0),
+        };
+        if range == 0.0 {
+            return GEO_STEP;
+        }
+        let mut step: i32 = 1;
+        while range < MERCATOR_MAX {
+            range *= 2.0;
+            step += 1;
+        }
+        step -= 2;
+        // Cells are narrower near the poles.
+        if lat.abs() > 66.0 {
+            step -= 1;
+            if lat.abs() > 80.0 {
+                step -= 1;
+            }
+        }
+        step.clamp(1, GEO_STEP as i32) as u32
+    }
+
+    /// Score ranges holding every point of the shape around `center`:
+    /// those of the 3x3 cells around it, at a step where they cover it.
+    fn covering_ranges(&self, center: &GeoPoint) -> Vec<ScoreRange> {
+        let (south_west, north_east) = self.bounding_box(center);
+        let mut step = self.estimate_step(center.lat);
+        let (lat_cell, lon_cell) = loop {
+            let (lat_cell, lon_cell) = center.cells(step);
+            let cells = (1u64 << step) as f64;
+            let lat_size = (LAT_MAX - LAT_MIN) / cells;
+            let lon_size = (LON_MAX - LON_MIN) / cells;
+            let lat_low = LAT_MIN + lat_cell as f64 * lat_size;
+            let lon_low = LON_MIN + lon_cell as f64 * lon_size;
+            let covered = south_west.lat >= lat_low - lat_size
+                && north_east.lat <= lat_low + 2.0 * lat_size
+                && south_west.lon >= lon_low - lon_size
+                && north_east.lon <= lon_low + 2.0 * lon_size;
+            if covered || step == 1 {
+                break (lat_cell as i64, lon_cell as i64);
+            }
+            step -= 1;
+        };
+        let cells = 1i64 << step;
+        let mut hashes = Vec::with_capacity(9);
+        for lat in lat_cell - 1..=lat_cell + 1 {
+            if !(0..cells).contains(&lat) {
+                continue;
+            }
+            for lon in lon_cell - 1..=lon_cell + 1 {
+                // Longitudes wrap around.
+                let lon = lon.rem_euclid(cells);
+                hashes.push(interleave(lat as u32, lon as u32));
+            }
+        }
+        hashes.sort_unstable();
+        hashes.dedup();
+        let shift = 2 * (GEO_STEP - step);
+        hashes
+            .into_iter()
+            .map(|hash| ScoreRange {
+                min: ScoreBound {
+                    score: (hash << shift) as Score,
+                    exclusive: false,
+                },
+                max: ScoreBound {
+                    score: ((hash + 1) << shift) as Score,
+                    exclusive: true,
+                },
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
+/// The options of GEOSEARCH and GEOSEARCHSTORE.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GeoQuery {
+    pub from: GeoFrom,
+    pub shape: GeoShape,
+    /// The unit of the shape, and of replied distances.
+    pub unit: GeoUnit,
+    pub order: Option<SortOrder>,
+    /// COUNT, and whether ANY match will do rather than the closest.
+    pub count: Option<(usize, bool)>,
+    pub with_coord: bool,
+    pub with_dist: bool,
+    pub with_hash: bool,
+}
+
+op_variants! {
+    GeoOps,
+    GeoPos(Key, RVec<Key>),
+    GeoDist(Key, Key, Key, GeoUnit),
+    GeoHash(Key, RVec<Key>),
+    GeoSearch(Key, GeoQuery),
+    GeoSearchStore(Key, Key, GeoQuery, bool)
+}
+
+impl GeoOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            GeoOps::GeoPos(key, _)
+            | GeoOps::GeoDist(key, ..)
+            | GeoOps::GeoHash(key, _)
+            | GeoOps::GeoSearch(key, _) => vec![key.clone()],
+            GeoOps::GeoSearchStore(dest, source, ..) => vec![dest.clone(), source.clone()],
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(self, GeoOps::GeoSearchStore(..))
+    }
+
+    /// The type the keys must hold, or None if any type will do.
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            // GEOSEARCHSTORE overwrites its destination, whatever it holds.
+            GeoOps::GeoSearchStore(..) => None,
+            _ => Some(ValueType::ZSet),
+        }
+    }
+}
+
+const MEMBER_NOT_FOUND: &[u8] = b"ERR could not decode requested zset member";
+
+/// The distance in `unit`, formatted like redis.
+fn distance_reply(meters: f64, unit: GeoUnit) -> ReturnValue {
+    ReturnValue::StringRes(format!("{:.4}", meters / unit.meters()).into())
+}
+
+fn point_reply(point: &GeoPoint) -> ReturnValue {
+    ReturnValue::Array(vec![
+        ReturnValue::Double(point.lon),
+        ReturnValue::Double(point.lat),
+    ])
+}
+
+/// The members matching `query` with their distance in meters, sorted and
+/// limited as asked.
+fn search(zset: &SortedSet, query: &GeoQuery) -> Result<Vec<(SortedSetMember, f64)>, ReturnValue> {
+    let center = match &query.from {
+        GeoFrom::Point(point) => *point,
+        GeoFrom::Member(member) => match zset.score(member.clone()) {
+            Some(score) => GeoPoint::from_score(score),
+            None => return Err(ReturnValue::Error(MEMBER_NOT_FOUND)),
+        },
+    };
+    let mut found = Vec::new();
+    for range in query.shape.covering_ranges(&center) {
+        for member in zset.range_by_score(&range) {
+            let point = GeoPoint::from_score(member.score);
+            if let Some(distance) = query.shape.distance_if_inside(&center, &point) {
+                found.push((member.clone(), distance));
+            }
+        }
+    }
+    // ANY takes the first matches found, rather than the closest.
+    if let Some((count, true)) = query.count {
+        found.truncate(count);
+    }
+    let by_distance = |a: &(SortedSetMember, f64), b: &(SortedSetMember, f64)| a.1.total_cmp(&b.1);
+    match query.order {
+        Some(SortOrder::Desc) => found.sort_by(|a, b| by_distance(b, a)),
+        // COUNT without ANY returns the closest matches.
+        Some(SortOrder::Asc) | None => found.sort_by(by_distance),
+    }
+    if let Some((count, false)) = query.count {
+        found.truncate(count);
+    }
+    Ok(found)
+}
+
+fn search_reply(found: Vec<(SortedSetMember, f64)>, query: &GeoQuery) -> ReturnValue {
+    let with_any = query.with_dist || query.with_hash || query.with_coord;
+    let replies = found
+        .into_iter()
+        .map(|(member, distance)| {
+            if !with_any {
+                return ReturnValue::StringRes(member.member);
+            }
+            let mut reply = vec![ReturnValue::StringRes(member.member)];
+            if query.with_dist {
+                reply.push(distance_reply(distance, query.unit));
+            }
+            if query.with_hash {
+                reply.push(ReturnValue::IntRes(member.score as Count));
+            }
+            if query.with_coord {
+                reply.push(point_reply(&GeoPoint::from_score(member.score)));
+            }
+            ReturnValue::Array(reply)
+        })
+        .collect();
+    ReturnValue::Array(replies)
+}
+
+fn search_store(
+    state: &StateRef,
+    dest: Key,
+    source: Key,
+    query: &GeoQuery,
+    store_dist: bool,
+) -> ReturnValue {
+    let found = match state.zsets.get(&source) {
+        Some(zset) => match search(&zset, query) {
+            Ok(found) => found,
+            Err(err) => return err,
+        },
+        None if state.contains_key(&source) => return ReturnValue::Error(WRONGTYPE),
+        None => Vec::new(),
+    };
+    let mut zset = SortedSet::new();
+    zset.add(
+        found
+            .into_iter()
+            .map(|(member, distance)| match store_dist {
+                true => (distance / query.unit.meters(), member.member),
+                false => (member.score, member.member),
+            })
+            .collect(),
+    );
+    let card = zset.card();
+    state.remove_key(&dest);
+    if card > 0 {
+        state.zsets.insert(dest, zset);
+    }
+    ReturnValue::IntRes(card)
+}
+
+pub async fn geo_interact(geo_op: GeoOps, state: StateRef) -> ReturnValue {
+    match geo_op {
+        GeoOps::GeoPos(key, members) => {
+            let zset = state.zsets.get(&key);
+            let positions = members
+                .into_iter()
+                .map(|member| {
+                    zset.as_ref()
+                        .and_then(|zset| zset.score(member))
+                        .map(|score| point_reply(&GeoPoint::from_score(score)))
+                        .unwrap_or(ReturnValue::Nil)
+                })
+                .collect();
+            ReturnValue::Array(positions)
+        }
+        GeoOps::GeoDist(key, from, to, unit) => {
+            let zset = match state.zsets.get(&key) {
+                Some(zset) => zset,
+                None => return ReturnValue::Nil,
+            };
+            match (zset.score(from), zset.score(to)) {
+                (Some(from), Some(to)) => {
+                    let distance = GeoPoint::from_score(from).distance(&GeoPoint::from_score(to));
+                    distance_reply(distance, unit)
+                }
+                _ => ReturnValue::Nil,
+            }
+        }
+        GeoOps::GeoHash(key, members) => {
+            let zset = state.zsets.get(&key);
+            let hashes = members
+                .into_iter()
+                .map(|member| {
+                    zset.as_ref()
+                        .and_then(|zset| zset.score(member))
+                        .map(|score| ReturnValue::StringRes(geohash_string(score).into()))
+                        .unwrap_or(ReturnValue::Nil)
+                })
+                .collect();
+            ReturnValue::Array(hashes)
+        }
+        GeoOps::GeoSearch(key, query) => match state.zsets.get(&key) {
+            Some(zset) => match search(&zset, &query) {
+                Ok(found) => search_reply(found, &query),
+                Err(err) => err,
+            },
+            None => ReturnValue::Array(vec![]),
+        },
+        GeoOps::GeoSearchStore(dest, source, query, store_dist) => {
+            search_store(&state, dest, source, &query, store_dist)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_geo {
+    use crate::data_structures::sorted_set::AddOptions;
+    use crate::geo::{
+        geo_interact, geohash_string, GeoFrom, GeoOps, GeoPoint, GeoQuery, GeoShape, GeoUnit,
+        SortOrder,
+    };
+    use crate::sorted_sets::{zset_interact, ZSetOps};
+    use crate::types::{Key, ReturnValue, State, StateRef};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    fn key(k: &'static str) -> Key {
+        Bytes::from_static(k.as_bytes())
+    }
+
+    /// The GEOADD example from the redis docs.
+    async fn sicily() -> StateRef {
+        let state = Arc::new(State::default());
+        let places = [
+            (13.361389, 38.115556, "Palermo"),
+            (15.087269, 37.502669, "Catania"),
+        ];
+        let members = places
+            .iter()
+            .map(|(lon, lat, name)| (GeoPoint::new(*lon, *lat).unwrap().score(), key(name)))
+            .collect();
+        let op = ZSetOps::ZAdd(key("Sicily"), members, AddOptions::default());
+        zset_interact(op, state.clone()).await;
+        state
+    }
+
+    fn query(from: GeoFrom, shape: GeoShape, unit: GeoUnit) -> GeoQuery {
+        GeoQuery {
+            from,
+            shape,
+            unit,
+            order: Some(SortOrder::Asc),
+            count: None,
+            with_coord: false,
+            with_dist: true,
+            with_hash: false,
+        }
+    }
+
+    #[test]
+    fn test_encoding() {
+        let palermo = GeoPoint::new(13.361389, 38.115556).unwrap();
+        assert_eq!(palermo.score(), 3479099956230698.0);
+        let decoded = GeoPoint::from_score(palermo.score());
+        assert!((decoded.lon - 13.361389338970184).abs() < 1e-9);
+        assert!((decoded.lat - 38.1155563954963).abs() < 1e-9);
+        assert_eq!(geohash_string(palermo.score()), "sqc8b49rny0");
+        assert!(GeoPoint::new(181.0, 0.0).is_none());
+        assert!(GeoPoint::new(0.0, 86.0).is_none());
+        // The edges of the map still fit in 52 bits.
+        let corner = GeoPoint::new(180.0, 85.05112878).unwrap();
+        assert!(corner.score() < (1u64 << 52) as f64);
+    }
+
+    #[tokio::test]
+    async fn test_dist() {
+        let state = sicily().await;
+        let dist = GeoOps::GeoDist(
+            key("Sicily"),
+            key("Palermo"),
+            key("Catania"),
+            GeoUnit::Kilometers,
+        );
+        assert_eq!(
+            geo_interact(dist, state.clone()).await,
+            ReturnValue::StringRes(Bytes::from_static(b"166.2742"))
+        );
+        let missing = GeoOps::GeoDist(key("Sicily"), key("Palermo"), key("Rome"), GeoUnit::Meters);
+        assert_eq!(geo_interact(missing, state).await, ReturnValue::Nil);
+    }
+
+    #[tokio::test]
+    async fn test_search() {
+        let state = sicily().await;
+        let center = GeoFrom::Point(GeoPoint::new(15.0, 37.0).unwrap());
+        let within = |radius_km: f64| {
+            let shape = GeoShape::Radius(radius_km * 1000.0);
+            GeoOps::GeoSearch(
+                key("Sicily"),
+                query(center.clone(), shape, GeoUnit::Kilometers),
+            )
+        };
+        let found = |name: &'static str, dist: &'static str| {
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(key(name)),
+                ReturnValue::StringRes(key(dist)),
+            ])
+        };
+        assert_eq!(
+            geo_interact(within(200.0), state.clone()).await,
+            ReturnValue::Array(vec![
+                found("Catania", "56.4413"),
+                found("Palermo", "190.4424")
+            ])
+        );
+        assert_eq!(
+            geo_interact(within(100.0), state.clone()).await,
+            ReturnValue::Array(vec![found("Catania", "56.4413")])
+        );
+        let boxed = GeoShape::Box {
+            width: 400_000.0,
+            height: 400_000.0,
+        };
+        let mut by_box = query(GeoFrom::Member(key("Palermo")), boxed, GeoUnit::Kilometers);
+        by_box.order = Some(SortOrder::Desc);
+        by_box.count = Some((1, false));
+        assert_eq!(
+            geo_interact(GeoOps::GeoSearch(key("Sicily"), by_box), state.clone()).await,
+            ReturnValue::Array(vec![found("Catania", "166.2742")])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_search_across_the_antimeridian() {
+        let state = Arc::new(State::default());
+        let members = [(179.99, 0.0, "east"), (-179.99, 0.0, "west")]
+            .iter()
+            .map(|(lon, lat, name)| (GeoPoint::new(*lon, *lat).unwrap().score(), key(name)))
+            .collect();
+        let op = ZSetOps::ZAdd(key("date line"), members, AddOptions::default());
+        zset_interact(op, state.clone()).await;
+        let mut near = query(
+            GeoFrom::Member(key("east")),
+            GeoShape::Radius(10_000.0),
+            GeoUnit::Meters,
+        );
+        near.with_dist = false;
+        let store = GeoOps::GeoSearchStore(key("dest"), key("date line"), near, false);
+        assert_eq!(
+            geo_interact(store, state.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+        assert_eq!(state.zsets.get(&key("dest")).unwrap().card(), 2);
+    }
+}
@@ -0,0 +1,149 @@
+/// Redis style glob matching.
+///
+/// Supports `*`, `?`, `[abc]`, `[^abc]`, `[a-z]` and `\` escapes.
+/// Matching is done on bytes, so keys don't need to be valid utf8.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume if the current attempt after a `*` fails.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while s < string.len() {
+        if p < pattern.len() {
+            match pattern[p] {
+                b'*' => {
+                    // Collapse runs of stars.
+                    while p < pattern.len() && pattern[p] == b'*' {
+                        p += 1;
+                    }
+                    if p == pattern.len() {
+                        return true;
+                    }
+                    backtrack = Some((p, s));
+                    continue;
+                }
+                b'?' => {
+                    p += 1;
+                    s += 1;
+                    continue;
+                }
+                b'[' => match match_class(pattern, p, string[s]) {
+                    Some((true, next_p)) => {
+                        p = next_p;
+                        s += 1;
+                        continue;
+                    }
+                    Some((false, _)) => {}
+                    None if string[s] == b'[' => {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                    None => {}
+                },
+                b'\\' if p + 1 < pattern.len() => {
+                    if pattern[p + 1] == string[s] {
+                        p += 2;
+                        s += 1;
+                        continue;
+                    }
+                }
+                c => {
+                    if c == string[s] {
+                        p += 1;
+                        s += 1;
+                        continue;
+                    }
+                }
+            }
+        }
+        // Mismatch: let the last star eat one more byte, or give up.
+        match backtrack {
+            Some((star_p, star_s)) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, star_s + 1));
+            }
+            None => return false,
+        }
+    }
+    // Trailing stars match the empty string.
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Match `c` against the character class starting at `pattern[start] == b'['`.
+///
+/// Returns whether it matched, and the index after the class.
+/// An unterminated class is treated as a literal `[`.
+fn match_class(pattern: &[u8], start: usize, c: u8) -> Option<(bool, usize)> {
+    let mut i = start + 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let curr = *pattern.get(i)?;
+        if curr == b']' && !first {
+            break;
+        }
+        first = false;
+        if curr == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == c;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (mut lo, mut hi) = (curr, pattern[i + 2]);
+            if lo > hi {
+                std::mem::swap(&mut lo, &mut hi);
+            }
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= curr == c;
+            i += 1;
+        }
+    }
+    Some((matched != negate, i + 1))
+}
+
+#[cfg(test)]
+mod test_glob {
+    use crate::glob::glob_match;
+
+    #[test]
+    fn test_literals_and_wildcards() {
+        assert!(glob_match(b"foo", b"foo"));
+        assert!(!glob_match(b"foo", b"foobar"));
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"*", b"anything"));
+        assert!(glob_match(b"foo*", b"foobar"));
+        assert!(glob_match(b"*bar", b"foobar"));
+        assert!(glob_match(b"f*o*r", b"foobar"));
+        assert!(!glob_match(b"f*o*z", b"foobar"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"**a**", b"banana"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(glob_match(b"h[ae]llo", b"hello"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"h[a-b]llo", b"hbllo"));
+        assert!(!glob_match(b"h[a-b]llo", b"hcllo"));
+        assert!(glob_match(b"[z-a]", b"m"));
+        assert!(glob_match(b"[]]", b"]"));
+        // Unterminated classes are literal
+        assert!(glob_match(b"a[b", b"a[b"));
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert!(glob_match(b"h\\*llo", b"h*llo"));
+        assert!(!glob_match(b"h\\*llo", b"hello"));
+        assert!(glob_match(b"h\\?", b"h?"));
+        assert!(glob_match(b"[\\]]", b"]"));
+    }
+}
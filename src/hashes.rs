@@ -1,7 +1,7 @@
 use crate::op_variants;
 use crate::ops::RVec;
+use crate::scan::{scan_reply, ScanOptions};
 use crate::types::{Count, Key, ReturnValue, StateRef, Value};
-use std::collections::hash_map::Entry;
 
 op_variants! {
     HashOps,
@@ -17,7 +17,41 @@ op_variants! {
     HIncrBy(Key, Key, Count),
     HVals(Key),
     HStrLen(Key, Key),
-    HSetNX(Key, Key, Value)
+    HSetNX(Key, Key, Value),
+    HScan(Key, ScanOptions)
+}
+
+impl HashOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            HashOps::HGet(key, _)
+            | HashOps::HSet(key, _, _)
+            | HashOps::HExists(key, _)
+            | HashOps::HGetAll(key)
+            | HashOps::HMGet(key, _)
+            | HashOps::HKeys(key)
+            | HashOps::HMSet(key, _)
+            | HashOps::HLen(key)
+            | HashOps::HDel(key, _)
+            | HashOps::HIncrBy(key, _, _)
+            | HashOps::HVals(key)
+            | HashOps::HStrLen(key, _)
+            | HashOps::HSetNX(key, _, _)
+            | HashOps::HScan(key, _) => vec![key.clone()],
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            HashOps::HSet(..)
+                | HashOps::HMSet(..)
+                | HashOps::HDel(..)
+                | HashOps::HIncrBy(..)
+                | HashOps::HSetNX(..)
+        )
+    }
 }
 
 make_reader!(hashes, read_hashes);
@@ -54,15 +88,17 @@ pub async fn hash_interact(hash_ops: HashOps, state: StateRef) -> ReturnValue {
         //     ReturnValue::MultiStringRes(ret)
         // }
         HashOps::HGetAll(key) => match read_hashes!(state, &key) {
-            Some(hash) => {
-                let mut ret = Vec::with_capacity(hash.len() * 2);
-                for (key, value) in hash.iter() {
-                    ret.push(key.clone());
-                    ret.push(value.clone());
-                }
-                ReturnValue::MultiStringRes(ret)
-            }
-            None => ReturnValue::MultiStringRes(Vec::with_capacity(0)),
+            Some(hash) => ReturnValue::Map(
+                hash.iter()
+                    .map(|(key, value)| {
+                        (
+                            ReturnValue::StringRes(key.clone()),
+                            ReturnValue::StringRes(value.clone()),
+                        )
+                    })
+                    .collect(),
+            ),
+            None => ReturnValue::Map(Vec::with_capacity(0)),
         },
         HashOps::HMGet(key, fields) => ReturnValue::Array(match read_hashes!(state, &key) {
             None => std::iter::repeat_with(|| ReturnValue::Nil)
@@ -137,12 +173,32 @@ pub async fn hash_interact(hash_ops: HashOps, state: StateRef) -> ReturnValue {
                 .map_or(ReturnValue::Nil, |f| ReturnValue::IntRes(f.len() as Count)),
         },
         HashOps::HSetNX(key, field, value) => {
-            if let Entry::Vacant(ent) = state.hashes.entry(key).or_default().entry(field) {
-                ent.insert(value);
+            let mut hash = state.hashes.entry(key).or_default();
+            if !hash.contains_key(&field) {
+                hash.insert(field, value);
                 ReturnValue::IntRes(1)
             } else {
                 ReturnValue::IntRes(0)
             }
         }
+        HashOps::HScan(key, options) => {
+            let (cursor, pairs) = match read_hashes!(state, &key) {
+                Some(hash) => {
+                    let (cursor, pairs) = hash.scan(options.cursor, options.count);
+                    let pairs = pairs
+                        .into_iter()
+                        .map(|(field, value)| (field, value.clone()))
+                        .collect();
+                    (cursor, pairs)
+                }
+                None => (0, Vec::new()),
+            };
+            let fields = pairs
+                .into_iter()
+                .filter(|(field, _)| options.matches(field))
+                .flat_map(|(field, value)| vec![field, value])
+                .collect();
+            scan_reply(cursor, fields)
+        }
     }
 }
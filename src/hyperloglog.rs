@@ -9,6 +9,27 @@ op_variants! {
     PfMerge(Key, RVec<Key>)
 }
 
+impl HyperLogLogOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            HyperLogLogOps::PfAdd(key, _) => vec![key.clone()],
+            HyperLogLogOps::PfCount(keys) => keys.to_vec(),
+            HyperLogLogOps::PfMerge(dest, sources) => std::iter::once(dest)
+                .chain(sources.iter())
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            HyperLogLogOps::PfAdd(..) | HyperLogLogOps::PfMerge(..)
+        )
+    }
+}
+
 make_reader!(hyperloglogs, read_hyperloglogs);
 
 // Error ratio from http://antirez.com/news/75
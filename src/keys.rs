@@ -1,16 +1,230 @@
+use crate::expiry::now_millis;
 use crate::op_variants;
 use crate::ops::RVec;
-use crate::types::{Count, Key, ReturnValue, StateRef, Value};
+use crate::types::{
+    Count, Index, Key, RedisValueRef, ReturnValue, StateRef, Timestamp, Value, ValueType, WRONGTYPE,
+};
+use bytes::BytesMut;
+use dashmap::mapref::entry::Entry;
+
+pub const NOT_AN_INTEGER: &[u8] = b"ERR value is not an integer or out of range";
+pub const NOT_A_FLOAT: &[u8] = b"ERR value is not a valid float";
+const OVERFLOW: &[u8] = b"ERR increment or decrement would overflow";
+const NAN_OR_INFINITY: &[u8] = b"ERR increment would produce NaN or Infinity";
+const STRING_TOO_LONG: &[u8] = b"ERR string exceeds maximum allowed size (proto-max-bulk-len)";
+
+/// Strings can't grow past 512MB, like in redis.
+const MAX_STRING_SIZE: usize = 512 * 1024 * 1024;
+
+/// NX / XX: only set the key if it doesn't / does exist.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetCondition {
+    IfAbsent,
+    IfPresent,
+}
+
+/// What SET does to the key's time to live.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetExpiry {
+    /// EX: seconds from now.
+    Ex(Count),
+    /// PX: milliseconds from now.
+    Px(Count),
+    /// EXAT: unix time in seconds.
+    ExAt(Count),
+    /// PXAT: unix time in milliseconds.
+    PxAt(Count),
+    /// KEEPTTL: leave it as it is.
+    KeepTtl,
+}
+
+impl SetExpiry {
+    /// The new deadline, or None if it overflows or the TTL is kept.
+    fn deadline(self, now: Timestamp) -> Option<Timestamp> {
+        match self {
+            SetExpiry::Ex(secs) => secs.checked_mul(1000)?.checked_add(now),
+            SetExpiry::Px(millis) => millis.checked_add(now),
+            SetExpiry::ExAt(secs) => secs.checked_mul(1000),
+            SetExpiry::PxAt(millis) => Some(millis),
+            SetExpiry::KeepTtl => None,
+        }
+    }
+}
+
+/// The options of SET, besides the key and value.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SetOptions {
+    pub condition: Option<SetCondition>,
+    /// None discards any time to live.
+    pub expiry: Option<SetExpiry>,
+    /// GET: reply with the old value.
+    pub get: bool,
+}
+
+/// Parse an integer the way redis does: no sign other than a leading
+/// minus, no leading zeros and no whitespace.
+pub fn parse_integer(value: &[u8]) -> Option<Count> {
+    let digits = value.strip_prefix(b"-").unwrap_or(value);
+    match digits {
+        [] | [b'+', ..] => None,
+        [b'0', _, ..] => None,
+        [b'0'] if digits.len() != value.len() => None,
+        _ => std::str::from_utf8(value).ok()?.parse().ok(),
+    }
+}
+
+/// Parse a float, rejecting NaN and whitespace.
+pub fn parse_float(value: &[u8]) -> Option<f64> {
+    let value: f64 = std::str::from_utf8(value).ok()?.parse().ok()?;
+    if value.is_nan() {
+        return None;
+    }
+    Some(value)
+}
 
 op_variants! {
     KeyOps,
-    Set(Key, Value),
+    Set(Key, Value, SetOptions),
     MSet(RVec<(Key, Value)>),
     Get(Key),
     MGet(RVec<Key>),
     Del(RVec<Key>),
     Rename(Key, Key),
-    RenameNx(Key, Key)
+    RenameNx(Key, Key),
+    Type(Key),
+    IncrBy(Key, Count),
+    IncrByFloat(Key, f64),
+    Append(Key, Value),
+    GetRange(Key, Index, Index),
+    SetRange(Key, Index, Value),
+    StrLen(Key),
+    GetSet(Key, Value),
+    GetDel(Key),
+    MSetNx(RVec<(Key, Value)>),
+    SetNx(Key, Value)
+}
+
+impl KeyOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            KeyOps::Set(key, ..)
+            | KeyOps::Get(key)
+            | KeyOps::Type(key)
+            | KeyOps::IncrBy(key, _)
+            | KeyOps::IncrByFloat(key, _)
+            | KeyOps::Append(key, _)
+            | KeyOps::GetRange(key, ..)
+            | KeyOps::SetRange(key, ..)
+            | KeyOps::StrLen(key)
+            | KeyOps::GetSet(key, _)
+            | KeyOps::GetDel(key)
+            | KeyOps::SetNx(key, _) => vec![key.clone()],
+            KeyOps::MSet(key_vals) | KeyOps::MSetNx(key_vals) => {
+                key_vals.iter().map(|(key, _)| key.clone()).collect()
+            }
+            KeyOps::MGet(keys) | KeyOps::Del(keys) => keys.to_vec(),
+            KeyOps::Rename(key, new_key) | KeyOps::RenameNx(key, new_key) => {
+                vec![key.clone(), new_key.clone()]
+            }
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            KeyOps::Set(..)
+                | KeyOps::MSet(..)
+                | KeyOps::Del(..)
+                | KeyOps::Rename(..)
+                | KeyOps::RenameNx(..)
+                | KeyOps::IncrBy(..)
+                | KeyOps::IncrByFloat(..)
+                | KeyOps::Append(..)
+                | KeyOps::SetRange(..)
+                | KeyOps::GetSet(..)
+                | KeyOps::GetDel(..)
+                | KeyOps::MSetNx(..)
+                | KeyOps::SetNx(..)
+        )
+    }
+
+    /// The type the keys must hold, or None if any type will do.
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            KeyOps::Get(_)
+            | KeyOps::IncrBy(..)
+            | KeyOps::IncrByFloat(..)
+            | KeyOps::Append(..)
+            | KeyOps::GetRange(..)
+            | KeyOps::SetRange(..)
+            | KeyOps::StrLen(_)
+            | KeyOps::GetSet(..)
+            | KeyOps::GetDel(_) => Some(ValueType::String),
+            // SET overwrites any type, and MGET returns nil for non strings.
+            KeyOps::Set(..)
+            | KeyOps::MSet(..)
+            | KeyOps::MSetNx(..)
+            | KeyOps::SetNx(..)
+            | KeyOps::MGet(..)
+            | KeyOps::Del(..)
+            | KeyOps::Rename(..)
+            | KeyOps::RenameNx(..)
+            | KeyOps::Type(..) => None,
+        }
+    }
+}
+
+fn set(state: &StateRef, key: Key, value: Value, options: SetOptions) -> ReturnValue {
+    let deadline = match options.expiry {
+        None | Some(SetExpiry::KeepTtl) => None,
+        Some(expiry) => match expiry.deadline(now_millis()) {
+            Some(deadline) => Some(deadline),
+            None => return ReturnValue::Error(b"ERR invalid expire time in 'set' command"),
+        },
+    };
+    // SET replaces values of any type, but GET can only return strings.
+    let other_type = !state.kv.contains_key(&key) && state.contains_key(&key);
+    if other_type && options.get {
+        return ReturnValue::Error(WRONGTYPE);
+    }
+    if other_type && options.condition != Some(SetCondition::IfAbsent) {
+        state.remove_key(&key);
+    }
+    // The condition is checked and the value written under the entry's lock.
+    let entry = state.kv.entry(key.clone());
+    let old = match &entry {
+        Entry::Occupied(entry) => Some(entry.get().clone()),
+        Entry::Vacant(_) => None,
+    };
+    let exists = old.is_some() || other_type;
+    let allowed = match options.condition {
+        Some(SetCondition::IfAbsent) => !exists,
+        Some(SetCondition::IfPresent) => exists,
+        None => true,
+    };
+    if allowed {
+        match entry {
+            Entry::Occupied(mut entry) => {
+                entry.insert(value);
+            }
+            Entry::Vacant(entry) => {
+                entry.insert(value);
+            }
+        }
+        match (deadline, options.expiry) {
+            (Some(deadline), _) => state.expirations.set(key, deadline),
+            (None, Some(SetExpiry::KeepTtl)) => {}
+            (None, _) => {
+                state.expirations.remove(&key);
+            }
+        }
+    }
+    match (options.get, allowed) {
+        (true, _) => old.map_or(ReturnValue::Nil, ReturnValue::StringRes),
+        (false, true) => ReturnValue::Ok,
+        (false, false) => ReturnValue::Nil,
+    }
 }
 
 pub async fn key_interact(key_op: KeyOps, state: StateRef) -> ReturnValue {
@@ -28,42 +242,171 @@ pub async fn key_interact(key_op: KeyOps, state: StateRef) -> ReturnValue {
                 .collect();
             ReturnValue::Array(vals)
         }
-        KeyOps::Set(key, value) => {
-            state.kv.insert(key, value);
-            ReturnValue::Ok
-        }
+        KeyOps::Set(key, value, options) => set(&state, key, value, options),
         KeyOps::MSet(key_vals) => {
             let kv = &state.kv;
             for (key, val) in key_vals.into_iter() {
+                state.remove_key(&key);
                 kv.insert(key, val);
             }
             ReturnValue::Ok
         }
         KeyOps::Del(keys) => {
-            let deleted = keys
-                .iter()
-                .map(|x| state.kv.remove(x))
-                .filter(Option::is_some)
-                .count();
+            let deleted = keys.iter().filter(|key| state.remove_key(key)).count();
             ReturnValue::IntRes(deleted as Count)
         }
-        KeyOps::Rename(key, new_key) => match state.kv.remove(&key) {
-            Some((_, value)) => {
-                state.kv.insert(new_key, value);
+        KeyOps::Rename(key, new_key) => {
+            if state.rename_key(&key, new_key) {
                 ReturnValue::Ok
+            } else {
+                ReturnValue::Error(b"ERR no such key")
             }
-            None => ReturnValue::Error(b"no such key"),
-        },
+        }
         KeyOps::RenameNx(key, new_key) => {
-            if state.kv.contains_key(&new_key) {
+            if !state.contains_key(&key) {
+                return ReturnValue::Error(b"ERR no such key");
+            }
+            if state.contains_key(&new_key) {
                 return ReturnValue::IntRes(0);
             }
-            match state.kv.remove(&key) {
-                Some((_, value)) => {
-                    state.kv.insert(new_key, value);
+            ReturnValue::IntRes(state.rename_key(&key, new_key) as Count)
+        }
+        KeyOps::Type(key) => {
+            let name = state.value_type(&key).map_or("none", |t| t.name());
+            ReturnValue::Ident(RedisValueRef::SimpleString(Value::from_static(
+                name.as_bytes(),
+            )))
+        }
+        KeyOps::IncrBy(key, delta) => {
+            // Read and write under the entry's lock, so concurrent increments aren't lost.
+            let mut value = state
+                .kv
+                .entry(key)
+                .or_insert_with(|| Value::from_static(b"0"));
+            let current = match parse_integer(&value) {
+                Some(current) => current,
+                None => return ReturnValue::Error(NOT_AN_INTEGER),
+            };
+            match current.checked_add(delta) {
+                Some(new) => {
+                    *value = new.to_string().into();
+                    ReturnValue::IntRes(new)
+                }
+                None => ReturnValue::Error(OVERFLOW),
+            }
+        }
+        KeyOps::IncrByFloat(key, delta) => {
+            // Only create the key once the increment is known to succeed.
+            let entry = state.kv.entry(key);
+            let current = match &entry {
+                Entry::Occupied(entry) => match parse_float(entry.get()) {
+                    Some(current) => current,
+                    None => return ReturnValue::Error(NOT_A_FLOAT),
+                },
+                Entry::Vacant(_) => 0.0,
+            };
+            let new = current + delta;
+            if !new.is_finite() {
+                return ReturnValue::Error(NAN_OR_INFINITY);
+            }
+            let new = Value::from(new.to_string());
+            *entry.or_default() = new.clone();
+            ReturnValue::StringRes(new)
+        }
+        KeyOps::Append(key, suffix) => {
+            let entry = state.kv.entry(key);
+            let len = match &entry {
+                Entry::Occupied(entry) => entry.get().len(),
+                Entry::Vacant(_) => 0,
+            };
+            if len + suffix.len() > MAX_STRING_SIZE {
+                return ReturnValue::Error(STRING_TOO_LONG);
+            }
+            let mut value = entry.or_default();
+            // Reuses the buffer when nothing else holds it, so repeated
+            // appends grow it in place.
+            let mut appended = BytesMut::from(std::mem::take(&mut *value));
+            appended.extend_from_slice(&suffix);
+            *value = appended.freeze();
+            ReturnValue::IntRes(value.len() as Count)
+        }
+        KeyOps::GetRange(key, start, end) => {
+            let value = match state.kv.get(&key) {
+                Some(value) => value.clone(),
+                None => return ReturnValue::StringRes(Value::new()),
+            };
+            let len = value.len() as Index;
+            let start = if start < 0 {
+                (len + start).max(0)
+            } else {
+                start
+            };
+            let end = if end < 0 { len + end } else { end.min(len - 1) };
+            if start > end || len == 0 {
+                return ReturnValue::StringRes(Value::new());
+            }
+            ReturnValue::StringRes(value.slice(start as usize..=end as usize))
+        }
+        KeyOps::SetRange(key, offset, patch) => {
+            if offset < 0 {
+                return ReturnValue::Error(b"ERR offset is out of range");
+            }
+            let offset = offset as usize;
+            if patch.is_empty() {
+                // Nothing to write, so don't create the key.
+                return ReturnValue::IntRes(state.kv.get(&key).map_or(0, |v| v.len()) as Count);
+            }
+            if offset + patch.len() > MAX_STRING_SIZE {
+                return ReturnValue::Error(STRING_TOO_LONG);
+            }
+            let mut value = state.kv.entry(key).or_default();
+            let mut patched = value.to_vec();
+            if patched.len() < offset + patch.len() {
+                patched.resize(offset + patch.len(), 0);
+            }
+            patched[offset..offset + patch.len()].copy_from_slice(&patch);
+            *value = patched.into();
+            ReturnValue::IntRes(value.len() as Count)
+        }
+        KeyOps::StrLen(key) => {
+            ReturnValue::IntRes(state.kv.get(&key).map_or(0, |v| v.len()) as Count)
+        }
+        KeyOps::GetSet(key, value) => {
+            // Like SET, GETSET discards the time to live.
+            state.expirations.remove(&key);
+            state
+                .kv
+                .insert(key, value)
+                .map_or(ReturnValue::Nil, ReturnValue::StringRes)
+        }
+        KeyOps::GetDel(key) => match state.kv.get(&key).map(|v| v.clone()) {
+            Some(value) => {
+                state.remove_key(&key);
+                ReturnValue::StringRes(value)
+            }
+            None => ReturnValue::Nil,
+        },
+        KeyOps::MSetNx(key_vals) => {
+            // Writes to these keys wait on their key locks, so none can
+            // be set in between.
+            if key_vals.iter().any(|(key, _)| state.contains_key(key)) {
+                return ReturnValue::IntRes(0);
+            }
+            for (key, val) in key_vals.into_iter() {
+                state.kv.insert(key, val);
+            }
+            ReturnValue::IntRes(1)
+        }
+        KeyOps::SetNx(key, value) => {
+            if state.contains_key(&key) {
+                return ReturnValue::IntRes(0);
+            }
+            match state.kv.entry(key) {
+                Entry::Occupied(_) => ReturnValue::IntRes(0),
+                Entry::Vacant(entry) => {
+                    entry.insert(value);
                     ReturnValue::IntRes(1)
                 }
-                None => ReturnValue::Error(b"no such key"),
             }
         }
     }
@@ -71,8 +414,13 @@ pub async fn key_interact(key_op: KeyOps, state: StateRef) -> ReturnValue {
 
 #[cfg(test)]
 mod test_keys {
-    use crate::keys::{key_interact, KeyOps};
-    use crate::types::{ReturnValue, State};
+    use crate::keys::{
+        key_interact, parse_integer, KeyOps, SetCondition, SetExpiry, SetOptions, MAX_STRING_SIZE,
+        STRING_TOO_LONG,
+    };
+    use crate::lists::ListOps;
+    use crate::ops::{op_interact, Ops};
+    use crate::types::{RedisValueRef, ReturnValue, State, WRONGTYPE};
     use bytes::Bytes;
     use smallvec::smallvec;
     use std::sync::Arc;
@@ -86,7 +434,11 @@ mod test_keys {
             ReturnValue::Nil,
             key_interact(KeyOps::Get(v.clone()), eng.clone()).await
         );
-        key_interact(KeyOps::Set(v.clone(), v.clone()), eng.clone()).await;
+        key_interact(
+            KeyOps::Set(v.clone(), v.clone(), SetOptions::default()),
+            eng.clone(),
+        )
+        .await;
         assert_eq!(
             ReturnValue::StringRes(v.clone()),
             key_interact(KeyOps::Get(v.clone()), eng.clone()).await
@@ -97,7 +449,11 @@ mod test_keys {
     async fn test_set() {
         let (l, r) = (Bytes::from_static(b"l"), Bytes::from_static(b"r"));
         let eng = Arc::new(State::default());
-        key_interact(KeyOps::Set(l.clone(), r.clone()), eng.clone()).await;
+        key_interact(
+            KeyOps::Set(l.clone(), r.clone(), SetOptions::default()),
+            eng.clone(),
+        )
+        .await;
         assert_eq!(
             ReturnValue::StringRes(r.clone()),
             key_interact(KeyOps::Get(l.clone()), eng.clone()).await
@@ -108,7 +464,11 @@ mod test_keys {
     async fn test_del() {
         let (l, unused) = (Bytes::from_static(b"l"), Bytes::from_static(b"r"));
         let eng = Arc::new(State::default());
-        key_interact(KeyOps::Set(l.clone(), l.clone()), eng.clone()).await;
+        key_interact(
+            KeyOps::Set(l.clone(), l.clone(), SetOptions::default()),
+            eng.clone(),
+        )
+        .await;
 
         assert_eq!(
             ReturnValue::IntRes(1),
@@ -128,11 +488,313 @@ mod test_keys {
             Bytes::from_static(b"new"),
         );
         let eng = Arc::new(State::default());
-        key_interact(KeyOps::Set(old.clone(), v.clone()), eng.clone()).await;
+        key_interact(
+            KeyOps::Set(old.clone(), v.clone(), SetOptions::default()),
+            eng.clone(),
+        )
+        .await;
         key_interact(KeyOps::Rename(old.clone(), new.clone()), eng.clone()).await;
         assert_eq!(
             ReturnValue::StringRes(v.clone()),
             key_interact(KeyOps::Get(new), eng.clone()).await
         );
     }
+
+    #[tokio::test]
+    async fn test_scan_follows_writes() {
+        let (old, new) = (Bytes::from_static(b"old"), Bytes::from_static(b"new"));
+        let eng = Arc::new(State::default());
+        let set = KeyOps::Set(old.clone(), old.clone(), SetOptions::default());
+        op_interact(Ops::Keys(set), eng.clone()).await;
+        op_interact(Ops::Keys(KeyOps::Rename(old, new.clone())), eng.clone()).await;
+        assert_eq!(eng.scan_keys(0, 10), (0, vec![new.clone()]));
+        op_interact(Ops::Keys(KeyOps::Del(smallvec![new])), eng.clone()).await;
+        assert_eq!(eng.scan_keys(0, 10), (0, vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_single_keyspace() {
+        let (key, new, v) = (
+            Bytes::from_static(b"key"),
+            Bytes::from_static(b"new"),
+            Bytes::from_static(b"v"),
+        );
+        let eng = Arc::new(State::default());
+        let type_of = |key: &Bytes| {
+            let eng = eng.clone();
+            let key = key.clone();
+            async move { key_interact(KeyOps::Type(key), eng).await }
+        };
+        let simple = |s: &'static str| {
+            ReturnValue::Ident(RedisValueRef::SimpleString(Bytes::from_static(
+                s.as_bytes(),
+            )))
+        };
+        assert_eq!(type_of(&key).await, simple("none"));
+        let push = Ops::Lists(ListOps::LPush(key.clone(), smallvec![v.clone()]));
+        op_interact(push, eng.clone()).await;
+        assert_eq!(type_of(&key).await, simple("list"));
+        assert_eq!(
+            ReturnValue::Error(WRONGTYPE),
+            op_interact(Ops::Keys(KeyOps::Get(key.clone())), eng.clone()).await
+        );
+        // RENAME moves any type, DEL removes any type.
+        key_interact(KeyOps::Rename(key.clone(), new.clone()), eng.clone()).await;
+        assert_eq!(type_of(&new).await, simple("list"));
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            key_interact(KeyOps::Del(smallvec![new.clone()]), eng.clone()).await
+        );
+        assert!(!eng.contains_key(&new));
+        // SET replaces values of any type.
+        let push = Ops::Lists(ListOps::LPush(key.clone(), smallvec![v.clone()]));
+        op_interact(push, eng.clone()).await;
+        op_interact(
+            Ops::Keys(KeyOps::Set(key.clone(), v, SetOptions::default())),
+            eng.clone(),
+        )
+        .await;
+        assert_eq!(type_of(&key).await, simple("string"));
+        assert!(eng.lists.is_empty());
+    }
+
+    #[test]
+    fn test_parse_integer() {
+        assert_eq!(parse_integer(b"0"), Some(0));
+        assert_eq!(parse_integer(b"-12"), Some(-12));
+        assert_eq!(parse_integer(b"9223372036854775807"), Some(i64::MAX));
+        for invalid in [
+            &b""[..],
+            b"-",
+            b"+1",
+            b"01",
+            b"-0",
+            b" 1",
+            b"1.0",
+            b"9223372036854775808",
+        ] {
+            assert_eq!(parse_integer(invalid), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_counters() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let run = |op| key_interact(op, eng.clone());
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            run(KeyOps::IncrBy(key.clone(), 1)).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(-9),
+            run(KeyOps::IncrBy(key.clone(), -10)).await
+        );
+        let overflow = run(KeyOps::IncrBy(key.clone(), i64::MIN)).await;
+        assert_eq!(
+            ReturnValue::Error(b"ERR increment or decrement would overflow"),
+            overflow
+        );
+        assert_eq!(
+            ReturnValue::StringRes(Bytes::from_static(b"-8.5")),
+            run(KeyOps::IncrByFloat(key.clone(), 0.5)).await
+        );
+        assert_eq!(
+            ReturnValue::Error(b"ERR value is not an integer or out of range"),
+            run(KeyOps::IncrBy(key.clone(), 1)).await
+        );
+        run(KeyOps::Set(
+            key.clone(),
+            Bytes::from_static(b"abc"),
+            SetOptions::default(),
+        ))
+        .await;
+        assert_eq!(
+            ReturnValue::Error(b"ERR value is not a valid float"),
+            run(KeyOps::IncrByFloat(key.clone(), 1.0)).await
+        );
+        run(KeyOps::Set(
+            key.clone(),
+            Bytes::from_static(b"1e308"),
+            SetOptions::default(),
+        ))
+        .await;
+        assert_eq!(
+            ReturnValue::Error(b"ERR increment would produce NaN or Infinity"),
+            run(KeyOps::IncrByFloat(key.clone(), 1e308)).await
+        );
+        // A failed increment doesn't create the key.
+        let new = Bytes::from_static(b"new");
+        assert_eq!(
+            ReturnValue::Error(b"ERR increment would produce NaN or Infinity"),
+            run(KeyOps::IncrByFloat(new.clone(), f64::INFINITY)).await
+        );
+        assert!(!eng.contains_key(&new));
+    }
+
+    #[tokio::test]
+    async fn test_string_ranges() {
+        let key = Bytes::from_static(b"key");
+        let eng = Arc::new(State::default());
+        let run = |op| key_interact(op, eng.clone());
+        let string = |s: &'static str| ReturnValue::StringRes(Bytes::from_static(s.as_bytes()));
+        let append = KeyOps::Append(key.clone(), Bytes::from_static(b"Hello"));
+        assert_eq!(ReturnValue::IntRes(5), run(append).await);
+        let append = KeyOps::Append(key.clone(), Bytes::from_static(b" World"));
+        assert_eq!(ReturnValue::IntRes(11), run(append).await);
+        assert_eq!(
+            string("Hello"),
+            run(KeyOps::GetRange(key.clone(), 0, 4)).await
+        );
+        assert_eq!(
+            string("World"),
+            run(KeyOps::GetRange(key.clone(), -5, -1)).await
+        );
+        assert_eq!(
+            string("Hello World"),
+            run(KeyOps::GetRange(key.clone(), -100, 100)).await
+        );
+        assert_eq!(string(""), run(KeyOps::GetRange(key.clone(), 5, 3)).await);
+        let setrange = KeyOps::SetRange(key.clone(), 6, Bytes::from_static(b"Redis"));
+        assert_eq!(ReturnValue::IntRes(11), run(setrange).await);
+        assert_eq!(string("Hello Redis"), run(KeyOps::Get(key.clone())).await);
+        let other = Bytes::from_static(b"other");
+        let setrange = KeyOps::SetRange(other.clone(), 2, Bytes::from_static(b"x"));
+        assert_eq!(ReturnValue::IntRes(3), run(setrange).await);
+        assert_eq!(string("\0\0x"), run(KeyOps::Get(other.clone())).await);
+        assert_eq!(ReturnValue::IntRes(3), run(KeyOps::StrLen(other)).await);
+        assert_eq!(
+            string("Hello Redis"),
+            run(KeyOps::GetDel(key.clone())).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(0),
+            run(KeyOps::StrLen(key.clone())).await
+        );
+        // Too long a string isn't created.
+        let huge = Bytes::from(vec![0; MAX_STRING_SIZE + 1]);
+        assert_eq!(
+            ReturnValue::Error(STRING_TOO_LONG),
+            run(KeyOps::Append(key.clone(), huge)).await
+        );
+        assert!(!eng.contains_key(&key));
+    }
+
+    #[tokio::test]
+    async fn test_conditional_sets() {
+        let (a, b, v) = (
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+            Bytes::from_static(b"v"),
+        );
+        let eng = Arc::new(State::default());
+        let run = |op| key_interact(op, eng.clone());
+        assert_eq!(
+            ReturnValue::IntRes(1),
+            run(KeyOps::SetNx(a.clone(), v.clone())).await
+        );
+        assert_eq!(
+            ReturnValue::IntRes(0),
+            run(KeyOps::SetNx(a.clone(), b.clone())).await
+        );
+        let msetnx = KeyOps::MSetNx(smallvec![(a.clone(), b.clone()), (b.clone(), v.clone())]);
+        assert_eq!(ReturnValue::IntRes(0), run(msetnx).await);
+        assert!(!eng.kv.contains_key(&b));
+        assert_eq!(
+            ReturnValue::StringRes(v.clone()),
+            run(KeyOps::GetSet(a.clone(), b.clone())).await
+        );
+        assert_eq!(ReturnValue::Nil, run(KeyOps::GetSet(b, v)).await);
+    }
+
+    #[tokio::test]
+    async fn test_set_options() {
+        let (key, a, b) = (
+            Bytes::from_static(b"key"),
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"b"),
+        );
+        let eng = Arc::new(State::default());
+        let run = |op| key_interact(op, eng.clone());
+        let with = |condition, expiry, get| SetOptions {
+            condition,
+            expiry,
+            get,
+        };
+        let xx = with(Some(SetCondition::IfPresent), None, false);
+        assert_eq!(
+            ReturnValue::Nil,
+            run(KeyOps::Set(key.clone(), a.clone(), xx)).await
+        );
+        assert!(!eng.kv.contains_key(&key));
+        let nx = with(
+            Some(SetCondition::IfAbsent),
+            Some(SetExpiry::Px(10_000)),
+            false,
+        );
+        assert_eq!(
+            ReturnValue::Ok,
+            run(KeyOps::Set(key.clone(), a.clone(), nx)).await
+        );
+        assert!(eng.expirations.get(&key).is_some());
+        assert_eq!(
+            ReturnValue::Nil,
+            run(KeyOps::Set(key.clone(), b.clone(), nx)).await
+        );
+        // KEEPTTL keeps the deadline, a plain SET discards it.
+        let keep = with(None, Some(SetExpiry::KeepTtl), true);
+        assert_eq!(
+            ReturnValue::StringRes(a.clone()),
+            run(KeyOps::Set(key.clone(), b.clone(), keep)).await
+        );
+        assert!(eng.expirations.get(&key).is_some());
+        let get = with(None, None, true);
+        assert_eq!(
+            ReturnValue::StringRes(b.clone()),
+            run(KeyOps::Set(key.clone(), a.clone(), get)).await
+        );
+        assert!(eng.expirations.get(&key).is_none());
+        let overflow = with(None, Some(SetExpiry::Ex(i64::MAX)), false);
+        assert!(run(KeyOps::Set(key.clone(), a.clone(), overflow))
+            .await
+            .is_error());
+        // GET only works on strings, but a plain SET replaces any type.
+        eng.kv.remove(&key);
+        eng.lists.insert(key.clone(), vec![a.clone()].into());
+        assert_eq!(
+            ReturnValue::Error(WRONGTYPE),
+            run(KeyOps::Set(key.clone(), a.clone(), get)).await
+        );
+        assert_eq!(
+            ReturnValue::Nil,
+            run(KeyOps::Set(key.clone(), a.clone(), nx)).await
+        );
+        assert_eq!(
+            ReturnValue::Ok,
+            run(KeyOps::Set(key.clone(), a.clone(), xx)).await
+        );
+        assert!(eng.lists.is_empty());
+        assert_eq!(Some(a), eng.kv.get(&key).map(|v| v.clone()));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_msetnx_sets_all_or_nothing() {
+        let state = Arc::new(State::default());
+        for i in 0..1000 {
+            let key = |name: &str| Bytes::from(format!("{}_{}", name, i));
+            let value = Bytes::from_static(b"v");
+            let first = KeyOps::MSetNx(smallvec![
+                (key("a"), value.clone()),
+                (key("b"), value.clone())
+            ]);
+            let second = KeyOps::MSetNx(smallvec![(key("b"), value.clone()), (key("c"), value)]);
+            let first = tokio::spawn(op_interact(Ops::Keys(first), state.clone()));
+            let second = tokio::spawn(op_interact(Ops::Keys(second), state.clone()));
+            let first = first.await.unwrap() == ReturnValue::IntRes(1);
+            let second = second.await.unwrap() == ReturnValue::IntRes(1);
+            assert!(first != second);
+            assert_eq!(state.kv.contains_key(&key("a")), first);
+            assert_eq!(state.kv.contains_key(&key("c")), second);
+        }
+    }
 }
@@ -7,6 +7,7 @@ extern crate lazy_static;
 #[macro_use]
 extern crate slog;
 
+pub mod aof;
 pub mod asyncresp;
 pub mod database;
 pub mod logger;
@@ -15,18 +16,28 @@ pub mod startup;
 pub mod types;
 #[macro_use]
 pub mod macros;
+pub mod bitmaps;
 pub mod blocking;
 pub mod bloom;
+pub mod client;
 pub mod data_structures;
+pub mod expiry;
+pub mod geo;
+pub mod glob;
 pub mod hashes;
 pub mod hyperloglog;
 pub mod keys;
 pub mod lists;
+pub mod memory;
 pub mod misc;
+pub mod pubsub;
+pub mod scan;
 pub mod scripting;
 pub mod server;
 pub mod sets;
 pub mod sorted_sets;
 pub mod stack;
 pub mod state;
+pub mod streams;
 pub mod timeouts;
+pub mod transaction;
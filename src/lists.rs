@@ -1,13 +1,55 @@
+use crate::data_structures::quicklist::QuickList;
+use crate::data_structures::receipt_map::KeyTypes;
 use crate::ops::RVec;
-use crate::timeouts::blocking_key_timeout;
-use crate::types::{Count, Index, Key, ReturnValue, StateRef, UTimeout, Value};
+use crate::timeouts::blocking_keys_timeout;
+use crate::types::{Count, Index, Key, ReturnValue, StateRef, Value};
 use crate::{make_reader, make_writer, op_variants};
+use std::time::Duration;
+
+/// Which end of a list to push to or pop from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// Where LINSERT puts a value, relative to its pivot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertAt {
+    Before,
+    After,
+}
+
+/// The options of LPOS.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PosOptions {
+    /// Skip to the nth match, or the nth from the end if negative.
+    pub rank: Index,
+    /// Reply with up to this many positions, or all of them if zero.
+    /// None replies with a single position.
+    pub count: Option<usize>,
+    /// Compare at most this many elements, or all of them if zero.
+    pub max_len: usize,
+}
+
+impl Default for PosOptions {
+    fn default() -> Self {
+        PosOptions {
+            rank: 1,
+            count: None,
+            max_len: 0,
+        }
+    }
+}
+
+// Blocking ops wait up to their duration, or forever if it's zero.
+// None means they don't block at all, like inside transactions.
 
 op_variants! {
     ListOps,
     LPush(Key, RVec<Value>),
-    LPushX(Key, Value),
-    RPushX(Key, Value),
+    LPushX(Key, RVec<Value>),
+    RPushX(Key, RVec<Value>),
     LLen(Key),
     LPop(Key),
     RPop(Key),
@@ -17,171 +59,905 @@ op_variants! {
     LRange(Key, Index, Index),
     LTrim(Key, Index, Index),
     RPopLPush(Key, Key),
-    BLPop(Key, UTimeout),
-    BRPop(Key, UTimeout)
+    LMove(Key, Key, ListEnd, ListEnd),
+    LMPop(RVec<Key>, ListEnd, usize),
+    LRem(Key, Count, Value),
+    LPos(Key, Value, PosOptions),
+    LInsert(Key, InsertAt, Value, Value),
+    BLPop(RVec<Key>, Option<Duration>),
+    BRPop(RVec<Key>, Option<Duration>),
+    BLMove(Key, Key, ListEnd, ListEnd, Option<Duration>),
+    BLMPop(RVec<Key>, ListEnd, usize, Option<Duration>)
+}
+
+impl ListOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            ListOps::LPush(key, _)
+            | ListOps::LPushX(key, _)
+            | ListOps::RPushX(key, _)
+            | ListOps::LLen(key)
+            | ListOps::LPop(key)
+            | ListOps::RPop(key)
+            | ListOps::RPush(key, _)
+            | ListOps::LIndex(key, _)
+            | ListOps::LSet(key, _, _)
+            | ListOps::LRange(key, _, _)
+            | ListOps::LTrim(key, _, _)
+            | ListOps::LRem(key, ..)
+            | ListOps::LPos(key, ..)
+            | ListOps::LInsert(key, ..) => vec![key.clone()],
+            ListOps::BLPop(keys, _)
+            | ListOps::BRPop(keys, _)
+            | ListOps::LMPop(keys, ..)
+            | ListOps::BLMPop(keys, ..) => keys.to_vec(),
+            ListOps::RPopLPush(source, dest)
+            | ListOps::LMove(source, dest, ..)
+            | ListOps::BLMove(source, dest, ..) => {
+                vec![source.clone(), dest.clone()]
+            }
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            ListOps::LPush(..)
+                | ListOps::LPushX(..)
+                | ListOps::RPushX(..)
+                | ListOps::LPop(..)
+                | ListOps::RPop(..)
+                | ListOps::RPush(..)
+                | ListOps::LSet(..)
+                | ListOps::LTrim(..)
+                | ListOps::RPopLPush(..)
+                | ListOps::LMove(..)
+                | ListOps::LMPop(..)
+                | ListOps::LRem(..)
+                | ListOps::LInsert(..)
+                | ListOps::BLPop(..)
+                | ListOps::BRPop(..)
+                | ListOps::BLMove(..)
+                | ListOps::BLMPop(..)
+        )
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        matches!(
+            self,
+            ListOps::BLPop(_, Some(_))
+                | ListOps::BRPop(_, Some(_))
+                | ListOps::BLMove(.., Some(_))
+                | ListOps::BLMPop(.., Some(_))
+        )
+    }
+
+    /// Get the non-blocking version of this operation.
+    /// Used inside transactions, where blocking makes no sense.
+    pub fn without_blocking(self) -> ListOps {
+        match self {
+            ListOps::BLPop(keys, _) => ListOps::BLPop(keys, None),
+            ListOps::BRPop(keys, _) => ListOps::BRPop(keys, None),
+            ListOps::BLMove(source, dest, from, to, _) => {
+                ListOps::BLMove(source, dest, from, to, None)
+            }
+            ListOps::BLMPop(keys, end, count, _) => ListOps::BLMPop(keys, end, count, None),
+            op => op,
+        }
+    }
 }
 
 make_reader!(lists, read_lists);
 make_writer!(lists, write_lists);
 
+/// Lists emptied by a removal are deleted, like redis does.
+fn remove_if_empty(state: &StateRef, key: &Key) {
+    state.lists.remove_if(key, |_, list| list.is_empty());
+}
+
+fn pop(list: &mut QuickList, end: ListEnd) -> Option<Value> {
+    match end {
+        ListEnd::Left => list.pop_front(),
+        ListEnd::Right => list.pop_back(),
+    }
+}
+
+fn push(list: &mut QuickList, end: ListEnd, value: &[u8]) {
+    match end {
+        ListEnd::Left => list.push_front(value),
+        ListEnd::Right => list.push_back(value),
+    }
+}
+
+/// Resolve a possibly negative index into `len` elements.
+fn list_index(index: Index, len: usize) -> Option<usize> {
+    let index = if index < 0 {
+        len as Index + index
+    } else {
+        index
+    };
+    usize::try_from(index).ok().filter(|index| *index < len)
+}
+
+/// Resolve an inclusive range of possibly negative indices into `len`
+/// elements, clamping it like redis. None if it's empty.
+fn list_range(start: Index, end: Index, len: usize) -> Option<(usize, usize)> {
+    let len = len as Index;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 { len + end } else { end.min(len - 1) };
+    match start > end || start >= len {
+        true => None,
+        false => Some((start as usize, end as usize)),
+    }
+}
+
+/// Remove up to `count` occurrences of `value`, from the end if `count`
+/// is negative, or all of them if it's zero. Returns how many were removed.
+fn remove_value(list: &mut QuickList, value: &Value, count: Count) -> usize {
+    let limit = match count {
+        0 => usize::MAX,
+        count => count.unsigned_abs() as usize,
+    };
+    let mut matches: Vec<_> = list
+        .iter()
+        .enumerate()
+        .filter(|(_, v)| v == value)
+        .map(|(index, _)| index)
+        .collect();
+    if count < 0 {
+        matches.reverse();
+    }
+    matches.truncate(limit);
+    if matches.is_empty() {
+        return 0;
+    }
+    matches.sort_unstable();
+    let mut matches = matches.into_iter().peekable();
+    let mut index = 0;
+    list.retain(|_| {
+        let keep = matches.next_if_eq(&index).is_none();
+        index += 1;
+        keep
+    })
+}
+
+/// The positions of `value` in `list`, as LPOS finds them.
+fn positions(list: &QuickList, value: &Value, options: &PosOptions) -> Vec<usize> {
+    let max_len = match options.max_len {
+        0 => list.len(),
+        max_len => max_len,
+    };
+    let limit = match options.count {
+        Some(0) => usize::MAX,
+        Some(count) => count,
+        None => 1,
+    };
+    let skip = (options.rank.unsigned_abs() - 1) as usize;
+    let matching = |(index, v): (usize, Value)| (v == *value).then_some(index);
+    match options.rank > 0 {
+        true => list
+            .iter()
+            .enumerate()
+            .take(max_len)
+            .filter_map(matching)
+            .skip(skip)
+            .take(limit)
+            .collect(),
+        false => list
+            .iter()
+            .enumerate()
+            .rev()
+            .take(max_len)
+            .filter_map(matching)
+            .skip(skip)
+            .take(limit)
+            .collect(),
+    }
+}
+
+/// Pop up to `count` values from the first non-empty list of `keys`.
+fn pop_first(
+    state: &StateRef,
+    keys: &[Key],
+    end: ListEnd,
+    count: usize,
+) -> Option<(Key, Vec<Value>)> {
+    keys.iter().find_map(|key| {
+        let values: Vec<_> = {
+            let mut list = write_lists!(state, key)?;
+            (0..count).map_while(|_| pop(&mut list, end)).collect()
+        };
+        remove_if_empty(state, key);
+        (!values.is_empty()).then(|| (key.clone(), values))
+    })
+}
+
+/// Pop a value from `source` and push it to `dest`, which may be the same list.
+fn move_value(
+    state: &StateRef,
+    source: &Key,
+    dest: &Key,
+    from: ListEnd,
+    to: ListEnd,
+) -> Option<Value> {
+    let value = {
+        let mut source_list = write_lists!(state, source)?;
+        let value = pop(&mut source_list, from)?;
+        if source == dest {
+            push(&mut source_list, to, &value);
+            return Some(value);
+        }
+        value
+    };
+    remove_if_empty(state, source);
+    push(
+        &mut state.lists.entry(dest.clone()).or_default(),
+        to,
+        &value,
+    );
+    state.wake_list(dest);
+    Some(value)
+}
+
+/// The reply of LMPOP and BLMPOP.
+fn mpop_reply(popped: Option<(Key, Vec<Value>)>) -> Option<ReturnValue> {
+    let (key, values) = popped?;
+    Some(ReturnValue::Array(vec![
+        ReturnValue::StringRes(key),
+        ReturnValue::MultiStringRes(values),
+    ]))
+}
+
+/// Run `f` now if `wait` is None, or block on `keys` until it yields.
+async fn block_on_lists(
+    f: impl Fn() -> Option<ReturnValue> + Send + 'static,
+    state: StateRef,
+    keys: &[Key],
+    wait: Option<Duration>,
+) -> ReturnValue {
+    let wait = match wait {
+        Some(wait) => wait,
+        None => return f().unwrap_or(ReturnValue::Nil),
+    };
+    let keys = keys.iter().map(|key| KeyTypes::list(key)).collect();
+    let wait = (!wait.is_zero()).then_some(wait);
+    blocking_keys_timeout(Box::new(f), state, keys, wait).await
+}
+
+/// LPUSHX and RPUSHX: push `vals` only if the list exists.
+fn push_existing(state: &StateRef, key: &Key, vals: RVec<Value>, end: ListEnd) -> ReturnValue {
+    let mut list = match write_lists!(state, key) {
+        Some(list) => list,
+        None => return ReturnValue::IntRes(0),
+    };
+    for val in vals {
+        push(&mut list, end, &val);
+    }
+    state.wake_list(key);
+    ReturnValue::IntRes(list.len() as Count)
+}
+
+/// BLPOP and BRPOP: reply with the key popped from and the value.
+async fn block_pop(
+    state: StateRef,
+    keys: RVec<Key>,
+    end: ListEnd,
+    wait: Option<Duration>,
+) -> ReturnValue {
+    let (state_clone, keys_clone) = (state.clone(), keys.clone());
+    let bpop = move || {
+        let (key, mut values) = pop_first(&state, &keys, end, 1)?;
+        Some(ReturnValue::MultiStringRes(vec![key, values.remove(0)]))
+    };
+    block_on_lists(bpop, state_clone, &keys_clone, wait).await
+}
+
 #[allow(clippy::cognitive_complexity)]
 pub async fn list_interact(list_op: ListOps, state: StateRef) -> ReturnValue {
     match list_op {
         ListOps::LPush(key, vals) => {
             let mut list = state.lists.entry(key.clone()).or_default();
             for val in vals {
-                list.push_front(val);
+                list.push_front(&val);
             }
             state.wake_list(&key);
             ReturnValue::IntRes(list.len() as Count)
         }
-        ListOps::LPushX(key, val) => match state.lists.get_mut(&key) {
-            Some(mut list) => {
-                list.push_front(val);
-                state.wake_list(&key);
-                ReturnValue::IntRes(list.len() as Count)
-            }
-            None => ReturnValue::IntRes(0),
-        },
-        ListOps::RPushX(key, val) => match state.lists.get_mut(&key) {
-            Some(mut list) => {
-                list.push_back(val);
-                state.wake_list(&key);
-                ReturnValue::IntRes(list.len() as Count)
-            }
-            None => ReturnValue::IntRes(0),
-        },
+        ListOps::LPushX(key, vals) => push_existing(&state, &key, vals, ListEnd::Left),
+        ListOps::RPushX(key, vals) => push_existing(&state, &key, vals, ListEnd::Right),
         ListOps::LLen(key) => match read_lists!(state, &key) {
             Some(l) => ReturnValue::IntRes(l.len() as Count),
             None => ReturnValue::IntRes(0),
         },
-        ListOps::LPop(key) => match write_lists!(state, &key).and_then(|mut v| v.pop_front()) {
-            Some(v) => ReturnValue::StringRes(v),
-            None => ReturnValue::Nil,
-        },
-        ListOps::RPop(key) => match write_lists!(state, &key).and_then(|mut v| v.pop_back()) {
-            Some(v) => ReturnValue::StringRes(v),
-            None => ReturnValue::Nil,
-        },
+        ListOps::LPop(key) => {
+            let value = write_lists!(state, &key).and_then(|mut v| v.pop_front());
+            remove_if_empty(&state, &key);
+            value.map_or(ReturnValue::Nil, ReturnValue::StringRes)
+        }
+        ListOps::RPop(key) => {
+            let value = write_lists!(state, &key).and_then(|mut v| v.pop_back());
+            remove_if_empty(&state, &key);
+            value.map_or(ReturnValue::Nil, ReturnValue::StringRes)
+        }
         ListOps::RPush(key, vals) => {
-            let mut list = state.lists.entry(key).or_default();
+            let mut list = state.lists.entry(key.clone()).or_default();
             for val in vals {
-                list.push_back(val)
+                list.push_back(&val)
             }
+            state.wake_list(&key);
             ReturnValue::IntRes(list.len() as Count)
         }
-        ListOps::LIndex(key, index) => match write_lists!(state, &key) {
-            Some(list) => {
-                let llen = list.len() as i64;
-                let real_index = if index < 0 { llen + index } else { index };
-                if !(0 <= real_index && real_index < llen) {
-                    return ReturnValue::Error(b"Bad Range!");
-                }
-                let real_index = real_index as usize;
-                ReturnValue::StringRes(list[real_index].clone())
-            }
-            None => ReturnValue::Nil,
-        },
+        ListOps::LIndex(key, index) => {
+            let value =
+                read_lists!(state, &key).and_then(|list| list.get(list_index(index, list.len())?));
+            value.map_or(ReturnValue::Nil, ReturnValue::StringRes)
+        }
         ListOps::LSet(key, index, value) => match write_lists!(state, &key) {
-            Some(mut list) => {
-                let llen = list.len() as i64;
-                let real_index = if index < 0 { llen + index } else { index };
-                if !(0 <= real_index && real_index < llen) {
-                    return ReturnValue::Error(b"Bad Range!");
+            Some(mut list) => match list_index(index, list.len()) {
+                Some(index) => {
+                    list.set(index, &value);
+                    ReturnValue::Ok
                 }
-                let real_index = real_index as usize;
-                list[real_index] = value;
-                ReturnValue::Ok
-            }
-            None => ReturnValue::Error(b"No list at key!"),
+                None => ReturnValue::Error(b"ERR index out of range"),
+            },
+            None => ReturnValue::Error(b"ERR no such key"),
         },
         ListOps::LRange(key, start_index, end_index) => match read_lists!(state, &key) {
-            Some(list) => {
-                let start_index =
-                    std::cmp::max(0, if start_index < 0 { 0 } else { start_index } as usize);
-                let end_index = std::cmp::min(
-                    list.len(),
-                    if end_index < 0 {
-                        list.len() as i64 + end_index
-                    } else {
-                        end_index
-                    } as usize,
-                );
-                let mut ret = Vec::new();
-                for (index, value) in list.iter().enumerate() {
-                    if start_index <= index && index <= end_index {
-                        ret.push(value.clone());
-                    }
-                    if index > end_index {
-                        break;
-                    }
-                }
-                ReturnValue::MultiStringRes(ret)
-            }
+            Some(list) => match list_range(start_index, end_index, list.len()) {
+                Some((start, end)) => ReturnValue::MultiStringRes(
+                    list.iter().skip(start).take(end - start + 1).collect(),
+                ),
+                None => ReturnValue::MultiStringRes(vec![]),
+            },
             None => ReturnValue::MultiStringRes(vec![]),
         },
         ListOps::LTrim(key, start_index, end_index) => {
-            match write_lists!(state, &key) {
-                Some(mut list) => {
-                    let start_index =
-                        std::cmp::max(0, if start_index < 0 { 0 } else { start_index } as usize);
-                    let end_index = std::cmp::min(
-                        list.len(),
-                        if end_index < 0 {
-                            list.len() as i64 + end_index
-                        } else {
-                            end_index
-                        } as usize,
-                    ) + 1;
-                    // Deal with right side
-                    list.truncate(end_index);
-                    // Deal with left side
-                    for _ in 0..start_index {
-                        list.pop_front();
+            if let Some(mut list) = write_lists!(state, &key) {
+                match list_range(start_index, end_index, list.len()) {
+                    Some((start, end)) => {
+                        list.truncate(end + 1);
+                        list.remove_front(start);
                     }
-                    ReturnValue::Ok
+                    None => list.clear(),
                 }
-                None => ReturnValue::Ok,
             }
+            remove_if_empty(&state, &key);
+            ReturnValue::Ok
         }
-        ListOps::RPopLPush(source, dest) => match state.lists.get_mut(&source) {
-            None => ReturnValue::Nil,
-            Some(mut source_list) => match source_list.pop_back() {
-                None => ReturnValue::Nil,
-                Some(value) => {
-                    if source == dest {
-                        source_list.push_back(value.clone());
-                    } else {
-                        state
-                            .lists
-                            .entry(dest.clone())
-                            .or_default()
-                            .push_back(value.clone());
-                        state.wake_list(&dest);
-                    }
-                    ReturnValue::StringRes(value)
+        ListOps::RPopLPush(source, dest) => {
+            move_value(&state, &source, &dest, ListEnd::Right, ListEnd::Left)
+                .map_or(ReturnValue::Nil, ReturnValue::StringRes)
+        }
+        ListOps::LMove(source, dest, from, to) => move_value(&state, &source, &dest, from, to)
+            .map_or(ReturnValue::Nil, ReturnValue::StringRes),
+        ListOps::LMPop(keys, end, count) => {
+            mpop_reply(pop_first(&state, &keys, end, count)).unwrap_or(ReturnValue::Nil)
+        }
+        ListOps::LRem(key, count, value) => {
+            let removed = write_lists!(state, &key)
+                .map_or(0, |mut list| remove_value(&mut list, &value, count));
+            remove_if_empty(&state, &key);
+            ReturnValue::IntRes(removed as Count)
+        }
+        ListOps::LPos(key, value, options) => {
+            let found = read_lists!(state, &key)
+                .map(|list| positions(&list, &value, &options))
+                .unwrap_or_default();
+            let found = found.into_iter().map(|index| index as Count);
+            match options.count {
+                Some(_) => ReturnValue::Array(found.map(ReturnValue::IntRes).collect()),
+                None => found
+                    .map(ReturnValue::IntRes)
+                    .next()
+                    .unwrap_or(ReturnValue::Nil),
+            }
+        }
+        ListOps::LInsert(key, at, pivot, value) => match write_lists!(state, &key) {
+            Some(mut list) => match list.iter().position(|v| *v == pivot) {
+                Some(index) => {
+                    let index = match at {
+                        InsertAt::Before => index,
+                        InsertAt::After => index + 1,
+                    };
+                    list.insert(index, &value);
+                    state.wake_list(&key);
+                    ReturnValue::IntRes(list.len() as Count)
                 }
+                None => ReturnValue::IntRes(-1),
             },
+            None => ReturnValue::IntRes(0),
         },
-        ListOps::BLPop(key, timeout) => {
+        ListOps::BLPop(keys, wait) => block_pop(state, keys, ListEnd::Left, wait).await,
+        ListOps::BRPop(keys, wait) => block_pop(state, keys, ListEnd::Right, wait).await,
+        ListOps::BLMove(source, dest, from, to, wait) => {
             let state_clone = state.clone();
-            let key_clone = key.clone();
-            let bl = move || {
-                write_lists!(state, &key)
-                    .and_then(|mut v| v.pop_front())
-                    .map(ReturnValue::StringRes)
-            };
-            blocking_key_timeout(Box::new(bl), state_clone, key_clone, timeout).await
-        }
-        ListOps::BRPop(key, timeout) => {
-            let state_clone = state.clone();
-            let key_clone = key.clone();
-            let br = move || {
-                write_lists!(state, &key)
-                    .and_then(|mut v| v.pop_back())
-                    .map(ReturnValue::StringRes)
-            };
-            blocking_key_timeout(Box::new(br), state_clone, key_clone, timeout).await
+            let keys = [source.clone()];
+            let bmove =
+                move || move_value(&state, &source, &dest, from, to).map(ReturnValue::StringRes);
+            block_on_lists(bmove, state_clone, &keys, wait).await
+        }
+        ListOps::BLMPop(keys, end, count, wait) => {
+            let (state_clone, keys_clone) = (state.clone(), keys.clone());
+            let bmpop = move || mpop_reply(pop_first(&state, &keys, end, count));
+            block_on_lists(bmpop, state_clone, &keys_clone, wait).await
         }
     }
 }
+
+#[cfg(test)]
+mod test_lists {
+    use crate::lists::{list_interact, InsertAt, ListEnd, ListOps, PosOptions};
+    use crate::types::{ReturnValue, State, StateRef};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn push(key: &'static [u8], value: &'static [u8]) -> ListOps {
+        ListOps::LPush(
+            Bytes::from_static(key),
+            smallvec![Bytes::from_static(value)],
+        )
+    }
+
+    fn bytes(values: &[&'static [u8]]) -> Vec<Bytes> {
+        values.iter().map(|v| Bytes::from_static(v)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_pop_in_key_order() {
+        let state: StateRef = Arc::new(State::default());
+        list_interact(push(b"b", b"1"), state.clone()).await;
+        list_interact(push(b"c", b"2"), state.clone()).await;
+        let keys = smallvec![
+            Bytes::from_static(b"a"),
+            Bytes::from_static(b"c"),
+            Bytes::from_static(b"b")
+        ];
+        assert_eq!(
+            list_interact(ListOps::BLPop(keys.clone(), None), state.clone()).await,
+            ReturnValue::MultiStringRes(bytes(&[b"c", b"2"]))
+        );
+        assert_eq!(
+            list_interact(ListOps::BRPop(keys.clone(), None), state.clone()).await,
+            ReturnValue::MultiStringRes(bytes(&[b"b", b"1"]))
+        );
+        // Fractional timeouts.
+        let wait = Some(Duration::from_millis(10));
+        assert_eq!(
+            list_interact(ListOps::BLPop(keys, wait), state.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocked_on_many_keys() {
+        let state: StateRef = Arc::new(State::default());
+        let keys = smallvec![Bytes::from_static(b"a"), Bytes::from_static(b"b")];
+        // A zero timeout waits forever.
+        let pop = ListOps::BLPop(keys, Some(Duration::ZERO));
+        let popper = tokio::spawn(list_interact(pop, state.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!popper.is_finished());
+        list_interact(push(b"b", b"1"), state.clone()).await;
+        assert_eq!(
+            popper.await.unwrap(),
+            ReturnValue::MultiStringRes(bytes(&[b"b", b"1"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_move() {
+        let state: StateRef = Arc::new(State::default());
+        let (source, dest) = (Bytes::from_static(b"s"), Bytes::from_static(b"d"));
+        let bmove = ListOps::BLMove(
+            source.clone(),
+            dest.clone(),
+            ListEnd::Right,
+            ListEnd::Left,
+            Some(Duration::ZERO),
+        );
+        let mover = tokio::spawn(list_interact(bmove, state.clone()));
+        // And the move wakes clients blocked on the destination.
+        let pop = ListOps::BLPop(smallvec![dest.clone()], Some(Duration::ZERO));
+        let popper = tokio::spawn(list_interact(pop, state.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        list_interact(push(b"s", b"1"), state.clone()).await;
+        assert_eq!(
+            mover.await.unwrap(),
+            ReturnValue::StringRes(Bytes::from_static(b"1"))
+        );
+        assert_eq!(
+            popper.await.unwrap(),
+            ReturnValue::MultiStringRes(bytes(&[b"d", b"1"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blocking_mpop() {
+        let state: StateRef = Arc::new(State::default());
+        for value in [b"1", b"2", b"3"] {
+            list_interact(push(b"l", value), state.clone()).await;
+        }
+        let keys = smallvec![Bytes::from_static(b"k"), Bytes::from_static(b"l")];
+        let mpop = |count| ListOps::BLMPop(keys.clone(), ListEnd::Left, count, None);
+        assert_eq!(
+            list_interact(mpop(2), state.clone()).await,
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(Bytes::from_static(b"l")),
+                ReturnValue::MultiStringRes(bytes(&[b"3", b"2"]))
+            ])
+        );
+        assert_eq!(
+            list_interact(mpop(5), state.clone()).await,
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(Bytes::from_static(b"l")),
+                ReturnValue::MultiStringRes(bytes(&[b"1"]))
+            ])
+        );
+        assert_eq!(
+            list_interact(mpop(1), state.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fifo_wakeups() {
+        let state: StateRef = Arc::new(State::default());
+        let mut poppers = Vec::new();
+        for i in 0..10 {
+            let pop = ListOps::BLPop(smallvec![Bytes::from_static(b"l")], Some(Duration::ZERO));
+            poppers.push(tokio::spawn(list_interact(pop, state.clone())));
+            // Let each client block before the next one.
+            while state.reciept_map.lock().len() != i + 1 {
+                tokio::task::yield_now().await;
+            }
+        }
+        let values: Vec<Bytes> = (0..10).map(|i| i.to_string().into()).collect();
+        let push = ListOps::RPush(Bytes::from_static(b"l"), values.iter().cloned().collect());
+        list_interact(push, state.clone()).await;
+        for (popper, value) in poppers.into_iter().zip(values) {
+            assert_eq!(
+                popper.await.unwrap(),
+                ReturnValue::MultiStringRes(vec![Bytes::from_static(b"l"), value])
+            );
+        }
+        assert!(state.reciept_map.lock().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_pops_and_pushes() {
+        let state: StateRef = Arc::new(State::default());
+        let keys: crate::ops::RVec<Bytes> =
+            smallvec![Bytes::from_static(b"a"), Bytes::from_static(b"b")];
+        let poppers: Vec<_> = (0..100)
+            .map(|_| {
+                let pop = ListOps::BLPop(keys.clone(), Some(Duration::ZERO));
+                tokio::spawn(list_interact(pop, state.clone()))
+            })
+            .collect();
+        let pushers: Vec<_> = (0..4)
+            .map(|pusher| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for i in 0..25 {
+                        let value: Bytes = format!("{}-{}", pusher, i).into();
+                        let push = match i & 1 {
+                            0 => ListOps::LPush(Bytes::from_static(b"a"), smallvec![value]),
+                            _ => ListOps::RPush(Bytes::from_static(b"b"), smallvec![value]),
+                        };
+                        list_interact(push, state.clone()).await;
+                        tokio::task::yield_now().await;
+                    }
+                })
+            })
+            .collect();
+        for pusher in pushers {
+            pusher.await.unwrap();
+        }
+        let mut popped = Vec::new();
+        for popper in poppers {
+            let res = tokio::time::timeout(Duration::from_secs(5), popper)
+                .await
+                .expect("a client was never woken")
+                .unwrap();
+            match res {
+                ReturnValue::MultiStringRes(res) => popped.push(res[1].clone()),
+                other => panic!("unexpected reply {:?}", other),
+            }
+        }
+        popped.sort();
+        let mut pushed: Vec<Bytes> = (0..4)
+            .flat_map(|pusher| (0..25).map(move |i| format!("{}-{}", pusher, i).into()))
+            .collect();
+        pushed.sort();
+        assert_eq!(popped, pushed);
+        assert!(state.reciept_map.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_receipts_cleaned_up() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"l");
+        let pop = |wait| ListOps::BLPop(smallvec![key.clone()], Some(wait));
+        assert_eq!(
+            list_interact(pop(Duration::from_millis(10)), state.clone()).await,
+            ReturnValue::Nil
+        );
+        assert!(state.reciept_map.lock().is_empty());
+        // A client disconnecting drops its pop.
+        let popper = tokio::spawn(list_interact(pop(Duration::ZERO), state.clone()));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(state.reciept_map.lock().len(), 1);
+        popper.abort();
+        assert!(popper.await.is_err());
+        assert!(state.reciept_map.lock().is_empty());
+        list_interact(push(b"l", b"1"), state.clone()).await;
+        assert_eq!(
+            list_interact(ListOps::LLen(key.clone()), state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+    }
+
+    fn rpush(key: &'static [u8], values: &[&'static [u8]]) -> ListOps {
+        ListOps::RPush(Bytes::from_static(key), bytes(values).into_iter().collect())
+    }
+
+    async fn range(state: &StateRef, key: &'static [u8]) -> ReturnValue {
+        let op = ListOps::LRange(Bytes::from_static(key), 0, -1);
+        list_interact(op, state.clone()).await
+    }
+
+    #[tokio::test]
+    async fn test_emptied_lists_are_removed() {
+        let state: StateRef = Arc::new(State::default());
+        let key = |k: &'static [u8]| Bytes::from_static(k);
+        let a = bytes(&[b"a"]).remove(0);
+        let emptying = vec![
+            ListOps::LPop(key(b"l")),
+            ListOps::RPop(key(b"l")),
+            ListOps::LMPop(smallvec![key(b"l")], ListEnd::Left, 5),
+            ListOps::BLMPop(smallvec![key(b"l")], ListEnd::Right, 1, None),
+            ListOps::BLPop(smallvec![key(b"l")], None),
+            ListOps::LMove(key(b"l"), key(b"d"), ListEnd::Left, ListEnd::Left),
+            ListOps::BLMove(key(b"l"), key(b"d"), ListEnd::Left, ListEnd::Left, None),
+            ListOps::RPopLPush(key(b"l"), key(b"d")),
+            ListOps::LRem(key(b"l"), 0, a),
+            ListOps::LTrim(key(b"l"), 1, 0),
+        ];
+        for op in emptying {
+            list_interact(rpush(b"l", &[b"a"]), state.clone()).await;
+            let name = format!("{:?}", op);
+            list_interact(op, state.clone()).await;
+            assert!(!state.lists.contains_key(&key(b"l")), "{}", name);
+        }
+        // Moving a list's only value onto itself keeps it.
+        let rotate = ListOps::LMove(key(b"l"), key(b"l"), ListEnd::Left, ListEnd::Right);
+        list_interact(rpush(b"l", &[b"a"]), state.clone()).await;
+        list_interact(rotate, state.clone()).await;
+        assert!(state.lists.contains_key(&key(b"l")));
+    }
+
+    #[tokio::test]
+    async fn test_indices() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"l");
+        list_interact(rpush(b"l", &[b"a", b"b", b"c"]), state.clone()).await;
+        let index = |i| ListOps::LIndex(key.clone(), i);
+        assert_eq!(
+            list_interact(index(-1), state.clone()).await,
+            ReturnValue::StringRes(Bytes::from_static(b"c"))
+        );
+        assert_eq!(
+            list_interact(index(3), state.clone()).await,
+            ReturnValue::Nil
+        );
+        assert_eq!(
+            list_interact(index(-4), state.clone()).await,
+            ReturnValue::Nil
+        );
+        let lrange = |start, end| ListOps::LRange(key.clone(), start, end);
+        assert_eq!(
+            list_interact(lrange(-2, 100), state.clone()).await,
+            ReturnValue::MultiStringRes(bytes(&[b"b", b"c"]))
+        );
+        assert_eq!(
+            list_interact(lrange(-100, 0), state.clone()).await,
+            ReturnValue::MultiStringRes(bytes(&[b"a"]))
+        );
+        assert_eq!(
+            list_interact(lrange(2, 1), state.clone()).await,
+            ReturnValue::MultiStringRes(vec![])
+        );
+        let lset = |i| ListOps::LSet(key.clone(), i, Bytes::from_static(b"z"));
+        assert_eq!(
+            list_interact(lset(3), state.clone()).await,
+            ReturnValue::Error(b"ERR index out of range")
+        );
+        assert_eq!(
+            list_interact(lset(-3), state.clone()).await,
+            ReturnValue::Ok
+        );
+        list_interact(ListOps::LTrim(key.clone(), 1, -1), state.clone()).await;
+        assert_eq!(
+            range(&state, b"l").await,
+            ReturnValue::MultiStringRes(bytes(&[b"b", b"c"]))
+        );
+        list_interact(ListOps::LTrim(key.clone(), 5, 10), state.clone()).await;
+        assert_eq!(
+            range(&state, b"l").await,
+            ReturnValue::MultiStringRes(vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove_and_insert() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"l");
+        let values: &[&'static [u8]] = &[b"a", b"b", b"a", b"c", b"a"];
+        list_interact(rpush(b"l", values), state.clone()).await;
+        let lrem = |count| ListOps::LRem(key.clone(), count, Bytes::from_static(b"a"));
+        assert_eq!(
+            list_interact(lrem(-1), state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert_eq!(
+            range(&state, b"l").await,
+            ReturnValue::MultiStringRes(bytes(&[b"a", b"b", b"a", b"c"]))
+        );
+        assert_eq!(
+            list_interact(lrem(0), state.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+        let linsert = |at, pivot: &'static [u8]| {
+            ListOps::LInsert(
+                key.clone(),
+                at,
+                Bytes::from_static(pivot),
+                Bytes::from_static(b"x"),
+            )
+        };
+        assert_eq!(
+            list_interact(linsert(InsertAt::Before, b"c"), state.clone()).await,
+            ReturnValue::IntRes(3)
+        );
+        assert_eq!(
+            list_interact(linsert(InsertAt::After, b"c"), state.clone()).await,
+            ReturnValue::IntRes(4)
+        );
+        assert_eq!(
+            list_interact(linsert(InsertAt::After, b"q"), state.clone()).await,
+            ReturnValue::IntRes(-1)
+        );
+        assert_eq!(
+            range(&state, b"l").await,
+            ReturnValue::MultiStringRes(bytes(&[b"b", b"x", b"c", b"x"]))
+        );
+        let missing = ListOps::LInsert(
+            Bytes::from_static(b"missing"),
+            InsertAt::Before,
+            Bytes::from_static(b"c"),
+            Bytes::from_static(b"x"),
+        );
+        assert_eq!(
+            list_interact(missing, state.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        let pushx = ListOps::RPushX(Bytes::from_static(b"missing"), bytes(&[b"x"]).into());
+        assert_eq!(
+            list_interact(pushx, state.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        let pushx = ListOps::LPushX(key.clone(), bytes(&[b"y", b"z"]).into());
+        assert_eq!(
+            list_interact(pushx, state.clone()).await,
+            ReturnValue::IntRes(6)
+        );
+        assert_eq!(
+            range(&state, b"l").await,
+            ReturnValue::MultiStringRes(bytes(&[b"z", b"y", b"b", b"x", b"c", b"x"]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_positions() {
+        let state: StateRef = Arc::new(State::default());
+        let values: &[&'static [u8]] = &[b"a", b"b", b"c", b"1", b"2", b"3", b"c", b"c"];
+        list_interact(rpush(b"l", values), state.clone()).await;
+        let lpos =
+            |options| ListOps::LPos(Bytes::from_static(b"l"), Bytes::from_static(b"c"), options);
+        let ints = |positions: &[i64]| {
+            ReturnValue::Array(positions.iter().map(|p| ReturnValue::IntRes(*p)).collect())
+        };
+        assert_eq!(
+            list_interact(lpos(PosOptions::default()), state.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+        let options = PosOptions {
+            rank: -1,
+            ..Default::default()
+        };
+        assert_eq!(
+            list_interact(lpos(options), state.clone()).await,
+            ReturnValue::IntRes(7)
+        );
+        let options = PosOptions {
+            count: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            list_interact(lpos(options), state.clone()).await,
+            ints(&[2, 6, 7])
+        );
+        let options = PosOptions {
+            rank: -2,
+            count: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(
+            list_interact(lpos(options), state.clone()).await,
+            ints(&[6, 2])
+        );
+        let options = PosOptions {
+            count: Some(0),
+            max_len: 6,
+            ..Default::default()
+        };
+        assert_eq!(
+            list_interact(lpos(options), state.clone()).await,
+            ints(&[2])
+        );
+        let options = PosOptions {
+            rank: 4,
+            ..Default::default()
+        };
+        assert_eq!(
+            list_interact(lpos(options), state.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+
+    #[tokio::test]
+    async fn test_move_and_mpop() {
+        let state: StateRef = Arc::new(State::default());
+        let (a, b) = (Bytes::from_static(b"a"), Bytes::from_static(b"b"));
+        list_interact(rpush(b"a", &[b"1", b"2", b"3"]), state.clone()).await;
+        let lmove = ListOps::LMove(a.clone(), b.clone(), ListEnd::Left, ListEnd::Right);
+        assert_eq!(
+            list_interact(lmove, state.clone()).await,
+            ReturnValue::StringRes(Bytes::from_static(b"1"))
+        );
+        // Rotating a list onto itself.
+        let lmove = ListOps::LMove(a.clone(), a.clone(), ListEnd::Right, ListEnd::Left);
+        list_interact(lmove, state.clone()).await;
+        assert_eq!(
+            range(&state, b"a").await,
+            ReturnValue::MultiStringRes(bytes(&[b"3", b"2"]))
+        );
+        let lmove = ListOps::LMove(
+            Bytes::from_static(b"missing"),
+            a.clone(),
+            ListEnd::Left,
+            ListEnd::Left,
+        );
+        assert_eq!(list_interact(lmove, state.clone()).await, ReturnValue::Nil);
+        let keys = smallvec![Bytes::from_static(b"missing"), b.clone(), a.clone()];
+        assert_eq!(
+            list_interact(ListOps::LMPop(keys, ListEnd::Right, 5), state.clone()).await,
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(b.clone()),
+                ReturnValue::MultiStringRes(bytes(&[b"1"])),
+            ])
+        );
+        let keys = smallvec![b.clone()];
+        assert_eq!(
+            list_interact(ListOps::LMPop(keys, ListEnd::Left, 1), state.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+}
@@ -1,4 +1,6 @@
+use redis_proto::aof::fsync_aof_interval;
 use redis_proto::database::{get_dump_file, load_state, save_state_interval};
+use redis_proto::expiry::expire_keys_interval;
 use redis_proto::logger::LOGGER;
 use redis_proto::scripting::{handle_redis_cmd, ScriptingBridge, ScriptingEngine};
 use redis_proto::server::socket_listener;
@@ -19,7 +21,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let dump_file = get_dump_file(&opt);
     // 4. Load database state if it exists.
     info!(LOGGER, "Opening Datafile...");
-    let state = load_state(dump_file.clone(), &opt)?;
+    let state = load_state(dump_file.clone(), &opt).await?;
     // 5. Spawn the save-occasionally service.
     info!(LOGGER, "Starting Server...");
     if !opt.memory_only {
@@ -31,7 +33,13 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             "Database is in memory-only mode. STATE WILL NOT BE SAVED!"
         );
     }
-    // 6. Create the channels for scripting
+    if let Some(aof) = &state.aof {
+        info!(LOGGER, "Spawning append only file fsync task...");
+        tokio::spawn(fsync_aof_interval(aof.clone()));
+    }
+    // 6. Spawn the active key expiry service.
+    tokio::spawn(expire_keys_interval(state.clone()));
+    // 7. Create the channels for scripting
     let (prog_string_sx, prog_string_rx) = channel(12);
     let (cmd_result_sx, cmd_result_rx) = channel(12);
 
@@ -50,7 +58,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         scripting_bridge.clone(),
     ));
 
-    // 7. Start the server! It will start listening for connections.
+    // 8. Start the server! It will start listening for connections.
     socket_listener(state.clone(), dump_file.clone(), opt, scripting_bridge).await;
     Ok(())
 }
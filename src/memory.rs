@@ -0,0 +1,259 @@
+/// maxmemory: evicting keys once their approximate size goes over the limit.
+///
+/// Like redis, victims are picked from a small random sample of keys
+/// rather than by keeping every key ordered by the policy.
+use crate::data_structures::memory_tracker::random_u64;
+use crate::expiry::now_millis;
+use crate::types::{Index, Key, StateRef, StateStore, Timestamp};
+use std::str::FromStr;
+
+/// Keys looked at, per db, to pick each victim.
+const EVICTION_SAMPLES: usize = 5;
+
+/// Keys evicted, along with their db.
+pub type Evicted = Vec<(Index, Key)>;
+
+pub const OOM_ERROR: &[u8] = b"OOM command not allowed when used memory > 'maxmemory'.";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EvictionPolicy {
+    /// Refuse writes instead of evicting.
+    #[default]
+    NoEviction,
+    /// Evict the least recently used keys.
+    AllKeysLru,
+    /// Evict the least frequently used keys.
+    AllKeysLfu,
+    /// Evict random keys.
+    AllKeysRandom,
+    /// Evict the least recently used keys with a time to live.
+    VolatileLru,
+    /// Evict the keys closest to expiring.
+    VolatileTtl,
+}
+
+impl EvictionPolicy {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::AllKeysLfu => "allkeys-lfu",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::VolatileTtl => "volatile-ttl",
+        }
+    }
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_ref() {
+            "noeviction" => Ok(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Ok(EvictionPolicy::AllKeysLru),
+            "allkeys-lfu" => Ok(EvictionPolicy::AllKeysLfu),
+            "allkeys-random" => Ok(EvictionPolicy::AllKeysRandom),
+            "volatile-lru" => Ok(EvictionPolicy::VolatileLru),
+            "volatile-ttl" => Ok(EvictionPolicy::VolatileTtl),
+            _ => Err(format!(
+                "Unknown maxmemory policy {}, expected noeviction, allkeys-lru, \
+                 allkeys-lfu, allkeys-random, volatile-lru or volatile-ttl",
+                s
+            )),
+        }
+    }
+}
+
+/// Parse a memory size like redis does: "100", "1k" (1000), "1kb" (1024),
+/// "10mb", "2gb"...
+pub fn parse_memory(s: &str) -> Result<usize, String> {
+    let lower = s.trim().to_lowercase();
+    let split = lower
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(lower.len());
+    let (number, unit) = lower.split_at(split);
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(format!("Invalid memory size {}", s)),
+    };
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(|| format!("Invalid memory size {}", s))
+}
+
+/// Pick the key to evict next, from a sample of every db.
+fn pick_victim(state_store: &StateStore) -> Option<(Index, StateRef, Key)> {
+    let now = now_millis();
+    let states: Vec<(Index, StateRef)> = state_store
+        .states
+        .iter()
+        .map(|ent| (*ent.key(), ent.value().clone()))
+        .collect();
+    // The candidate with the lowest score is evicted.
+    let mut best: Option<((Timestamp, Timestamp), Index, StateRef, Key)> = None;
+    for (index, state) in states {
+        let policy = state_store.maxmemory_policy;
+        let candidates: Vec<(Key, (Timestamp, Timestamp))> = match policy {
+            EvictionPolicy::NoEviction => return None,
+            EvictionPolicy::AllKeysRandom => state
+                .memory
+                .sample(1)
+                .into_iter()
+                .map(|(key, _)| (key, ((random_u64() >> 1) as Timestamp, 0)))
+                .collect(),
+            EvictionPolicy::AllKeysLru => state
+                .memory
+                .sample(EVICTION_SAMPLES)
+                .into_iter()
+                .map(|(key, usage)| (key, (usage.last_access, 0)))
+                .collect(),
+            EvictionPolicy::AllKeysLfu => state
+                .memory
+                .sample(EVICTION_SAMPLES)
+                .into_iter()
+                .map(|(key, usage)| {
+                    let frequency = usage.frequency_at(now) as Timestamp;
+                    (key, (frequency, usage.last_access))
+                })
+                .collect(),
+            EvictionPolicy::VolatileLru => state
+                .expirations
+                .sample(EVICTION_SAMPLES)
+                .into_iter()
+                .filter_map(|key| {
+                    let usage = state.memory.get(&key)?;
+                    Some((key, (usage.last_access, 0)))
+                })
+                .collect(),
+            EvictionPolicy::VolatileTtl => state
+                .expirations
+                .due(Timestamp::MAX, EVICTION_SAMPLES)
+                .into_iter()
+                .filter_map(|key| {
+                    let deadline = state.expirations.get(&key)?;
+                    Some((key, (deadline, 0)))
+                })
+                .collect(),
+        };
+        for (key, score) in candidates {
+            match &best {
+                Some((best_score, ..)) if *best_score <= score => {}
+                _ => best = Some((score, index, state.clone(), key)),
+            }
+        }
+    }
+    best.map(|(_, index, state, key)| (index, state, key))
+}
+
+/// Evict keys until memory use is back under maxmemory.
+///
+/// Returns the evicted keys. They're an error if not enough memory could
+/// be freed and writes must be refused, but they're gone all the same.
+pub fn evict_keys(state_store: &StateStore) -> Result<Evicted, Evicted> {
+    let mut evicted = Vec::new();
+    if state_store.maxmemory == 0 {
+        return Ok(evicted);
+    }
+    while state_store.used_memory() > state_store.maxmemory {
+        let (index, state, key) = match pick_victim(state_store) {
+            Some(victim) => victim,
+            None => return Err(evicted),
+        };
+        state.remove_key(&key);
+        state.watches.touch(&key);
+        evicted.push((index, key));
+    }
+    Ok(evicted)
+}
+
+#[cfg(test)]
+mod test_memory {
+    use crate::memory::{evict_keys, parse_memory, EvictionPolicy};
+    use crate::types::StateStore;
+    use bytes::Bytes;
+
+    fn fill(state_store: &StateStore, keys: usize) {
+        let state = state_store.get_default();
+        for i in 0..keys {
+            let key = Bytes::from(format!("key_{}", i));
+            state.kv.insert(key.clone(), Bytes::from(vec![0; 100]));
+            state.track_memory(&[key], true);
+        }
+    }
+
+    #[test]
+    fn test_parse_memory() {
+        assert_eq!(parse_memory("100"), Ok(100));
+        assert_eq!(parse_memory("1k"), Ok(1000));
+        assert_eq!(parse_memory("1KB"), Ok(1024));
+        assert_eq!(parse_memory("2mb"), Ok(2 * 1024 * 1024));
+        assert!(parse_memory("1tb").is_err());
+        assert!(parse_memory("mb").is_err());
+    }
+
+    #[test]
+    fn test_untracked_without_maxmemory() {
+        let state_store = StateStore::default();
+        fill(&state_store, 20);
+        assert_eq!(state_store.used_memory(), 0);
+        assert_eq!(evict_keys(&state_store), Ok(vec![]));
+    }
+
+    #[test]
+    fn test_noeviction() {
+        let state_store = StateStore {
+            maxmemory: 1000,
+            ..Default::default()
+        };
+        fill(&state_store, 20);
+        assert!(state_store.used_memory() > 1000);
+        assert_eq!(evict_keys(&state_store), Err(vec![]));
+        assert_eq!(state_store.get_default().kv.len(), 20);
+    }
+
+    #[test]
+    fn test_evicts_under_limit() {
+        for policy in ["allkeys-lru", "allkeys-lfu", "allkeys-random"] {
+            let state_store = StateStore {
+                maxmemory: 1000,
+                maxmemory_policy: policy.parse().unwrap(),
+                ..Default::default()
+            };
+            fill(&state_store, 20);
+            let evicted = evict_keys(&state_store).unwrap();
+            assert!(!evicted.is_empty());
+            assert!(state_store.used_memory() <= 1000);
+            assert_eq!(state_store.get_default().kv.len(), 20 - evicted.len());
+        }
+    }
+
+    #[test]
+    fn test_volatile_only_evicts_expiring_keys() {
+        let state_store = StateStore {
+            maxmemory: 1000,
+            maxmemory_policy: EvictionPolicy::VolatileTtl,
+            ..Default::default()
+        };
+        fill(&state_store, 20);
+        let state = state_store.get_default();
+        state
+            .expirations
+            .set(Bytes::from_static(b"key_3"), i64::MAX);
+        // Evicting the one volatile key isn't enough.
+        assert_eq!(
+            evict_keys(&state_store),
+            Err(vec![(0, Bytes::from_static(b"key_3"))])
+        );
+        assert!(!state.kv.contains_key(&b"key_3"[..]));
+        assert_eq!(state.kv.len(), 19);
+    }
+}
@@ -1,11 +1,20 @@
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+use crate::aof::rewrite;
+use crate::database::{bgsave, save_state, SaveError};
+use crate::glob::glob_match;
+use crate::logger::LOGGER;
+use crate::scan::{scan_reply, ScanOptions};
 use crate::scripting::{Program, ScriptingBridge};
-use crate::types::{Count, Index, Key, RedisValueRef, ReturnValue, StateRef, StateStoreRef, Value};
+use crate::types::{
+    Count, DumpFile, Index, Key, RedisValueRef, ReturnValue, StateRef, StateStoreRef, Value,
+};
 
 op_variants! {
     MiscOps,
-    Keys(), // TODO: Add optional glob
+    Keys(Key),
+    Scan(ScanOptions),
     Exists(Vec<Key>),
     Pong(),
     FlushAll(),
@@ -16,7 +25,17 @@ op_variants! {
     Select(Index),
     Script(Value),
     EmbeddedScript(Value, Vec<RedisValueRef>),
-    Info()
+    Info(),
+    Multi(),
+    Exec(),
+    Discard(),
+    Watch(Vec<Key>),
+    Unwatch(),
+    Hello(Option<Count>),
+    BgRewriteAof(),
+    Save(),
+    BgSave(),
+    LastSave()
 }
 
 macro_rules! create_commands_list {
@@ -32,19 +51,6 @@ macro_rules! create_commands_list {
     };
 }
 
-/// Easily get all keys out of each passed type.
-macro_rules! get_all_keys {
-    ($state:expr, $($type:ident),*) => {
-        {
-            let mut all = Vec::new();
-            $(
-                all.extend($state.$type.iter().map(|r| r.key().clone()));
-            )*
-            all
-        }
-    }
-}
-
 lazy_static! {
     static ref ALL_COMMANDS: ReturnValue = {
         use crate::keys::OP_VARIANTS as KEY_VARIANTS;
@@ -55,6 +61,9 @@ lazy_static! {
         use crate::bloom::OP_VARIANTS as BLOOM_VARIANTS;
         use crate::stack::OP_VARIANTS as STACK_VARIANTS;
         use crate::hyperloglog::OP_VARIANTS as HYPERLOGLOG_VARIANTS;
+        use crate::bitmaps::OP_VARIANTS as BITMAP_VARIANTS;
+        use crate::geo::OP_VARIANTS as GEO_VARIANTS;
+        use crate::streams::OP_VARIANTS as STREAM_VARIANTS;
         create_commands_list!(
             KEY_VARIANTS,
             LIST_VARIANTS,
@@ -64,6 +73,9 @@ lazy_static! {
             BLOOM_VARIANTS,
             STACK_VARIANTS,
             HYPERLOGLOG_VARIANTS,
+            BITMAP_VARIANTS,
+            GEO_VARIANTS,
+            STREAM_VARIANTS,
             OP_VARIANTS // Misc variants
         )
     };
@@ -73,22 +85,13 @@ pub async fn misc_interact(
     misc_op: MiscOps,
     state: &mut StateRef,
     state_store: StateStoreRef,
+    dump_file: DumpFile,
     scripting_bridge: Arc<ScriptingBridge>,
 ) -> ReturnValue {
     match misc_op {
         MiscOps::Pong() => ReturnValue::StringRes(Value::from_static(b"PONG")),
         MiscOps::FlushAll() => {
-            let clear = |state: &StateRef| {
-                state.kv.clear();
-                state.sets.clear();
-                state.lists.clear();
-                state.hashes.clear();
-                state.zsets.clear();
-                state.blooms.clear();
-            };
-            for state in state_store.states.iter_mut() {
-                clear(&state);
-            }
+            state_store.flush_all();
             // let state_guard = state_store.states.lock();
             // for state in state_guard.values() {
             //     clear(state);
@@ -96,19 +99,26 @@ pub async fn misc_interact(
             ReturnValue::Ok
         }
         MiscOps::FlushDB() => {
-            *state = Default::default();
+            state.clear();
             ReturnValue::Ok
         }
         MiscOps::Exists(keys) => ReturnValue::IntRes(
             keys.iter()
-                .map(|key| state.kv.contains_key(key))
+                .map(|key| !state.expire_if_needed(key) && state.contains_key(key))
                 .filter(|exists| *exists)
                 .count() as Count,
         ),
-        MiscOps::Keys() => {
-            let kv_keys = get_all_keys!(state, kv, sets, lists, hashes, zsets, blooms);
+        MiscOps::Keys(pattern) => {
+            let mut kv_keys = state.all_keys();
+            kv_keys.retain(|key| glob_match(&pattern, key) && !state.expire_if_needed(key));
             ReturnValue::MultiStringRes(kv_keys)
         }
+        MiscOps::Scan(options) => {
+            // Unlocked before filtering, which may expire keys.
+            let (cursor, mut keys) = state.scan_keys(options.cursor, options.count);
+            keys.retain(|key| options.matches_key(state, key));
+            scan_reply(cursor, keys)
+        }
         MiscOps::PrintCmds() => (*ALL_COMMANDS).clone(),
         MiscOps::Select(index) => {
             let state_store = state_store.get_or_create(index);
@@ -120,6 +130,9 @@ pub async fn misc_interact(
             let info: String = [
                 concat!("redis_version", ":", env!("CARGO_PKG_VERSION")),
                 "arch_bits:64",
+                &format!("used_memory:{}", state_store.used_memory()),
+                &format!("maxmemory:{}", state_store.maxmemory),
+                &format!("maxmemory_policy:{}", state_store.maxmemory_policy.name()),
             ]
             .join("\r\n");
             ReturnValue::StringRes(info.into())
@@ -140,5 +153,47 @@ pub async fn misc_interact(
                 .await;
             ReturnValue::Ident(res)
         }
+        MiscOps::Multi()
+        | MiscOps::Exec()
+        | MiscOps::Discard()
+        | MiscOps::Watch(_)
+        | MiscOps::Unwatch() => unreachable!("transactions are handled by the server"),
+        MiscOps::Hello(_) => unreachable!("HELLO is handled by the server"),
+        MiscOps::Save() | MiscOps::BgSave() if state_store.memory_only => {
+            ReturnValue::Error(b"ERR saving is disabled in memory-only mode")
+        }
+        MiscOps::Save() => match save_state(state_store, dump_file) {
+            Ok(()) => ReturnValue::Ok,
+            Err(SaveError::InProgress) => {
+                ReturnValue::Error(b"ERR Background save already in progress")
+            }
+            Err(SaveError::Failed(_)) => {
+                ReturnValue::Error(b"ERR failed to save, check the server logs")
+            }
+        },
+        MiscOps::BgSave() => {
+            let status: &'static [u8] = match bgsave(state_store, dump_file) {
+                Ok(()) => b"Background saving started",
+                Err(_) => b"Background save already in progress",
+            };
+            ReturnValue::Ident(RedisValueRef::SimpleString(Value::from_static(status)))
+        }
+        MiscOps::LastSave() => ReturnValue::IntRes(state_store.last_save.load(Ordering::SeqCst)),
+        MiscOps::BgRewriteAof() => match &state_store.aof {
+            None => ReturnValue::Error(b"ERR append only file is not enabled"),
+            Some(aof) if aof.is_rewriting() => {
+                ReturnValue::Error(b"ERR Background append only file rewriting already in progress")
+            }
+            Some(_) => {
+                tokio::spawn(async move {
+                    if let Err(e) = rewrite(state_store).await {
+                        error!(LOGGER, "Failed to rewrite the append only file! {:?}", e);
+                    }
+                });
+                ReturnValue::Ident(RedisValueRef::SimpleString(Value::from_static(
+                    b"Background append only file rewriting started",
+                )))
+            }
+        },
     }
 }
@@ -1,19 +1,38 @@
 use bytes::Bytes;
 use std::convert::TryFrom;
 use std::fmt::Debug;
+use std::time::Duration;
 
+use crate::bitmaps::{
+    bitmap_interact, BitOperation, BitRange, BitmapOps, FieldOp, FieldType, Overflow, RangeUnit,
+    MAX_BIT_OFFSET,
+};
 use crate::bloom::{bloom_interact, BloomOps};
+use crate::data_structures::sorted_set::{
+    AddCondition, AddOptions, LexBound, LexRange, ScoreBound, ScoreRange, UpdateCondition,
+};
+use crate::data_structures::stream::{
+    ClaimOptions, NewId, StreamId, Trim, TrimStrategy, DEFAULT_TRIM_LIMIT,
+};
+use crate::expiry::{expiry_interact, ExpiryOps};
+use crate::geo::{geo_interact, GeoFrom, GeoOps, GeoPoint, GeoQuery, GeoShape, GeoUnit, SortOrder};
 use crate::hashes::{hash_interact, HashOps};
 use crate::hyperloglog::{hyperloglog_interact, HyperLogLogOps};
-use crate::keys::{key_interact, KeyOps};
-use crate::lists::{list_interact, ListOps};
+use crate::keys::{
+    key_interact, parse_float, parse_integer, KeyOps, SetCondition, SetExpiry, SetOptions,
+    NOT_AN_INTEGER, NOT_A_FLOAT,
+};
+use crate::lists::{list_interact, InsertAt, ListEnd, ListOps, PosOptions};
 use crate::misc::MiscOps;
+use crate::pubsub::PubSubOps;
+use crate::scan::{ScanOptions, DEFAULT_SCAN_COUNT};
 use crate::sets::{set_interact, SetOps};
-use crate::sorted_sets::{zset_interact, ZSetOps};
+use crate::sorted_sets::{zset_interact, Aggregate, Combination, Limit, ZSetOps};
 use crate::stack::{stack_interact, StackOps};
-use crate::types::{ReturnValue, StateRef, StateStoreRef};
+use crate::streams::{stream_interact, GroupReadFrom, PendingRange, ReadFrom, StreamOps};
+use crate::types::{ReturnValue, StateRef, StateStoreRef, ValueType, WRONGTYPE};
 
-use crate::types::{Count, Index, Key, RedisValueRef, Score, UTimeout, Value};
+use crate::types::{Count, Index, Key, RedisValueRef, Score, Value};
 
 #[derive(Debug, Clone)]
 pub enum Ops {
@@ -26,22 +45,165 @@ pub enum Ops {
     Stacks(StackOps),
     Blooms(BloomOps),
     HyperLogLogs(HyperLogLogOps),
+    Bitmaps(BitmapOps),
+    Geo(GeoOps),
+    Streams(StreamOps),
+    Expiry(ExpiryOps),
+    PubSub(PubSubOps),
+}
+
+impl Ops {
+    /// The keys touched by this operation.
+    ///
+    /// Misc and pub/sub operations are handled by the server and report no keys.
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            Ops::Keys(op) => op.keys(),
+            Ops::Sets(op) => op.keys(),
+            Ops::Lists(op) => op.keys(),
+            Ops::Hashes(op) => op.keys(),
+            Ops::ZSets(op) => op.keys(),
+            Ops::Stacks(op) => op.keys(),
+            Ops::Blooms(op) => op.keys(),
+            Ops::HyperLogLogs(op) => op.keys(),
+            Ops::Bitmaps(op) => op.keys(),
+            Ops::Geo(op) => op.keys(),
+            Ops::Streams(op) => op.keys(),
+            Ops::Expiry(op) => op.keys(),
+            Ops::Misc(_) | Ops::PubSub(_) => Vec::new(),
+        }
+    }
+
+    /// Whether this operation may modify the keys it touches.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Ops::Keys(op) => op.is_write(),
+            Ops::Sets(op) => op.is_write(),
+            Ops::Lists(op) => op.is_write(),
+            Ops::Hashes(op) => op.is_write(),
+            Ops::ZSets(op) => op.is_write(),
+            Ops::Stacks(op) => op.is_write(),
+            Ops::Blooms(op) => op.is_write(),
+            Ops::HyperLogLogs(op) => op.is_write(),
+            Ops::Bitmaps(op) => op.is_write(),
+            Ops::Geo(op) => op.is_write(),
+            Ops::Streams(op) => op.is_write(),
+            Ops::Expiry(op) => op.is_write(),
+            Ops::Misc(_) | Ops::PubSub(_) => false,
+        }
+    }
+
+    /// The type every key of this operation must hold, or None if any
+    /// type will do. Keys of another type fail with WRONGTYPE.
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            Ops::Keys(op) => op.value_type(),
+            Ops::Sets(op) => op.value_type(),
+            Ops::Lists(_) => Some(ValueType::List),
+            Ops::Hashes(_) => Some(ValueType::Hash),
+            Ops::ZSets(op) => op.value_type(),
+            Ops::Stacks(_) => Some(ValueType::Stack),
+            Ops::Blooms(_) => Some(ValueType::Bloom),
+            Ops::HyperLogLogs(_) => Some(ValueType::HyperLogLog),
+            Ops::Bitmaps(op) => op.value_type(),
+            Ops::Geo(op) => op.value_type(),
+            Ops::Streams(_) => Some(ValueType::Stream),
+            Ops::Expiry(_) | Ops::Misc(_) | Ops::PubSub(_) => None,
+        }
+    }
+
+    /// Whether this operation may use more memory. These are refused
+    /// once over maxmemory if nothing can be evicted.
+    pub fn may_grow(&self) -> bool {
+        match self {
+            Ops::Keys(KeyOps::Del(_)) | Ops::Expiry(_) => false,
+            op => op.is_write() && !op.is_blocking(),
+        }
+    }
+
+    /// Whether this operation keeps state in the client: transactions,
+    /// watches, subscriptions and the protocol.
+    pub fn uses_client_state(&self) -> bool {
+        match self {
+            Ops::Misc(op) => matches!(
+                op,
+                MiscOps::Multi()
+                    | MiscOps::Exec()
+                    | MiscOps::Discard()
+                    | MiscOps::Watch(_)
+                    | MiscOps::Unwatch()
+                    | MiscOps::Hello(_)
+            ),
+            Ops::PubSub(op) => op.is_subscription(),
+            _ => false,
+        }
+    }
+
+    /// Whether this operation can wait on other clients.
+    pub fn is_blocking(&self) -> bool {
+        match self {
+            Ops::Lists(op) => op.is_blocking(),
+            Ops::Streams(op) => op.is_blocking(),
+            _ => false,
+        }
+    }
+
+    /// Get the non-blocking version of this operation.
+    pub fn without_blocking(self) -> Ops {
+        match self {
+            Ops::Lists(op) => Ops::Lists(op.without_blocking()),
+            Ops::Streams(op) => Ops::Streams(op.without_blocking()),
+            op => op,
+        }
+    }
 }
 
 /// Top level interaction function. Used by the server to run
 /// operations against state.
 pub async fn op_interact(op: Ops, state: StateRef) -> ReturnValue {
-    match op {
-        Ops::Keys(op) => key_interact(op, state).await,
-        Ops::Sets(op) => set_interact(op, state).await,
-        Ops::Lists(op) => list_interact(op, state).await,
-        Ops::Hashes(op) => hash_interact(op, state).await,
-        Ops::ZSets(op) => zset_interact(op, state).await,
-        Ops::Stacks(op) => stack_interact(op, state).await,
-        Ops::Blooms(op) => bloom_interact(op, state).await,
-        Ops::HyperLogLogs(op) => hyperloglog_interact(op, state).await,
+    // Lazily expire every key the operation is about to touch.
+    let keys = op.keys();
+    for key in keys.iter() {
+        state.expire_if_needed(key);
+    }
+    // Hold the keys until written, so no other write can create one of
+    // them as another type in between. Blocking ops wait on other
+    // clients' writes, so they can't.
+    let is_write = op.is_write();
+    let _locked = if is_write && !op.is_blocking() {
+        Some(state.key_locks.lock(&keys).await)
+    } else {
+        None
+    };
+    if let Some(value_type) = op.value_type() {
+        let wrong_type = |key: &Key| state.value_type(key).is_some_and(|t| t != value_type);
+        if keys.iter().any(wrong_type) {
+            return ReturnValue::Error(WRONGTYPE);
+        }
+    }
+    let res = match op {
+        Ops::Keys(op) => key_interact(op, state.clone()).await,
+        Ops::Sets(op) => set_interact(op, state.clone()).await,
+        Ops::Lists(op) => list_interact(op, state.clone()).await,
+        Ops::Hashes(op) => hash_interact(op, state.clone()).await,
+        Ops::ZSets(op) => zset_interact(op, state.clone()).await,
+        Ops::Stacks(op) => stack_interact(op, state.clone()).await,
+        Ops::Blooms(op) => bloom_interact(op, state.clone()).await,
+        Ops::HyperLogLogs(op) => hyperloglog_interact(op, state.clone()).await,
+        Ops::Bitmaps(op) => bitmap_interact(op, state.clone()).await,
+        Ops::Geo(op) => geo_interact(op, state.clone()).await,
+        Ops::Streams(op) => stream_interact(op, state.clone()).await,
+        Ops::Expiry(op) => expiry_interact(op, state.clone()).await,
         _ => unreachable!(),
+    };
+    // Invalidate WATCHes on the modified keys.
+    if is_write {
+        for key in keys.iter() {
+            state.watches.touch(key);
+        }
     }
+    state.track_memory(&keys, is_write);
+    res
 }
 
 #[derive(Debug)]
@@ -144,6 +306,14 @@ impl TryFrom<&RedisValueRef> for Count {
     }
 }
 
+impl TryFrom<&RedisValueRef> for Score {
+    type Error = OpsError;
+
+    fn try_from(r: &RedisValueRef) -> Result<Score, Self::Error> {
+        get_float(r)
+    }
+}
+
 /// Ensure the passed collection has an even number of arguments.
 #[inline]
 fn ensure_even<T>(v: &[T]) -> Result<(), OpsError> {
@@ -192,6 +362,1105 @@ fn verify_size_lower<T>(v: &[T], min_size: usize) -> Result<(), OpsError> {
     Ok(())
 }
 
+/// Parse an integer argument as strictly as redis does.
+fn get_integer(arg: &RedisValueRef) -> Result<Count, OpsError> {
+    match arg {
+        RedisValueRef::Int(i) => Ok(*i),
+        arg => parse_integer(&Value::try_from(arg)?).ok_or_else(|| {
+            OpsError::InvalidArgs(String::from_utf8_lossy(NOT_AN_INTEGER).to_string())
+        }),
+    }
+}
+
+/// Parse a float argument, rejecting NaN.
+fn get_float(arg: &RedisValueRef) -> Result<f64, OpsError> {
+    match arg {
+        RedisValueRef::Int(i) => Ok(*i as f64),
+        RedisValueRef::Double(d) if !d.is_nan() => Ok(*d),
+        arg => parse_float(&Value::try_from(arg)?)
+            .ok_or_else(|| OpsError::InvalidArgs(String::from_utf8_lossy(NOT_A_FLOAT).to_string())),
+    }
+}
+
+/// Parse a ZRANGEBYSCORE style bound: a score, "(" followed by a score
+/// to exclude it, "-inf" or "+inf".
+fn get_score_bound(arg: &RedisValueRef) -> Result<ScoreBound, OpsError> {
+    let not_a_float = || OpsError::InvalidArgs("ERR min or max is not a float".to_string());
+    let arg = Value::try_from(arg)?;
+    let (exclusive, score) = match arg.strip_prefix(b"(") {
+        Some(score) => (true, score),
+        None => (false, &arg[..]),
+    };
+    let score = parse_float(score).ok_or_else(not_a_float)?;
+    Ok(ScoreBound { score, exclusive })
+}
+
+fn get_score_range(min: &RedisValueRef, max: &RedisValueRef) -> Result<ScoreRange, OpsError> {
+    Ok(ScoreRange {
+        min: get_score_bound(min)?,
+        max: get_score_bound(max)?,
+    })
+}
+
+/// Parse a ZRANGEBYLEX style bound: "-", "+", "[member" or "(member".
+fn get_lex_bound(arg: &RedisValueRef) -> Result<LexBound, OpsError> {
+    let arg = Value::try_from(arg)?;
+    match arg.first() {
+        Some(b'-') if arg.len() == 1 => Ok(LexBound::NegInf),
+        Some(b'+') if arg.len() == 1 => Ok(LexBound::PosInf),
+        Some(b'[') => Ok(LexBound::Inclusive(arg.slice(1..))),
+        Some(b'(') => Ok(LexBound::Exclusive(arg.slice(1..))),
+        _ => Err(OpsError::InvalidArgs(
+            "ERR min or max not valid string range item".to_string(),
+        )),
+    }
+}
+
+fn get_lex_range(min: &RedisValueRef, max: &RedisValueRef) -> Result<LexRange, OpsError> {
+    Ok(LexRange {
+        min: get_lex_bound(min)?,
+        max: get_lex_bound(max)?,
+    })
+}
+
+/// Parse the `[LIMIT offset count]` of ZRANGEBYLEX, which has no WITHSCORES.
+fn get_lex_limit(args: &[&RedisValueRef]) -> Result<Option<Limit>, OpsError> {
+    match get_zrange_options(args, true)? {
+        (limit, false) => Ok(limit),
+        (_, true) => Err(OpsError::SyntaxError),
+    }
+}
+
+/// Parse `numkeys key [key ...] [WEIGHTS weight ...] [AGGREGATE SUM | MIN | MAX] [WITHSCORES]`
+/// for the `command` ZUNION, ZINTER or ZDIFF. ZDIFF takes no WEIGHTS or
+/// AGGREGATE, and the STORE variants take no WITHSCORES.
+fn get_combination(
+    command: &str,
+    args: &[&RedisValueRef],
+    allow_weights: bool,
+    allow_with_scores: bool,
+) -> Result<(Combination, bool), OpsError> {
+    verify_size_lower(args, 2)?;
+    let num_keys = get_integer(args[0])?;
+    if num_keys < 1 {
+        return Err(OpsError::InvalidArgs(format!(
+            "ERR at least 1 input key is needed for '{}' command",
+            command
+        )));
+    }
+    let num_keys = num_keys as usize;
+    if num_keys > args.len() - 1 {
+        return Err(OpsError::SyntaxError);
+    }
+    let mut combination = Combination {
+        keys: collect_from_tail(&args[1..=num_keys])?,
+        weights: RVec::new(),
+        aggregate: Aggregate::Sum,
+    };
+    let mut with_scores = false;
+    let mut args = args[num_keys + 1..].iter();
+    while let Some(arg) = args.next() {
+        match String::try_from(*arg)?.to_lowercase().as_ref() {
+            "weights" if allow_weights => {
+                for _ in 0..num_keys {
+                    let weight = args.next().ok_or(OpsError::SyntaxError)?;
+                    let weight = get_float(weight).map_err(|_| {
+                        OpsError::InvalidArgs("ERR weight value is not a float".to_string())
+                    })?;
+                    combination.weights.push(weight);
+                }
+            }
+            "aggregate" if allow_weights => {
+                let aggregate = args.next().ok_or(OpsError::SyntaxError)?;
+                combination.aggregate = match String::try_from(*aggregate)?.to_lowercase().as_ref()
+                {
+                    "sum" => Aggregate::Sum,
+                    "min" => Aggregate::Min,
+                    "max" => Aggregate::Max,
+                    _ => return Err(OpsError::SyntaxError),
+                };
+            }
+            "withscores" if allow_with_scores => with_scores = true,
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok((combination, with_scores))
+}
+
+/// Parse `numkeys key [key ...] [LIMIT limit]` for ZINTERCARD.
+/// A limit of 0 means no limit.
+fn get_intercard_args(args: &[&RedisValueRef]) -> Result<(RVec<Key>, Option<usize>), OpsError> {
+    verify_size_lower(args, 2)?;
+    let num_keys = get_integer(args[0])?;
+    if num_keys < 1 {
+        return Err(OpsError::InvalidArgs(
+            "ERR numkeys should be greater than 0".to_string(),
+        ));
+    }
+    let num_keys = num_keys as usize;
+    if num_keys > args.len() - 1 {
+        return Err(OpsError::InvalidArgs(
+            "ERR Number of keys can't be greater than number of args".to_string(),
+        ));
+    }
+    let keys = collect_from_tail(&args[1..=num_keys])?;
+    let limit = match &args[num_keys + 1..] {
+        [] => None,
+        [option, limit] if String::try_from(*option)?.eq_ignore_ascii_case("limit") => {
+            match get_integer(limit)? {
+                limit if limit < 0 => {
+                    return Err(OpsError::InvalidArgs(
+                        "ERR LIMIT can't be negative".to_string(),
+                    ))
+                }
+                0 => None,
+                limit => Some(limit as usize),
+            }
+        }
+        _ => return Err(OpsError::SyntaxError),
+    };
+    Ok((keys, limit))
+}
+
+/// Parse `[NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]`.
+fn get_zadd_args(args: &[&RedisValueRef]) -> Result<(AddOptions, RVec<(Score, Key)>), OpsError> {
+    let mut options = AddOptions::default();
+    let (mut nx, mut xx, mut gt, mut lt) = (false, false, false, false);
+    let mut flags = 0;
+    for arg in args {
+        let flag = match arg {
+            RedisValueRef::BulkString(s) => s.to_ascii_lowercase(),
+            _ => break,
+        };
+        match &flag[..] {
+            b"nx" => nx = true,
+            b"xx" => xx = true,
+            b"gt" => gt = true,
+            b"lt" => lt = true,
+            b"ch" => options.changed = true,
+            b"incr" => options.incr = true,
+            _ => break,
+        }
+        flags += 1;
+    }
+    let incompatible = |msg: &str| Err(OpsError::InvalidArgs(msg.to_string()));
+    if nx && xx {
+        return incompatible("ERR XX and NX options at the same time are not compatible");
+    }
+    if (gt && lt) || (nx && (gt || lt)) {
+        return incompatible("ERR GT, LT, and/or NX options at the same time are not compatible");
+    }
+    let pairs = &args[flags..];
+    if pairs.is_empty() || pairs.len() & 1 != 0 {
+        return Err(OpsError::SyntaxError);
+    }
+    if options.incr && pairs.len() != 2 {
+        return incompatible("ERR INCR option supports a single increment-element pair");
+    }
+    options.condition = match (nx, xx) {
+        (true, _) => Some(AddCondition::IfAbsent),
+        (_, true) => Some(AddCondition::IfPresent),
+        _ => None,
+    };
+    options.update = match (gt, lt) {
+        (true, _) => Some(UpdateCondition::GreaterThan),
+        (_, true) => Some(UpdateCondition::LessThan),
+        _ => None,
+    };
+    Ok((options, get_key_value_pairs(pairs)?))
+}
+
+/// Parse `[WITHSCORES]`, and `[LIMIT offset count]` if `allow_limit` is set.
+fn get_zrange_options(
+    args: &[&RedisValueRef],
+    allow_limit: bool,
+) -> Result<(Option<Limit>, bool), OpsError> {
+    let mut limit = None;
+    let mut with_scores = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match String::try_from(*arg)?.to_lowercase().as_ref() {
+            "withscores" => with_scores = true,
+            "limit" if allow_limit => {
+                let offset = get_integer(args.next().ok_or(OpsError::SyntaxError)?)?;
+                let count = get_integer(args.next().ok_or(OpsError::SyntaxError)?)?;
+                // Like redis, a negative offset returns nothing and a
+                // negative count returns everything after the offset.
+                limit = Some(match (offset, count) {
+                    (offset, _) if offset < 0 => Limit {
+                        offset: 0,
+                        count: Some(0),
+                    },
+                    (offset, count) => Limit {
+                        offset: offset as usize,
+                        count: usize::try_from(count).ok(),
+                    },
+                });
+            }
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok((limit, with_scores))
+}
+
+fn get_geo_point(lon: &RedisValueRef, lat: &RedisValueRef) -> Result<GeoPoint, OpsError> {
+    let (lon, lat) = (get_float(lon)?, get_float(lat)?);
+    GeoPoint::new(lon, lat).ok_or_else(|| {
+        OpsError::InvalidArgs(format!(
+            "ERR invalid longitude,latitude pair {:.6},{:.6}",
+            lon, lat
+        ))
+    })
+}
+
+fn get_geo_unit(arg: &RedisValueRef) -> Result<GeoUnit, OpsError> {
+    String::try_from(arg)?.parse().map_err(|_| {
+        OpsError::InvalidArgs("ERR unsupported unit provided. please use M, KM, FT, MI".to_string())
+    })
+}
+
+/// Parse the arguments of GEOADD: `[NX | XX] [CH] lon lat member [lon lat member ...]`,
+/// as the ZADD they amount to.
+fn get_geoadd_args(args: &[&RedisValueRef]) -> Result<(AddOptions, RVec<(Score, Key)>), OpsError> {
+    let mut options = AddOptions::default();
+    let (mut nx, mut xx) = (false, false);
+    let mut flags = 0;
+    for arg in args {
+        let flag = match arg {
+            RedisValueRef::BulkString(s) => s.to_ascii_lowercase(),
+            _ => break,
+        };
+        match &flag[..] {
+            b"nx" => nx = true,
+            b"xx" => xx = true,
+            b"ch" => options.changed = true,
+            _ => break,
+        }
+        flags += 1;
+    }
+    if nx && xx {
+        return Err(OpsError::InvalidArgs(
+            "ERR XX and NX options at the same time are not compatible".to_string(),
+        ));
+    }
+    options.condition = match (nx, xx) {
+        (true, _) => Some(AddCondition::IfAbsent),
+        (_, true) => Some(AddCondition::IfPresent),
+        _ => None,
+    };
+    let triples = args[flags..].chunks_exact(3);
+    if args.len() == flags || !triples.remainder().is_empty() {
+        return Err(OpsError::SyntaxError);
+    }
+    let members = triples
+        .map(|triple| {
+            let point = get_geo_point(triple[0], triple[1])?;
+            Ok((point.score(), Key::try_from(triple[2])?))
+        })
+        .collect::<Result<_, OpsError>>()?;
+    Ok((options, members))
+}
+
+/// Parse the options of GEOSEARCH, or of GEOSEARCHSTORE if `store` is set,
+/// along with whether STOREDIST was given.
+fn get_geo_query(
+    command: &str,
+    args: &[&RedisValueRef],
+    store: bool,
+) -> Result<(GeoQuery, bool), OpsError> {
+    let invalid = |msg: String| Err(OpsError::InvalidArgs(msg));
+    let (mut from, mut shape, mut unit) = (None, None, GeoUnit::default());
+    let (mut from_count, mut shape_count) = (0, 0);
+    let (mut order, mut count, mut any) = (None, None, false);
+    let (mut with_coord, mut with_dist, mut with_hash, mut store_dist) =
+        (false, false, false, false);
+    let mut args = args.iter();
+    let mut next = || args.next().copied().ok_or(OpsError::SyntaxError);
+    while let Ok(arg) = next() {
+        match String::try_from(arg)?.to_lowercase().as_ref() {
+            "frommember" => {
+                from = Some(GeoFrom::Member(Key::try_from(next()?)?));
+                from_count += 1;
+            }
+            "fromlonlat" => {
+                let (lon, lat) = (next()?, next()?);
+                from = Some(GeoFrom::Point(get_geo_point(lon, lat)?));
+                from_count += 1;
+            }
+            "byradius" => {
+                let radius = get_float(next()?)?;
+                if radius < 0.0 {
+                    return invalid("ERR radius cannot be negative".to_string());
+                }
+                unit = get_geo_unit(next()?)?;
+                shape = Some(GeoShape::Radius(radius * unit.meters()));
+                shape_count += 1;
+            }
+            "bybox" => {
+                let (width, height) = (get_float(next()?)?, get_float(next()?)?);
+                if width < 0.0 || height < 0.0 {
+                    return invalid("ERR height or width cannot be negative".to_string());
+                }
+                unit = get_geo_unit(next()?)?;
+                shape = Some(GeoShape::Box {
+                    width: width * unit.meters(),
+                    height: height * unit.meters(),
+                });
+                shape_count += 1;
+            }
+            "asc" => order = Some(SortOrder::Asc),
+            "desc" => order = Some(SortOrder::Desc),
+            "count" => {
+                let n = get_integer(next()?)?;
+                if n <= 0 {
+                    return invalid("ERR COUNT must be > 0".to_string());
+                }
+                count = Some(n as usize);
+            }
+            "any" => any = true,
+            "withcoord" if !store => with_coord = true,
+            "withdist" if !store => with_dist = true,
+            "withhash" if !store => with_hash = true,
+            "storedist" if store => store_dist = true,
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    let command = command.to_uppercase();
+    let from = match from {
+        Some(from) if from_count == 1 => from,
+        _ => {
+            return invalid(format!(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                command
+            ))
+        }
+    };
+    let shape = match shape {
+        Some(shape) if shape_count == 1 => shape,
+        _ => {
+            return invalid(format!(
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for {}",
+                command
+            ))
+        }
+    };
+    if any && count.is_none() {
+        return invalid("ERR the ANY argument requires COUNT argument".to_string());
+    }
+    let query = GeoQuery {
+        from,
+        shape,
+        unit,
+        order,
+        count: count.map(|count| (count, any)),
+        with_coord,
+        with_dist,
+        with_hash,
+    };
+    Ok((query, store_dist))
+}
+
+fn invalid_stream_id() -> OpsError {
+    OpsError::InvalidArgs("ERR Invalid stream ID specified as stream command argument".to_string())
+}
+
+/// Parse a stream ID, where `ms` alone means `ms-missing_seq`.
+fn get_stream_id(arg: &RedisValueRef, missing_seq: u64) -> Result<StreamId, OpsError> {
+    StreamId::parse(&Value::try_from(arg)?, missing_seq).ok_or_else(invalid_stream_id)
+}
+
+/// Parse an XRANGE bound: `-`, `+`, an ID, or "(" followed by an ID to exclude it.
+fn get_stream_bound(arg: &RedisValueRef, is_start: bool) -> Result<StreamId, OpsError> {
+    let arg = Value::try_from(arg)?;
+    match (&arg[..], is_start) {
+        (b"-", _) => return Ok(StreamId::MIN),
+        (b"+", _) => return Ok(StreamId::MAX),
+        _ => {}
+    }
+    let missing_seq = if is_start { 0 } else { u64::MAX };
+    let id = match arg.strip_prefix(b"(") {
+        Some(id) => StreamId::parse(id, missing_seq).ok_or_else(invalid_stream_id)?,
+        None => return StreamId::parse(&arg, missing_seq).ok_or_else(invalid_stream_id),
+    };
+    let invalid = |msg: &str| OpsError::InvalidArgs(msg.to_string());
+    match is_start {
+        true => id
+            .next()
+            .ok_or_else(|| invalid("ERR invalid start ID for the interval")),
+        false => id
+            .prev()
+            .ok_or_else(|| invalid("ERR invalid end ID for the interval")),
+    }
+}
+
+/// Parse `MAXLEN | MINID [= | ~] threshold [LIMIT count]`.
+/// Returns the trim, and the number of arguments it took.
+fn get_stream_trim(args: &[&RedisValueRef]) -> Result<(Trim, usize), OpsError> {
+    let invalid = |msg: &str| Err(OpsError::InvalidArgs(msg.to_string()));
+    let mut taken = 0;
+    let mut next = || {
+        let arg = args.get(taken).copied().ok_or(OpsError::SyntaxError);
+        taken += 1;
+        arg
+    };
+    let is_max_len = match String::try_from(next()?)?.to_lowercase().as_ref() {
+        "maxlen" => true,
+        "minid" => false,
+        _ => return Err(OpsError::SyntaxError),
+    };
+    let threshold = next()?;
+    let (approximate, threshold) = match &Value::try_from(threshold)?[..] {
+        b"~" => (true, next()?),
+        b"=" => (false, next()?),
+        _ => (false, threshold),
+    };
+    let strategy = if is_max_len {
+        match usize::try_from(get_integer(threshold)?) {
+            Ok(max_len) => TrimStrategy::MaxLen(max_len),
+            Err(_) => return invalid("ERR The MAXLEN argument must be >= 0."),
+        }
+    } else {
+        TrimStrategy::MinId(get_stream_id(threshold, 0)?)
+    };
+    let mut limit = approximate.then_some(DEFAULT_TRIM_LIMIT);
+    let is_limit = |arg: &RedisValueRef| {
+        Value::try_from(arg).is_ok_and(|arg| arg.eq_ignore_ascii_case(b"limit"))
+    };
+    if args.get(taken).is_some_and(|arg| is_limit(arg)) {
+        let count = args.get(taken + 1).ok_or(OpsError::SyntaxError)?;
+        taken += 2;
+        if !approximate {
+            return invalid("ERR syntax error, LIMIT cannot be used without the special ~ option");
+        }
+        limit = match usize::try_from(get_integer(count)?) {
+            // LIMIT 0 removes the limit.
+            Ok(0) => None,
+            Ok(count) => Some(count),
+            Err(_) => return invalid("ERR The LIMIT argument must be >= 0."),
+        };
+    }
+    let trim = Trim {
+        strategy,
+        approximate,
+        limit,
+    };
+    Ok((trim, taken))
+}
+
+/// Parse the arguments of XADD after the key:
+/// `[NOMKSTREAM] [MAXLEN | MINID ...] * | id field value [field value ...]`.
+#[allow(clippy::type_complexity)]
+fn get_xadd_args(
+    args: &[&RedisValueRef],
+) -> Result<(bool, Option<Trim>, NewId, RVec<(Key, Value)>), OpsError> {
+    let (mut no_mkstream, mut trim) = (false, None);
+    let mut args = args;
+    loop {
+        let option = args.first().ok_or(OpsError::SyntaxError)?;
+        match String::try_from(*option)?.to_lowercase().as_ref() {
+            "nomkstream" => {
+                no_mkstream = true;
+                args = &args[1..];
+            }
+            "maxlen" | "minid" => {
+                let (parsed, taken) = get_stream_trim(args)?;
+                trim = Some(parsed);
+                args = &args[taken..];
+            }
+            _ => break,
+        }
+    }
+    let fields = &args[1..];
+    if fields.is_empty() || fields.len() & 1 != 0 {
+        return Err(OpsError::InvalidArgs(
+            "ERR wrong number of arguments for 'xadd' command".to_string(),
+        ));
+    }
+    let id = Value::try_from(args[0])?;
+    let id = if &id[..] == b"*" {
+        NewId::Auto
+    } else if let Some(ms) = id.strip_suffix(b"-*") {
+        let ms = std::str::from_utf8(ms).ok().and_then(|ms| ms.parse().ok());
+        NewId::Sequence(ms.ok_or_else(invalid_stream_id)?)
+    } else {
+        NewId::Exact(get_stream_id(args[0], 0)?)
+    };
+    Ok((no_mkstream, trim, id, get_key_value_pairs(fields)?))
+}
+
+/// Parse an optional `COUNT count`; a negative count is zero.
+fn get_stream_count(args: &[&RedisValueRef]) -> Result<Option<usize>, OpsError> {
+    match args {
+        [] => Ok(None),
+        [option, count] if String::try_from(*option)?.eq_ignore_ascii_case("count") => {
+            Ok(Some(usize::try_from(get_integer(count)?).unwrap_or(0)))
+        }
+        _ => Err(OpsError::SyntaxError),
+    }
+}
+
+/// Parse a non-negative number of milliseconds.
+fn get_millis(arg: &RedisValueRef, error: &str) -> Result<u64, OpsError> {
+    u64::try_from(get_integer(arg)?).map_err(|_| OpsError::InvalidArgs(error.to_string()))
+}
+
+/// Parse `key [key ...] id [id ...]`, the tail of XREAD and XREADGROUP.
+fn get_stream_keys<T>(
+    args: &[&RedisValueRef],
+    command: &str,
+    get_from: impl Fn(&RedisValueRef) -> Result<T, OpsError>,
+) -> Result<RVec<(Key, T)>, OpsError> {
+    if args.is_empty() || args.len() & 1 != 0 {
+        return Err(OpsError::InvalidArgs(format!(
+            "ERR Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be specified.",
+            command,
+            if command == "xread" { "$" } else { ">" }
+        )));
+    }
+    let (keys, ids) = args.split_at(args.len() / 2);
+    keys.iter()
+        .zip(ids)
+        .map(|(key, id)| Ok((Key::try_from(*key)?, get_from(id)?)))
+        .collect()
+}
+
+/// Parse the options of XREAD and XREADGROUP up to STREAMS, and the streams after it.
+/// Returns the COUNT, BLOCK and NOACK options, and the arguments after STREAMS.
+#[allow(clippy::type_complexity)]
+fn get_stream_read_options<'a, 'b>(
+    mut args: &'a [&'b RedisValueRef],
+    allow_no_ack: bool,
+) -> Result<(Option<usize>, Option<u64>, bool, &'a [&'b RedisValueRef]), OpsError> {
+    let (mut count, mut block, mut no_ack) = (None, None, false);
+    loop {
+        let (option, value) = match args {
+            [option, rest @ ..] => (String::try_from(*option)?.to_lowercase(), rest.first()),
+            [] => return Err(OpsError::SyntaxError),
+        };
+        match (option.as_ref(), value) {
+            ("streams", _) => return Ok((count, block, no_ack, &args[1..])),
+            ("noack", _) if allow_no_ack => {
+                no_ack = true;
+                args = &args[1..];
+                continue;
+            }
+            ("count", Some(value)) => {
+                // Zero or less means no limit.
+                count = usize::try_from(get_integer(value)?).ok().filter(|c| *c > 0);
+            }
+            ("block", Some(value)) => block = Some(get_millis(value, "ERR timeout is negative")?),
+            _ => return Err(OpsError::SyntaxError),
+        }
+        args = &args[2..];
+    }
+}
+
+/// Parse the arguments of XREAD: `[COUNT count] [BLOCK ms] STREAMS key [key ...] id [id ...]`.
+#[allow(clippy::type_complexity)]
+fn get_xread_args(
+    args: &[&RedisValueRef],
+) -> Result<(RVec<(Key, ReadFrom)>, Option<usize>, Option<u64>), OpsError> {
+    let (count, block, _, args) = get_stream_read_options(args, false)?;
+    let streams = get_stream_keys(args, "xread", |id| {
+        Ok(match &Value::try_from(id)?[..] {
+            b"$" => ReadFrom::Last,
+            _ => ReadFrom::Id(get_stream_id(id, 0)?),
+        })
+    })?;
+    Ok((streams, count, block))
+}
+
+/// Parse the arguments of XREADGROUP after the group and consumer:
+/// `[COUNT count] [BLOCK ms] [NOACK] STREAMS key [key ...] id [id ...]`.
+#[allow(clippy::type_complexity)]
+fn get_xreadgroup_args(
+    args: &[&RedisValueRef],
+) -> Result<(RVec<(Key, GroupReadFrom)>, Option<usize>, Option<u64>, bool), OpsError> {
+    let (count, block, no_ack, args) = get_stream_read_options(args, true)?;
+    let streams = get_stream_keys(args, "xreadgroup", |id| {
+        Ok(match &Value::try_from(id)?[..] {
+            b">" => GroupReadFrom::Undelivered,
+            b"$" => {
+                return Err(OpsError::InvalidArgs(
+                    "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.".to_string(),
+                ))
+            }
+            _ => GroupReadFrom::Pending(get_stream_id(id, 0)?),
+        })
+    })?;
+    Ok((streams, count, block, no_ack))
+}
+
+/// Parse the ID XGROUP CREATE and SETID start from: an ID, or `$` for the last one.
+fn get_group_start(arg: &RedisValueRef) -> Result<ReadFrom, OpsError> {
+    match &Value::try_from(arg)?[..] {
+        b"$" => Ok(ReadFrom::Last),
+        _ => Ok(ReadFrom::Id(get_stream_id(arg, 0)?)),
+    }
+}
+
+/// Parse `ENTRIESREAD n`, where -1 means unknown.
+fn get_entries_read(args: &[&RedisValueRef]) -> Result<Option<u64>, OpsError> {
+    match get_integer(args.get(1).ok_or(OpsError::SyntaxError)?)? {
+        -1 => Ok(None),
+        n => u64::try_from(n).map(Some).map_err(|_| {
+            OpsError::InvalidArgs("ERR value for ENTRIESREAD must be positive or -1".to_string())
+        }),
+    }
+}
+
+/// Parse the subcommands of XGROUP.
+fn get_xgroup_op(args: &[&RedisValueRef]) -> Result<StreamOps, OpsError> {
+    let subcommand = String::try_from(args[0])?.to_lowercase();
+    let args = &args[1..];
+    let arity = |n: usize| match args.len() == n {
+        true => Ok(()),
+        false => Err(OpsError::InvalidArgs(format!(
+            "ERR wrong number of arguments for 'xgroup|{}' command",
+            subcommand
+        ))),
+    };
+    match subcommand.as_ref() {
+        "create" | "setid" => {
+            verify_size_lower(args, 3)?;
+            let key = Key::try_from(args[0])?;
+            let group = Key::try_from(args[1])?;
+            let start = get_group_start(args[2])?;
+            let (mut mk_stream, mut entries_read) = (false, None);
+            let mut options = &args[3..];
+            while let [option, ..] = options {
+                match String::try_from(*option)?.to_lowercase().as_ref() {
+                    "mkstream" if subcommand == "create" => {
+                        mk_stream = true;
+                        options = &options[1..];
+                    }
+                    "entriesread" => {
+                        entries_read = get_entries_read(options)?;
+                        options = &options[2..];
+                    }
+                    _ => return Err(OpsError::SyntaxError),
+                }
+            }
+            Ok(match subcommand.as_ref() {
+                "create" => StreamOps::XGroupCreate(key, group, start, mk_stream, entries_read),
+                _ => StreamOps::XGroupSetId(key, group, start, entries_read),
+            })
+        }
+        "destroy" => {
+            arity(2)?;
+            Ok(StreamOps::XGroupDestroy(
+                Key::try_from(args[0])?,
+                Key::try_from(args[1])?,
+            ))
+        }
+        "createconsumer" | "delconsumer" => {
+            arity(3)?;
+            let key = Key::try_from(args[0])?;
+            let group = Key::try_from(args[1])?;
+            let consumer = Key::try_from(args[2])?;
+            Ok(match subcommand.as_ref() {
+                "createconsumer" => StreamOps::XGroupCreateConsumer(key, group, consumer),
+                _ => StreamOps::XGroupDelConsumer(key, group, consumer),
+            })
+        }
+        _ => Err(OpsError::InvalidArgs(format!(
+            "ERR unknown subcommand '{}'. Try XGROUP HELP.",
+            subcommand
+        ))),
+    }
+}
+
+/// Parse the arguments of XPENDING after the key and group:
+/// `[[IDLE min-idle-time] start end count [consumer]]`.
+fn get_pending_range(args: &[&RedisValueRef]) -> Result<Option<PendingRange>, OpsError> {
+    if args.is_empty() {
+        return Ok(None);
+    }
+    let (min_idle, args) = match String::try_from(args[0])?.eq_ignore_ascii_case("idle") {
+        true if args.len() > 1 => (
+            Some(get_millis(
+                args[1],
+                "ERR Invalid min-idle-time argument for XPENDING",
+            )?),
+            &args[2..],
+        ),
+        true => return Err(OpsError::SyntaxError),
+        false => (None, args),
+    };
+    let consumer = match args {
+        [_, _, _] => None,
+        [_, _, _, consumer] => Some(Key::try_from(*consumer)?),
+        _ => return Err(OpsError::SyntaxError),
+    };
+    Ok(Some(PendingRange {
+        min_idle,
+        start: get_stream_bound(args[0], true)?,
+        end: get_stream_bound(args[1], false)?,
+        // A negative count is zero.
+        count: usize::try_from(get_integer(args[2])?).unwrap_or(0),
+        consumer,
+    }))
+}
+
+/// Parse the arguments of XCLAIM after the min-idle-time: `id [id ...] [IDLE ms]
+/// [TIME unix-time-ms] [RETRYCOUNT count] [FORCE] [JUSTID] [LASTID lastid]`.
+fn get_xclaim_args(
+    args: &[&RedisValueRef],
+    options: &mut ClaimOptions,
+) -> Result<RVec<StreamId>, OpsError> {
+    let mut ids = RVec::new();
+    let mut args = args.iter();
+    // IDs come first, up to the first option.
+    let mut next = args.next();
+    while let Some(id) = next {
+        match get_stream_id(id, 0) {
+            Ok(id) => ids.push(id),
+            Err(_) if !ids.is_empty() => break,
+            Err(e) => return Err(e),
+        }
+        next = args.next();
+    }
+    while let Some(option) = next {
+        let mut value = || args.next().ok_or(OpsError::SyntaxError);
+        match String::try_from(*option)?.to_lowercase().as_ref() {
+            "idle" => {
+                options.idle = Some(get_millis(
+                    value()?,
+                    "ERR Invalid IDLE option argument for XCLAIM",
+                )?)
+            }
+            "time" => {
+                options.time = Some(get_millis(
+                    value()?,
+                    "ERR Invalid TIME option argument for XCLAIM",
+                )?)
+            }
+            "retrycount" => {
+                options.retry_count = Some(get_millis(
+                    value()?,
+                    "ERR Invalid RETRYCOUNT option argument for XCLAIM",
+                )?)
+            }
+            "force" => options.force = true,
+            "justid" => options.just_id = true,
+            "lastid" => options.last_id = Some(get_stream_id(value()?, 0)?),
+            _ => return Err(OpsError::SyntaxError),
+        }
+        next = args.next();
+    }
+    Ok(ids)
+}
+
+/// Parse the arguments of XAUTOCLAIM after the start: `[COUNT count] [JUSTID]`.
+fn get_xautoclaim_args(
+    args: &[&RedisValueRef],
+    options: &mut ClaimOptions,
+) -> Result<usize, OpsError> {
+    let mut count = 100;
+    let mut args = args.iter();
+    while let Some(option) = args.next() {
+        match String::try_from(*option)?.to_lowercase().as_ref() {
+            "count" => {
+                let value = args.next().ok_or(OpsError::SyntaxError)?;
+                count = usize::try_from(get_integer(value)?)
+                    .ok()
+                    .filter(|count| (1..=usize::MAX / 10).contains(count))
+                    .ok_or_else(|| OpsError::InvalidArgs("ERR COUNT must be > 0".to_string()))?;
+            }
+            "justid" => options.just_id = true,
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok(count)
+}
+
+/// Parse the subcommands of XINFO.
+fn get_xinfo_op(args: &[&RedisValueRef]) -> Result<StreamOps, OpsError> {
+    let subcommand = String::try_from(args[0])?.to_lowercase();
+    let args = &args[1..];
+    match (subcommand.as_ref(), args) {
+        ("stream", [key]) => Ok(StreamOps::XInfoStream(Key::try_from(*key)?)),
+        ("groups", [key]) => Ok(StreamOps::XInfoGroups(Key::try_from(*key)?)),
+        ("consumers", [key, group]) => Ok(StreamOps::XInfoConsumers(
+            Key::try_from(*key)?,
+            Key::try_from(*group)?,
+        )),
+        ("stream" | "groups" | "consumers", _) => Err(OpsError::InvalidArgs(format!(
+            "ERR wrong number of arguments for 'xinfo|{}' command",
+            subcommand
+        ))),
+        _ => Err(OpsError::InvalidArgs(format!(
+            "ERR unknown subcommand '{}'. Try XINFO HELP.",
+            subcommand
+        ))),
+    }
+}
+
+/// Parse a blocking timeout in seconds, which may be fractional.
+fn get_timeout(arg: &RedisValueRef) -> Result<Duration, OpsError> {
+    let seconds = match arg {
+        RedisValueRef::Int(i) => Some(*i as f64),
+        arg => parse_float(&Value::try_from(arg)?),
+    };
+    match seconds {
+        Some(seconds) if seconds < 0.0 => {
+            Err(OpsError::InvalidArgs("ERR timeout is negative".to_string()))
+        }
+        Some(seconds) if seconds.is_finite() && seconds < u32::MAX as f64 => {
+            Ok(Duration::from_secs_f64(seconds))
+        }
+        _ => Err(OpsError::InvalidArgs(
+            "ERR timeout is not a float or out of range".to_string(),
+        )),
+    }
+}
+
+/// Parse the arguments of BLPOP and BRPOP: `key [key ...] timeout`.
+fn get_bpop_args(args: &[&RedisValueRef]) -> Result<(RVec<Key>, Duration), OpsError> {
+    let (keys, timeout) = args.split_at(args.len() - 1);
+    let keys = keys
+        .iter()
+        .map(|key| Key::try_from(*key))
+        .collect::<Result<_, _>>()?;
+    Ok((keys, get_timeout(timeout[0])?))
+}
+
+fn get_list_end(arg: &RedisValueRef) -> Result<ListEnd, OpsError> {
+    match String::try_from(arg)?.to_lowercase().as_ref() {
+        "left" => Ok(ListEnd::Left),
+        "right" => Ok(ListEnd::Right),
+        _ => Err(OpsError::SyntaxError),
+    }
+}
+
+/// Parse the arguments of LMPOP: `numkeys key [key ...] LEFT | RIGHT [COUNT count]`.
+fn get_lmpop_args(args: &[&RedisValueRef]) -> Result<(RVec<Key>, ListEnd, usize), OpsError> {
+    let num_keys = usize::try_from(get_integer(args[0])?)
+        .ok()
+        .filter(|n| *n > 0)
+        .ok_or_else(|| OpsError::InvalidArgs("ERR numkeys should be greater than 0".to_string()))?;
+    let args = &args[1..];
+    if args.len() <= num_keys {
+        return Err(OpsError::SyntaxError);
+    }
+    let (keys, args) = args.split_at(num_keys);
+    let keys = keys
+        .iter()
+        .map(|key| Key::try_from(*key))
+        .collect::<Result<_, _>>()?;
+    let end = get_list_end(args[0])?;
+    let count = match &args[1..] {
+        [] => 1,
+        [option, count] if String::try_from(*option)?.eq_ignore_ascii_case("count") => {
+            usize::try_from(get_integer(count)?)
+                .ok()
+                .filter(|count| *count > 0)
+                .ok_or_else(|| {
+                    OpsError::InvalidArgs("ERR count should be greater than 0".to_string())
+                })?
+        }
+        _ => return Err(OpsError::SyntaxError),
+    };
+    Ok((keys, end, count))
+}
+
+/// Parse the options of LPOS: `[RANK rank] [COUNT count] [MAXLEN len]`.
+fn get_pos_options(args: &[&RedisValueRef]) -> Result<PosOptions, OpsError> {
+    let mut options = PosOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = String::try_from(*arg)?.to_lowercase();
+        let value = get_integer(args.next().ok_or(OpsError::SyntaxError)?)?;
+        match option.as_ref() {
+            "rank" if value == 0 => {
+                return Err(OpsError::InvalidArgs(
+                    "ERR RANK can't be zero: use 1 to start from the first match, \
+                     2 from the second ... or use negative to start from the end of the list"
+                        .to_string(),
+                ))
+            }
+            "rank" if value == Index::MIN => {
+                return Err(OpsError::InvalidArgs(
+                    "ERR value is out of range, value must between -9223372036854775807 and 9223372036854775807"
+                        .to_string(),
+                ))
+            }
+            "rank" => options.rank = value,
+            "count" => {
+                options.count = Some(usize::try_from(value).map_err(|_| {
+                    OpsError::InvalidArgs("ERR COUNT can't be negative".to_string())
+                })?)
+            }
+            "maxlen" => {
+                options.max_len = usize::try_from(value).map_err(|_| {
+                    OpsError::InvalidArgs("ERR MAXLEN can't be negative".to_string())
+                })?
+            }
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok(options)
+}
+
+/// Parse the options of SET: `[NX | XX] [GET] [EX s | PX ms | EXAT s | PXAT ms | KEEPTTL]`.
+fn get_set_options(args: &[&RedisValueRef]) -> Result<SetOptions, OpsError> {
+    let mut options = SetOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let option = String::try_from(*arg)?.to_lowercase();
+        match option.as_ref() {
+            "nx" if options.condition.is_none() => options.condition = Some(SetCondition::IfAbsent),
+            "xx" if options.condition.is_none() => {
+                options.condition = Some(SetCondition::IfPresent)
+            }
+            "get" => options.get = true,
+            "keepttl" if options.expiry.is_none() => options.expiry = Some(SetExpiry::KeepTtl),
+            "ex" | "px" | "exat" | "pxat" if options.expiry.is_none() => {
+                let time = get_integer(args.next().ok_or(OpsError::SyntaxError)?)?;
+                if time <= 0 {
+                    return Err(OpsError::InvalidArgs(
+                        "ERR invalid expire time in 'set' command".to_string(),
+                    ));
+                }
+                options.expiry = Some(match option.as_ref() {
+                    "ex" => SetExpiry::Ex(time),
+                    "px" => SetExpiry::Px(time),
+                    "exat" => SetExpiry::ExAt(time),
+                    _ => SetExpiry::PxAt(time),
+                });
+            }
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok(options)
+}
+
+/// Parse a bit offset, which must fit in a 512MB string.
+fn get_bit_offset(arg: &RedisValueRef) -> Result<u64, OpsError> {
+    match get_integer(arg) {
+        Ok(offset) if (0..=MAX_BIT_OFFSET as Count).contains(&offset) => Ok(offset as u64),
+        _ => Err(OpsError::InvalidArgs(
+            "ERR bit offset is not an integer or out of range".to_string(),
+        )),
+    }
+}
+
+/// Parse the `start end [BYTE | BIT]` range of BITCOUNT and BITPOS.
+/// `end` may only be left out if `end_optional` is set.
+fn get_bit_range(
+    args: &[&RedisValueRef],
+    end_optional: bool,
+) -> Result<Option<BitRange>, OpsError> {
+    let (start, end, unit) = match args {
+        [] => return Ok(None),
+        [start] if end_optional => (start, None, None),
+        [start, end] => (start, Some(end), None),
+        [start, end, unit] => (start, Some(end), Some(unit)),
+        _ => return Err(OpsError::SyntaxError),
+    };
+    let unit = match unit {
+        None => RangeUnit::Byte,
+        Some(unit) => match String::try_from(*unit)?.to_lowercase().as_ref() {
+            "byte" => RangeUnit::Byte,
+            "bit" => RangeUnit::Bit,
+            _ => return Err(OpsError::SyntaxError),
+        },
+    };
+    Ok(Some(BitRange {
+        start: get_integer(start)?,
+        end: end.map(|end| get_integer(end)).transpose()?,
+        unit,
+    }))
+}
+
+/// Parse the subcommands of BITFIELD.
+fn get_bitfield_ops(args: &[&RedisValueRef]) -> Result<Vec<FieldOp>, OpsError> {
+    let mut ops = Vec::new();
+    let mut args = args.iter();
+    let mut next = || args.next().copied().ok_or(OpsError::SyntaxError);
+    loop {
+        let subcommand = match next() {
+            Ok(subcommand) => String::try_from(subcommand)?.to_lowercase(),
+            Err(_) => return Ok(ops),
+        };
+        if subcommand == "overflow" {
+            let mode = String::try_from(next()?)?
+                .parse::<Overflow>()
+                .map_err(|_| {
+                    OpsError::InvalidArgs("ERR Invalid OVERFLOW type specified".to_string())
+                })?;
+            ops.push(FieldOp::Overflow(mode));
+            continue;
+        }
+        if !["get", "set", "incrby"].contains(&subcommand.as_ref()) {
+            return Err(OpsError::SyntaxError);
+        }
+        let ty = String::try_from(next()?)?
+            .parse::<FieldType>()
+            .map_err(|_| {
+                OpsError::InvalidArgs(
+                    "ERR Invalid bitfield type. Use something like i16 u8. \
+                 Note that u64 is not supported but i64 is."
+                        .to_string(),
+                )
+            })?;
+        // "#N" is the N-th field of this type.
+        let offset = Value::try_from(next()?)?;
+        let offset = match offset.strip_prefix(b"#") {
+            Some(index) => parse_integer(index).and_then(|i| i.checked_mul(ty.bits as Count)),
+            None => parse_integer(&offset),
+        };
+        let offset = match offset {
+            // The field's last bit must fit too. Offsets are client supplied,
+            // so don't add to them.
+            Some(offset)
+                if offset >= 0 && offset <= MAX_BIT_OFFSET as Count - (ty.bits as Count - 1) =>
+            {
+                offset as u64
+            }
+            _ => {
+                return Err(OpsError::InvalidArgs(
+                    "ERR bit offset is not an integer or out of range".to_string(),
+                ))
+            }
+        };
+        ops.push(match subcommand.as_ref() {
+            "get" => FieldOp::Get(ty, offset),
+            "set" => FieldOp::Set(ty, offset, get_integer(next()?)?),
+            _ => FieldOp::IncrBy(ty, offset, get_integer(next()?)?),
+        });
+    }
+}
+
+/// Parse `cursor [MATCH pattern] [COUNT count] [TYPE type]`.
+/// TYPE is only allowed when `allow_type` is set.
+fn get_scan_options(args: &[&RedisValueRef], allow_type: bool) -> Result<ScanOptions, OpsError> {
+    verify_size_lower(args, 1)?;
+    let cursor = String::try_from(args[0])?
+        .parse::<u64>()
+        .map_err(|_| OpsError::InvalidArgs("invalid cursor".to_string()))?;
+    let mut options = ScanOptions {
+        cursor,
+        pattern: None,
+        count: DEFAULT_SCAN_COUNT,
+        value_type: None,
+    };
+    let mut opts = args[1..].iter();
+    while let Some(opt) = opts.next() {
+        let arg = opts.next().ok_or(OpsError::SyntaxError)?;
+        match String::try_from(*opt)?.to_lowercase().as_ref() {
+            "match" => options.pattern = Some(Value::try_from(*arg)?),
+            "count" => match Count::try_from(*arg)? {
+                count if count < 1 => return Err(OpsError::SyntaxError),
+                count => options.count = count as usize,
+            },
+            "type" if allow_type => options.value_type = Some(String::try_from(*arg)?),
+            _ => return Err(OpsError::SyntaxError),
+        }
+    }
+    Ok(options)
+}
+
 /// Verify the exact size of a sequence.
 /// Useful for some commands that require an exact number of arguments (like get and set)
 fn verify_size<T>(v: &[T], size: usize) -> Result<(), OpsError> {
@@ -292,6 +1561,21 @@ macro_rules! ok {
     (HyperLogLogOps::$OpName:ident($($OpArg:expr),*)) => {
         Ok(Ops::HyperLogLogs(HyperLogLogOps::$OpName($( $OpArg ),*)))
     };
+    (BitmapOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::Bitmaps(BitmapOps::$OpName($( $OpArg ),*)))
+    };
+    (GeoOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::Geo(GeoOps::$OpName($( $OpArg ),*)))
+    };
+    (StreamOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::Streams(StreamOps::$OpName($( $OpArg ),*)))
+    };
+    (ExpiryOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::Expiry(ExpiryOps::$OpName($( $OpArg ),*)))
+    };
+    (PubSubOps::$OpName:ident($($OpArg:expr),*)) => {
+        Ok(Ops::PubSub(PubSubOps::$OpName($( $OpArg ),*)))
+    };
 }
 
 fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Result<Ops, OpsError> {
@@ -306,20 +1590,112 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
     let tail: Vec<&RedisValueRef> = array.iter().skip(1).collect();
     match head_s.to_lowercase().as_ref() {
         "ping" => ok!(MiscOps::Pong()),
-        "keys" => ok!(MiscOps::Keys()),
+        "keys" => {
+            // Without a pattern, every key is returned.
+            let pattern = match tail.first() {
+                Some(pattern) => Key::try_from(*pattern)?,
+                None => Key::from_static(b"*"),
+            };
+            ok!(MiscOps::Keys(pattern))
+        }
+        "scan" => ok!(MiscOps::Scan(get_scan_options(&tail, true)?)),
         "flushall" => ok!(MiscOps::FlushAll()),
         "flushdb" => ok!(MiscOps::FlushDB()),
+        "bgrewriteaof" => ok!(MiscOps::BgRewriteAof()),
+        "save" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::Save())
+        }
+        "bgsave" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::BgSave())
+        }
+        "lastsave" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::LastSave())
+        }
         "script" => {
             verify_size(&tail, 1)?;
             let program = Value::try_from(tail[0])?;
             ok!(MiscOps::Script(program))
         }
-        // Key-Value
-        "set" => {
+        // Key-Value
+        "set" => {
+            let (key, val) = get_key_and_value(array)?;
+            ok!(KeyOps::Set(key, val, get_set_options(&tail[2..])?))
+        }
+        "mset" => ok!(KeyOps::MSet(get_key_value_pairs(&tail)?)),
+        "msetnx" => {
+            verify_size_lower(&tail, 2)?;
+            ok!(KeyOps::MSetNx(get_key_value_pairs(&tail)?))
+        }
+        "setnx" => {
+            verify_size(&tail, 2)?;
+            let (key, val) = get_key_and_value(array)?;
+            ok!(KeyOps::SetNx(key, val))
+        }
+        "getset" => {
+            verify_size(&tail, 2)?;
+            let (key, val) = get_key_and_value(array)?;
+            ok!(KeyOps::GetSet(key, val))
+        }
+        "getdel" => {
+            verify_size(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::GetDel(key))
+        }
+        "incr" => {
+            verify_size(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::IncrBy(key, 1))
+        }
+        "decr" => {
+            verify_size(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::IncrBy(key, -1))
+        }
+        "incrby" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::IncrBy(key, get_integer(tail[1])?))
+        }
+        "decrby" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let delta = get_integer(tail[1])?
+                .checked_neg()
+                .ok_or_else(|| OpsError::InvalidArgs("ERR decrement would overflow".to_string()))?;
+            ok!(KeyOps::IncrBy(key, delta))
+        }
+        "incrbyfloat" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::IncrByFloat(key, get_float(tail[1])?))
+        }
+        "append" => {
+            verify_size(&tail, 2)?;
             let (key, val) = get_key_and_value(array)?;
-            ok!(KeyOps::Set(key, val))
+            ok!(KeyOps::Append(key, val))
+        }
+        "getrange" | "substr" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let start = get_integer(tail[1])?;
+            let end = get_integer(tail[2])?;
+            ok!(KeyOps::GetRange(key, start, end))
+        }
+        "setrange" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let offset = get_integer(tail[1])?;
+            let val = Value::try_from(tail[2])?;
+            ok!(KeyOps::SetRange(key, offset, val))
+        }
+        "strlen" => {
+            verify_size(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::StrLen(key))
         }
-        "mset" => ok!(KeyOps::MSet(get_key_value_pairs(&tail)?)),
         "get" => {
             verify_size(&tail, 1)?;
             let key = Key::try_from(tail[0])?;
@@ -335,6 +1711,11 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let keys = collect_from_tail(&tail)?;
             ok!(KeyOps::Del(keys))
         }
+        "type" => {
+            verify_size(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(KeyOps::Type(key))
+        }
         "rename" => {
             verify_size(&tail, 2)?;
             let key = Key::try_from(tail[0])?;
@@ -428,6 +1809,11 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             };
             ok!(SetOps::SRandMembers(key, count))
         }
+        "sscan" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(SetOps::SScan(key, get_scan_options(&tail[1..], false)?))
+        }
         "lpush" => {
             let (key, vals) = get_key_and_tail(array)?;
             ok!(ListOps::LPush(key, vals))
@@ -437,16 +1823,12 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             ok!(ListOps::RPush(key, vals))
         }
         "lpushx" => {
-            verify_size(&tail, 2)?;
-            let key = Key::try_from(tail[0])?;
-            let val = Value::try_from(tail[1])?;
-            ok!(ListOps::LPushX(key, val))
+            let (key, vals) = get_key_and_tail(array)?;
+            ok!(ListOps::LPushX(key, vals))
         }
         "rpushx" => {
-            verify_size(&tail, 2)?;
-            let key = Key::try_from(tail[0])?;
-            let val = Value::try_from(tail[1])?;
-            ok!(ListOps::RPushX(key, val))
+            let (key, vals) = get_key_and_tail(array)?;
+            ok!(ListOps::RPushX(key, vals))
         }
         "llen" => {
             verify_size(&tail, 1)?;
@@ -459,16 +1841,42 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             ok!(ListOps::LPop(key))
         }
         "blpop" => {
-            verify_size(&tail, 2)?;
-            let key = Key::try_from(tail[0])?;
-            let timeout = UTimeout::try_from(tail[1])?;
-            ok!(ListOps::BLPop(key, timeout))
+            verify_size_lower(&tail, 2)?;
+            let (keys, timeout) = get_bpop_args(&tail)?;
+            ok!(ListOps::BLPop(keys, Some(timeout)))
         }
         "brpop" => {
-            verify_size(&tail, 2)?;
-            let key = Key::try_from(tail[0])?;
-            let timeout = UTimeout::try_from(tail[1])?;
-            ok!(ListOps::BRPop(key, timeout))
+            verify_size_lower(&tail, 2)?;
+            let (keys, timeout) = get_bpop_args(&tail)?;
+            ok!(ListOps::BRPop(keys, Some(timeout)))
+        }
+        "blmove" => {
+            verify_size(&tail, 5)?;
+            let source = Key::try_from(tail[0])?;
+            let dest = Key::try_from(tail[1])?;
+            let from = get_list_end(tail[2])?;
+            let to = get_list_end(tail[3])?;
+            let timeout = Some(get_timeout(tail[4])?);
+            ok!(ListOps::BLMove(source, dest, from, to, timeout))
+        }
+        "brpoplpush" => {
+            verify_size(&tail, 3)?;
+            let source = Key::try_from(tail[0])?;
+            let dest = Key::try_from(tail[1])?;
+            let timeout = Some(get_timeout(tail[2])?);
+            ok!(ListOps::BLMove(
+                source,
+                dest,
+                ListEnd::Right,
+                ListEnd::Left,
+                timeout
+            ))
+        }
+        "blmpop" => {
+            verify_size_lower(&tail, 4)?;
+            let timeout = Some(get_timeout(tail[0])?);
+            let (keys, end, count) = get_lmpop_args(&tail[1..])?;
+            ok!(ListOps::BLMPop(keys, end, count, timeout))
         }
         "rpop" => {
             verify_size(&tail, 1)?;
@@ -476,9 +1884,29 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             ok!(ListOps::RPop(key))
         }
         "linsert" => {
-            verify_size(&tail, 1)?;
+            verify_size(&tail, 4)?;
             let key = Key::try_from(tail[0])?;
-            ok!(ListOps::LPop(key))
+            let at = match String::try_from(tail[1])?.to_lowercase().as_ref() {
+                "before" => InsertAt::Before,
+                "after" => InsertAt::After,
+                _ => return Err(OpsError::SyntaxError),
+            };
+            let pivot = Value::try_from(tail[2])?;
+            let value = Value::try_from(tail[3])?;
+            ok!(ListOps::LInsert(key, at, pivot, value))
+        }
+        "lrem" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let count = Count::try_from(tail[1])?;
+            let value = Value::try_from(tail[2])?;
+            ok!(ListOps::LRem(key, count, value))
+        }
+        "lpos" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let value = Value::try_from(tail[1])?;
+            ok!(ListOps::LPos(key, value, get_pos_options(&tail[2..])?))
         }
         "lindex" => {
             verify_size(&tail, 2)?;
@@ -513,6 +1941,19 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let dest = Key::try_from(tail[1])?;
             ok!(ListOps::RPopLPush(source, dest))
         }
+        "lmove" => {
+            verify_size(&tail, 4)?;
+            let source = Key::try_from(tail[0])?;
+            let dest = Key::try_from(tail[1])?;
+            let from = get_list_end(tail[2])?;
+            let to = get_list_end(tail[3])?;
+            ok!(ListOps::LMove(source, dest, from, to))
+        }
+        "lmpop" => {
+            verify_size_lower(&tail, 3)?;
+            let (keys, end, count) = get_lmpop_args(&tail)?;
+            ok!(ListOps::LMPop(keys, end, count))
+        }
         // Hashes
         "hget" => {
             verify_size(&tail, 2)?;
@@ -534,6 +1975,11 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let value = Key::try_from(tail[2])?;
             ok!(HashOps::HSetNX(key, field, value))
         }
+        "hscan" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(HashOps::HScan(key, get_scan_options(&tail[1..], false)?))
+        }
         "hmset" => {
             verify_size_lower(&tail, 3)?;
             let key = Key::try_from(tail[0])?;
@@ -606,20 +2052,128 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
         "zadd" => {
             verify_size_lower(&tail, 3)?;
             let key = Key::try_from(tail[0])?;
-            let member_scores = get_key_value_pairs(&tail[1..])?;
-            ok!(ZSetOps::ZAdd(key, member_scores))
+            let (options, member_scores) = get_zadd_args(&tail[1..])?;
+            ok!(ZSetOps::ZAdd(key, member_scores, options))
         }
         "zrem" => {
             verify_size_lower(&tail, 2)?;
-            let (key, keys_to_rem) = get_key_and_tail(&array[1..])?;
+            let (key, keys_to_rem) = get_key_and_tail(array)?;
             ok!(ZSetOps::ZRem(key, keys_to_rem))
         }
         "zrange" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let (start, stop) = (get_integer(tail[1])?, get_integer(tail[2])?);
+            let (_, with_scores) = get_zrange_options(&tail[3..], false)?;
+            ok!(ZSetOps::ZRange(key, start, stop, with_scores))
+        }
+        "zrevrange" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let (start, stop) = (get_integer(tail[1])?, get_integer(tail[2])?);
+            let (_, with_scores) = get_zrange_options(&tail[3..], false)?;
+            ok!(ZSetOps::ZRevRange(key, start, stop, with_scores))
+        }
+        "zrangebyscore" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let range = get_score_range(tail[1], tail[2])?;
+            let (limit, with_scores) = get_zrange_options(&tail[3..], true)?;
+            ok!(ZSetOps::ZRangeByScore(key, range, limit, with_scores))
+        }
+        "zrevrangebyscore" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            // Note the order: max comes first.
+            let range = get_score_range(tail[2], tail[1])?;
+            let (limit, with_scores) = get_zrange_options(&tail[3..], true)?;
+            ok!(ZSetOps::ZRevRangeByScore(key, range, limit, with_scores))
+        }
+        "zcount" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let range = get_score_range(tail[1], tail[2])?;
+            ok!(ZSetOps::ZCount(key, range))
+        }
+        "zremrangebyscore" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let range = get_score_range(tail[1], tail[2])?;
+            ok!(ZSetOps::ZRemRangeByScore(key, range))
+        }
+        "zrangebylex" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let range = get_lex_range(tail[1], tail[2])?;
+            let limit = get_lex_limit(&tail[3..])?;
+            ok!(ZSetOps::ZRangeByLex(key, range, limit))
+        }
+        "zrevrangebylex" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            // Note the order: max comes first.
+            let range = get_lex_range(tail[2], tail[1])?;
+            let limit = get_lex_limit(&tail[3..])?;
+            ok!(ZSetOps::ZRevRangeByLex(key, range, limit))
+        }
+        "zlexcount" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let range = get_lex_range(tail[1], tail[2])?;
+            ok!(ZSetOps::ZLexCount(key, range))
+        }
+        "zremrangebylex" => {
             verify_size(&tail, 3)?;
             let key = Key::try_from(tail[0])?;
-            let lower = Score::try_from(tail[1])?;
-            let upper = Score::try_from(tail[2])?;
-            ok!(ZSetOps::ZRange(key, lower, upper))
+            let range = get_lex_range(tail[1], tail[2])?;
+            ok!(ZSetOps::ZRemRangeByLex(key, range))
+        }
+        "zunion" => {
+            let (combination, with_scores) = get_combination("zunion", &tail, true, true)?;
+            ok!(ZSetOps::ZUnion(combination, with_scores))
+        }
+        "zinter" => {
+            let (combination, with_scores) = get_combination("zinter", &tail, true, true)?;
+            ok!(ZSetOps::ZInter(combination, with_scores))
+        }
+        "zdiff" => {
+            let (combination, with_scores) = get_combination("zdiff", &tail, false, true)?;
+            ok!(ZSetOps::ZDiff(combination, with_scores))
+        }
+        "zunionstore" => {
+            verify_size_lower(&tail, 3)?;
+            let dest = Key::try_from(tail[0])?;
+            let (combination, _) = get_combination("zunionstore", &tail[1..], true, false)?;
+            ok!(ZSetOps::ZUnionStore(dest, combination))
+        }
+        "zinterstore" => {
+            verify_size_lower(&tail, 3)?;
+            let dest = Key::try_from(tail[0])?;
+            let (combination, _) = get_combination("zinterstore", &tail[1..], true, false)?;
+            ok!(ZSetOps::ZInterStore(dest, combination))
+        }
+        "zdiffstore" => {
+            verify_size_lower(&tail, 3)?;
+            let dest = Key::try_from(tail[0])?;
+            let (combination, _) = get_combination("zdiffstore", &tail[1..], false, false)?;
+            ok!(ZSetOps::ZDiffStore(dest, combination))
+        }
+        "zintercard" => {
+            let (keys, limit) = get_intercard_args(&tail)?;
+            ok!(ZSetOps::ZInterCard(keys, limit))
+        }
+        "zremrangebyrank" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let (start, stop) = (get_integer(tail[1])?, get_integer(tail[2])?);
+            ok!(ZSetOps::ZRemRangeByRank(key, start, stop))
+        }
+        "zincrby" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let delta = get_float(tail[1])?;
+            let member_key = Key::try_from(tail[2])?;
+            ok!(ZSetOps::ZIncrBy(key, delta, member_key))
         }
         "zcard" => {
             verify_size(&tail, 1)?;
@@ -632,10 +2186,19 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let score = Key::try_from(tail[1])?;
             ok!(ZSetOps::ZScore(key, score))
         }
+        "zmscore" => {
+            verify_size_lower(&tail, 2)?;
+            let (key, member_keys) = get_key_and_tail(array)?;
+            ok!(ZSetOps::ZMScore(key, member_keys))
+        }
         "zpopmax" => {
-            verify_size(&tail, 2)?;
+            verify_size_lower(&tail, 1)?;
             let key = Key::try_from(tail[0])?;
-            let count = Count::try_from(tail[1])?;
+            let count = if tail.len() == 1 {
+                1.into()
+            } else {
+                Count::try_from(tail[1])?
+            };
             ok!(ZSetOps::ZPopMax(key, count))
         }
         "zpopmin" => {
@@ -654,6 +2217,174 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let member_key = Key::try_from(tail[1])?;
             ok!(ZSetOps::ZRank(key, member_key))
         }
+        "zrevrank" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let member_key = Key::try_from(tail[1])?;
+            ok!(ZSetOps::ZRevRank(key, member_key))
+        }
+        // Streams
+        "xadd" => {
+            verify_size_lower(&tail, 4)?;
+            let key = Key::try_from(tail[0])?;
+            let (no_mkstream, trim, id, fields) = get_xadd_args(&tail[1..])?;
+            ok!(StreamOps::XAdd(key, id, fields, trim, no_mkstream))
+        }
+        "xlen" => {
+            verify_size(&tail, 1)?;
+            ok!(StreamOps::XLen(Key::try_from(tail[0])?))
+        }
+        "xrange" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let start = get_stream_bound(tail[1], true)?;
+            let end = get_stream_bound(tail[2], false)?;
+            let count = get_stream_count(&tail[3..])?;
+            ok!(StreamOps::XRange(key, start, end, count))
+        }
+        "xrevrange" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let end = get_stream_bound(tail[1], false)?;
+            let start = get_stream_bound(tail[2], true)?;
+            let count = get_stream_count(&tail[3..])?;
+            ok!(StreamOps::XRevRange(key, end, start, count))
+        }
+        "xdel" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let ids = tail[1..]
+                .iter()
+                .map(|id| get_stream_id(id, 0))
+                .collect::<Result<_, _>>()?;
+            ok!(StreamOps::XDel(key, ids))
+        }
+        "xtrim" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let (trim, taken) = get_stream_trim(&tail[1..])?;
+            if taken != tail.len() - 1 {
+                return Err(OpsError::SyntaxError);
+            }
+            ok!(StreamOps::XTrim(key, trim))
+        }
+        "xread" => {
+            verify_size_lower(&tail, 3)?;
+            let (streams, count, block) = get_xread_args(&tail)?;
+            ok!(StreamOps::XRead(streams, count, block))
+        }
+        "xgroup" => {
+            verify_size_lower(&tail, 1)?;
+            Ok(Ops::Streams(get_xgroup_op(&tail)?))
+        }
+        "xreadgroup" => {
+            verify_size_lower(&tail, 6)?;
+            if !String::try_from(tail[0])?.eq_ignore_ascii_case("group") {
+                return Err(OpsError::SyntaxError);
+            }
+            let group = Key::try_from(tail[1])?;
+            let consumer = Key::try_from(tail[2])?;
+            let (streams, count, block, no_ack) = get_xreadgroup_args(&tail[3..])?;
+            ok!(StreamOps::XReadGroup(
+                group, consumer, streams, count, block, no_ack
+            ))
+        }
+        "xack" => {
+            verify_size_lower(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let group = Key::try_from(tail[1])?;
+            let ids = tail[2..]
+                .iter()
+                .map(|id| get_stream_id(id, 0))
+                .collect::<Result<_, _>>()?;
+            ok!(StreamOps::XAck(key, group, ids))
+        }
+        "xpending" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let group = Key::try_from(tail[1])?;
+            let range = get_pending_range(&tail[2..])?;
+            ok!(StreamOps::XPending(key, group, range))
+        }
+        "xclaim" => {
+            verify_size_lower(&tail, 5)?;
+            let key = Key::try_from(tail[0])?;
+            let group = Key::try_from(tail[1])?;
+            let consumer = Key::try_from(tail[2])?;
+            let mut options = ClaimOptions {
+                min_idle: get_millis(tail[3], "ERR Invalid min-idle-time argument for XCLAIM")?,
+                ..Default::default()
+            };
+            let ids = get_xclaim_args(&tail[4..], &mut options)?;
+            ok!(StreamOps::XClaim(key, group, consumer, ids, options))
+        }
+        "xautoclaim" => {
+            verify_size_lower(&tail, 5)?;
+            let key = Key::try_from(tail[0])?;
+            let group = Key::try_from(tail[1])?;
+            let consumer = Key::try_from(tail[2])?;
+            let mut options = ClaimOptions {
+                min_idle: get_millis(tail[3], "ERR Invalid min-idle-time argument for XAUTOCLAIM")?,
+                ..Default::default()
+            };
+            let start = get_stream_bound(tail[4], true)?;
+            let count = get_xautoclaim_args(&tail[5..], &mut options)?;
+            ok!(StreamOps::XAutoClaim(
+                key, group, consumer, start, count, options
+            ))
+        }
+        "xinfo" => {
+            verify_size_lower(&tail, 1)?;
+            Ok(Ops::Streams(get_xinfo_op(&tail)?))
+        }
+        // Geo
+        "geoadd" => {
+            verify_size_lower(&tail, 4)?;
+            let key = Key::try_from(tail[0])?;
+            let (options, members) = get_geoadd_args(&tail[1..])?;
+            ok!(ZSetOps::ZAdd(key, members, options))
+        }
+        "geopos" => {
+            verify_size_lower(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(GeoOps::GeoPos(key, collect_from_tail(&tail[1..])?))
+        }
+        "geodist" => {
+            if !(3..=4).contains(&tail.len()) {
+                return Err(OpsError::WrongNumberOfArgs(3, tail.len()));
+            }
+            let key = Key::try_from(tail[0])?;
+            let from = Key::try_from(tail[1])?;
+            let to = Key::try_from(tail[2])?;
+            let unit = match tail.get(3) {
+                Some(unit) => get_geo_unit(unit)?,
+                None => GeoUnit::default(),
+            };
+            ok!(GeoOps::GeoDist(key, from, to, unit))
+        }
+        "geohash" => {
+            verify_size_lower(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(GeoOps::GeoHash(key, collect_from_tail(&tail[1..])?))
+        }
+        "geosearch" => {
+            verify_size_lower(&tail, 5)?;
+            let key = Key::try_from(tail[0])?;
+            let (query, _) = get_geo_query("geosearch", &tail[1..], false)?;
+            ok!(GeoOps::GeoSearch(key, query))
+        }
+        "geosearchstore" => {
+            verify_size_lower(&tail, 6)?;
+            let dest = Key::try_from(tail[0])?;
+            let source = Key::try_from(tail[1])?;
+            let (query, store_dist) = get_geo_query("geosearchstore", &tail[2..], true)?;
+            ok!(GeoOps::GeoSearchStore(dest, source, query, store_dist))
+        }
+        "zscan" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(ZSetOps::ZScan(key, get_scan_options(&tail[1..], false)?))
+        }
         // Bloom filters
         "binsert" => {
             verify_size(&tail, 2)?;
@@ -682,6 +2413,37 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             verify_size(&tail, 0)?;
             ok!(MiscOps::Info())
         }
+        "hello" => {
+            if tail.len() > 1 {
+                return Err(OpsError::InvalidArgs(
+                    "HELLO options (AUTH, SETNAME) are not supported".to_string(),
+                ));
+            }
+            let protocol = tail.first().map(|p| Count::try_from(*p)).transpose()?;
+            ok!(MiscOps::Hello(protocol))
+        }
+        // Transactions
+        "multi" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::Multi())
+        }
+        "exec" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::Exec())
+        }
+        "discard" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::Discard())
+        }
+        "watch" => {
+            verify_size_lower(&tail, 1)?;
+            let keys = values_from_tail(&tail)?;
+            ok!(MiscOps::Watch(keys))
+        }
+        "unwatch" => {
+            verify_size(&tail, 0)?;
+            ok!(MiscOps::Unwatch())
+        }
         // StackOps
         "stpush" => {
             verify_size(&tail, 2)?;
@@ -722,6 +2484,143 @@ fn translate_array(array: &[RedisValueRef], state_store: StateStoreRef) -> Resul
             let sources = collect_from_tail(&tail[1..])?;
             ok!(HyperLogLogOps::PfMerge(dest, sources))
         }
+        // Bitmaps
+        "setbit" => {
+            verify_size(&tail, 3)?;
+            let key = Key::try_from(tail[0])?;
+            let offset = get_bit_offset(tail[1])?;
+            let bit = match get_integer(tail[2]) {
+                Ok(bit @ 0..=1) => bit == 1,
+                _ => {
+                    return Err(OpsError::InvalidArgs(
+                        "ERR bit is not an integer or out of range".to_string(),
+                    ))
+                }
+            };
+            ok!(BitmapOps::SetBit(key, offset, bit))
+        }
+        "getbit" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(BitmapOps::GetBit(key, get_bit_offset(tail[1])?))
+        }
+        "bitcount" => {
+            verify_size_lower(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(BitmapOps::BitCount(key, get_bit_range(&tail[1..], false)?))
+        }
+        "bitpos" => {
+            verify_size_lower(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let bit = match get_integer(tail[1]) {
+                Ok(bit @ 0..=1) => bit == 1,
+                _ => {
+                    return Err(OpsError::InvalidArgs(
+                        "ERR The bit argument must be 1 or 0.".to_string(),
+                    ))
+                }
+            };
+            ok!(BitmapOps::BitPos(
+                key,
+                bit,
+                get_bit_range(&tail[2..], true)?
+            ))
+        }
+        "bitop" => {
+            verify_size_lower(&tail, 3)?;
+            let op = String::try_from(tail[0])?
+                .parse::<BitOperation>()
+                .map_err(|_| OpsError::SyntaxError)?;
+            let dest = Key::try_from(tail[1])?;
+            let sources = collect_from_tail(&tail[2..])?;
+            if op == BitOperation::Not && sources.len() != 1 {
+                return Err(OpsError::InvalidArgs(
+                    "ERR BITOP NOT must be called with a single source key.".to_string(),
+                ));
+            }
+            ok!(BitmapOps::BitOp(op, dest, sources))
+        }
+        "bitfield" => {
+            verify_size_lower(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(BitmapOps::BitField(key, get_bitfield_ops(&tail[1..])?))
+        }
+        // Expiry
+        "expire" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let secs = Count::try_from(tail[1])?;
+            ok!(ExpiryOps::Expire(key, secs))
+        }
+        "pexpire" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let millis = Count::try_from(tail[1])?;
+            ok!(ExpiryOps::PExpire(key, millis))
+        }
+        "expireat" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let unix_secs = Count::try_from(tail[1])?;
+            ok!(ExpiryOps::ExpireAt(key, unix_secs))
+        }
+        "pexpireat" => {
+            verify_size(&tail, 2)?;
+            let key = Key::try_from(tail[0])?;
+            let unix_millis = Count::try_from(tail[1])?;
+            ok!(ExpiryOps::PExpireAt(key, unix_millis))
+        }
+        "ttl" => {
+            verify_size(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(ExpiryOps::Ttl(key))
+        }
+        "pttl" => {
+            verify_size(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(ExpiryOps::PTtl(key))
+        }
+        "persist" => {
+            verify_size(&tail, 1)?;
+            let key = Key::try_from(tail[0])?;
+            ok!(ExpiryOps::Persist(key))
+        }
+        // Pub/Sub
+        "subscribe" => {
+            verify_size_lower(&tail, 1)?;
+            ok!(PubSubOps::Subscribe(values_from_tail(&tail)?))
+        }
+        "unsubscribe" => ok!(PubSubOps::Unsubscribe(values_from_tail(&tail)?)),
+        "psubscribe" => {
+            verify_size_lower(&tail, 1)?;
+            ok!(PubSubOps::PSubscribe(values_from_tail(&tail)?))
+        }
+        "punsubscribe" => ok!(PubSubOps::PUnsubscribe(values_from_tail(&tail)?)),
+        "publish" => {
+            verify_size(&tail, 2)?;
+            let channel = Key::try_from(tail[0])?;
+            let message = Value::try_from(tail[1])?;
+            ok!(PubSubOps::Publish(channel, message))
+        }
+        "pubsub" => {
+            verify_size_lower(&tail, 1)?;
+            let sub_command = String::try_from(tail[0])?;
+            match sub_command.to_lowercase().as_ref() {
+                "channels" => {
+                    if tail.len() > 2 {
+                        return Err(OpsError::WrongNumberOfArgs(2, tail.len()));
+                    }
+                    let pattern = tail.get(1).map(|p| Key::try_from(*p)).transpose()?;
+                    ok!(PubSubOps::PubSubChannels(pattern))
+                }
+                "numsub" => ok!(PubSubOps::PubSubNumSub(values_from_tail(&tail[1..])?)),
+                "numpat" => {
+                    verify_size(&tail, 1)?;
+                    ok!(PubSubOps::PubSubNumPat())
+                }
+                _ => Err(OpsError::UnknownOp),
+            }
+        }
         _ => Err(OpsError::UnknownOp),
     }
 }
@@ -733,3 +2632,52 @@ pub fn translate(rv: RedisValueRef, state_store: StateStoreRef) -> Result<Ops, O
         _ => Err(OpsError::UnknownOp),
     }
 }
+
+#[cfg(test)]
+mod test_ops {
+    use crate::bitmaps::MAX_BIT_OFFSET;
+    use crate::lists::ListOps;
+    use crate::ops::{get_bitfield_ops, op_interact, Ops};
+    use crate::sets::SetOps;
+    use crate::types::{RedisValueRef, State};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    fn bitfield_offset_ok(ty: &str, offset: &str) -> bool {
+        let args: Vec<RedisValueRef> = ["set", ty, offset, "1"]
+            .iter()
+            .map(|arg| RedisValueRef::BulkString(Bytes::from(arg.to_string())))
+            .collect();
+        get_bitfield_ops(&args.iter().collect::<Vec<_>>()).is_ok()
+    }
+
+    #[test]
+    fn test_bitfield_offsets_in_range() {
+        let last = (MAX_BIT_OFFSET - 7).to_string();
+        assert!(bitfield_offset_ok("u8", &last));
+        assert!(!bitfield_offset_ok("u8", &(MAX_BIT_OFFSET - 6).to_string()));
+        assert!(!bitfield_offset_ok("u8", &i64::MAX.to_string()));
+        assert!(!bitfield_offset_ok("i64", &i64::MAX.to_string()));
+        assert!(!bitfield_offset_ok("u8", "-1"));
+        assert!(bitfield_offset_ok("u8", "#0"));
+        assert!(!bitfield_offset_ok("u8", &format!("#{}", i64::MAX / 8)));
+        assert!(!bitfield_offset_ok("u8", &format!("#{}", i64::MAX)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_new_key_gets_one_type() {
+        let state = Arc::new(State::default());
+        for i in 0..200 {
+            let key = Bytes::from(format!("key_{}", i));
+            let value = Bytes::from_static(b"v");
+            let push = Ops::Lists(ListOps::LPush(key.clone(), smallvec![value.clone()]));
+            let add = Ops::Sets(SetOps::SAdd(key.clone(), smallvec![value]));
+            let push = tokio::spawn(op_interact(push, state.clone()));
+            let add = tokio::spawn(op_interact(add, state.clone()));
+            push.await.unwrap();
+            add.await.unwrap();
+            assert!(state.lists.contains_key(&key) != state.sets.contains_key(&key));
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use crate::client::ClientId;
+use crate::glob::glob_match;
+use crate::op_variants;
+use crate::types::{Count, Key, RedisValueRef, ReturnValue, StateStoreRef, Value};
+use bytes::Bytes;
+use dashmap::DashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+
+op_variants! {
+    PubSubOps,
+    Subscribe(Vec<Key>),
+    Unsubscribe(Vec<Key>),
+    PSubscribe(Vec<Key>),
+    PUnsubscribe(Vec<Key>),
+    Publish(Key, Value),
+    PubSubChannels(Option<Key>),
+    PubSubNumSub(Vec<Key>),
+    PubSubNumPat()
+}
+
+impl PubSubOps {
+    /// Subscription commands are the only ones allowed once a
+    /// connection is subscribed to something.
+    pub fn is_subscription(&self) -> bool {
+        matches!(
+            self,
+            PubSubOps::Subscribe(_)
+                | PubSubOps::Unsubscribe(_)
+                | PubSubOps::PSubscribe(_)
+                | PubSubOps::PUnsubscribe(_)
+        )
+    }
+}
+
+pub type PushSender = UnboundedSender<RedisValueRef>;
+
+/// Routes published messages to subscribed connections.
+#[derive(Default, Debug)]
+pub struct Broker {
+    channels: DashMap<Key, HashMap<ClientId, PushSender>>,
+    patterns: DashMap<Key, HashMap<ClientId, PushSender>>,
+}
+
+fn bulk(b: Bytes) -> RedisValueRef {
+    RedisValueRef::BulkString(b)
+}
+
+/// Add `id` under `name`. Returns true if it wasn't there already.
+fn add(
+    map: &DashMap<Key, HashMap<ClientId, PushSender>>,
+    name: Key,
+    id: ClientId,
+    sender: &PushSender,
+) -> bool {
+    map.entry(name)
+        .or_default()
+        .insert(id, sender.clone())
+        .is_none()
+}
+
+fn remove(map: &DashMap<Key, HashMap<ClientId, PushSender>>, name: &[u8], id: ClientId) {
+    if let Some(mut subs) = map.get_mut(name) {
+        subs.remove(&id);
+    }
+    map.remove_if(name, |_, subs| subs.is_empty());
+}
+
+impl Broker {
+    /// Send `message` to everyone subscribed to `channel`, directly
+    /// or through a pattern. Returns the number of receivers.
+    pub fn publish(&self, channel: &Key, message: &Value) -> Count {
+        let mut receivers = 0;
+        if let Some(subs) = self.channels.get(channel) {
+            for sender in subs.values() {
+                let msg = RedisValueRef::Push(vec![
+                    bulk(Bytes::from_static(b"message")),
+                    bulk(channel.clone()),
+                    bulk(message.clone()),
+                ]);
+                if sender.send(msg).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        for ent in self.patterns.iter() {
+            if !glob_match(ent.key(), channel) {
+                continue;
+            }
+            for sender in ent.value().values() {
+                let msg = RedisValueRef::Push(vec![
+                    bulk(Bytes::from_static(b"pmessage")),
+                    bulk(ent.key().clone()),
+                    bulk(channel.clone()),
+                    bulk(message.clone()),
+                ]);
+                if sender.send(msg).is_ok() {
+                    receivers += 1;
+                }
+            }
+        }
+        receivers
+    }
+
+    /// Active channels, optionally filtered by a glob pattern.
+    pub fn channels(&self, pattern: Option<&[u8]>) -> Vec<Key> {
+        self.channels
+            .iter()
+            .filter(|ent| match pattern {
+                Some(p) => glob_match(p, ent.key()),
+                None => true,
+            })
+            .map(|ent| ent.key().clone())
+            .collect()
+    }
+
+    /// Number of subscribers to a channel (not counting patterns).
+    pub fn num_sub(&self, channel: &[u8]) -> Count {
+        self.channels
+            .get(channel)
+            .map_or(0, |subs| subs.len() as Count)
+    }
+
+    /// Number of unique patterns subscribed to.
+    pub fn num_pat(&self) -> Count {
+        self.patterns.len() as Count
+    }
+}
+
+/// The pub/sub side of a connection.
+///
+/// Connections that can't receive pushed messages (e.g. scripts)
+/// have no `push` sender, and can't subscribe.
+#[derive(Default)]
+pub struct Subscriber {
+    id: ClientId,
+    push: Option<PushSender>,
+    broker: Option<Arc<Broker>>,
+    channels: HashSet<Key>,
+    patterns: HashSet<Key>,
+}
+
+impl Subscriber {
+    pub fn new(id: ClientId, push: PushSender) -> Self {
+        Subscriber {
+            id,
+            push: Some(push),
+            broker: None,
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+        }
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        self.subscription_count() != 0
+    }
+
+    fn subscription_count(&self) -> Count {
+        (self.channels.len() + self.patterns.len()) as Count
+    }
+
+    fn confirmation(&self, kind: &'static [u8], name: Option<Key>) -> RedisValueRef {
+        RedisValueRef::Push(vec![
+            bulk(Bytes::from_static(kind)),
+            name.map_or(RedisValueRef::NullBulkString, bulk),
+            RedisValueRef::Int(self.subscription_count()),
+        ])
+    }
+
+    /// Handle a (un)subscribe command.
+    ///
+    /// Redis sends one confirmation per channel. All but the last are
+    /// pushed; the last is the reply to the command.
+    fn subscription(&mut self, op: PubSubOps, broker: &Arc<Broker>) -> ReturnValue {
+        let push = match &self.push {
+            Some(push) => push.clone(),
+            None => return ReturnValue::Error(b"ERR pub/sub is not allowed in this context"),
+        };
+        self.broker.get_or_insert_with(|| broker.clone());
+        let mut confirmations = Vec::new();
+        match op {
+            PubSubOps::Subscribe(channels) => {
+                for channel in channels {
+                    add(&broker.channels, channel.clone(), self.id, &push);
+                    self.channels.insert(channel.clone());
+                    confirmations.push(self.confirmation(b"subscribe", Some(channel)));
+                }
+            }
+            PubSubOps::PSubscribe(patterns) => {
+                for pattern in patterns {
+                    add(&broker.patterns, pattern.clone(), self.id, &push);
+                    self.patterns.insert(pattern.clone());
+                    confirmations.push(self.confirmation(b"psubscribe", Some(pattern)));
+                }
+            }
+            PubSubOps::Unsubscribe(channels) => {
+                let channels = if channels.is_empty() {
+                    self.channels.iter().cloned().collect()
+                } else {
+                    channels
+                };
+                for channel in channels {
+                    remove(&broker.channels, &channel, self.id);
+                    self.channels.remove(&channel);
+                    confirmations.push(self.confirmation(b"unsubscribe", Some(channel)));
+                }
+                if confirmations.is_empty() {
+                    confirmations.push(self.confirmation(b"unsubscribe", None));
+                }
+            }
+            PubSubOps::PUnsubscribe(patterns) => {
+                let patterns = if patterns.is_empty() {
+                    self.patterns.iter().cloned().collect()
+                } else {
+                    patterns
+                };
+                for pattern in patterns {
+                    remove(&broker.patterns, &pattern, self.id);
+                    self.patterns.remove(&pattern);
+                    confirmations.push(self.confirmation(b"punsubscribe", Some(pattern)));
+                }
+                if confirmations.is_empty() {
+                    confirmations.push(self.confirmation(b"punsubscribe", None));
+                }
+            }
+            _ => unreachable!(),
+        }
+        let last = confirmations.pop();
+        for confirmation in confirmations {
+            let _ = push.send(confirmation);
+        }
+        last.map_or(ReturnValue::Nil, ReturnValue::Ident)
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        if let Some(broker) = &self.broker {
+            for channel in self.channels.iter() {
+                remove(&broker.channels, channel, self.id);
+            }
+            for pattern in self.patterns.iter() {
+                remove(&broker.patterns, pattern, self.id);
+            }
+        }
+    }
+}
+
+pub fn pubsub_interact(
+    pubsub_op: PubSubOps,
+    state_store: StateStoreRef,
+    subscriber: &mut Subscriber,
+) -> ReturnValue {
+    let broker = &state_store.pubsub;
+    match pubsub_op {
+        PubSubOps::Publish(channel, message) => {
+            ReturnValue::IntRes(broker.publish(&channel, &message))
+        }
+        PubSubOps::PubSubChannels(pattern) => {
+            ReturnValue::MultiStringRes(broker.channels(pattern.as_deref()))
+        }
+        PubSubOps::PubSubNumSub(channels) => ReturnValue::Map(
+            channels
+                .into_iter()
+                .map(|channel| {
+                    let count = broker.num_sub(&channel);
+                    (ReturnValue::StringRes(channel), ReturnValue::IntRes(count))
+                })
+                .collect(),
+        ),
+        PubSubOps::PubSubNumPat() => ReturnValue::IntRes(broker.num_pat()),
+        op => subscriber.subscription(op, broker),
+    }
+}
+
+#[cfg(test)]
+mod test_pubsub {
+    use crate::pubsub::{pubsub_interact, PubSubOps, Subscriber};
+    use crate::types::{RedisValueRef, ReturnValue, StateStore};
+    use bytes::Bytes;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[tokio::test]
+    async fn test_publish_subscribe() {
+        let store = Arc::new(StateStore::default());
+        let (sx, mut rx) = unbounded_channel();
+        let mut sub = Subscriber::new(1, sx);
+        let mut publisher = Subscriber::default();
+        let (chan, msg) = (Bytes::from_static(b"chan"), Bytes::from_static(b"msg"));
+
+        let res = pubsub_interact(
+            PubSubOps::Subscribe(vec![chan.clone()]),
+            store.clone(),
+            &mut sub,
+        );
+        assert!(!res.is_error());
+        assert!(sub.is_subscribed());
+        let res = pubsub_interact(
+            PubSubOps::Publish(chan.clone(), msg.clone()),
+            store.clone(),
+            &mut publisher,
+        );
+        assert_eq!(res, ReturnValue::IntRes(1));
+        assert_eq!(
+            rx.recv().await,
+            Some(RedisValueRef::Push(vec![
+                RedisValueRef::BulkString(Bytes::from_static(b"message")),
+                RedisValueRef::BulkString(chan.clone()),
+                RedisValueRef::BulkString(msg.clone()),
+            ]))
+        );
+        drop(sub);
+        let res = pubsub_interact(PubSubOps::Publish(chan, msg), store.clone(), &mut publisher);
+        assert_eq!(res, ReturnValue::IntRes(0));
+        assert_eq!(
+            pubsub_interact(PubSubOps::PubSubChannels(None), store, &mut publisher),
+            ReturnValue::MultiStringRes(vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_psubscribe() {
+        let store = Arc::new(StateStore::default());
+        let (sx, mut rx) = unbounded_channel();
+        let mut sub = Subscriber::new(1, sx);
+        let mut publisher = Subscriber::default();
+        let pattern = Bytes::from_static(b"news.*");
+        pubsub_interact(
+            PubSubOps::PSubscribe(vec![pattern.clone(), Bytes::from_static(b"n*")]),
+            store.clone(),
+            &mut sub,
+        );
+        // The first confirmation is pushed, the second is the reply.
+        assert!(rx.try_recv().is_ok());
+        let res = pubsub_interact(
+            PubSubOps::Publish(Bytes::from_static(b"news.tech"), Bytes::from_static(b"hi")),
+            store.clone(),
+            &mut publisher,
+        );
+        assert_eq!(res, ReturnValue::IntRes(2));
+        assert_eq!(
+            pubsub_interact(PubSubOps::PubSubNumPat(), store.clone(), &mut publisher),
+            ReturnValue::IntRes(2)
+        );
+        pubsub_interact(PubSubOps::PUnsubscribe(vec![]), store.clone(), &mut sub);
+        assert!(!sub.is_subscribed());
+        assert_eq!(
+            pubsub_interact(PubSubOps::PubSubNumPat(), store, &mut publisher),
+            ReturnValue::IntRes(0)
+        );
+    }
+
+    #[test]
+    fn test_subscribe_without_push() {
+        let store = Arc::new(StateStore::default());
+        let mut sub = Subscriber::default();
+        let res = pubsub_interact(
+            PubSubOps::Subscribe(vec![Bytes::from_static(b"chan")]),
+            store,
+            &mut sub,
+        );
+        assert!(res.is_error());
+    }
+}
@@ -0,0 +1,72 @@
+/// Cursor based iteration for SCAN, SSCAN, HSCAN and ZSCAN.
+///
+/// DashMaps and HashSets can't be resumed from a saved position, so the
+/// cursor is a position in hash order instead: each call returns the
+/// elements with the smallest hashes at or after the cursor. Sets, hashes
+/// and sorted sets are kept in that order; keys are ordered within each
+/// DashMap shard, and the cursor also says which shard. Like redis, an
+/// element present for the whole iteration is returned, while one added
+/// or removed midway may or may not be.
+use crate::glob::glob_match;
+use crate::types::{ReturnValue, StateRef, Value};
+
+pub const DEFAULT_SCAN_COUNT: usize = 10;
+
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    pub cursor: u64,
+    /// MATCH: only return elements matching this glob.
+    pub pattern: Option<Value>,
+    /// COUNT: roughly how many elements to look at.
+    pub count: usize,
+    /// TYPE: only return keys holding this type. SCAN only.
+    pub value_type: Option<String>,
+}
+
+impl ScanOptions {
+    /// Whether `item` matches the MATCH pattern, if any.
+    pub fn matches(&self, item: &[u8]) -> bool {
+        match &self.pattern {
+            Some(pattern) => glob_match(pattern, item),
+            None => true,
+        }
+    }
+
+    /// Whether `key` is a live key matching the MATCH and TYPE options.
+    pub fn matches_key(&self, state: &StateRef, key: &[u8]) -> bool {
+        if state.expire_if_needed(key) || !self.matches(key) {
+            return false;
+        }
+        match &self.value_type {
+            Some(wanted) => state
+                .value_type(key)
+                .is_some_and(|t| t.name().eq_ignore_ascii_case(wanted)),
+            None => true,
+        }
+    }
+}
+
+/// The reply to a scan: the next cursor and the elements.
+pub fn scan_reply(cursor: u64, items: Vec<Value>) -> ReturnValue {
+    ReturnValue::Array(vec![
+        ReturnValue::StringRes(cursor.to_string().into()),
+        ReturnValue::MultiStringRes(items),
+    ])
+}
+
+#[cfg(test)]
+mod test_scan {
+    use crate::scan::ScanOptions;
+
+    #[test]
+    fn test_matches() {
+        let options = ScanOptions {
+            cursor: 0,
+            pattern: Some("user:*".into()),
+            count: 10,
+            value_type: None,
+        };
+        assert!(options.matches(b"user:1"));
+        assert!(!options.matches(b"session:1"));
+    }
+}
@@ -3,6 +3,8 @@ use num_traits::cast::ToPrimitive;
 use std::{error::Error, sync::Arc};
 use tokio::sync::mpsc::{Receiver, Sender};
 
+use crate::asyncresp::format_double;
+use crate::client::Client;
 use crate::startup::Config;
 use crate::types::DumpFile;
 use crate::types::RedisValueRef;
@@ -54,7 +56,24 @@ impl ForeignData for RedisValueRef {
             RedisValueRef::Array(a) => {
                 Expr::Tuple(a.iter().map(|ele| ele.to_x9()).collect::<Result<_, _>>()?)
             }
-            RedisValueRef::NullArray | RedisValueRef::NullBulkString => Expr::Nil,
+            RedisValueRef::NullArray | RedisValueRef::NullBulkString | RedisValueRef::Null => {
+                Expr::Nil
+            }
+            RedisValueRef::Map(pairs) => Expr::Tuple(
+                pairs
+                    .iter()
+                    .flat_map(|(k, v)| vec![k.to_x9(), v.to_x9()])
+                    .collect::<Result<_, _>>()?,
+            ),
+            RedisValueRef::Set(a) | RedisValueRef::Push(a) => {
+                Expr::Tuple(a.iter().map(|ele| ele.to_x9()).collect::<Result<_, _>>()?)
+            }
+            RedisValueRef::Double(d) => Expr::String(format_double(*d).into()),
+            RedisValueRef::Boolean(b) => Expr::Bool(*b),
+            RedisValueRef::BigNumber(s) | RedisValueRef::VerbatimString(_, s) => {
+                Expr::String(bytes_to_string(s).into())
+            }
+            RedisValueRef::Attribute(_, value) => return value.to_x9(),
         };
         Ok(res)
     }
@@ -139,6 +158,7 @@ pub async fn handle_redis_cmd(
     // TODO: Support, or return an error when interacting with
     // change db commands
     let mut state = state_store.get_default();
+    let mut client = Client::for_scripts();
     while let Some((cmd, return_channel)) = cmd_recv.recv().await {
         debug!(LOGGER, "Recieved redis command: {:?}", cmd);
         let res = process_command(
@@ -146,6 +166,7 @@ pub async fn handle_redis_cmd(
             state_store.clone(),
             dump_file.clone(),
             scripting_engine.clone(),
+            &mut client,
             RedisValueRef::Array(cmd),
         )
         .await;
@@ -1,36 +1,202 @@
-use crate::database::save_state;
-use crate::misc::misc_interact;
+use crate::aof::{self, AofWriter, Record};
+use crate::blocking::{Retries, RETRIES};
+use crate::client::Client;
+use crate::memory::{evict_keys, OOM_ERROR};
+use crate::misc::{misc_interact, MiscOps};
 use crate::ops::{op_interact, Ops};
+use crate::pubsub::pubsub_interact;
 /// Server launch file. Starts the services to make redis-proto work.
-use crate::{asyncresp::RespParser, scripting::ScriptingBridge};
+use crate::{
+    asyncresp::{ProtocolVersion, RespParser},
+    scripting::ScriptingBridge,
+};
 use crate::{logger::LOGGER, types::StateRef};
 use crate::{
     ops::translate,
     startup::Config,
-    types::{DumpFile, RedisValueRef, ReturnValue, StateStoreRef},
+    types::{DumpFile, Index, RedisValueRef, ReturnValue, StateStoreRef},
 };
+use bytes::Bytes;
 use futures::StreamExt;
 use futures_util::sink::SinkExt;
+use std::collections::VecDeque;
 use std::sync::atomic::Ordering;
 use std::{net::SocketAddr, sync::Arc};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::OwnedMutexGuard;
 use tokio_util::codec::Decoder;
 
-fn incr_and_save_if_required(state: StateStoreRef, dump_file: DumpFile) {
-    state.commands_ran_since_save.fetch_add(1, Ordering::SeqCst);
-    let should_save = state.commands_ran_since_save.compare_exchange(
-        state.commands_threshold,
-        0,
-        Ordering::SeqCst,
-        Ordering::SeqCst,
-    );
-    if should_save.is_ok() {
-        let state_clone = state;
-        let dump_file_clone = dump_file;
-        tokio::spawn(async {
-            save_state(state_clone, dump_file_clone);
-        });
+/// Run `op`, logging `command` to the append only file if it's a write.
+///
+/// `command` is only needed when the append only file is enabled.
+/// Run `op`, logging it to the append only file. `held_aof` is the log
+/// when a transaction holds it, and is left held.
+#[allow(clippy::too_many_arguments)]
+async fn run_op(
+    op: Ops,
+    command: Option<RedisValueRef>,
+    db: &mut Index,
+    state: &mut StateRef,
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+    held_aof: &mut Option<OwnedMutexGuard<AofWriter>>,
+) -> ReturnValue {
+    let aof = state_store.aof.clone();
+    let record = Record::of(&op);
+    let changes_data = record.is_some();
+    let record = match (&aof, &command) {
+        (Some(_), Some(_)) => record,
+        _ => None,
+    };
+    // Writes hold the log while running so they're logged in order.
+    // Blocking ops wait on other writers, so the retry serving them takes it.
+    let retries = op.is_blocking().then(|| {
+        let aof = record.as_ref().and(aof.clone());
+        Arc::new(Retries::new(state_store.exec_lock.clone(), aof))
+    });
+    let in_transaction = held_aof.is_some();
+    let mut aof_writer = match (&aof, &record) {
+        _ if in_transaction => held_aof.take(),
+        (Some(aof), Some(_)) if retries.is_none() => Some(aof.lock().await),
+        _ => None,
+    };
+    // Step 0: Make room for anything the operation adds
+    if op.may_grow() {
+        let (evicted, out_of_memory) = match evict_keys(&state_store) {
+            Ok(evicted) => (evicted, false),
+            Err(evicted) => (evicted, true),
+        };
+        // Log what was evicted even if the op can't run after all.
+        if let (Some(aof), false) = (&aof, evicted.is_empty()) {
+            let mut writer = match aof_writer.take() {
+                Some(writer) => writer,
+                None => aof.lock().await,
+            };
+            for (index, key) in evicted {
+                if let Err(e) = writer.append(index, vec![aof::eviction(key)]) {
+                    error!(LOGGER, "Failed to write to the append only file! {:?}", e);
+                }
+            }
+            if retries.is_none() {
+                aof_writer = Some(writer);
+            }
+        }
+        if out_of_memory {
+            if in_transaction {
+                *held_aof = aof_writer;
+            }
+            return ReturnValue::Error(OOM_ERROR);
+        }
+    }
+    let selected = match op {
+        Ops::Misc(MiscOps::Select(index)) => Some(index),
+        _ => None,
+    };
+    // Step 1: Execute the operation the operation (from translate above)
+    let res: ReturnValue = match op {
+        Ops::Misc(op) => {
+            misc_interact(op, state, state_store.clone(), dump_file, scripting_bridge).await
+        }
+        op => match &retries {
+            Some(retries) => {
+                RETRIES
+                    .scope(retries.clone(), op_interact(op, state.clone()))
+                    .await
+            }
+            None => op_interact(op, state.clone()).await,
+        },
+    };
+    if let Some(retries) = &retries {
+        aof_writer = retries.take_writer();
+    }
+    if let (Some(index), ReturnValue::Ok) = (selected, &res) {
+        *db = index;
+    }
+    // Step 2: Log the write to the append only file
+    if let (Some(aof), Some(record), Some(command)) = (&aof, record, command) {
+        let commands = record.commands(command, &res, state);
+        let writer = match aof_writer.take() {
+            Some(writer) => writer,
+            None => aof.lock().await,
+        };
+        let writer = aof_writer.insert(writer);
+        if let Err(e) = writer.append(*db, commands) {
+            error!(LOGGER, "Failed to write to the append only file! {:?}", e);
+        }
+    }
+    if in_transaction {
+        *held_aof = aof_writer.take();
+    }
+    drop(aof_writer);
+    // Step 3: Count the change towards the save points
+    if changes_data && !res.is_error() {
+        state_store
+            .commands_ran_since_save
+            .fetch_add(1, Ordering::SeqCst);
+    }
+    res
+}
+
+/// Run the queued operations of a transaction, without letting
+/// other connections interleave their commands.
+async fn exec(
+    state: &mut StateRef,
+    state_store: StateStoreRef,
+    dump_file: DumpFile,
+    scripting_bridge: Arc<ScriptingBridge>,
+    client: &mut Client,
+) -> ReturnValue {
+    let transaction = &mut client.transaction;
+    let queued = match transaction.take_for_exec() {
+        Ok(queued) => queued,
+        Err(e) => return e,
+    };
+    let _guard = state_store.exec_lock.write().await;
+    let watched_keys_changed = transaction.watched_keys_changed();
+    transaction.unwatch();
+    if watched_keys_changed {
+        return ReturnValue::Ident(RedisValueRef::NullArray);
     }
+    // Log the transaction as one block, holding the log so nothing else
+    // is logged in the middle of it.
+    let mut aof_writer = match &state_store.aof {
+        Some(aof) => Some(aof.lock().await),
+        None => None,
+    };
+    if let Some(writer) = aof_writer.as_mut() {
+        writer.begin_transaction();
+    }
+    let mut results = Vec::with_capacity(queued.len());
+    for (op, command) in queued {
+        let res = match op {
+            // EXEC unwatches everything anyway.
+            Ops::Misc(MiscOps::Unwatch()) => ReturnValue::Ok,
+            // Subscriptions can't be queued, but PUBLISH and PUBSUB can.
+            Ops::PubSub(op) => pubsub_interact(op, state_store.clone(), &mut client.subscriber),
+            op => {
+                run_op(
+                    op,
+                    command,
+                    &mut client.db,
+                    state,
+                    state_store.clone(),
+                    dump_file.clone(),
+                    scripting_bridge.clone(),
+                    &mut aof_writer,
+                )
+                .await
+            }
+        };
+        results.push(res);
+    }
+    if let Some(writer) = aof_writer.as_mut() {
+        if let Err(e) = writer.end_transaction() {
+            error!(LOGGER, "Failed to write to the append only file! {:?}", e);
+        }
+    }
+    ReturnValue::Array(results)
 }
 
 pub async fn process_command(
@@ -38,27 +204,101 @@ pub async fn process_command(
     state_store: StateStoreRef,
     dump_file: DumpFile,
     scripting_bridge: Arc<ScriptingBridge>,
+    client: &mut Client,
     redis_value: RedisValueRef,
 ) -> RedisValueRef {
-    match translate(redis_value, state_store.clone()) {
-        Ok(op) => {
-            debug!(LOGGER, "running op {:?}", op.clone());
-            // Step 1: Execute the operation the operation (from translate above)
-            let res: ReturnValue = match op {
-                Ops::Misc(op) => {
-                    misc_interact(op, state, state_store.clone(), scripting_bridge.clone()).await
-                }
-                _ => op_interact(op, state.clone()).await,
-            };
-            // Step 2: Update commands_ran_since_save counter, and save if necessary
-            if !state_store.memory_only {
-                incr_and_save_if_required(state_store.clone(), dump_file.clone());
+    // Keep the command around if it may need to go in the append only file.
+    let command = state_store.aof.as_ref().map(|_| redis_value.clone());
+    let transaction = &mut client.transaction;
+    let op = match translate(redis_value, state_store.clone()) {
+        Ok(op) => op,
+        Err(e) => {
+            // Commands that fail to parse poison the current transaction.
+            transaction.abort();
+            return RedisValueRef::from(e);
+        }
+    };
+    debug!(LOGGER, "running op {:?}", op.clone());
+    if client.scripted && op.uses_client_state() {
+        return RedisValueRef::from(ReturnValue::Error(
+            b"ERR this command is not allowed from scripts",
+        ));
+    }
+    // Subscribed RESP2 connections may only manage their subscriptions,
+    // as replies couldn't be told apart from published messages.
+    if client.subscriber.is_subscribed() && client.protocol == ProtocolVersion::Resp2 {
+        match op {
+            Ops::PubSub(ref op) if op.is_subscription() => {}
+            Ops::Misc(MiscOps::Pong()) => {
+                return RedisValueRef::Array(vec![
+                    RedisValueRef::BulkString(Bytes::from_static(b"pong")),
+                    RedisValueRef::BulkString(Bytes::new()),
+                ])
             }
-            // Step 3: Finally Return
-            res.into()
+            _ => return RedisValueRef::from(ReturnValue::Error(
+                b"ERR only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT are allowed in this context",
+            )),
         }
-        Err(e) => RedisValueRef::from(e),
     }
+    let res = match op {
+        Ops::Misc(MiscOps::Multi()) => transaction.multi(),
+        Ops::Misc(MiscOps::Discard()) => transaction.discard(),
+        Ops::Misc(MiscOps::Exec()) => {
+            exec(state, state_store, dump_file, scripting_bridge, client).await
+        }
+        Ops::Misc(MiscOps::Watch(keys)) => transaction.watch(state, keys),
+        Ops::Misc(MiscOps::Hello(protocol)) => client.hello(protocol),
+        Ops::Misc(MiscOps::Unwatch()) if !transaction.in_multi() => {
+            transaction.unwatch();
+            ReturnValue::Ok
+        }
+        Ops::PubSub(ref op) if transaction.in_multi() && op.is_subscription() => {
+            transaction.abort();
+            ReturnValue::Error(b"ERR subscriptions are not allowed inside MULTI")
+        }
+        op if transaction.in_multi() => transaction.queue(op, command),
+        Ops::PubSub(op) => pubsub_interact(op, state_store, &mut client.subscriber),
+        op if op.is_blocking() => {
+            // Blocking ops wait on other clients, so they can't hold the lock.
+            // They take it for each try instead.
+            let disconnected = client.disconnected.clone();
+            let mut no_aof = None;
+            let run = run_op(
+                op,
+                command,
+                &mut client.db,
+                state,
+                state_store.clone(),
+                dump_file,
+                scripting_bridge,
+                &mut no_aof,
+            );
+            tokio::select! {
+                res = run => res,
+                // Dropping the op stops it waiting on its keys.
+                _ = disconnected.cancelled() => ReturnValue::Nil,
+            }
+        }
+        op => {
+            // Scripts wait on other clients, so they can't hold the lock either.
+            let _guard = match op {
+                Ops::Misc(MiscOps::Script(_)) | Ops::Misc(MiscOps::EmbeddedScript(..)) => None,
+                _ => Some(state_store.exec_lock.read().await),
+            };
+            run_op(
+                op,
+                command,
+                &mut client.db,
+                state,
+                state_store.clone(),
+                dump_file,
+                scripting_bridge,
+                &mut None,
+            )
+            .await
+        }
+    };
+    res.into()
 }
 
 /// Spawn a RESP handler for the given socket.
@@ -74,20 +314,67 @@ async fn process(
 ) {
     tokio::spawn(async move {
         let mut state = state_store.get_default();
+        let (push_sender, mut push_receiver) = unbounded_channel();
+        let mut client = Client::new(push_sender);
         let mut transport = RespParser::default().framed(socket);
-        while let Some(redis_value) = transport.next().await {
+        // Commands read while an earlier one was running.
+        let mut pipelined = VecDeque::new();
+        let mut closed = false;
+        loop {
+            let redis_value = match pipelined.pop_front() {
+                Some(redis_value) => redis_value,
+                None if closed => break,
+                None => tokio::select! {
+                    redis_value = transport.next() => match redis_value {
+                        Some(redis_value) => redis_value,
+                        None => break,
+                    },
+                    // Published messages are sent as soon as they arrive.
+                    Some(message) = push_receiver.recv() => {
+                        if let Err(e) = transport.send(message).await {
+                            error!(LOGGER, "Failed to send data to client! {:?}", e)
+                        };
+                        continue;
+                    }
+                },
+            };
             if let Err(e) = redis_value {
                 error!(LOGGER, "Error recieving redis value {:?}", e);
                 continue;
             }
-            let res = process_command(
-                &mut state,
-                state_store.clone(),
-                dump_file.clone(),
-                scripting_bridge.clone(),
-                redis_value.unwrap(),
-            )
-            .await;
+            let res = {
+                let disconnected = client.disconnected.clone();
+                let command = process_command(
+                    &mut state,
+                    state_store.clone(),
+                    dump_file.clone(),
+                    scripting_bridge.clone(),
+                    &mut client,
+                    redis_value.unwrap(),
+                );
+                tokio::pin!(command);
+                // Keep reading meanwhile, to notice the client going away
+                // while it's blocked.
+                loop {
+                    tokio::select! {
+                        res = &mut command => break res,
+                        redis_value = transport.next(), if !closed => match redis_value {
+                            Some(redis_value) => pipelined.push_back(redis_value),
+                            None => {
+                                closed = true;
+                                disconnected.cancel();
+                            }
+                        },
+                    }
+                }
+            };
+            transport.codec_mut().set_protocol(client.protocol);
+            // Messages pushed while running the command go out first.
+            while let Ok(message) = push_receiver.try_recv() {
+                if let Err(e) = transport.send(message).await {
+                    error!(LOGGER, "Failed to send data to client! {:?}", e)
+                };
+            }
             // let res = match translate(redis_value.unwrap()) {
             //     Ok(op) => {
             //         debug!(LOGGER, "running op {:?}", op.clone());
@@ -174,3 +461,132 @@ pub async fn socket_listener(
         };
     }
 }
+
+#[cfg(test)]
+mod test_server {
+    use crate::client::Client;
+    use crate::scripting::ScriptingBridge;
+    use crate::server::process_command;
+    use crate::types::{DumpFile, RedisValueRef, StateStore, StateStoreRef};
+    use bytes::Bytes;
+    use parking_lot::Mutex;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::channel;
+
+    fn command(args: &[&str]) -> RedisValueRef {
+        RedisValueRef::Array(
+            args.iter()
+                .map(|arg| RedisValueRef::BulkString(Bytes::from(arg.to_string())))
+                .collect(),
+        )
+    }
+
+    async fn run(state_store: &StateStoreRef, client: &mut Client, args: &[&str]) -> RedisValueRef {
+        let (prog_send, _) = channel(1);
+        let dump_file: DumpFile = Arc::new(Mutex::new(PathBuf::new()));
+        let mut state = state_store.get_or_create(client.db);
+        process_command(
+            &mut state,
+            state_store.clone(),
+            dump_file,
+            ScriptingBridge::new(prog_send),
+            client,
+            command(args),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_scripts_cant_keep_client_state() {
+        let state_store = Arc::new(StateStore::default());
+        let mut client = Client::for_scripts();
+        let refused = RedisValueRef::Error(Bytes::from_static(
+            b"ERR this command is not allowed from scripts",
+        ));
+        for args in [&["MULTI"][..], &["WATCH", "k"], &["SUBSCRIBE", "c"]] {
+            assert_eq!(run(&state_store, &mut client, args).await, refused);
+        }
+        assert!(!client.transaction.in_multi());
+        assert_eq!(
+            run(&state_store, &mut client, &["SET", "k", "v"]).await,
+            RedisValueRef::SimpleString(Bytes::from_static(b"OK"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_in_multi() {
+        let state_store = Arc::new(StateStore::default());
+        let mut client = Client::default();
+        run(&state_store, &mut client, &["MULTI"]).await;
+        run(&state_store, &mut client, &["PUBLISH", "c", "m"]).await;
+        run(&state_store, &mut client, &["PUBSUB", "NUMPAT"]).await;
+        assert_eq!(
+            run(&state_store, &mut client, &["EXEC"]).await,
+            RedisValueRef::Array(vec![RedisValueRef::Int(0), RedisValueRef::Int(0)])
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_blocked_pop_waits_for_exec() {
+        let state_store = Arc::new(StateStore::default());
+        let state = state_store.get_default();
+        for _ in 0..10 {
+            let blocked_store = state_store.clone();
+            let blocked = tokio::spawn(async move {
+                run(&blocked_store, &mut Client::default(), &["BLPOP", "l", "0"]).await
+            });
+            while state.reciept_map.lock().is_empty() {
+                tokio::task::yield_now().await;
+            }
+            let mut client = Client::default();
+            run(&state_store, &mut client, &["MULTI"]).await;
+            run(&state_store, &mut client, &["RPUSH", "l", "a"]).await;
+            // Give the woken client time to get in between.
+            for _ in 0..1000 {
+                run(&state_store, &mut client, &["LLEN", "l"]).await;
+            }
+            assert_eq!(
+                run(&state_store, &mut client, &["EXEC"]).await,
+                RedisValueRef::Array(vec![RedisValueRef::Int(1); 1001])
+            );
+            assert_eq!(
+                blocked.await.unwrap(),
+                RedisValueRef::Array(vec![
+                    RedisValueRef::BulkString(Bytes::from_static(b"l")),
+                    RedisValueRef::BulkString(Bytes::from_static(b"a")),
+                ])
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_save_commands_take_no_arguments() {
+        let state_store = Arc::new(StateStore::default());
+        let wrong_args =
+            RedisValueRef::ErrorMsg(b"Wrong number of arguments! (0 required, 1 given)".to_vec());
+        for cmd in ["SAVE", "BGSAVE", "LASTSAVE"] {
+            let res = run(&state_store, &mut Client::default(), &[cmd, "now"]).await;
+            assert_eq!(res, wrong_args, "{}", cmd);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flushall_waits_for_exec() {
+        let state_store = Arc::new(StateStore::default());
+        // A transaction is running.
+        let exec = state_store.exec_lock.clone().write_owned().await;
+        let flush_store = state_store.clone();
+        let flush =
+            tokio::spawn(
+                async move { run(&flush_store, &mut Client::default(), &["FLUSHALL"]).await },
+            );
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert!(!flush.is_finished());
+        drop(exec);
+        assert_eq!(
+            flush.await.unwrap(),
+            RedisValueRef::SimpleString(Bytes::from_static(b"OK"))
+        );
+    }
+}
@@ -2,7 +2,8 @@ use std::collections::HashSet;
 
 use crate::op_variants;
 use crate::ops::RVec;
-use crate::types::{Count, Key, ReturnValue, StateRef, Value};
+use crate::scan::{scan_reply, ScanOptions};
+use crate::types::{Count, Key, ReturnValue, StateRef, Value, ValueType, WRONGTYPE};
 
 op_variants! {
     SetOps,
@@ -19,7 +20,53 @@ op_variants! {
     SPop(Key, Option<Count>),
     SIsMember(Key, Value),
     SMove(Key, Key, Value),
-    SRandMembers(Key, Option<Count>)
+    SRandMembers(Key, Option<Count>),
+    SScan(Key, ScanOptions)
+}
+
+impl SetOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            SetOps::SAdd(key, _)
+            | SetOps::SMembers(key)
+            | SetOps::SCard(key)
+            | SetOps::SRem(key, _)
+            | SetOps::SPop(key, _)
+            | SetOps::SIsMember(key, _)
+            | SetOps::SRandMembers(key, _)
+            | SetOps::SScan(key, _) => vec![key.clone()],
+            SetOps::SDiff(keys) | SetOps::SUnion(keys) | SetOps::SInter(keys) => keys.to_vec(),
+            SetOps::SDiffStore(dest, keys)
+            | SetOps::SUnionStore(dest, keys)
+            | SetOps::SInterStore(dest, keys) => {
+                std::iter::once(dest).chain(keys.iter()).cloned().collect()
+            }
+            SetOps::SMove(src, dest, _) => vec![src.clone(), dest.clone()],
+        }
+    }
+
+    /// The type the keys must hold, or None if any type will do.
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            // Destinations are overwritten; sources are checked when run.
+            SetOps::SDiffStore(..) | SetOps::SUnionStore(..) | SetOps::SInterStore(..) => None,
+            _ => Some(ValueType::Set),
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            SetOps::SAdd(..)
+                | SetOps::SRem(..)
+                | SetOps::SDiffStore(..)
+                | SetOps::SUnionStore(..)
+                | SetOps::SInterStore(..)
+                | SetOps::SPop(..)
+                | SetOps::SMove(..)
+        )
+    }
 }
 
 pub enum SetAction {
@@ -42,30 +89,38 @@ fn many_set_op(state: &StateRef, keys: RVec<Key>, op: SetAction) -> Option<HashS
     #[allow(clippy::mutable_key_type)]
     let mut head: HashSet<Key> = state
         .sets
-        .get_mut(sets_that_exist[0])
+        .get(sets_that_exist[0])
         .unwrap()
-        .value_mut()
-        .clone();
-    // TODO: Make this _way_ cleaner.
+        .iter()
+        .cloned()
+        .collect();
     for set_key in sets_that_exist.into_iter().skip(1) {
-        head = match op {
-            SetAction::Diff => head
-                .difference(state.sets.get(set_key).unwrap().value())
-                .cloned()
-                .collect(),
-            SetAction::Union => head
-                .union(state.sets.get(set_key).unwrap().value())
-                .cloned()
-                .collect(),
-            SetAction::Inter => head
-                .intersection(state.sets.get(set_key).unwrap().value())
-                .cloned()
-                .collect(),
+        let set = state.sets.get(set_key).unwrap();
+        match op {
+            SetAction::Diff => head.retain(|member| !set.contains(member)),
+            SetAction::Union => head.extend(set.iter().cloned()),
+            SetAction::Inter => head.retain(|member| set.contains(member)),
         }
     }
     Some(head)
 }
 
+/// Store the result of `op` on `keys` at `dest`, replacing whatever
+/// was there. Empty results delete `dest`.
+fn store_set_op(state: &StateRef, dest: Key, keys: RVec<Key>, op: SetAction) -> ReturnValue {
+    let wrong_type = |key: &Key| state.value_type(key).is_some_and(|t| t != ValueType::Set);
+    if keys.iter().any(wrong_type) {
+        return ReturnValue::Error(WRONGTYPE);
+    }
+    let set = many_set_op(state, keys, op).unwrap_or_default();
+    let size = set.len();
+    state.remove_key(&dest);
+    if size > 0 {
+        state.sets.insert(dest, set.into_iter().collect());
+    }
+    ReturnValue::IntRes(size as Count)
+}
+
 pub async fn set_interact(set_op: SetOps, state: StateRef) -> ReturnValue {
     match set_op {
         SetOps::SAdd(set_key, vals) => {
@@ -85,7 +140,7 @@ pub async fn set_interact(set_op: SetOps, state: StateRef) -> ReturnValue {
         SetOps::SRem(set_key, vals) => write_sets!(state, &set_key)
             .map(|mut set| {
                 vals.into_iter()
-                    .fold(0, |acc, val| acc + set.insert(val) as Count)
+                    .fold(0, |acc, val| acc + set.remove(&val) as Count)
             })
             .unwrap_or(0)
             .into(),
@@ -101,30 +156,13 @@ pub async fn set_interact(set_op: SetOps, state: StateRef) -> ReturnValue {
             .map(|set| set.into_iter().collect())
             .unwrap_or_else(RVec::new)
             .into(),
-        SetOps::SDiffStore(to_store, keys) => match many_set_op(&state, keys, SetAction::Diff) {
-            Some(hash_set) => {
-                let hash_set_size = hash_set.len();
-                write_sets!(state).insert(to_store, hash_set);
-                ReturnValue::IntRes(hash_set_size as Count)
-            }
-            None => ReturnValue::IntRes(0),
-        },
-        SetOps::SUnionStore(to_store, keys) => match many_set_op(&state, keys, SetAction::Union) {
-            Some(hash_set) => {
-                let hash_set_size = hash_set.len();
-                write_sets!(state).insert(to_store, hash_set);
-                ReturnValue::IntRes(hash_set_size as Count)
-            }
-            None => ReturnValue::IntRes(0),
-        },
-        SetOps::SInterStore(to_store, keys) => match many_set_op(&state, keys, SetAction::Inter) {
-            Some(hash_set) => {
-                let hash_set_size = hash_set.len();
-                write_sets!(state).insert(to_store, hash_set);
-                ReturnValue::IntRes(hash_set_size as Count)
-            }
-            None => ReturnValue::IntRes(0),
-        },
+        SetOps::SDiffStore(to_store, keys) => store_set_op(&state, to_store, keys, SetAction::Diff),
+        SetOps::SUnionStore(to_store, keys) => {
+            store_set_op(&state, to_store, keys, SetAction::Union)
+        }
+        SetOps::SInterStore(to_store, keys) => {
+            store_set_op(&state, to_store, keys, SetAction::Inter)
+        }
         // There's some surprising complexity behind this command
         SetOps::SPop(key, count) => {
             let mut set = match state.sets.get_mut(&key) {
@@ -181,5 +219,57 @@ pub async fn set_interact(set_op: SetOps, state: StateRef) -> ReturnValue {
             }
             None => ReturnValue::Nil,
         },
+        SetOps::SScan(key, options) => {
+            let (cursor, members) = match read_sets!(state, &key) {
+                Some(set) => set.scan(options.cursor, options.count),
+                None => (0, Vec::new()),
+            };
+            let members = members
+                .into_iter()
+                .filter(|member| options.matches(member))
+                .collect();
+            scan_reply(cursor, members)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_sets {
+    use crate::ops::{op_interact, Ops};
+    use crate::sets::SetOps;
+    use crate::types::{ReturnValue, State, ValueType, WRONGTYPE};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_store_overwrites_other_types() {
+        let state = Arc::new(State::default());
+        let key = |k: &'static str| Bytes::from_static(k.as_bytes());
+        state.kv.insert(key("dest"), key("string"));
+        state.kv.insert(key("string"), key("string"));
+        let add = Ops::Sets(SetOps::SAdd(key("a"), smallvec![key("1"), key("2")]));
+        op_interact(add, state.clone()).await;
+
+        let store = SetOps::SUnionStore(key("dest"), smallvec![key("a"), key("missing")]);
+        assert_eq!(
+            op_interact(Ops::Sets(store), state.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+        assert_eq!(state.value_type(b"dest"), Some(ValueType::Set));
+
+        let store = SetOps::SInterStore(key("dest"), smallvec![key("a"), key("string")]);
+        assert_eq!(
+            op_interact(Ops::Sets(store), state.clone()).await,
+            ReturnValue::Error(WRONGTYPE)
+        );
+
+        // Empty results delete the destination.
+        let store = SetOps::SDiffStore(key("dest"), smallvec![key("a"), key("a")]);
+        assert_eq!(
+            op_interact(Ops::Sets(store), state.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        assert_eq!(state.value_type(b"dest"), None);
     }
 }
@@ -1,49 +1,409 @@
+use crate::data_structures::sorted_set::{
+    AddOptions, AddOutcome, LexRange, ScoreRange, SortedSet, SortedSetMember,
+};
 use crate::ops::RVec;
-use crate::types::{Count, Index, Key, ReturnValue, Score, StateRef};
+use crate::scan::{scan_reply, ScanOptions};
+use crate::types::{Count, Index, Key, ReturnValue, Score, StateRef, ValueType, WRONGTYPE};
 use crate::{make_reader, make_writer, op_variants};
+use std::collections::HashMap;
+
+const NAN_SCORE: &[u8] = b"ERR resulting score is not a number (NaN)";
+
+/// The LIMIT option of ZRANGEBYSCORE: skip `offset` members, then
+/// return at most `count` (all of them if None).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub offset: usize,
+    pub count: Option<usize>,
+}
+
+impl Limit {
+    fn apply<'a, T: 'a>(
+        limit: Option<Limit>,
+        items: impl Iterator<Item = T> + 'a,
+    ) -> Box<dyn Iterator<Item = T> + 'a> {
+        match limit {
+            None => Box::new(items),
+            Some(Limit {
+                offset,
+                count: None,
+            }) => Box::new(items.skip(offset)),
+            Some(Limit {
+                offset,
+                count: Some(count),
+            }) => Box::new(items.skip(offset).take(count)),
+        }
+    }
+}
+
+/// How ZUNION and ZINTER combine the scores of a member found in several inputs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Aggregate {
+    #[default]
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(&self, acc: Score, score: Score) -> Score {
+        match self {
+            // inf + -inf is NaN, which redis turns into 0.
+            Aggregate::Sum => match acc + score {
+                sum if sum.is_nan() => 0.0,
+                sum => sum,
+            },
+            Aggregate::Min => acc.min(score),
+            Aggregate::Max => acc.max(score),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZSetAction {
+    Union,
+    Inter,
+    Diff,
+}
+
+/// The inputs of ZUNION, ZINTER and ZDIFF. Each input's scores are
+/// multiplied by its weight; ZDIFF has no weights or aggregate.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Combination {
+    pub keys: RVec<Key>,
+    pub weights: RVec<Score>,
+    pub aggregate: Aggregate,
+}
 
 op_variants! {
     ZSetOps,
-    ZAdd(Key, RVec<(Score, Key)>),
+    ZAdd(Key, RVec<(Score, Key)>, AddOptions),
     ZRem(Key, RVec<Key>),
-    ZRange(Key, Score, Score),
+    ZRange(Key, Index, Index, bool),
+    ZRevRange(Key, Index, Index, bool),
+    ZRangeByScore(Key, ScoreRange, Option<Limit>, bool),
+    ZRevRangeByScore(Key, ScoreRange, Option<Limit>, bool),
+    ZCount(Key, ScoreRange),
+    ZRangeByLex(Key, LexRange, Option<Limit>),
+    ZRevRangeByLex(Key, LexRange, Option<Limit>),
+    ZLexCount(Key, LexRange),
     ZCard(Key),
     ZScore(Key, Key),
+    ZMScore(Key, RVec<Key>),
+    ZIncrBy(Key, Score, Key),
     ZPopMax(Key, Count),
     ZPopMin(Key, Count),
-    ZRank(Key, Key)
+    ZRank(Key, Key),
+    ZRevRank(Key, Key),
+    ZRemRangeByScore(Key, ScoreRange),
+    ZRemRangeByRank(Key, Index, Index),
+    ZRemRangeByLex(Key, LexRange),
+    ZUnion(Combination, bool),
+    ZInter(Combination, bool),
+    ZDiff(Combination, bool),
+    ZUnionStore(Key, Combination),
+    ZInterStore(Key, Combination),
+    ZDiffStore(Key, Combination),
+    ZInterCard(RVec<Key>, Option<usize>),
+    ZScan(Key, ScanOptions)
+}
+
+impl ZSetOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            ZSetOps::ZAdd(key, ..)
+            | ZSetOps::ZRem(key, _)
+            | ZSetOps::ZRange(key, ..)
+            | ZSetOps::ZRevRange(key, ..)
+            | ZSetOps::ZRangeByScore(key, ..)
+            | ZSetOps::ZRevRangeByScore(key, ..)
+            | ZSetOps::ZCount(key, _)
+            | ZSetOps::ZRangeByLex(key, ..)
+            | ZSetOps::ZRevRangeByLex(key, ..)
+            | ZSetOps::ZLexCount(key, _)
+            | ZSetOps::ZCard(key)
+            | ZSetOps::ZScore(key, _)
+            | ZSetOps::ZMScore(key, _)
+            | ZSetOps::ZIncrBy(key, ..)
+            | ZSetOps::ZPopMax(key, _)
+            | ZSetOps::ZPopMin(key, _)
+            | ZSetOps::ZRank(key, _)
+            | ZSetOps::ZRevRank(key, _)
+            | ZSetOps::ZRemRangeByScore(key, _)
+            | ZSetOps::ZRemRangeByRank(key, ..)
+            | ZSetOps::ZRemRangeByLex(key, _)
+            | ZSetOps::ZScan(key, _) => vec![key.clone()],
+            ZSetOps::ZUnion(combination, _)
+            | ZSetOps::ZInter(combination, _)
+            | ZSetOps::ZDiff(combination, _) => combination.keys.to_vec(),
+            ZSetOps::ZUnionStore(dest, combination)
+            | ZSetOps::ZInterStore(dest, combination)
+            | ZSetOps::ZDiffStore(dest, combination) => {
+                let mut keys = vec![dest.clone()];
+                keys.extend(combination.keys.iter().cloned());
+                keys
+            }
+            ZSetOps::ZInterCard(keys, _) => keys.to_vec(),
+        }
+    }
+
+    /// The type the keys must hold, or None if any type will do.
+    pub fn value_type(&self) -> Option<ValueType> {
+        match self {
+            // Sets are valid inputs, and destinations are overwritten.
+            ZSetOps::ZUnion(..)
+            | ZSetOps::ZInter(..)
+            | ZSetOps::ZDiff(..)
+            | ZSetOps::ZUnionStore(..)
+            | ZSetOps::ZInterStore(..)
+            | ZSetOps::ZDiffStore(..)
+            | ZSetOps::ZInterCard(..) => None,
+            _ => Some(ValueType::ZSet),
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            ZSetOps::ZAdd(..)
+                | ZSetOps::ZRem(..)
+                | ZSetOps::ZIncrBy(..)
+                | ZSetOps::ZPopMax(..)
+                | ZSetOps::ZPopMin(..)
+                | ZSetOps::ZRemRangeByScore(..)
+                | ZSetOps::ZRemRangeByRank(..)
+                | ZSetOps::ZRemRangeByLex(..)
+                | ZSetOps::ZUnionStore(..)
+                | ZSetOps::ZInterStore(..)
+                | ZSetOps::ZDiffStore(..)
+        )
+    }
 }
 
 make_reader!(zsets, read_zsets);
 make_writer!(zsets, write_zsets);
 
-fn deal_with_negative_indices(coll_size: Count, bounds: (Index, Index)) -> (Index, Index) {
-    let (start, end) = bounds;
-    let start = if start < 0 { start + coll_size } else { start };
-    let end = if end < 0 { end + coll_size } else { end };
-    (start, end)
+/// Members, each followed by its score if `with_scores` is set.
+fn members_reply(
+    members: impl IntoIterator<Item = SortedSetMember>,
+    with_scores: bool,
+) -> ReturnValue {
+    let mut reply = Vec::new();
+    for member in members {
+        reply.push(ReturnValue::StringRes(member.member));
+        if with_scores {
+            reply.push(ReturnValue::Double(member.score));
+        }
+    }
+    ReturnValue::Array(reply)
+}
+
+/// The members and scores of `key`, which may also be a set, whose
+/// members all score 1. Errors with WRONGTYPE for other types.
+fn zset_input(state: &StateRef, key: &Key) -> Result<Vec<(Key, Score)>, ReturnValue> {
+    if let Some(zset) = state.zsets.get(key) {
+        return Ok(zset.iter().map(|m| (m.member.clone(), m.score)).collect());
+    }
+    if let Some(set) = state.sets.get(key) {
+        return Ok(set.iter().map(|member| (member.clone(), 1.0)).collect());
+    }
+    match state.contains_key(key) {
+        true => Err(ReturnValue::Error(WRONGTYPE)),
+        false => Ok(Vec::new()),
+    }
+}
+
+/// Combine the inputs of ZUNION, ZINTER or ZDIFF into a new sorted set.
+fn combine(
+    state: &StateRef,
+    action: ZSetAction,
+    combination: &Combination,
+) -> Result<SortedSet, ReturnValue> {
+    let mut inputs = Vec::with_capacity(combination.keys.len());
+    for key in combination.keys.iter() {
+        inputs.push(zset_input(state, key)?);
+    }
+    let weight = |i: usize| combination.weights.get(i).copied().unwrap_or(1.0);
+    // Like redis, inf * 0 is 0 rather than NaN.
+    let weighted = |score: Score, weight: Score| match score * weight {
+        score if score.is_nan() => 0.0,
+        score => score,
+    };
+    let aggregate = combination.aggregate;
+    let mut inputs = inputs.into_iter().enumerate();
+    #[allow(clippy::mutable_key_type)]
+    let mut result: HashMap<Key, Score> = match inputs.next() {
+        Some((i, first)) => first
+            .into_iter()
+            .map(|(member, score)| (member, weighted(score, weight(i))))
+            .collect(),
+        None => HashMap::new(),
+    };
+    for (i, input) in inputs {
+        match action {
+            ZSetAction::Union => {
+                for (member, score) in input {
+                    let score = weighted(score, weight(i));
+                    result
+                        .entry(member)
+                        .and_modify(|acc| *acc = aggregate.apply(*acc, score))
+                        .or_insert(score);
+                }
+            }
+            ZSetAction::Inter => {
+                #[allow(clippy::mutable_key_type)]
+                let input: HashMap<Key, Score> = input.into_iter().collect();
+                result.retain(|member, acc| match input.get(member) {
+                    Some(score) => {
+                        *acc = aggregate.apply(*acc, weighted(*score, weight(i)));
+                        true
+                    }
+                    None => false,
+                });
+            }
+            ZSetAction::Diff => {
+                for (member, _) in input {
+                    result.remove(&member);
+                }
+            }
+        }
+    }
+    let mut zset = SortedSet::new();
+    zset.add(
+        result
+            .into_iter()
+            .map(|(key, score)| (score, key))
+            .collect(),
+    );
+    Ok(zset)
+}
+
+/// Reply with the combined sorted set, lowest score first.
+fn combine_reply(
+    state: &StateRef,
+    action: ZSetAction,
+    combination: &Combination,
+    with_scores: bool,
+) -> ReturnValue {
+    match combine(state, action, combination) {
+        Ok(zset) => members_reply(zset.iter().cloned().collect::<Vec<_>>(), with_scores),
+        Err(err) => err,
+    }
+}
+
+/// Store the combined sorted set at `dest`, replacing whatever it held.
+fn combine_store(
+    state: &StateRef,
+    action: ZSetAction,
+    dest: Key,
+    combination: &Combination,
+) -> ReturnValue {
+    let zset = match combine(state, action, combination) {
+        Ok(zset) => zset,
+        Err(err) => return err,
+    };
+    let card = zset.card();
+    state.remove_key(&dest);
+    if card > 0 {
+        state.zsets.insert(dest, zset);
+    }
+    ReturnValue::IntRes(card)
+}
+
+/// Sorted sets emptied by a removal are deleted, like redis does.
+fn remove_if_empty(state: &StateRef, key: &Key) {
+    state.zsets.remove_if(key, |_, zset| zset.card() == 0);
 }
 
 pub async fn zset_interact(zset_op: ZSetOps, state: StateRef) -> ReturnValue {
     match zset_op {
-        ZSetOps::ZAdd(zset_key, member_scores) => {
-            let mut zset = state.zsets.entry(zset_key).or_default();
-            let num_added = zset.add(member_scores);
-            ReturnValue::IntRes(num_added)
+        ZSetOps::ZAdd(zset_key, member_scores, options) => {
+            let outcomes: Vec<AddOutcome> = {
+                let mut zset = state.zsets.entry(zset_key.clone()).or_default();
+                member_scores
+                    .into_iter()
+                    .map(|(score, key)| zset.add_with(key, score, &options))
+                    .collect()
+            };
+            // XX on a new key adds nothing.
+            remove_if_empty(&state, &zset_key);
+            if options.incr {
+                // INCR takes a single member, and replies like ZINCRBY.
+                return match outcomes[0] {
+                    AddOutcome::Added(score)
+                    | AddOutcome::Updated(score)
+                    | AddOutcome::Unchanged(score) => ReturnValue::Double(score),
+                    AddOutcome::Skipped => ReturnValue::Nil,
+                    AddOutcome::NotANumber => ReturnValue::Error(NAN_SCORE),
+                };
+            }
+            let counted = outcomes
+                .into_iter()
+                .filter(|outcome| match outcome {
+                    AddOutcome::Added(_) => true,
+                    AddOutcome::Updated(_) => options.changed,
+                    _ => false,
+                })
+                .count();
+            ReturnValue::IntRes(counted as Count)
+        }
+        ZSetOps::ZRem(zset_key, keys) => {
+            let removed = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.remove(&keys))
+                .unwrap_or(0);
+            remove_if_empty(&state, &zset_key);
+            removed.into()
         }
-        ZSetOps::ZRem(zset_key, keys) => write_zsets!(state, &zset_key)
-            .map(|mut zset| zset.remove(&keys))
+        ZSetOps::ZRange(zset_key, start, stop, with_scores) => {
+            let members = read_zsets!(state, &zset_key)
+                .map(|zset| zset.range_by_rank(start, stop))
+                .unwrap_or_default();
+            members_reply(members, with_scores)
+        }
+        ZSetOps::ZRevRange(zset_key, start, stop, with_scores) => {
+            let members = read_zsets!(state, &zset_key)
+                .map(|zset| zset.rev_range_by_rank(start, stop))
+                .unwrap_or_default();
+            members_reply(members, with_scores)
+        }
+        ZSetOps::ZRangeByScore(zset_key, range, limit, with_scores) => {
+            let members: Vec<SortedSetMember> = read_zsets!(state, &zset_key)
+                .map(|zset| Limit::apply(limit, zset.range_by_score(&range).cloned()).collect())
+                .unwrap_or_default();
+            members_reply(members, with_scores)
+        }
+        ZSetOps::ZRevRangeByScore(zset_key, range, limit, with_scores) => {
+            let members: Vec<SortedSetMember> = read_zsets!(state, &zset_key)
+                .map(|zset| {
+                    let in_range: Vec<&SortedSetMember> = zset.range_by_score(&range).collect();
+                    Limit::apply(limit, in_range.into_iter().rev().cloned()).collect()
+                })
+                .unwrap_or_default();
+            members_reply(members, with_scores)
+        }
+        ZSetOps::ZCount(zset_key, range) => read_zsets!(state, &zset_key)
+            .map(|zset| zset.count(&range))
             .unwrap_or(0)
             .into(),
-        ZSetOps::ZRange(zset_key, start, stop) => read_zsets!(state, &zset_key)
-            .map(|zset| {
-                let (start, stop) = deal_with_negative_indices(zset.card(), (start, stop));
-                zset.range((start, stop))
-                    .into_iter()
-                    .map(|item| item.member)
-                    .collect::<Vec<_>>()
-            })
-            .unwrap_or_default()
+        ZSetOps::ZRangeByLex(zset_key, range, limit) => {
+            let members: Vec<SortedSetMember> = read_zsets!(state, &zset_key)
+                .map(|zset| Limit::apply(limit, zset.range_by_lex(&range).cloned()).collect())
+                .unwrap_or_default();
+            members_reply(members, false)
+        }
+        ZSetOps::ZRevRangeByLex(zset_key, range, limit) => {
+            let members: Vec<SortedSetMember> = read_zsets!(state, &zset_key)
+                .map(|zset| {
+                    let in_range: Vec<&SortedSetMember> = zset.range_by_lex(&range).collect();
+                    Limit::apply(limit, in_range.into_iter().rev().cloned()).collect()
+                })
+                .unwrap_or_default();
+            members_reply(members, false)
+        }
+        ZSetOps::ZLexCount(zset_key, range) => read_zsets!(state, &zset_key)
+            .map(|zset| zset.lex_count(&range))
+            .unwrap_or(0)
             .into(),
         ZSetOps::ZCard(zset_key) => read_zsets!(state, &zset_key)
             .map(|zset| zset.card())
@@ -51,35 +411,224 @@ pub async fn zset_interact(zset_op: ZSetOps, state: StateRef) -> ReturnValue {
             .into(),
         ZSetOps::ZScore(zset_key, member_key) => read_zsets!(state, &zset_key)
             .and_then(|zset| zset.score(member_key))
-            .map(ReturnValue::IntRes)
+            .map(ReturnValue::Double)
             .unwrap_or(ReturnValue::Nil),
-        ZSetOps::ZPopMax(zset_key, count) => write_zsets!(state, &zset_key)
-            .map(|mut zset| {
-                zset.pop_max(count)
-                    .into_iter()
-                    .fold(Vec::new(), |mut acc, zset_mem| {
-                        acc.push(ReturnValue::IntRes(zset_mem.score));
-                        acc.push(ReturnValue::StringRes(zset_mem.member.into()));
-                        acc
-                    })
-            })
-            .map(ReturnValue::Array)
-            .unwrap_or_else(|| ReturnValue::Array(vec![])),
-        ZSetOps::ZPopMin(zset_key, count) => write_zsets!(state, &zset_key)
-            .map(|mut zset| {
-                zset.pop_min(count)
-                    .into_iter()
-                    .fold(Vec::new(), |mut acc, zset_mem| {
-                        acc.push(ReturnValue::IntRes(zset_mem.score));
-                        acc.push(ReturnValue::StringRes(zset_mem.member.into()));
-                        acc
-                    })
-            })
-            .map(ReturnValue::Array)
-            .unwrap_or_else(|| ReturnValue::Array(vec![])),
+        ZSetOps::ZMScore(zset_key, member_keys) => {
+            let zset = read_zsets!(state, &zset_key);
+            let scores = member_keys
+                .into_iter()
+                .map(|member_key| {
+                    zset.as_ref()
+                        .and_then(|zset| zset.score(member_key))
+                        .map(ReturnValue::Double)
+                        .unwrap_or(ReturnValue::Nil)
+                })
+                .collect();
+            ReturnValue::Array(scores)
+        }
+        ZSetOps::ZIncrBy(zset_key, delta, member_key) => {
+            let new_score = state
+                .zsets
+                .entry(zset_key.clone())
+                .or_default()
+                .incr(member_key, delta);
+            match new_score {
+                Some(score) => ReturnValue::Double(score),
+                None => {
+                    remove_if_empty(&state, &zset_key);
+                    ReturnValue::Error(NAN_SCORE)
+                }
+            }
+        }
+        ZSetOps::ZPopMax(zset_key, count) => {
+            let popped = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.pop_max(count))
+                .unwrap_or_default();
+            remove_if_empty(&state, &zset_key);
+            members_reply(popped, true)
+        }
+        ZSetOps::ZPopMin(zset_key, count) => {
+            let popped = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.pop_min(count))
+                .unwrap_or_default();
+            remove_if_empty(&state, &zset_key);
+            members_reply(popped, true)
+        }
         ZSetOps::ZRank(zset_key, mem_key) => read_zsets!(state, &zset_key)
             .and_then(|zset| zset.rank(mem_key))
             .map(ReturnValue::IntRes)
             .unwrap_or(ReturnValue::Nil),
+        ZSetOps::ZRevRank(zset_key, mem_key) => read_zsets!(state, &zset_key)
+            .and_then(|zset| zset.rank(mem_key).map(|rank| zset.card() - 1 - rank))
+            .map(ReturnValue::IntRes)
+            .unwrap_or(ReturnValue::Nil),
+        ZSetOps::ZRemRangeByScore(zset_key, range) => {
+            let removed = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.remove_range_by_score(&range))
+                .unwrap_or(0);
+            remove_if_empty(&state, &zset_key);
+            removed.into()
+        }
+        ZSetOps::ZRemRangeByRank(zset_key, start, stop) => {
+            let removed = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.remove_range_by_rank(start, stop))
+                .unwrap_or(0);
+            remove_if_empty(&state, &zset_key);
+            removed.into()
+        }
+        ZSetOps::ZRemRangeByLex(zset_key, range) => {
+            let removed = write_zsets!(state, &zset_key)
+                .map(|mut zset| zset.remove_range_by_lex(&range))
+                .unwrap_or(0);
+            remove_if_empty(&state, &zset_key);
+            removed.into()
+        }
+        ZSetOps::ZUnion(combination, with_scores) => {
+            combine_reply(&state, ZSetAction::Union, &combination, with_scores)
+        }
+        ZSetOps::ZInter(combination, with_scores) => {
+            combine_reply(&state, ZSetAction::Inter, &combination, with_scores)
+        }
+        ZSetOps::ZDiff(combination, with_scores) => {
+            combine_reply(&state, ZSetAction::Diff, &combination, with_scores)
+        }
+        ZSetOps::ZUnionStore(dest, combination) => {
+            combine_store(&state, ZSetAction::Union, dest, &combination)
+        }
+        ZSetOps::ZInterStore(dest, combination) => {
+            combine_store(&state, ZSetAction::Inter, dest, &combination)
+        }
+        ZSetOps::ZDiffStore(dest, combination) => {
+            combine_store(&state, ZSetAction::Diff, dest, &combination)
+        }
+        ZSetOps::ZInterCard(keys, limit) => {
+            let combination = Combination {
+                keys,
+                weights: RVec::new(),
+                aggregate: Aggregate::Sum,
+            };
+            match combine(&state, ZSetAction::Inter, &combination) {
+                Ok(zset) => match limit {
+                    Some(limit) => zset.card().min(limit as Count).into(),
+                    None => zset.card().into(),
+                },
+                Err(err) => err,
+            }
+        }
+        ZSetOps::ZScan(key, options) => {
+            let (cursor, members) = match read_zsets!(state, &key) {
+                Some(zset) => zset.scan(options.cursor, options.count),
+                None => (0, Vec::new()),
+            };
+            let members = members
+                .into_iter()
+                .filter(|(member, _)| options.matches(member))
+                .flat_map(|(member, score)| vec![member, score.to_string().into()])
+                .collect();
+            scan_reply(cursor, members)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_sorted_sets {
+    use crate::data_structures::sorted_set::AddOptions;
+    use crate::ops::{op_interact, Ops, RVec};
+    use crate::sorted_sets::{zset_interact, Aggregate, Combination, ZSetOps};
+    use crate::types::{Key, ReturnValue, State, StateRef, WRONGTYPE};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+
+    fn key(k: &'static str) -> Key {
+        Bytes::from_static(k.as_bytes())
+    }
+
+    async fn add(state: &StateRef, zset: &'static str, members: &[(f64, &'static str)]) {
+        let members = members.iter().map(|(s, m)| (*s, key(m))).collect();
+        let op = ZSetOps::ZAdd(key(zset), members, AddOptions::default());
+        zset_interact(op, state.clone()).await;
+    }
+
+    fn combination(keys: &[&'static str], weights: RVec<f64>, aggregate: Aggregate) -> Combination {
+        Combination {
+            keys: keys.iter().map(|k| key(k)).collect(),
+            weights,
+            aggregate,
+        }
+    }
+
+    fn scored(members: &[(&'static str, f64)]) -> ReturnValue {
+        let mut reply = Vec::new();
+        for (member, score) in members {
+            reply.push(ReturnValue::StringRes(key(member)));
+            reply.push(ReturnValue::Double(*score));
+        }
+        ReturnValue::Array(reply)
+    }
+
+    #[tokio::test]
+    async fn test_union_and_inter() {
+        let state = Arc::new(State::default());
+        add(&state, "a", &[(1.0, "x"), (2.0, "y")]).await;
+        add(&state, "b", &[(10.0, "y"), (20.0, "z")]).await;
+        state.sets.entry(key("s")).or_default().insert(key("x"));
+
+        let union = combination(&["a", "b", "s"], smallvec![2.0, 1.0, 5.0], Aggregate::Sum);
+        assert_eq!(
+            zset_interact(ZSetOps::ZUnion(union, true), state.clone()).await,
+            scored(&[("x", 7.0), ("y", 14.0), ("z", 20.0)])
+        );
+        let inter = combination(&["a", "b"], smallvec![], Aggregate::Max);
+        assert_eq!(
+            zset_interact(ZSetOps::ZInter(inter, true), state.clone()).await,
+            scored(&[("y", 10.0)])
+        );
+        let diff = combination(&["a", "b", "missing"], smallvec![], Aggregate::Sum);
+        assert_eq!(
+            zset_interact(ZSetOps::ZDiff(diff, true), state.clone()).await,
+            scored(&[("x", 1.0)])
+        );
+        let card = ZSetOps::ZInterCard(smallvec![key("a"), key("b"), key("s")], None);
+        assert_eq!(
+            zset_interact(card, state.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_store() {
+        let state = Arc::new(State::default());
+        add(&state, "a", &[(1.0, "x"), (f64::INFINITY, "y")]).await;
+        state.kv.insert(key("dest"), key("string"));
+
+        // inf * 0 is 0, not NaN.
+        let union = combination(&["a", "a"], smallvec![0.0, 1.0], Aggregate::Min);
+        let store = ZSetOps::ZUnionStore(key("dest"), union);
+        assert_eq!(
+            op_interact(Ops::ZSets(store), state.clone()).await,
+            ReturnValue::IntRes(2)
+        );
+        assert!(!state.kv.contains_key(&key("dest")));
+        assert_eq!(
+            state.zsets.get(&key("dest")).unwrap().score(key("y")),
+            Some(0.0)
+        );
+
+        // An empty result deletes the destination.
+        let inter = combination(&["a", "missing"], smallvec![], Aggregate::Sum);
+        let store = ZSetOps::ZInterStore(key("dest"), inter);
+        assert_eq!(
+            op_interact(Ops::ZSets(store), state.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        assert!(!state.zsets.contains_key(&key("dest")));
+
+        let wrong = combination(&["a", "dest_string"], smallvec![], Aggregate::Sum);
+        state.kv.insert(key("dest_string"), key("string"));
+        assert_eq!(
+            op_interact(Ops::ZSets(ZSetOps::ZUnion(wrong, false)), state.clone()).await,
+            ReturnValue::Error(WRONGTYPE)
+        );
     }
 }
@@ -9,6 +9,22 @@ op_variants! {
     STSize(Key)
 }
 
+impl StackOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            StackOps::STPush(key, _)
+            | StackOps::STPop(key)
+            | StackOps::STPeek(key)
+            | StackOps::STSize(key) => vec![key.clone()],
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(self, StackOps::STPush(..) | StackOps::STPop(..))
+    }
+}
+
 make_reader!(stacks, read_stacks);
 
 pub async fn stack_interact(stack_op: StackOps, state: StateRef) -> ReturnValue {
@@ -1,7 +1,11 @@
 use slog::info;
 use structopt::StructOpt;
 
+use crate::aof::FsyncPolicy;
+use crate::data_structures::quicklist::Fill;
+use crate::database::SavePoints;
 use crate::logger::LOGGER;
+use crate::memory::{parse_memory, EvictionPolicy};
 use std::path::PathBuf;
 
 #[derive(Debug, StructOpt)]
@@ -16,8 +20,10 @@ pub struct Config {
     /// Don't show the starting graphic
     #[structopt(short = "g", long = "no-graphic")]
     pub dont_show_graphic: bool,
-    #[structopt(short = "s", long = "ops-until-save", default_value = "10000")]
-    pub ops_until_save: u64,
+    /// Save points as "<seconds> <changes> ...": save after <seconds> if
+    /// at least <changes> writes happened. Empty to disable
+    #[structopt(long = "save", default_value = "3600 1 300 100 60 10000")]
+    pub save: SavePoints,
     #[structopt(short = "p", long = "port", default_value = "6379")]
     pub port: u64,
     /// Run in memory only mode. Don't save database state to disk
@@ -25,6 +31,30 @@ pub struct Config {
     pub memory_only: bool,
     #[structopt(short = "f", long = "scripts-dir")]
     pub scripts_dir: Option<std::path::PathBuf>,
+    /// Log every write to an append only file, and replay it on startup
+    #[structopt(long = "appendonly")]
+    pub append_only: bool,
+    /// When to fsync the append only file: always, everysec or no
+    #[structopt(long = "appendfsync", default_value = "everysec")]
+    pub append_fsync: FsyncPolicy,
+    /// Memory limit for keys, like "100mb". 0 for no limit
+    #[structopt(long = "maxmemory", default_value = "0", parse(try_from_str = parse_memory))]
+    pub maxmemory: usize,
+    /// What to evict once over maxmemory: noeviction, allkeys-lru, allkeys-lfu,
+    /// allkeys-random, volatile-lru or volatile-ttl
+    #[structopt(long = "maxmemory-policy", default_value = "noeviction")]
+    pub maxmemory_policy: EvictionPolicy,
+    /// How full list nodes get: a number of entries, or -1 to -5 for
+    /// 4kb to 64kb nodes
+    #[structopt(
+        long = "list-max-listpack-size",
+        default_value = "-2",
+        allow_hyphen_values = true
+    )]
+    pub list_max_listpack_size: Fill,
+    /// List nodes to leave uncompressed at each end. 0 to never compress
+    #[structopt(long = "list-compress-depth", default_value = "0")]
+    pub list_compress_depth: usize,
 }
 
 pub fn startup_message(config: &Config) {
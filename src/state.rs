@@ -1,14 +1,70 @@
+use crate::data_structures::quicklist::QuickList;
 use crate::data_structures::receipt_map::{KeyTypes, Receipt};
-use crate::types::{Index, ReturnValue, State, StateRef, StateStore};
+use crate::data_structures::scan_index::{scan_keys, ScanMap, ScanSet, ShardedKeys};
+use crate::data_structures::sorted_set::{SortedSet, SortedSetMember};
+use crate::data_structures::stack::Stack;
+use crate::data_structures::stream::{Stream, StreamId};
+use crate::expiry::now_millis;
+use crate::types::{Index, Key, ReturnValue, Score, State, StateRef, StateStore, Value, ValueType};
+use amadeus_streaming::HyperLogLog;
+use growable_bloom_filter::GrowableBloom;
+use rmp_serde as rmps;
+use serde::{Deserialize, Serialize};
+use std::mem::size_of;
+use std::sync::Arc;
 
 const DEFAULT_DB: Index = 0;
 
+/// Rough bookkeeping cost of a key, on top of its name and value.
+const KEY_OVERHEAD: usize = 48;
+/// Rough bookkeeping cost of each element of a collection.
+const ELEMENT_OVERHEAD: usize = 16;
+/// Collections are sized from this many of their elements.
+const SIZE_SAMPLES: usize = 5;
+/// Blooms and hyperloglogs aren't inspected, and count as this many bytes.
+const SKETCH_SIZE: usize = 1024;
+
+/// Approximate size of a collection of `len` elements,
+/// from the sizes of its first few elements.
+fn collection_size(len: usize, sizes: impl Iterator<Item = usize>) -> usize {
+    let (sampled, total) = sizes
+        .take(SIZE_SAMPLES)
+        .fold((0, 0), |(sampled, total), size| (sampled + 1, total + size));
+    if sampled == 0 {
+        return 0;
+    }
+    len * (total / sampled + ELEMENT_OVERHEAD)
+}
+
+/// Bumped whenever the dumped payload format changes.
+const DUMP_VERSION: u16 = 2;
+
+/// A value that can't be rebuilt from commands, as an AOF rewrite
+/// dumps it.
+#[derive(Serialize)]
+enum DumpedValueRef<'a> {
+    Bloom(&'a GrowableBloom),
+    HyperLogLog(&'a HyperLogLog<Value>),
+    Stream(&'a Stream),
+}
+
+/// A value read back from an AOF rewrite.
+/// Must mirror `DumpedValueRef`.
+#[derive(Deserialize)]
+enum DumpedValue {
+    Bloom(GrowableBloom),
+    HyperLogLog(HyperLogLog<Value>),
+    Stream(Stream),
+}
+
 impl std::fmt::Display for ReturnValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             ReturnValue::Ok => write!(f, "OK"),
             ReturnValue::StringRes(s) => write!(f, "{:?}", s),
             ReturnValue::IntRes(i) => write!(f, "{:?}", i),
+            ReturnValue::Double(d) => write!(f, "{:?}", d),
+            ReturnValue::Map(pairs) => write!(f, "{:?}", pairs),
             ReturnValue::MultiStringRes(ss) => write!(f, "{:?}", ss),
             ReturnValue::Nil => write!(f, "(nil)"),
             ReturnValue::Error(e) => write!(f, "ERR {:?}", e),
@@ -24,26 +80,321 @@ impl State {
         rm.get_receipt()
     }
 
-    pub fn receipt_timed_out(&self, receipt: Receipt) -> bool {
-        let rm = self.reciept_map.lock();
-        rm.receipt_timed_out(receipt)
-    }
-
     pub fn wake_list(&self, list_key: &[u8]) {
         let mut rm = self.reciept_map.lock();
         rm.wake_with_key(KeyTypes::list(list_key));
     }
+
+    /// Wake every client blocked reading the stream at `stream_key`.
+    pub fn wake_stream(&self, stream_key: &[u8]) {
+        let mut rm = self.reciept_map.lock();
+        rm.wake_all_with_key(KeyTypes::stream(stream_key));
+    }
+
+    /// Wake clients blocked on `key`, whatever its type, after it's
+    /// replaced by a new value.
+    pub fn wake_key(&self, key: &[u8]) {
+        self.wake_list(key);
+        self.wake_stream(key);
+    }
+
+    /// Check if a key exists in any of the data structures.
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.value_type(key).is_some()
+    }
+
+    /// The kind of value held at `key`, if any.
+    pub fn value_type(&self, key: &[u8]) -> Option<ValueType> {
+        let value_type = if self.kv.contains_key(key) {
+            ValueType::String
+        } else if self.sets.contains_key(key) {
+            ValueType::Set
+        } else if self.lists.contains_key(key) {
+            ValueType::List
+        } else if self.hashes.contains_key(key) {
+            ValueType::Hash
+        } else if self.zsets.contains_key(key) {
+            ValueType::ZSet
+        } else if self.blooms.contains_key(key) {
+            ValueType::Bloom
+        } else if self.stacks.contains_key(key) {
+            ValueType::Stack
+        } else if self.hyperloglogs.contains_key(key) {
+            ValueType::HyperLogLog
+        } else if self.streams.contains_key(key) {
+            ValueType::Stream
+        } else {
+            return None;
+        };
+        Some(value_type)
+    }
+
+    /// Move the value at `key` to `new_key`, replacing whatever was
+    /// there. The time to live moves along with it.
+    /// Returns false if `key` doesn't exist.
+    pub fn rename_key(&self, key: &[u8], new_key: Key) -> bool {
+        if key == &new_key[..] {
+            return self.contains_key(key);
+        }
+        macro_rules! move_value {
+            ($($map:ident),*) => {
+                $(
+                    if let Some((_, value)) = self.$map.remove(key) {
+                        let deadline = self.expirations.remove(key);
+                        self.remove_key(&new_key);
+                        if let Some(deadline) = deadline {
+                            self.expirations.set(new_key.clone(), deadline);
+                        }
+                        self.$map.insert(new_key.clone(), value);
+                        self.wake_key(&new_key);
+                        return true;
+                    }
+                )*
+            };
+        }
+        move_value!(
+            kv,
+            sets,
+            lists,
+            hashes,
+            zsets,
+            blooms,
+            stacks,
+            hyperloglogs,
+            streams
+        );
+        false
+    }
+
+    /// Every key, of every type.
+    pub fn all_keys(&self) -> Vec<Key> {
+        let mut keys = Vec::new();
+        keys.extend(self.kv.iter().map(|r| r.key().clone()));
+        keys.extend(self.sets.iter().map(|r| r.key().clone()));
+        keys.extend(self.lists.iter().map(|r| r.key().clone()));
+        keys.extend(self.hashes.iter().map(|r| r.key().clone()));
+        keys.extend(self.zsets.iter().map(|r| r.key().clone()));
+        keys.extend(self.blooms.iter().map(|r| r.key().clone()));
+        keys.extend(self.stacks.iter().map(|r| r.key().clone()));
+        keys.extend(self.hyperloglogs.iter().map(|r| r.key().clone()));
+        keys.extend(self.streams.iter().map(|r| r.key().clone()));
+        keys
+    }
+
+    /// Approximate bytes used by `key` and its value, if it exists.
+    pub fn estimate_size(&self, key: &[u8]) -> Option<usize> {
+        let value_size = if let Some(v) = self.kv.get(key) {
+            v.len()
+        } else if let Some(v) = self.sets.get(key) {
+            collection_size(v.len(), v.iter().map(|m| m.len()))
+        } else if let Some(v) = self.lists.get(key) {
+            v.memory_usage()
+        } else if let Some(v) = self.hashes.get(key) {
+            collection_size(v.len(), v.iter().map(|(f, m)| f.len() + m.len()))
+        } else if let Some(v) = self.zsets.get(key) {
+            // Members are held both by score and by name.
+            let member_size = |m: &SortedSetMember| 2 * (m.member.len() + size_of::<Score>());
+            collection_size(v.card() as usize, v.iter().map(member_size))
+        } else if let Some(v) = self.stacks.get(key) {
+            collection_size(v.size() as usize, v.iter().map(|m| m.len()))
+        } else if let Some(v) = self.streams.get(key) {
+            let entry_size = |(_, fields): (_, &Vec<(Key, Value)>)| {
+                let field_size = |(f, v): &(Key, Value)| f.len() + v.len();
+                size_of::<StreamId>() + fields.iter().map(field_size).sum::<usize>()
+            };
+            collection_size(v.len(), v.iter().map(entry_size))
+        } else if self.blooms.contains_key(key) || self.hyperloglogs.contains_key(key) {
+            SKETCH_SIZE
+        } else {
+            return None;
+        };
+        Some(KEY_OVERHEAD + key.len() + value_size)
+    }
+
+    /// Update memory accounting after an operation on `keys`.
+    /// Written keys are resized, others only count as accessed.
+    pub fn track_memory(&self, keys: &[Key], written: bool) {
+        if !self.memory.is_enabled() {
+            return;
+        }
+        let now = now_millis();
+        for key in keys {
+            if !written {
+                self.memory.touch(key, now);
+                continue;
+            }
+            match self.estimate_size(key) {
+                Some(size) => self.memory.update(key, size, now),
+                None => self.memory.remove(key),
+            }
+        }
+    }
+
+    /// One step of SCAN over every key, see [`scan_keys`].
+    pub fn scan_keys(&self, cursor: u64, count: usize) -> (u64, Vec<Key>) {
+        let maps: [&dyn ShardedKeys; 9] = [
+            &self.kv,
+            &self.sets,
+            &self.lists,
+            &self.hashes,
+            &self.zsets,
+            &self.blooms,
+            &self.stacks,
+            &self.hyperloglogs,
+            &self.streams,
+        ];
+        scan_keys(&maps, cursor, count)
+    }
+
+    /// Remove a key from every data structure, along with its expiry.
+    /// Returns true if anything was removed.
+    pub fn remove_key(&self, key: &[u8]) -> bool {
+        self.expirations.remove(key);
+        self.remove_data(key)
+    }
+
+    fn remove_data(&self, key: &[u8]) -> bool {
+        self.memory.remove(key);
+        // Evaluate every removal; don't short circuit.
+        [
+            self.kv.remove(key).is_some(),
+            self.sets.remove(key).is_some(),
+            self.lists.remove(key).is_some(),
+            self.hashes.remove(key).is_some(),
+            self.zsets.remove(key).is_some(),
+            self.blooms.remove(key).is_some(),
+            self.stacks.remove(key).is_some(),
+            self.hyperloglogs.remove(key).is_some(),
+            self.streams.remove(key).is_some(),
+        ]
+        .contains(&true)
+    }
+
+    /// Remove every key.
+    pub fn clear(&self) {
+        self.kv.clear();
+        self.sets.clear();
+        self.lists.clear();
+        self.hashes.clear();
+        self.zsets.clear();
+        self.blooms.clear();
+        self.stacks.clear();
+        self.hyperloglogs.clear();
+        self.streams.clear();
+        self.expirations.clear();
+        self.memory.clear();
+        self.watches.touch_all();
+    }
+
+    /// Serialize the bloom, hyperloglog or stream at `key`, for AOF
+    /// rewrites. Other values are rebuilt from commands instead.
+    ///
+    /// The payload ends with a format version and a checksum, like redis.
+    pub fn dump_key(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let mut payload = if let Some(v) = self.blooms.get(key) {
+            rmps::to_vec(&DumpedValueRef::Bloom(&v))
+        } else if let Some(v) = self.hyperloglogs.get(key) {
+            rmps::to_vec(&DumpedValueRef::HyperLogLog(&v))
+        } else if let Some(v) = self.streams.get(key) {
+            rmps::to_vec(&DumpedValueRef::Stream(&v))
+        } else {
+            return None;
+        }
+        .ok()?;
+        payload.extend_from_slice(&DUMP_VERSION.to_le_bytes());
+        let checksum = seahash::hash(&payload);
+        payload.extend_from_slice(&checksum.to_le_bytes());
+        Some(payload)
+    }
+
+    /// Store a value serialized by `dump_key` at `key`.
+    /// Returns false if the payload is invalid.
+    pub fn restore_key(&self, key: Key, payload: &[u8]) -> bool {
+        if payload.len() < 10 {
+            return false;
+        }
+        let (body, checksum) = payload.split_at(payload.len() - 8);
+        if seahash::hash(body).to_le_bytes() != checksum {
+            return false;
+        }
+        let (value, version) = body.split_at(body.len() - 2);
+        if version != DUMP_VERSION.to_le_bytes() {
+            return false;
+        }
+        let value: DumpedValue = match rmps::decode::from_read(value) {
+            Ok(value) => value,
+            Err(_) => return false,
+        };
+        let restored = key.clone();
+        match value {
+            DumpedValue::Bloom(v) => self.blooms.insert(key, v).is_some(),
+            DumpedValue::HyperLogLog(v) => self.hyperloglogs.insert(key, v).is_some(),
+            DumpedValue::Stream(v) => self.streams.insert(key, v).is_some(),
+        };
+        self.wake_key(&restored);
+        true
+    }
+
+    /// Lazily expire a key. Returns true if the key had expired and was removed.
+    pub fn expire_if_needed(&self, key: &[u8]) -> bool {
+        let now = now_millis();
+        match self.expirations.get(key) {
+            Some(deadline) if deadline <= now => {}
+            _ => return false,
+        }
+        if self.expirations.remove_if_expired(key, now) {
+            self.remove_data(key);
+            self.watches.touch(key);
+            return true;
+        }
+        false
+    }
+
+    /// Actively remove every key whose deadline has passed.
+    /// Returns the number of keys removed.
+    pub fn remove_expired_keys(&self) -> usize {
+        const BATCH_SIZE: usize = 64;
+        let mut removed = 0;
+        loop {
+            let due = self.expirations.due(now_millis(), BATCH_SIZE);
+            if due.is_empty() {
+                return removed;
+            }
+            removed += due.iter().filter(|key| self.expire_if_needed(key)).count();
+        }
+    }
 }
 
 impl StateStore {
     pub fn get_or_create(&self, index: Index) -> StateRef {
-        self.states.entry(index).or_default().clone()
+        self.states
+            .entry(index)
+            .or_insert_with(|| {
+                let state = State::default();
+                if self.maxmemory > 0 {
+                    state.memory.enable();
+                }
+                Arc::new(state)
+            })
+            .clone()
     }
 
     pub fn get_default(&self) -> StateRef {
         self.get_or_create(DEFAULT_DB)
     }
 
+    /// Approximate bytes used by the keys of every db.
+    pub fn used_memory(&self) -> usize {
+        self.states.iter().map(|state| state.memory.used()).sum()
+    }
+
+    /// Remove every key in every db.
+    pub fn flush_all(&self) {
+        for state in self.states.iter() {
+            state.clear();
+        }
+    }
+
     pub fn contains_foreign_function(&self, function_symbol: &str) -> bool {
         self.foreign_functions.read().contains(function_symbol)
     }
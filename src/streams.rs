@@ -0,0 +1,916 @@
+/// Streams: append only logs of field / value entries.
+///
+/// Blocking XREADs and XREADGROUPs park on the same waker machinery as
+/// BLPOP, and every XADD wakes all of the readers of its stream.
+use crate::data_structures::receipt_map::KeyTypes;
+use crate::data_structures::stream::{
+    AddError, ClaimOptions, ConsumerGroup, NewId, Stream, StreamFields, StreamId, Trim,
+};
+use crate::expiry::now_millis;
+use crate::ops::RVec;
+use crate::timeouts::blocking_keys_timeout;
+use crate::types::{Count, Key, ReturnValue, StateRef, Value};
+use dashmap::mapref::entry::Entry;
+use std::time::Duration;
+
+/// Where XREAD starts reading a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadFrom {
+    /// After this ID.
+    Id(StreamId),
+    /// `$`: after the last entry when the command ran.
+    Last,
+}
+
+/// Where XREADGROUP reads a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GroupReadFrom {
+    /// `>`: entries not yet delivered to any consumer of the group.
+    Undelivered,
+    /// The consumer's pending entries after this ID.
+    Pending(StreamId),
+}
+
+/// The extended form of XPENDING: `[IDLE min-idle-time] start end count [consumer]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingRange {
+    pub min_idle: Option<u64>,
+    pub start: StreamId,
+    pub end: StreamId,
+    pub count: usize,
+    pub consumer: Option<Key>,
+}
+
+op_variants! {
+    StreamOps,
+    XAdd(Key, NewId, RVec<(Key, Value)>, Option<Trim>, bool),
+    XLen(Key),
+    XRange(Key, StreamId, StreamId, Option<usize>),
+    XRevRange(Key, StreamId, StreamId, Option<usize>),
+    XDel(Key, RVec<StreamId>),
+    XTrim(Key, Trim),
+    XRead(RVec<(Key, ReadFrom)>, Option<usize>, Option<u64>),
+    XGroupCreate(Key, Key, ReadFrom, bool, Option<u64>),
+    XGroupSetId(Key, Key, ReadFrom, Option<u64>),
+    XGroupDestroy(Key, Key),
+    XGroupCreateConsumer(Key, Key, Key),
+    XGroupDelConsumer(Key, Key, Key),
+    XReadGroup(Key, Key, RVec<(Key, GroupReadFrom)>, Option<usize>, Option<u64>, bool),
+    XAck(Key, Key, RVec<StreamId>),
+    XPending(Key, Key, Option<PendingRange>),
+    XClaim(Key, Key, Key, RVec<StreamId>, ClaimOptions),
+    XAutoClaim(Key, Key, Key, StreamId, usize, ClaimOptions),
+    XInfoStream(Key),
+    XInfoGroups(Key),
+    XInfoConsumers(Key, Key)
+}
+
+impl StreamOps {
+    pub fn keys(&self) -> Vec<Key> {
+        match self {
+            StreamOps::XAdd(key, ..)
+            | StreamOps::XLen(key)
+            | StreamOps::XRange(key, ..)
+            | StreamOps::XRevRange(key, ..)
+            | StreamOps::XDel(key, _)
+            | StreamOps::XTrim(key, _)
+            | StreamOps::XGroupCreate(key, ..)
+            | StreamOps::XGroupSetId(key, ..)
+            | StreamOps::XGroupDestroy(key, _)
+            | StreamOps::XGroupCreateConsumer(key, ..)
+            | StreamOps::XGroupDelConsumer(key, ..)
+            | StreamOps::XAck(key, ..)
+            | StreamOps::XPending(key, ..)
+            | StreamOps::XClaim(key, ..)
+            | StreamOps::XAutoClaim(key, ..)
+            | StreamOps::XInfoStream(key)
+            | StreamOps::XInfoGroups(key)
+            | StreamOps::XInfoConsumers(key, _) => vec![key.clone()],
+            StreamOps::XRead(streams, ..) => streams.iter().map(|(key, _)| key.clone()).collect(),
+            StreamOps::XReadGroup(_, _, streams, ..) => {
+                streams.iter().map(|(key, _)| key.clone()).collect()
+            }
+        }
+    }
+
+    /// Whether this operation may modify state.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            StreamOps::XAdd(..)
+                | StreamOps::XDel(..)
+                | StreamOps::XTrim(..)
+                | StreamOps::XGroupCreate(..)
+                | StreamOps::XGroupSetId(..)
+                | StreamOps::XGroupDestroy(..)
+                | StreamOps::XGroupCreateConsumer(..)
+                | StreamOps::XGroupDelConsumer(..)
+                // Reading as a group delivers entries.
+                | StreamOps::XReadGroup(..)
+                | StreamOps::XAck(..)
+                | StreamOps::XClaim(..)
+                | StreamOps::XAutoClaim(..)
+        )
+    }
+
+    pub fn is_blocking(&self) -> bool {
+        matches!(
+            self,
+            StreamOps::XRead(_, _, Some(_)) | StreamOps::XReadGroup(_, _, _, _, Some(_), _)
+        )
+    }
+
+    /// Get the non-blocking version of this operation.
+    /// Used inside transactions, where blocking makes no sense.
+    pub fn without_blocking(self) -> StreamOps {
+        match self {
+            StreamOps::XRead(streams, count, _) => StreamOps::XRead(streams, count, None),
+            StreamOps::XReadGroup(group, consumer, streams, count, _, no_ack) => {
+                StreamOps::XReadGroup(group, consumer, streams, count, None, no_ack)
+            }
+            op => op,
+        }
+    }
+}
+
+fn add_error(err: AddError) -> ReturnValue {
+    ReturnValue::Error(match err {
+        AddError::TooSmall => {
+            b"ERR The ID specified in XADD is equal or smaller than the target stream top item"
+        }
+        AddError::Zero => b"ERR The ID specified in XADD must be greater than 0-0",
+        AddError::Exhausted => {
+            b"ERR The stream has exhausted the last possible ID, unable to add more items"
+        }
+    })
+}
+
+const NO_GROUP: &[u8] = b"NOGROUP No such key or consumer group";
+const NO_GROUP_READ: &[u8] =
+    b"NOGROUP No such key or consumer group in XREADGROUP with GROUP option";
+const NO_KEY: &[u8] = b"ERR The XGROUP subcommand requires the key to exist. \
+Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.";
+
+fn id_reply(id: &StreamId) -> ReturnValue {
+    ReturnValue::StringRes(id.to_string().into())
+}
+
+/// A reply with named fields, like XINFO's.
+fn info_reply(fields: Vec<(&'static str, ReturnValue)>) -> ReturnValue {
+    let fields = fields
+        .into_iter()
+        .map(|(name, value)| (ReturnValue::StringRes(name.into()), value))
+        .collect();
+    ReturnValue::Map(fields)
+}
+
+fn entry_reply(id: &StreamId, fields: &[(Key, Value)]) -> ReturnValue {
+    let fields = fields
+        .iter()
+        .flat_map(|(field, value)| [field.clone(), value.clone()])
+        .collect();
+    ReturnValue::Array(vec![id_reply(id), ReturnValue::MultiStringRes(fields)])
+}
+
+/// Entries as XCLAIM replies: in full, or only their IDs.
+fn claimed_reply(claimed: Vec<(StreamId, StreamFields)>, just_id: bool) -> ReturnValue {
+    let claimed = claimed.iter().map(|(id, fields)| match just_id {
+        true => id_reply(id),
+        false => entry_reply(id, fields),
+    });
+    ReturnValue::Array(claimed.collect())
+}
+
+fn entries_reply<'a>(
+    entries: impl Iterator<Item = (&'a StreamId, &'a Vec<(Key, Value)>)>,
+    count: Option<usize>,
+) -> ReturnValue {
+    let entries = entries
+        .take(count.unwrap_or(usize::MAX))
+        .map(|(id, fields)| entry_reply(id, fields))
+        .collect();
+    ReturnValue::Array(entries)
+}
+
+/// The entries of each stream after its ID, or None if there are none.
+fn read_streams(
+    state: &StateRef,
+    streams: &[(Key, StreamId)],
+    count: Option<usize>,
+) -> Option<ReturnValue> {
+    let mut replies = Vec::new();
+    for (key, after) in streams {
+        let stream = match state.streams.get(key) {
+            Some(stream) => stream,
+            None => continue,
+        };
+        let start = match after.next() {
+            Some(start) => start,
+            None => continue,
+        };
+        let mut entries = stream.range(start, StreamId::MAX).peekable();
+        if entries.peek().is_none() {
+            continue;
+        }
+        replies.push(ReturnValue::Array(vec![
+            ReturnValue::StringRes(key.clone()),
+            entries_reply(entries, count),
+        ]));
+    }
+    (!replies.is_empty()).then_some(ReturnValue::Array(replies))
+}
+
+/// Read `streams` as `consumer` of `group`, or None if there's nothing to read.
+fn read_group(
+    state: &StateRef,
+    group: &Key,
+    consumer: &Key,
+    streams: &[(Key, GroupReadFrom)],
+    count: Option<usize>,
+    no_ack: bool,
+) -> Option<ReturnValue> {
+    let now = now_millis() as u64;
+    let mut replies = Vec::new();
+    for (key, from) in streams {
+        let mut stream = match state.streams.get_mut(key) {
+            Some(stream) => stream,
+            None => return Some(ReturnValue::Error(NO_GROUP_READ)),
+        };
+        let entries = match from {
+            GroupReadFrom::Undelivered => {
+                match stream.read_group(group, consumer, count, no_ack, now) {
+                    Some(entries) if entries.is_empty() => continue,
+                    Some(entries) => entries
+                        .iter()
+                        .map(|(id, fields)| entry_reply(id, fields))
+                        .collect(),
+                    None => return Some(ReturnValue::Error(NO_GROUP_READ)),
+                }
+            }
+            // The history of a consumer is always replied, even if empty.
+            GroupReadFrom::Pending(after) => {
+                match stream.read_pending(group, consumer, *after, count, now) {
+                    Some(entries) => entries
+                        .iter()
+                        .map(|(id, fields)| match fields {
+                            Some(fields) => entry_reply(id, fields),
+                            // Removed from the stream since.
+                            None => ReturnValue::Array(vec![id_reply(id), ReturnValue::Nil]),
+                        })
+                        .collect(),
+                    None => return Some(ReturnValue::Error(NO_GROUP_READ)),
+                }
+            }
+        };
+        replies.push(ReturnValue::Array(vec![
+            ReturnValue::StringRes(key.clone()),
+            ReturnValue::Array(entries),
+        ]));
+    }
+    (!replies.is_empty()).then_some(ReturnValue::Array(replies))
+}
+
+fn pending_reply(stream: &Stream, group: &Key, range: Option<PendingRange>) -> ReturnValue {
+    let group = match stream.group(group) {
+        Some(group) => group,
+        None => return ReturnValue::Error(NO_GROUP),
+    };
+    let range = match range {
+        Some(range) => range,
+        None => {
+            let (first, last) = match (group.pending.keys().next(), group.pending.keys().last()) {
+                (Some(first), Some(last)) => (first, last),
+                _ => {
+                    return ReturnValue::Array(vec![
+                        ReturnValue::IntRes(0),
+                        ReturnValue::Nil,
+                        ReturnValue::Nil,
+                        ReturnValue::Nil,
+                    ])
+                }
+            };
+            let consumers = group
+                .consumers
+                .iter()
+                .filter(|(_, consumer)| !consumer.pending.is_empty())
+                .map(|(name, consumer)| {
+                    ReturnValue::Array(vec![
+                        ReturnValue::StringRes(name.clone()),
+                        ReturnValue::StringRes(consumer.pending.len().to_string().into()),
+                    ])
+                })
+                .collect();
+            return ReturnValue::Array(vec![
+                ReturnValue::IntRes(group.pending.len() as Count),
+                id_reply(first),
+                id_reply(last),
+                ReturnValue::Array(consumers),
+            ]);
+        }
+    };
+    if range.start > range.end {
+        return ReturnValue::Array(vec![]);
+    }
+    let now = now_millis() as u64;
+    let pending = group
+        .pending
+        .range(range.start..=range.end)
+        .filter(|(_, pending)| match &range.consumer {
+            Some(consumer) => pending.consumer == consumer,
+            None => true,
+        })
+        .map(|(id, pending)| (id, pending, now.saturating_sub(pending.delivered_at)))
+        .filter(|(_, _, idle)| *idle >= range.min_idle.unwrap_or(0))
+        .take(range.count)
+        .map(|(id, pending, idle)| {
+            ReturnValue::Array(vec![
+                id_reply(id),
+                ReturnValue::StringRes(pending.consumer.clone()),
+                ReturnValue::IntRes(idle as Count),
+                ReturnValue::IntRes(pending.delivery_count as Count),
+            ])
+        })
+        .collect();
+    ReturnValue::Array(pending)
+}
+
+fn stream_info(stream: &Stream) -> ReturnValue {
+    let entry = |entry: Option<(&StreamId, &StreamFields)>| match entry {
+        Some((id, fields)) => entry_reply(id, fields),
+        None => ReturnValue::Nil,
+    };
+    let first_id = stream.first().map_or(StreamId::MIN, |(id, _)| *id);
+    info_reply(vec![
+        ("length", ReturnValue::IntRes(stream.len() as Count)),
+        ("last-generated-id", id_reply(&stream.last_id())),
+        ("max-deleted-entry-id", id_reply(&stream.max_deleted_id())),
+        (
+            "entries-added",
+            ReturnValue::IntRes(stream.entries_added() as Count),
+        ),
+        ("recorded-first-entry-id", id_reply(&first_id)),
+        (
+            "groups",
+            ReturnValue::IntRes(stream.groups().len() as Count),
+        ),
+        ("first-entry", entry(stream.first())),
+        ("last-entry", entry(stream.last())),
+    ])
+}
+
+fn group_info(stream: &Stream, name: &Key, group: &ConsumerGroup) -> ReturnValue {
+    let optional = |n: Option<u64>| n.map_or(ReturnValue::Nil, |n| ReturnValue::IntRes(n as Count));
+    info_reply(vec![
+        ("name", ReturnValue::StringRes(name.clone())),
+        (
+            "consumers",
+            ReturnValue::IntRes(group.consumers.len() as Count),
+        ),
+        ("pending", ReturnValue::IntRes(group.pending.len() as Count)),
+        ("last-delivered-id", id_reply(&group.last_delivered)),
+        ("entries-read", optional(group.entries_read)),
+        ("lag", optional(stream.lag(group))),
+    ])
+}
+
+fn consumers_info(group: &ConsumerGroup) -> ReturnValue {
+    let now = now_millis() as u64;
+    let consumers = group.consumers.iter().map(|(name, consumer)| {
+        let inactive = consumer
+            .active_at
+            .map_or(-1, |active_at| now.saturating_sub(active_at) as Count);
+        info_reply(vec![
+            ("name", ReturnValue::StringRes(name.clone())),
+            (
+                "pending",
+                ReturnValue::IntRes(consumer.pending.len() as Count),
+            ),
+            (
+                "idle",
+                ReturnValue::IntRes(now.saturating_sub(consumer.seen_at) as Count),
+            ),
+            ("inactive", ReturnValue::IntRes(inactive)),
+        ])
+    });
+    ReturnValue::Array(consumers.collect())
+}
+
+pub async fn stream_interact(stream_op: StreamOps, state: StateRef) -> ReturnValue {
+    match stream_op {
+        StreamOps::XAdd(key, id, fields, trim, no_mkstream) => {
+            let fields = fields.into_vec();
+            let now = now_millis() as u64;
+            let added = match state.streams.entry(key.clone()) {
+                Entry::Occupied(mut stream) => stream.get_mut().add(id, fields, now),
+                Entry::Vacant(_) if no_mkstream => return ReturnValue::Nil,
+                Entry::Vacant(vacant) => {
+                    // Don't create the stream if the ID is invalid.
+                    let mut stream = Stream::default();
+                    let added = stream.add(id, fields, now);
+                    if added.is_ok() {
+                        vacant.insert(stream);
+                    }
+                    added
+                }
+            };
+            let id = match added {
+                Ok(id) => id,
+                Err(err) => return add_error(err),
+            };
+            if let Some(trim) = trim {
+                if let Some(mut stream) = state.streams.get_mut(&key) {
+                    stream.trim(&trim);
+                }
+            }
+            state.wake_stream(&key);
+            id_reply(&id)
+        }
+        StreamOps::XLen(key) => {
+            let len = state.streams.get(&key).map_or(0, |stream| stream.len());
+            ReturnValue::IntRes(len as Count)
+        }
+        StreamOps::XRange(key, start, end, count) => match state.streams.get(&key) {
+            Some(stream) => entries_reply(stream.range(start, end), count),
+            None => ReturnValue::Array(vec![]),
+        },
+        StreamOps::XRevRange(key, end, start, count) => match state.streams.get(&key) {
+            Some(stream) => entries_reply(stream.range(start, end).rev(), count),
+            None => ReturnValue::Array(vec![]),
+        },
+        StreamOps::XDel(key, ids) => match state.streams.get_mut(&key) {
+            Some(mut stream) => {
+                let removed = ids.iter().filter(|id| stream.remove(id)).count();
+                ReturnValue::IntRes(removed as Count)
+            }
+            None => ReturnValue::IntRes(0),
+        },
+        StreamOps::XTrim(key, trim) => match state.streams.get_mut(&key) {
+            Some(mut stream) => ReturnValue::IntRes(stream.trim(&trim) as Count),
+            None => ReturnValue::IntRes(0),
+        },
+        StreamOps::XRead(streams, count, block) => {
+            // Resolve `$` now, so only entries added while blocked are read.
+            let streams: Vec<(Key, StreamId)> = streams
+                .into_iter()
+                .map(|(key, from)| {
+                    let after = match from {
+                        ReadFrom::Id(id) => id,
+                        ReadFrom::Last => state
+                            .streams
+                            .get(&key)
+                            .map_or(StreamId::MIN, |stream| stream.last_id()),
+                    };
+                    (key, after)
+                })
+                .collect();
+            let block = match block {
+                Some(block) => block,
+                None => return read_streams(&state, &streams, count).unwrap_or(ReturnValue::Nil),
+            };
+            let keys = streams
+                .iter()
+                .map(|(key, _)| KeyTypes::stream(key))
+                .collect();
+            let state_clone = state.clone();
+            let read = move || read_streams(&state, &streams, count);
+            // BLOCK 0 waits forever.
+            let wait = (block > 0).then(|| Duration::from_millis(block));
+            blocking_keys_timeout(Box::new(read), state_clone, keys, wait).await
+        }
+        StreamOps::XGroupCreate(key, group, from, mk_stream, entries_read) => {
+            let mut stream = match (state.streams.get_mut(&key), mk_stream) {
+                (Some(stream), _) => stream,
+                (None, true) => state.streams.entry(key).or_default(),
+                (None, false) => return ReturnValue::Error(NO_KEY),
+            };
+            let last_delivered = match from {
+                ReadFrom::Id(id) => id,
+                ReadFrom::Last => stream.last_id(),
+            };
+            match stream.create_group(group, ConsumerGroup::new(last_delivered, entries_read)) {
+                true => ReturnValue::Ok,
+                false => ReturnValue::Error(b"BUSYGROUP Consumer Group name already exists"),
+            }
+        }
+        StreamOps::XGroupSetId(key, group, from, entries_read) => {
+            let mut stream = match state.streams.get_mut(&key) {
+                Some(stream) => stream,
+                None => return ReturnValue::Error(NO_KEY),
+            };
+            let last_delivered = match from {
+                ReadFrom::Id(id) => id,
+                ReadFrom::Last => stream.last_id(),
+            };
+            match stream.group_mut(&group) {
+                Some(group) => {
+                    group.last_delivered = last_delivered;
+                    group.entries_read = entries_read;
+                    ReturnValue::Ok
+                }
+                None => ReturnValue::Error(NO_GROUP),
+            }
+        }
+        StreamOps::XGroupDestroy(key, group) => match state.streams.get_mut(&key) {
+            Some(mut stream) => ReturnValue::IntRes(stream.remove_group(&group) as Count),
+            None => ReturnValue::Error(NO_KEY),
+        },
+        StreamOps::XGroupCreateConsumer(key, group, consumer) => {
+            let mut stream = match state.streams.get_mut(&key) {
+                Some(stream) => stream,
+                None => return ReturnValue::Error(NO_KEY),
+            };
+            match stream.group_mut(&group) {
+                Some(group) => {
+                    let created = group.create_consumer(&consumer, now_millis() as u64);
+                    ReturnValue::IntRes(created as Count)
+                }
+                None => ReturnValue::Error(NO_GROUP),
+            }
+        }
+        StreamOps::XGroupDelConsumer(key, group, consumer) => {
+            let mut stream = match state.streams.get_mut(&key) {
+                Some(stream) => stream,
+                None => return ReturnValue::Error(NO_KEY),
+            };
+            match stream.group_mut(&group) {
+                Some(group) => {
+                    let pending = group.remove_consumer(&consumer).unwrap_or(0);
+                    ReturnValue::IntRes(pending as Count)
+                }
+                None => ReturnValue::Error(NO_GROUP),
+            }
+        }
+        StreamOps::XReadGroup(group, consumer, streams, count, block, no_ack) => {
+            let block = match block {
+                Some(block) => block,
+                None => {
+                    return read_group(&state, &group, &consumer, &streams, count, no_ack)
+                        .unwrap_or(ReturnValue::Nil)
+                }
+            };
+            let keys = streams
+                .iter()
+                .map(|(key, _)| KeyTypes::stream(key))
+                .collect();
+            let state_clone = state.clone();
+            let read = move || read_group(&state, &group, &consumer, &streams, count, no_ack);
+            let wait = (block > 0).then(|| Duration::from_millis(block));
+            blocking_keys_timeout(Box::new(read), state_clone, keys, wait).await
+        }
+        StreamOps::XAck(key, group, ids) => {
+            let mut stream = match state.streams.get_mut(&key) {
+                Some(stream) => stream,
+                None => return ReturnValue::IntRes(0),
+            };
+            match stream.group_mut(&group) {
+                Some(group) => {
+                    let acked = ids.iter().filter(|id| group.ack(id)).count();
+                    ReturnValue::IntRes(acked as Count)
+                }
+                None => ReturnValue::IntRes(0),
+            }
+        }
+        StreamOps::XPending(key, group, range) => match state.streams.get(&key) {
+            Some(stream) => pending_reply(&stream, &group, range),
+            None => ReturnValue::Error(NO_GROUP),
+        },
+        StreamOps::XClaim(key, group, consumer, ids, options) => {
+            let mut stream = match state.streams.get_mut(&key) {
+                Some(stream) => stream,
+                None => return ReturnValue::Error(NO_GROUP),
+            };
+            let now = now_millis() as u64;
+            match stream.claim(&group, &consumer, &ids, &options, now) {
+                Some(claimed) => claimed_reply(claimed, options.just_id),
+                None => ReturnValue::Error(NO_GROUP),
+            }
+        }
+        StreamOps::XAutoClaim(key, group, consumer, start, count, options) => {
+            let mut stream = match state.streams.get_mut(&key) {
+                Some(stream) => stream,
+                None => return ReturnValue::Error(NO_GROUP),
+            };
+            let now = now_millis() as u64;
+            match stream.auto_claim(&group, &consumer, start, count, &options, now) {
+                Some(claimed) => ReturnValue::Array(vec![
+                    id_reply(&claimed.cursor),
+                    claimed_reply(claimed.claimed, options.just_id),
+                    ReturnValue::Array(claimed.deleted.iter().map(id_reply).collect()),
+                ]),
+                None => ReturnValue::Error(NO_GROUP),
+            }
+        }
+        StreamOps::XInfoStream(key) => match state.streams.get(&key) {
+            Some(stream) => stream_info(&stream),
+            None => ReturnValue::Error(b"ERR no such key"),
+        },
+        StreamOps::XInfoGroups(key) => match state.streams.get(&key) {
+            Some(stream) => {
+                let groups = stream.groups().iter();
+                ReturnValue::Array(
+                    groups
+                        .map(|(name, group)| group_info(&stream, name, group))
+                        .collect(),
+                )
+            }
+            None => ReturnValue::Error(b"ERR no such key"),
+        },
+        StreamOps::XInfoConsumers(key, group) => match state.streams.get(&key) {
+            Some(stream) => match stream.group(&group) {
+                Some(group) => consumers_info(group),
+                None => ReturnValue::Error(NO_GROUP),
+            },
+            None => ReturnValue::Error(b"ERR no such key"),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test_streams {
+    use crate::data_structures::stream::{ClaimOptions, NewId, StreamId, Trim, TrimStrategy};
+    use crate::streams::{stream_interact, GroupReadFrom, PendingRange, ReadFrom, StreamOps};
+    use crate::types::{ReturnValue, State, StateRef};
+    use bytes::Bytes;
+    use smallvec::smallvec;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn add(key: &'static str, id: NewId) -> StreamOps {
+        let field = (Bytes::from_static(b"f"), Bytes::from_static(b"v"));
+        StreamOps::XAdd(
+            Bytes::from_static(key.as_bytes()),
+            id,
+            smallvec![field],
+            None,
+            false,
+        )
+    }
+
+    fn id_reply(ms: u64, seq: u64) -> ReturnValue {
+        ReturnValue::StringRes(StreamId::new(ms, seq).to_string().into())
+    }
+
+    fn entry(ms: u64, seq: u64) -> ReturnValue {
+        ReturnValue::Array(vec![
+            id_reply(ms, seq),
+            ReturnValue::MultiStringRes(vec![Bytes::from_static(b"f"), Bytes::from_static(b"v")]),
+        ])
+    }
+
+    #[tokio::test]
+    async fn test_add_and_range() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"s");
+        for ms in 1..=3 {
+            assert_eq!(
+                stream_interact(add("s", NewId::Sequence(ms)), state.clone()).await,
+                id_reply(ms, 0)
+            );
+        }
+        assert_eq!(
+            stream_interact(add("s", NewId::Exact(StreamId::new(2, 0))), state.clone()).await,
+            ReturnValue::Error(
+                b"ERR The ID specified in XADD is equal or smaller than the target stream top item"
+            )
+        );
+        // A failed XADD doesn't create the stream.
+        stream_interact(add("t", NewId::Exact(StreamId::MIN)), state.clone()).await;
+        assert!(!state.streams.contains_key(&Bytes::from_static(b"t")));
+        let range = StreamOps::XRange(key.clone(), StreamId::new(2, 0), StreamId::MAX, None);
+        assert_eq!(
+            stream_interact(range, state.clone()).await,
+            ReturnValue::Array(vec![entry(2, 0), entry(3, 0)])
+        );
+        let rev_range = StreamOps::XRevRange(key.clone(), StreamId::MAX, StreamId::MIN, Some(2));
+        assert_eq!(
+            stream_interact(rev_range, state.clone()).await,
+            ReturnValue::Array(vec![entry(3, 0), entry(2, 0)])
+        );
+        let del = StreamOps::XDel(
+            key.clone(),
+            smallvec![StreamId::new(1, 0), StreamId::new(9, 0)],
+        );
+        assert_eq!(
+            stream_interact(del, state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        let trim = Trim {
+            strategy: TrimStrategy::MaxLen(1),
+            approximate: false,
+            limit: None,
+        };
+        assert_eq!(
+            stream_interact(StreamOps::XTrim(key.clone(), trim), state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert_eq!(
+            stream_interact(StreamOps::XLen(key), state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read() {
+        let state: StateRef = Arc::new(State::default());
+        let key = Bytes::from_static(b"s");
+        stream_interact(add("s", NewId::Sequence(1)), state.clone()).await;
+        stream_interact(add("s", NewId::Sequence(2)), state.clone()).await;
+        let read = |from, block| StreamOps::XRead(smallvec![(key.clone(), from)], Some(1), block);
+        assert_eq!(
+            stream_interact(read(ReadFrom::Id(StreamId::new(1, 0)), None), state.clone()).await,
+            ReturnValue::Array(vec![ReturnValue::Array(vec![
+                ReturnValue::StringRes(key.clone()),
+                ReturnValue::Array(vec![entry(2, 0)])
+            ])])
+        );
+        assert_eq!(
+            stream_interact(read(ReadFrom::Last, None), state.clone()).await,
+            ReturnValue::Nil
+        );
+        // Blocked readers are woken by XADD.
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                tokio::spawn(stream_interact(
+                    read(ReadFrom::Last, Some(0)),
+                    state.clone(),
+                ))
+            })
+            .collect();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream_interact(add("s", NewId::Sequence(3)), state.clone()).await;
+        for reader in readers {
+            assert_eq!(
+                reader.await.unwrap(),
+                ReturnValue::Array(vec![ReturnValue::Array(vec![
+                    ReturnValue::StringRes(key.clone()),
+                    ReturnValue::Array(vec![entry(3, 0)])
+                ])])
+            );
+        }
+        // And time out otherwise.
+        assert_eq!(
+            stream_interact(read(ReadFrom::Last, Some(10)), state.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+
+    #[tokio::test]
+    async fn test_groups() {
+        let state: StateRef = Arc::new(State::default());
+        let (key, group) = (Bytes::from_static(b"s"), Bytes::from_static(b"g"));
+        let create = |mk_stream| {
+            StreamOps::XGroupCreate(key.clone(), group.clone(), ReadFrom::Last, mk_stream, None)
+        };
+        assert!(stream_interact(create(false), state.clone())
+            .await
+            .is_error());
+        assert_eq!(
+            stream_interact(create(true), state.clone()).await,
+            ReturnValue::Ok
+        );
+        assert_eq!(
+            stream_interact(create(true), state.clone()).await,
+            ReturnValue::Error(b"BUSYGROUP Consumer Group name already exists")
+        );
+        stream_interact(add("s", NewId::Sequence(1)), state.clone()).await;
+        stream_interact(add("s", NewId::Sequence(2)), state.clone()).await;
+        let read = |consumer: &'static str, from| {
+            StreamOps::XReadGroup(
+                group.clone(),
+                Bytes::from_static(consumer.as_bytes()),
+                smallvec![(key.clone(), from)],
+                Some(1),
+                None,
+                false,
+            )
+        };
+        let reply = |entries| {
+            ReturnValue::Array(vec![ReturnValue::Array(vec![
+                ReturnValue::StringRes(key.clone()),
+                ReturnValue::Array(entries),
+            ])])
+        };
+        assert_eq!(
+            stream_interact(read("a", GroupReadFrom::Undelivered), state.clone()).await,
+            reply(vec![entry(1, 0)])
+        );
+        assert_eq!(
+            stream_interact(read("b", GroupReadFrom::Undelivered), state.clone()).await,
+            reply(vec![entry(2, 0)])
+        );
+        assert_eq!(
+            stream_interact(read("b", GroupReadFrom::Undelivered), state.clone()).await,
+            ReturnValue::Nil
+        );
+        // The history is replied even when empty.
+        assert_eq!(
+            stream_interact(
+                read("c", GroupReadFrom::Pending(StreamId::MIN)),
+                state.clone()
+            )
+            .await,
+            reply(vec![])
+        );
+        let consumer = |name: &'static [u8]| {
+            ReturnValue::Array(vec![
+                ReturnValue::StringRes(Bytes::from_static(name)),
+                ReturnValue::StringRes(Bytes::from_static(b"1")),
+            ])
+        };
+        let pending = StreamOps::XPending(key.clone(), group.clone(), None);
+        assert_eq!(
+            stream_interact(pending.clone(), state.clone()).await,
+            ReturnValue::Array(vec![
+                ReturnValue::IntRes(2),
+                id_reply(1, 0),
+                id_reply(2, 0),
+                ReturnValue::Array(vec![consumer(b"a"), consumer(b"b")])
+            ])
+        );
+        let ack = StreamOps::XAck(
+            key.clone(),
+            group.clone(),
+            smallvec![StreamId::new(1, 0), StreamId::new(5, 0)],
+        );
+        assert_eq!(
+            stream_interact(ack, state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        let range = PendingRange {
+            min_idle: None,
+            start: StreamId::MIN,
+            end: StreamId::MAX,
+            count: 10,
+            consumer: None,
+        };
+        let extended = StreamOps::XPending(key.clone(), group.clone(), Some(range));
+        match stream_interact(extended, state.clone()).await {
+            ReturnValue::Array(pending) => match &pending[..] {
+                [ReturnValue::Array(entry)] => {
+                    assert_eq!(entry[0], id_reply(2, 0));
+                    assert_eq!(entry[1], ReturnValue::StringRes(Bytes::from_static(b"b")));
+                    assert_eq!(entry[3], ReturnValue::IntRes(1));
+                }
+                other => panic!("unexpected pending entries {:?}", other),
+            },
+            other => panic!("unexpected reply {:?}", other),
+        }
+        let claim = StreamOps::XClaim(
+            key.clone(),
+            group.clone(),
+            Bytes::from_static(b"a"),
+            smallvec![StreamId::new(2, 0)],
+            ClaimOptions {
+                just_id: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            stream_interact(claim, state.clone()).await,
+            ReturnValue::Array(vec![id_reply(2, 0)])
+        );
+        let destroy = || StreamOps::XGroupDestroy(key.clone(), group.clone());
+        assert_eq!(
+            stream_interact(destroy(), state.clone()).await,
+            ReturnValue::IntRes(1)
+        );
+        assert_eq!(
+            stream_interact(destroy(), state.clone()).await,
+            ReturnValue::IntRes(0)
+        );
+        assert!(stream_interact(pending, state.clone()).await.is_error());
+    }
+
+    #[tokio::test]
+    async fn test_blocking_read_group() {
+        let state: StateRef = Arc::new(State::default());
+        let (key, group) = (Bytes::from_static(b"s"), Bytes::from_static(b"g"));
+        let create =
+            StreamOps::XGroupCreate(key.clone(), group.clone(), ReadFrom::Last, true, None);
+        stream_interact(create, state.clone()).await;
+        let read = |block| {
+            StreamOps::XReadGroup(
+                group.clone(),
+                Bytes::from_static(b"c"),
+                smallvec![(key.clone(), GroupReadFrom::Undelivered)],
+                None,
+                Some(block),
+                false,
+            )
+        };
+        let reader = tokio::spawn(stream_interact(read(0), state.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        stream_interact(add("s", NewId::Sequence(1)), state.clone()).await;
+        assert_eq!(
+            reader.await.unwrap(),
+            ReturnValue::Array(vec![ReturnValue::Array(vec![
+                ReturnValue::StringRes(key.clone()),
+                ReturnValue::Array(vec![entry(1, 0)])
+            ])])
+        );
+        assert_eq!(
+            stream_interact(read(10), state.clone()).await,
+            ReturnValue::Nil
+        );
+    }
+}
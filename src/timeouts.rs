@@ -1,34 +1,23 @@
-use std::future::Future;
 use std::time::Duration;
 use tokio::time;
 
 use crate::blocking::{KeyBlocking, YieldingFn};
-use crate::data_structures::receipt_map::Receipt;
-use crate::types::{Key, ReturnValue, StateRef, UTimeout};
+use crate::data_structures::receipt_map::KeyTypes;
+use crate::types::{ReturnValue, StateRef};
 
-pub async fn blocking_key_timeout(
+/// Run `f` until it yields, retrying whenever one of `keys` is written.
+/// Gives up with nil after `wait`, or never if it's None.
+pub async fn blocking_keys_timeout(
     f: YieldingFn,
     state: StateRef,
-    key: Key,
-    seconds: UTimeout,
+    keys: Vec<KeyTypes>,
+    wait: Option<Duration>,
 ) -> ReturnValue {
     let receipt = state.get_receipt();
-    let kb = KeyBlocking::new(f, state.clone(), key.clone(), receipt);
-    timeout(kb, seconds, state, receipt).await
-}
-
-async fn timeout<T: Future<Output = ReturnValue>>(
-    fut: T,
-    secs: UTimeout,
-    state: StateRef,
-    receipt: Receipt,
-) -> ReturnValue {
-    match time::timeout(Duration::from_secs(secs as u64), fut).await {
-        Ok(ret) => ret,
-        Err(_) => {
-            let mut rm = state.reciept_map.lock();
-            rm.timeout_receipt(receipt);
-            ReturnValue::Nil
-        }
+    let kb = KeyBlocking::new(f, state, keys, receipt);
+    match wait {
+        // Timing out drops `kb`, which stops it waiting.
+        Some(wait) => time::timeout(wait, kb).await.unwrap_or(ReturnValue::Nil),
+        None => kb.await,
     }
 }
@@ -0,0 +1,164 @@
+/// Connection level bookkeeping for MULTI / EXEC / DISCARD / WATCH.
+use crate::data_structures::watch_map::Version;
+use crate::misc::MiscOps;
+use crate::ops::Ops;
+use crate::types::{Key, RedisValueRef, ReturnValue, StateRef};
+use bytes::Bytes;
+
+#[derive(Default)]
+pub struct Transaction {
+    /// Operations queued since MULTI, along with the command they came
+    /// from when it's needed for the append only file. `None` when not
+    /// in a transaction.
+    queued: Option<Vec<(Ops, Option<RedisValueRef>)>>,
+    /// Set when a command couldn't be queued. EXEC will refuse to run.
+    aborted: bool,
+    /// Watched keys, along with the version seen when WATCH was called.
+    watched: Vec<(StateRef, Key, Version)>,
+}
+
+impl Transaction {
+    pub fn in_multi(&self) -> bool {
+        self.queued.is_some()
+    }
+
+    pub fn multi(&mut self) -> ReturnValue {
+        if self.in_multi() {
+            return ReturnValue::Error(b"ERR MULTI calls can not be nested");
+        }
+        self.queued = Some(Vec::new());
+        ReturnValue::Ok
+    }
+
+    /// Queue an operation to be ran on EXEC.
+    pub fn queue(&mut self, op: Ops, command: Option<RedisValueRef>) -> ReturnValue {
+        if let Ops::Misc(MiscOps::Script(_)) | Ops::Misc(MiscOps::EmbeddedScript(..)) = op {
+            self.abort();
+            return ReturnValue::Error(b"ERR scripts are not allowed inside MULTI");
+        }
+        match self.queued.as_mut() {
+            Some(queued) => {
+                queued.push((op.without_blocking(), command));
+                ReturnValue::Ident(RedisValueRef::SimpleString(Bytes::from_static(b"QUEUED")))
+            }
+            None => ReturnValue::Error(b"ERR not in a transaction"),
+        }
+    }
+
+    /// Mark the transaction as failed. Used when a command fails to queue.
+    pub fn abort(&mut self) {
+        if self.in_multi() {
+            self.aborted = true;
+        }
+    }
+
+    pub fn discard(&mut self) -> ReturnValue {
+        if self.queued.take().is_none() {
+            return ReturnValue::Error(b"ERR DISCARD without MULTI");
+        }
+        self.aborted = false;
+        self.unwatch();
+        ReturnValue::Ok
+    }
+
+    pub fn watch(&mut self, state: &StateRef, keys: Vec<Key>) -> ReturnValue {
+        if self.in_multi() {
+            return ReturnValue::Error(b"ERR WATCH inside MULTI is not allowed");
+        }
+        for key in keys {
+            state.expire_if_needed(&key);
+            let version = state.watches.watch(key.clone());
+            self.watched.push((state.clone(), key, version));
+        }
+        ReturnValue::Ok
+    }
+
+    pub fn unwatch(&mut self) {
+        for (state, key, _) in self.watched.drain(..) {
+            state.watches.unwatch(&key);
+        }
+    }
+
+    /// Take the queued operations for EXEC.
+    ///
+    /// Returns the error EXEC should reply with if the transaction can't run.
+    pub fn take_for_exec(&mut self) -> Result<Vec<(Ops, Option<RedisValueRef>)>, ReturnValue> {
+        let queued = self
+            .queued
+            .take()
+            .ok_or(ReturnValue::Error(b"ERR EXEC without MULTI"))?;
+        if std::mem::take(&mut self.aborted) {
+            self.unwatch();
+            return Err(ReturnValue::Error(
+                b"EXECABORT Transaction discarded because of previous errors.",
+            ));
+        }
+        Ok(queued)
+    }
+
+    /// Check if any watched key was modified since WATCH.
+    ///
+    /// Only meaningful while holding the exec lock for writing.
+    pub fn watched_keys_changed(&self) -> bool {
+        self.watched.iter().any(|(state, key, version)| {
+            state.expire_if_needed(key);
+            state.watches.version(key) != Some(*version)
+        })
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.unwatch();
+    }
+}
+
+#[cfg(test)]
+mod test_transaction {
+    use crate::keys::{KeyOps, SetOptions};
+    use crate::ops::{op_interact, Ops};
+    use crate::transaction::Transaction;
+    use crate::types::{ReturnValue, State};
+    use bytes::Bytes;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_multi_discard() {
+        let mut tx = Transaction::default();
+        assert!(tx.discard().is_error());
+        assert_eq!(tx.multi(), ReturnValue::Ok);
+        assert!(tx.multi().is_error());
+        let op = Ops::Keys(KeyOps::Get(Bytes::from_static(b"key")));
+        assert!(!tx.queue(op, None).is_error());
+        assert_eq!(tx.discard(), ReturnValue::Ok);
+        assert!(!tx.in_multi());
+        assert!(tx.take_for_exec().is_err());
+    }
+
+    #[test]
+    fn test_aborted_transaction() {
+        let mut tx = Transaction::default();
+        tx.multi();
+        tx.abort();
+        assert!(tx.take_for_exec().is_err());
+        // The next transaction starts clean
+        tx.multi();
+        assert_eq!(tx.take_for_exec().map(|ops| ops.len()).ok(), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let key = Bytes::from_static(b"key");
+        let state = Arc::new(State::default());
+        let mut tx = Transaction::default();
+        tx.watch(&state, vec![key.clone()]);
+        let read = Ops::Keys(KeyOps::Get(key.clone()));
+        op_interact(read, state.clone()).await;
+        assert!(!tx.watched_keys_changed());
+        let write = Ops::Keys(KeyOps::Set(key.clone(), key.clone(), SetOptions::default()));
+        op_interact(write, state.clone()).await;
+        assert!(tx.watched_keys_changed());
+        tx.unwatch();
+        assert_eq!(state.watches.version(&key), None);
+    }
+}
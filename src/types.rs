@@ -3,17 +3,28 @@ use dashmap::DashMap;
 use growable_bloom_filter::GrowableBloom;
 use serde::{Deserialize, Serialize};
 /// Common Types in the project.
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::HashSet;
 use std::convert::From;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64};
 use std::sync::Arc;
 
 use parking_lot::{Mutex, RwLock};
-use std::fs::File;
+use std::path::PathBuf;
 
+use crate::aof::Aof;
+use crate::data_structures::expiry_index::ExpiryIndex;
+use crate::data_structures::key_locks::KeyLocks;
+use crate::data_structures::memory_tracker::MemoryTracker;
+use crate::data_structures::quicklist::QuickList;
 use crate::data_structures::receipt_map::RecieptMap;
+use crate::data_structures::scan_index::{ScanMap, ScanSet};
 use crate::data_structures::sorted_set::SortedSet;
 use crate::data_structures::stack::Stack;
+use crate::data_structures::stream::Stream;
+use crate::data_structures::watch_map::WatchMap;
+use crate::database::SavePoints;
+use crate::memory::EvictionPolicy;
+use crate::pubsub::Broker;
 
 /// These types are used by state and ops to actually perform useful work.
 pub type Value = Bytes;
@@ -24,14 +35,14 @@ pub type Count = i64;
 /// Index is used to represent indices in structures.
 pub type Index = i64;
 /// Score is used in sorted sets
-pub type Score = i64;
-/// Timeout unit
-pub type UTimeout = i64;
+pub type Score = f64;
 /// Bool type
 pub type RedisBool = i64;
+/// Unix time in milliseconds. Used for key expiry.
+pub type Timestamp = i64;
 
-/// DumpTimeoutUnitpe alias.
-pub type DumpFile = Arc<Mutex<File>>;
+/// Path of the dump file. Locked while saving or loading.
+pub type DumpFile = Arc<Mutex<PathBuf>>;
 
 /// RedisValueRef is the canonical type for values flowing
 /// through the system. Inputs are converted into RedisValues,
@@ -46,6 +57,18 @@ pub enum RedisValueRef {
     Array(Vec<RedisValueRef>),
     NullArray,
     NullBulkString,
+    // RESP3 types. Downgraded to RESP2 by the encoder when needed.
+    Map(Vec<(RedisValueRef, RedisValueRef)>),
+    Set(Vec<RedisValueRef>),
+    Double(f64),
+    Boolean(bool),
+    Null,
+    BigNumber(Bytes),
+    /// Format (e.g. `txt`) and contents.
+    VerbatimString(Bytes, Bytes),
+    /// Out of band data attached to the following value.
+    Attribute(Vec<(RedisValueRef, RedisValueRef)>, Box<RedisValueRef>),
+    Push(Vec<RedisValueRef>),
 }
 
 impl std::fmt::Debug for RedisValueRef {
@@ -78,6 +101,26 @@ impl std::fmt::Debug for RedisValueRef {
                 write!(f, ")")?;
                 Ok(())
             }
+            RedisValueRef::Map(pairs) => write!(f, "RedisValueRef::Map({:?})", pairs),
+            RedisValueRef::Set(items) => write!(f, "RedisValueRef::Set({:?})", items),
+            RedisValueRef::Double(d) => write!(f, "RedisValueRef::Double({:?})", d),
+            RedisValueRef::Boolean(b) => write!(f, "RedisValueRef::Boolean({:?})", b),
+            RedisValueRef::Null => write!(f, "RedisValueRef::Null"),
+            RedisValueRef::BigNumber(n) => write!(
+                f,
+                "RedisValueRef::BigNumber({:?})",
+                String::from_utf8_lossy(n)
+            ),
+            RedisValueRef::VerbatimString(format, s) => write!(
+                f,
+                "RedisValueRef::VerbatimString({:?}, {:?})",
+                String::from_utf8_lossy(format),
+                String::from_utf8_lossy(s)
+            ),
+            RedisValueRef::Attribute(attrs, value) => {
+                write!(f, "RedisValueRef::Attribute({:?}, {:?})", attrs, value)
+            }
+            RedisValueRef::Push(items) => write!(f, "RedisValueRef::Push({:?})", items),
         }
     }
 }
@@ -100,6 +143,9 @@ impl std::fmt::Debug for RedisValueRef {
 pub const NULL_BULK_STRING: &str = "$-1\r\n";
 pub const NULL_ARRAY: &str = "*-1\r\n";
 pub const EMPTY_ARRAY: &str = "*0\r\n";
+/// RESP3 has a single null type.
+pub const NULL: &str = "_\r\n";
+pub const WRONGTYPE: &[u8] = b"WRONGTYPE Operation against a key holding the wrong kind of value";
 
 use crate::ops::RVec;
 
@@ -112,6 +158,8 @@ pub enum ReturnValue {
     MultiStringRes(Vec<Value>),
     Array(Vec<ReturnValue>),
     IntRes(i64),
+    Double(f64),
+    Map(Vec<(ReturnValue, ReturnValue)>),
     Nil,
     Ident(RedisValueRef),
 }
@@ -154,17 +202,19 @@ impl ReturnValue {
 /// Canonical type for Key-Value storage.
 type KeyString = DashMap<Key, Value>;
 /// Canonical type for Key-Set storage.
-type KeySet = DashMap<Key, HashSet<Value>>;
+type KeySet = DashMap<Key, ScanSet>;
 /// Canonical type for Key-List storage.
-type KeyList = DashMap<Key, VecDeque<Value>>;
+type KeyList = DashMap<Key, QuickList>;
 /// Canonical type for Key-Hash storage.
-type KeyHash = DashMap<Key, HashMap<Key, Value>>;
+type KeyHash = DashMap<Key, ScanMap<Value>>;
 /// Canonical type for Key-Hash storage.
 type KeyZSet = DashMap<Key, SortedSet>;
 /// Canonical type for Key-Bloom storage.
 type KeyBloom = DashMap<Key, GrowableBloom>;
 type KeyStack = DashMap<Key, Stack<Value>>;
 type KeyHyperLogLog = DashMap<Key, amadeus_streaming::HyperLogLog<Value>>;
+/// Canonical type for Key-Stream storage.
+type KeyStream = DashMap<Key, Stream>;
 
 /// Top level database struct.
 /// Holds all StateRef dbs, and will hand them out on request.
@@ -174,11 +224,33 @@ pub struct StateStore {
     #[serde(skip)]
     pub commands_ran_since_save: AtomicU64,
     #[serde(skip)]
-    pub commands_threshold: u64,
+    pub save_points: SavePoints,
+    /// Unix time in seconds of the last successful save.
+    #[serde(skip)]
+    pub last_save: AtomicI64,
+    /// Set while a save is running.
+    #[serde(skip)]
+    pub saving: AtomicBool,
     #[serde(skip)]
     pub memory_only: bool,
     #[serde(skip)]
     pub foreign_functions: RwLock<HashSet<String>>,
+    /// Held for writing while a transaction runs, so other
+    /// connections can't interleave commands with it.
+    #[serde(skip)]
+    pub exec_lock: Arc<tokio::sync::RwLock<()>>,
+    /// Channel subscriptions. Shared by every db.
+    #[serde(skip)]
+    pub pubsub: Arc<Broker>,
+    /// Set when append only file persistence is enabled.
+    #[serde(skip)]
+    pub aof: Option<Arc<Aof>>,
+    /// Approximate memory limit in bytes, or 0 for no limit.
+    #[serde(skip)]
+    pub maxmemory: usize,
+    /// How keys are picked for eviction once over `maxmemory`.
+    #[serde(skip)]
+    pub maxmemory_policy: EvictionPolicy,
 }
 
 /// Reference type for `StateStore`
@@ -187,6 +259,37 @@ pub type StateStoreRef = Arc<StateStore>;
 /// Reference type for `State`
 pub type StateRef = Arc<State>;
 
+/// The kind of value a key holds. Every key holds a single kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueType {
+    String,
+    List,
+    Set,
+    Hash,
+    ZSet,
+    Bloom,
+    Stack,
+    HyperLogLog,
+    Stream,
+}
+
+impl ValueType {
+    /// The name reported by TYPE.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::String => "string",
+            ValueType::List => "list",
+            ValueType::Set => "set",
+            ValueType::Hash => "hash",
+            ValueType::ZSet => "zset",
+            ValueType::Bloom => "bloom",
+            ValueType::Stack => "stack",
+            ValueType::HyperLogLog => "hyperloglog",
+            ValueType::Stream => "stream",
+        }
+    }
+}
+
 /// The state stored by redis-proto. These fields are the ones
 /// used by the various datastructure files (keys.rs, etc)
 #[derive(Default, Serialize, Deserialize)]
@@ -207,8 +310,18 @@ pub struct State {
     pub stacks: KeyStack,
     #[serde(default)]
     pub hyperloglogs: KeyHyperLogLog,
+    #[serde(default)]
+    pub streams: KeyStream,
+    #[serde(default)]
+    pub expirations: ExpiryIndex,
     #[serde(skip)]
     pub reciept_map: Mutex<RecieptMap>,
+    #[serde(skip)]
+    pub watches: WatchMap,
+    #[serde(skip)]
+    pub memory: MemoryTracker,
+    #[serde(skip)]
+    pub key_locks: KeyLocks,
 }
 
 /// Mapping of a ReturnValue to a RedisValueRef.
@@ -226,6 +339,13 @@ impl From<ReturnValue> for RedisValueRef {
             ReturnValue::Array(a) => {
                 RedisValueRef::Array(a.into_iter().map(RedisValueRef::from).collect())
             }
+            ReturnValue::Double(d) => RedisValueRef::Double(d),
+            ReturnValue::Map(pairs) => RedisValueRef::Map(
+                pairs
+                    .into_iter()
+                    .map(|(k, v)| (RedisValueRef::from(k), RedisValueRef::from(v)))
+                    .collect(),
+            ),
             ReturnValue::Ident(r) => r,
         }
     }